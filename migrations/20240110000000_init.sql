@@ -0,0 +1,64 @@
+-- Initial schema for the SQL backend. Kept to types and syntax shared by
+-- SQLite and Postgres: ids are TEXT uuids, timestamps RFC 3339 TEXT.
+
+CREATE TABLE users (
+    uid TEXT PRIMARY KEY,
+    email TEXT NOT NULL UNIQUE,
+    password TEXT NOT NULL,
+    role TEXT NOT NULL,
+    name TEXT NOT NULL
+);
+
+CREATE INDEX users_role ON users (role);
+
+CREATE TABLE user_devices (
+    user_id TEXT NOT NULL REFERENCES users (uid),
+    token TEXT NOT NULL,
+    PRIMARY KEY (user_id, token)
+);
+
+CREATE TABLE students_parents (
+    student_id TEXT NOT NULL REFERENCES users (uid),
+    parent_id TEXT NOT NULL REFERENCES users (uid),
+    PRIMARY KEY (student_id, parent_id)
+);
+
+CREATE INDEX students_parents_parent_id ON students_parents (parent_id);
+
+CREATE TABLE courses (
+    id TEXT PRIMARY KEY,
+    title TEXT NOT NULL,
+    content TEXT NOT NULL,
+    teacher_id TEXT NOT NULL REFERENCES users (uid)
+);
+
+CREATE INDEX courses_teacher_id ON courses (teacher_id);
+
+CREATE TABLE enrollments (
+    id TEXT PRIMARY KEY,
+    course_id TEXT NOT NULL REFERENCES courses (id),
+    student_id TEXT NOT NULL REFERENCES users (uid),
+    UNIQUE (student_id, course_id)
+);
+
+CREATE INDEX enrollments_course_id ON enrollments (course_id);
+
+CREATE TABLE messages (
+    id TEXT PRIMARY KEY,
+    sender_id TEXT NOT NULL REFERENCES users (uid),
+    subject TEXT,
+    content TEXT NOT NULL,
+    state TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX messages_sender_id ON messages (sender_id, created_at);
+
+CREATE TABLE message_receivers (
+    message_id TEXT NOT NULL REFERENCES messages (id),
+    receiver TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    PRIMARY KEY (message_id, receiver)
+);
+
+CREATE INDEX message_receivers_receiver ON message_receivers (receiver);
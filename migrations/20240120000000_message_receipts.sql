@@ -0,0 +1,12 @@
+-- Per-receiver delivery and read state. Existing receivers start from the
+-- state of their message.
+
+ALTER TABLE message_receivers ADD COLUMN state TEXT NOT NULL DEFAULT 'pending';
+ALTER TABLE message_receivers ADD COLUMN sent_at TEXT;
+ALTER TABLE message_receivers ADD COLUMN received_at TEXT;
+ALTER TABLE message_receivers ADD COLUMN read_at TEXT;
+
+UPDATE message_receivers
+SET state = (SELECT state FROM messages WHERE messages.id = message_receivers.message_id);
+
+CREATE INDEX message_receivers_unread ON message_receivers (receiver, state);
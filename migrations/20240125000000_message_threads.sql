@@ -0,0 +1,7 @@
+-- Replies point at the message they answer and at the first message of the
+-- conversation.
+
+ALTER TABLE messages ADD COLUMN thread_id TEXT REFERENCES messages (id);
+ALTER TABLE messages ADD COLUMN reply_to TEXT REFERENCES messages (id);
+
+CREATE INDEX messages_thread_id ON messages (thread_id, created_at);
@@ -0,0 +1,15 @@
+-- Push notifications owed for a message, written in the same transaction as
+-- the message and retried by the outbox worker.
+
+CREATE TABLE outbox (
+    message_id TEXT PRIMARY KEY REFERENCES messages (id),
+    state TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TEXT NOT NULL,
+    last_error TEXT,
+    -- JSON array of device tokens, NULL for every device of the receivers
+    retry_tokens TEXT,
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX outbox_due ON outbox (state, next_attempt_at);
@@ -0,0 +1,6 @@
+-- Per-user notification preferences, kept as JSON since they are only ever
+-- read and written whole, and the category a message notifies about.
+
+ALTER TABLE users ADD COLUMN preferences TEXT;
+
+ALTER TABLE messages ADD COLUMN category TEXT NOT NULL DEFAULT 'messages';
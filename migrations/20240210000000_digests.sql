@@ -0,0 +1,13 @@
+-- When each user's next digest is due and which of their kids' enrollments
+-- the previous one already reported.
+
+CREATE TABLE digest_schedules (
+    user_id TEXT PRIMARY KEY REFERENCES users (uid),
+    frequency TEXT NOT NULL,
+    next_digest_at TEXT NOT NULL,
+    last_digest_at TEXT NOT NULL,
+    -- JSON array of `student_id:course_id`
+    enrollments TEXT NOT NULL
+);
+
+CREATE INDEX digest_schedules_due ON digest_schedules (next_digest_at);
@@ -0,0 +1,4 @@
+-- Courses are archived instead of deleted, so enrollments and messages
+-- about them stay valid.
+
+ALTER TABLE courses ADD COLUMN archived_at TEXT;
@@ -0,0 +1,36 @@
+-- Course outline: modules holding ordered lessons, and the lessons each
+-- student completed. Links and materials are JSON arrays.
+
+CREATE TABLE course_modules (
+    id TEXT PRIMARY KEY,
+    course_id TEXT NOT NULL REFERENCES courses (id),
+    title TEXT NOT NULL,
+    position INTEGER NOT NULL
+);
+
+CREATE INDEX course_modules_course_id ON course_modules (course_id);
+
+CREATE TABLE lessons (
+    id TEXT PRIMARY KEY,
+    course_id TEXT NOT NULL REFERENCES courses (id),
+    module_id TEXT NOT NULL REFERENCES course_modules (id),
+    title TEXT NOT NULL,
+    body TEXT NOT NULL,
+    links TEXT NOT NULL,
+    materials TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    published INTEGER NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+CREATE INDEX lessons_course_id ON lessons (course_id);
+
+CREATE TABLE lesson_completions (
+    lesson_id TEXT NOT NULL REFERENCES lessons (id),
+    student_id TEXT NOT NULL REFERENCES users (uid),
+    course_id TEXT NOT NULL REFERENCES courses (id),
+    completed_at TEXT NOT NULL,
+    PRIMARY KEY (lesson_id, student_id)
+);
+
+CREATE INDEX lesson_completions_course_id ON lesson_completions (course_id);
@@ -0,0 +1,15 @@
+-- Uploaded files. The bytes live in the attachment storage, `refs` is a JSON
+-- array of the messages and lessons they were attached to.
+
+CREATE TABLE attachments (
+    id TEXT PRIMARY KEY,
+    owner_id TEXT NOT NULL REFERENCES users (uid),
+    file_name TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    created_at TEXT NOT NULL,
+    refs TEXT NOT NULL
+);
+
+-- JSON array of attachment ids
+ALTER TABLE messages ADD COLUMN attachments TEXT NOT NULL DEFAULT '[]';
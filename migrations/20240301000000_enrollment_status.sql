@@ -0,0 +1,8 @@
+-- Enrollments end instead of being deleted, so parents can see past
+-- courses. Older enrollments get the epoch as their start.
+
+ALTER TABLE enrollments ADD COLUMN status TEXT NOT NULL DEFAULT 'active';
+ALTER TABLE enrollments ADD COLUMN enrolled_at TEXT NOT NULL DEFAULT '1970-01-01T00:00:00.000000Z';
+ALTER TABLE enrollments ADD COLUMN ended_at TEXT;
+
+CREATE INDEX enrollments_student_id ON enrollments (student_id);
@@ -0,0 +1,13 @@
+-- Courses may limit their seats, students beyond the limit are waitlisted.
+-- Taken seats are counted apart from the courses, so claiming one is a
+-- single conditional update.
+
+ALTER TABLE courses ADD COLUMN capacity INTEGER;
+
+CREATE TABLE course_seats (
+    course_id TEXT PRIMARY KEY REFERENCES courses (id),
+    taken INTEGER NOT NULL
+);
+
+INSERT INTO course_seats (course_id, taken)
+    SELECT course_id, COUNT(*) FROM enrollments WHERE status = 'active' GROUP BY course_id;
@@ -0,0 +1,8 @@
+-- Courses may have enrollments wait for the teacher's approval and a
+-- parent's consent. Who gave them is kept on the enrollment.
+
+ALTER TABLE courses ADD COLUMN teacher_approval INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE courses ADD COLUMN parent_consent INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE enrollments ADD COLUMN approved_by TEXT REFERENCES users (uid);
+ALTER TABLE enrollments ADD COLUMN consented_by TEXT REFERENCES users (uid);
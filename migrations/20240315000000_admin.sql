@@ -0,0 +1,15 @@
+-- Admins deactivate accounts instead of deleting them, and every change
+-- they make to an account is logged.
+
+ALTER TABLE users ADD COLUMN deactivated_at TEXT;
+
+CREATE TABLE audit_log (
+    id TEXT PRIMARY KEY,
+    actor_id TEXT NOT NULL REFERENCES users (uid),
+    action TEXT NOT NULL,
+    target_id TEXT NOT NULL,
+    details TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX audit_log_target_id ON audit_log (target_id);
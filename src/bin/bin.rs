@@ -1,22 +1,47 @@
+use actix_web::error::ErrorInternalServerError;
 use actix_web::{dev::ServiceRequest, middleware, web, App, Error, HttpMessage, HttpServer};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
 use actix_web_httpauth::extractors::{bearer, AuthenticationError};
 use actix_web_httpauth::middleware::HttpAuthentication;
 use dotenv::dotenv;
-use edclass_lib::api::auth::{login, register_user, TokenClaims};
-use edclass_lib::api::course::{get_course, list_courses, list_my_courses};
-use edclass_lib::api::enrollment::enroll;
+use edclass_lib::api::admin::{
+    bulk_create_users, change_role, create_user, deactivate_user, import_users, link_student,
+    list_audit_log, list_users, reactivate_user, reset_password, unlink_student, ImportUsersBody,
+};
+use edclass_lib::api::attachment::{download_attachment, upload_attachment};
+use edclass_lib::api::auth::{login, logout, refresh, register_user};
+use edclass_lib::api::course::{
+    archive_course, create_course, get_course, list_courses, list_my_courses, update_course,
+};
+use edclass_lib::api::enrollment::{
+    approve_enrollment, bulk_enroll, complete_student, enroll, import_roster,
+    list_enrollment_requests, reject_enrollment, remove_student, unenroll,
+};
+use edclass_lib::api::events::stream_events;
 use edclass_lib::api::kid::get_kids;
+use edclass_lib::api::lesson::{
+    complete_lesson, create_lesson, create_module, delete_lesson, delete_module, publish_lesson,
+    reorder_lessons, reorder_modules, unpublish_lesson, update_lesson, update_module,
+};
 use edclass_lib::api::message::{
-    get_message, list_all, list_inbox, list_sent, send_message, update_message_state,
+    count_unread, get_message, get_thread, list_all, list_inbox, list_sent, reply_message,
+    send_message, update_message_state,
+};
+use edclass_lib::api::user::{get_preferences, update_devices, update_preferences};
+use edclass_lib::common::admin::{parse_user_import, try_import_users};
+use edclass_lib::common::digest::run_digest_worker;
+use edclass_lib::common::outbox::run_outbox_worker;
+use edclass_lib::common::store::{FirestoreStore, MemoryStore, SqlStore, Store};
+use edclass_lib::common::token::verify_access_token;
+use edclass_lib::common::user::{get_system_user, get_user_by_id};
+use edclass_lib::common::{
+    config_env_var, AttachmentStorage, FcmClient, LocalStorage, NotificationChannel, Notifier,
+    ObjectStorage, SmtpChannel,
 };
-use edclass_lib::api::user::update_devices;
-use edclass_lib::common::config_env_var;
 use firestore::{FirestoreDb, FirestoreResult};
-use hmac::{Hmac, Mac};
-use jwt::VerifyWithKey;
-use sha2::Sha256;
+use log::warn;
 use std::io::ErrorKind;
+use std::sync::Arc;
 use std::time::Duration;
 
 async fn setup_firestore_client() -> FirestoreResult<FirestoreDb> {
@@ -24,24 +49,147 @@ async fn setup_firestore_client() -> FirestoreResult<FirestoreDb> {
     FirestoreDb::new(&project_id).await
 }
 
+/// Picks the data backend from `STORE_BACKEND`: `firestore` (default), `sql`
+/// connecting to `DATABASE_URL`, or `memory` seeded from the JSON file in
+/// `FIXTURES` when set.
+async fn setup_store() -> std::io::Result<Arc<dyn Store>> {
+    let backend = std::env::var("STORE_BACKEND").unwrap_or_else(|_| "firestore".to_string());
+
+    match backend.as_str() {
+        "memory" => {
+            let store = match std::env::var("FIXTURES") {
+                Ok(path) => MemoryStore::from_fixtures_file(&path)
+                    .map_err(|e| std::io::Error::other(format!("{}: {}", path, e)))?,
+                Err(_) => MemoryStore::new(),
+            };
+            Ok(Arc::new(store))
+        }
+        "sql" => {
+            let url = config_env_var("DATABASE_URL").map_err(std::io::Error::other)?;
+            let store = SqlStore::connect(&url)
+                .await
+                .map_err(|e| std::io::Error::other(format!("{}: {}", url, e)))?;
+            Ok(Arc::new(store))
+        }
+        "firestore" => {
+            let firestore_db = setup_firestore_client()
+                .await
+                .map_err(|_| std::io::Error::other("failed to connect firestore"))?;
+            Ok(Arc::new(FirestoreStore::new(firestore_db)))
+        }
+        other => Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("unknown STORE_BACKEND {}", other),
+        )),
+    }
+}
+
+/// Keeps attachments in the object store bucket configured with
+/// `OBJECT_STORE_URL` when set, in the local `ATTACHMENTS_DIR` otherwise.
+fn setup_storage() -> std::io::Result<Arc<dyn AttachmentStorage>> {
+    if config_env_var("OBJECT_STORE_URL").is_err() {
+        return Ok(Arc::new(LocalStorage::from_env()));
+    }
+
+    let storage = ObjectStorage::from_env(reqwest::Client::new())
+        .map_err(|e| std::io::Error::other(format!("object store: {}", e)))?;
+    Ok(Arc::new(storage))
+}
+
+/// Push first, falling back to email for users without a registered device.
+/// Each channel is left out when it isn't configured.
+fn setup_notifier() -> Notifier {
+    let mut channels: Vec<Arc<dyn NotificationChannel>> = Vec::new();
+    match FcmClient::from_env(reqwest::Client::new()) {
+        Ok(fcm) => channels.push(Arc::new(fcm)),
+        Err(e) => warn!("push notifications disabled: {:?}", e),
+    }
+    match SmtpChannel::from_env() {
+        Ok(smtp) => channels.push(Arc::new(smtp)),
+        Err(e) => warn!("email notifications disabled: {:?}", e),
+    }
+
+    Notifier::new(channels)
+}
+
+const IMPORT_USAGE: &str = "usage: edclass_bin import-users <file> --output <report> [--dry-run]";
+
+/// `edclass_bin import-users <file> --output <report> [--dry-run]` creates
+/// the accounts in a CSV file, or a JSON one like the body of
+/// `POST /admin/users/bulk`, as the system user. The report holds the
+/// generated passwords, so it's only written to the `--output` file, readable
+/// by its owner alone; a dry run without one prints it.
+async fn run_user_import(db: &dyn Store, args: &[String]) -> std::io::Result<()> {
+    let usage = || std::io::Error::new(ErrorKind::InvalidInput, IMPORT_USAGE);
+    let (mut path, mut output, mut dry_run) = (None, None, false);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--output" => output = Some(args.next().ok_or_else(usage)?),
+            a if a.starts_with("--") || path.is_some() => return Err(usage()),
+            _ => path = Some(arg),
+        }
+    }
+    let path = path.ok_or_else(usage)?;
+    if output.is_none() && !dry_run {
+        return Err(usage());
+    }
+
+    let data = std::fs::read_to_string(path)?;
+    let users = match path.ends_with(".json") {
+        true => serde_json::from_str::<ImportUsersBody>(&data)?.users,
+        false => parse_user_import(&data)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e.to_string()))?,
+    };
+    let system = get_system_user(db).await.map_err(std::io::Error::other)?;
+    let report = try_import_users(db, &system, &users, dry_run)
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    let report = serde_json::to_string_pretty(&report)?;
+
+    match output {
+        Some(output) => {
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            std::io::Write::write_all(&mut options.open(output)?, report.as_bytes())
+        }
+        None => {
+            println!("{}", report);
+            Ok(())
+        }
+    }
+}
+
 async fn validator(
     req: ServiceRequest,
     credentials: BearerAuth,
 ) -> Result<ServiceRequest, (Error, ServiceRequest)> {
-    let jwt_secret: String = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set!");
-    let key: Hmac<Sha256> = Hmac::new_from_slice(jwt_secret.as_bytes()).unwrap();
     let token_string = credentials.token();
 
-    let claims: Result<TokenClaims, &str> = token_string
-        .verify_with_key(&key)
-        .map_err(|_| "Invalid token");
+    // rejects bad signatures as well as expired and revoked tokens, then loads
+    // the caller once for `AuthUser` and `RequireRole`, unless deactivated
+    let auth = match req.app_data::<web::Data<dyn Store>>() {
+        Some(db) => match verify_access_token(db.get_ref(), token_string).await {
+            Ok(Some(claims)) => get_user_by_id(db.get_ref(), &claims.id)
+                .await
+                .map(|user| user.filter(|u| u.is_active()).map(|u| (claims, u))),
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        },
+        None => Ok(None),
+    };
 
-    match claims {
-        Ok(value) => {
-            req.extensions_mut().insert(value);
+    match auth {
+        Ok(Some((claims, user))) => {
+            req.extensions_mut().insert(claims);
+            req.extensions_mut().insert(user);
             Ok(req)
         }
-        Err(_) => {
+        Err(e) => Err((ErrorInternalServerError(e), req)),
+        Ok(None) => {
             let config = req
                 .app_data::<bearer::Config>()
                 .cloned()
@@ -65,35 +213,97 @@ async fn main() -> std::io::Result<()> {
 
     env_logger::init();
 
-    let firestore_db = setup_firestore_client()
-        .await
-        .map_err(|_| std::io::Error::new(ErrorKind::Other, "failed to connect firestore"))?;
-
-    let http_client = reqwest::Client::new();
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    // writes the fields user listings query on to users stored before them
+    if args.first().is_some_and(|a| a == "index-users") {
+        let firestore_db = setup_firestore_client()
+            .await
+            .map_err(|_| std::io::Error::other("failed to connect firestore"))?;
+        let count = FirestoreStore::new(firestore_db)
+            .index_users()
+            .await
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        println!("indexed {} users", count);
+        return Ok(());
+    }
+    let store = setup_store().await?;
+    if args.first().is_some_and(|a| a == "import-users") {
+        return run_user_import(store.as_ref(), &args[1..]).await;
+    }
+    let notifier = setup_notifier();
+    let storage = setup_storage()?;
+    // retries notifications that couldn't be delivered when the message was sent
+    actix_web::rt::spawn(run_outbox_worker(store.clone(), notifier.clone()));
+    // sends the daily and weekly digests users asked for
+    actix_web::rt::spawn(run_digest_worker(store.clone(), notifier.clone()));
 
     HttpServer::new(move || {
         let bearer = HttpAuthentication::bearer(validator);
         App::new()
             .wrap(middleware::Logger::default())
-            .app_data(web::Data::new(firestore_db.clone()))
-            .app_data(web::Data::new(http_client.clone()))
+            .app_data(web::Data::from(store.clone()))
+            .app_data(web::Data::new(notifier.clone()))
+            .app_data(web::Data::from(storage.clone()))
             .service(register_user)
             .service(login)
+            .service(refresh)
             .service(
                 web::scope("")
                     .wrap(bearer)
+                    .service(logout)
                     .service(send_message)
                     .service(list_inbox)
                     .service(list_sent)
                     .service(list_all)
+                    .service(count_unread)
                     .service(get_message)
                     .service(update_message_state)
+                    .service(reply_message)
+                    .service(get_thread)
                     .service(update_devices)
+                    .service(get_preferences)
+                    .service(update_preferences)
                     .service(list_courses)
                     .service(list_my_courses)
                     .service(get_course)
+                    .service(create_course)
+                    .service(update_course)
+                    .service(archive_course)
+                    .service(create_module)
+                    .service(reorder_modules)
+                    .service(update_module)
+                    .service(delete_module)
+                    .service(create_lesson)
+                    .service(reorder_lessons)
+                    .service(update_lesson)
+                    .service(delete_lesson)
+                    .service(publish_lesson)
+                    .service(unpublish_lesson)
+                    .service(complete_lesson)
                     .service(get_kids)
-                    .service(enroll),
+                    .service(enroll)
+                    .service(list_enrollment_requests)
+                    .service(approve_enrollment)
+                    .service(reject_enrollment)
+                    .service(unenroll)
+                    .service(remove_student)
+                    .service(bulk_enroll)
+                    .service(import_roster)
+                    .service(complete_student)
+                    .service(stream_events)
+                    .service(upload_attachment)
+                    .service(download_attachment)
+                    .service(list_users)
+                    .service(create_user)
+                    .service(bulk_create_users)
+                    .service(import_users)
+                    .service(change_role)
+                    .service(reset_password)
+                    .service(deactivate_user)
+                    .service(reactivate_user)
+                    .service(link_student)
+                    .service(unlink_student)
+                    .service(list_audit_log),
             )
     })
     .keep_alive(Duration::from_secs(75))
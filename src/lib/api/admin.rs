@@ -0,0 +1,257 @@
+use crate::api::guard::{AuthUser, RequireRole};
+use crate::common::admin::{self, AdminError, ImportUser};
+use crate::common::store::Store;
+use crate::common::user::UserQuery;
+use crate::common::{UserRole, DEFAULT_USERS_PAGE_SIZE, MAX_USERS_PAGE_SIZE};
+use crate::result_match;
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, post, put, web, HttpResponse, Responder, ResponseError};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+impl ResponseError for AdminError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AdminError::Invalid(_) => StatusCode::BAD_REQUEST,
+            AdminError::NotFound(_) => StatusCode::NOT_FOUND,
+            AdminError::Conflict(_) => StatusCode::CONFLICT,
+            AdminError::Store(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({"error": self.to_string()}))
+    }
+}
+
+/// Query string of the user listing, e.g. `?q=smith&role=parent&active=true`.
+#[derive(Debug, Deserialize)]
+pub struct ListUsersParams {
+    q: Option<String>,
+    role: Option<UserRole>,
+    active: Option<bool>,
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+#[get("/admin/users", wrap = "RequireRole::new(&[UserRole::Admin])")]
+pub async fn list_users(
+    db: web::Data<dyn Store>,
+    params: web::Query<ListUsersParams>,
+) -> impl Responder {
+    let params = params.into_inner();
+    let query = UserQuery {
+        search: params.q.filter(|q| !q.trim().is_empty()),
+        role: params.role,
+        active: params.active,
+        after: params.cursor,
+        limit: params
+            .limit
+            .unwrap_or(DEFAULT_USERS_PAGE_SIZE)
+            .clamp(1, MAX_USERS_PAGE_SIZE),
+    };
+
+    let res = admin::try_list_users(db.get_ref(), &query).await;
+    result_match!(res)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateUserBody {
+    pub name: String,
+    pub email: String,
+    // generated and returned once when left out
+    #[serde(default)]
+    pub password: Option<String>,
+    pub role: UserRole,
+    // ids of the students of a parent
+    #[serde(default)]
+    pub students: Option<Vec<Uuid>>,
+}
+
+#[post("/admin/users", wrap = "RequireRole::new(&[UserRole::Admin])")]
+pub async fn create_user(
+    db: web::Data<dyn Store>,
+    u: AuthUser,
+    body: web::Json<CreateUserBody>,
+) -> impl Responder {
+    match admin::try_create_user(db.get_ref(), &u, body.into_inner()).await {
+        Ok(created) => HttpResponse::Ok().json(created),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// `?dry_run=true` checks an import without creating anyone.
+#[derive(Debug, Deserialize)]
+pub struct ImportParams {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportUsersBody {
+    pub users: Vec<ImportUser>,
+}
+
+/// Creates the accounts in `{"users": [...]}` and reports each row, with the
+/// generated passwords.
+#[post("/admin/users/bulk", wrap = "RequireRole::new(&[UserRole::Admin])")]
+pub async fn bulk_create_users(
+    db: web::Data<dyn Store>,
+    u: AuthUser,
+    params: web::Query<ImportParams>,
+    body: web::Json<ImportUsersBody>,
+) -> impl Responder {
+    match admin::try_import_users(db.get_ref(), &u, &body.users, params.dry_run).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// Like `bulk_create_users`, with the accounts in a CSV body that has
+/// `name`, `email` and `role` columns, and optionally `password` and
+/// `students`.
+#[post("/admin/users/import", wrap = "RequireRole::new(&[UserRole::Admin])")]
+pub async fn import_users(
+    db: web::Data<dyn Store>,
+    u: AuthUser,
+    params: web::Query<ImportParams>,
+    body: String,
+) -> impl Responder {
+    let users = match admin::parse_user_import(&body) {
+        Ok(users) => users,
+        Err(e) => return e.error_response(),
+    };
+    match admin::try_import_users(db.get_ref(), &u, &users, params.dry_run).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoleBody {
+    role: UserRole,
+}
+
+#[put(
+    "/admin/users/{user_id}/role",
+    wrap = "RequireRole::new(&[UserRole::Admin])"
+)]
+pub async fn change_role(
+    db: web::Data<dyn Store>,
+    u: AuthUser,
+    path: web::Path<Uuid>,
+    body: web::Json<RoleBody>,
+) -> impl Responder {
+    match admin::try_change_role(db.get_ref(), &u, &path, body.role).await {
+        Ok(user) => HttpResponse::Ok().json(user),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct PasswordBody {
+    #[serde(default)]
+    password: Option<String>,
+}
+
+/// Sets the password from the body, or a generated one that's returned as
+/// `temporary_password`. The user is logged out everywhere.
+#[post(
+    "/admin/users/{user_id}/password",
+    wrap = "RequireRole::new(&[UserRole::Admin])"
+)]
+pub async fn reset_password(
+    db: web::Data<dyn Store>,
+    u: AuthUser,
+    path: web::Path<Uuid>,
+    body: Option<web::Json<PasswordBody>>,
+) -> impl Responder {
+    let password = body.map(|b| b.into_inner()).unwrap_or_default().password;
+    match admin::try_reset_password(db.get_ref(), &u, &path, password).await {
+        Ok(temporary) => HttpResponse::Ok().json(json!({ "temporary_password": temporary })),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post(
+    "/admin/users/{user_id}/deactivate",
+    wrap = "RequireRole::new(&[UserRole::Admin])"
+)]
+pub async fn deactivate_user(
+    db: web::Data<dyn Store>,
+    u: AuthUser,
+    path: web::Path<Uuid>,
+) -> impl Responder {
+    match admin::try_set_active(db.get_ref(), &u, &path, false).await {
+        Ok(user) => HttpResponse::Ok().json(user),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post(
+    "/admin/users/{user_id}/reactivate",
+    wrap = "RequireRole::new(&[UserRole::Admin])"
+)]
+pub async fn reactivate_user(
+    db: web::Data<dyn Store>,
+    u: AuthUser,
+    path: web::Path<Uuid>,
+) -> impl Responder {
+    match admin::try_set_active(db.get_ref(), &u, &path, true).await {
+        Ok(user) => HttpResponse::Ok().json(user),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post(
+    "/admin/users/{parent_id}/students/{student_id}",
+    wrap = "RequireRole::new(&[UserRole::Admin])"
+)]
+pub async fn link_student(
+    db: web::Data<dyn Store>,
+    u: AuthUser,
+    path: web::Path<(Uuid, Uuid)>,
+) -> impl Responder {
+    let (parent_id, student_id) = path.into_inner();
+    match admin::try_link_student(db.get_ref(), &u, &parent_id, &student_id).await {
+        Ok(_) => HttpResponse::Ok().json(json!({"success": true})),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[delete(
+    "/admin/users/{parent_id}/students/{student_id}",
+    wrap = "RequireRole::new(&[UserRole::Admin])"
+)]
+pub async fn unlink_student(
+    db: web::Data<dyn Store>,
+    u: AuthUser,
+    path: web::Path<(Uuid, Uuid)>,
+) -> impl Responder {
+    let (parent_id, student_id) = path.into_inner();
+    match admin::try_unlink_student(db.get_ref(), &u, &parent_id, &student_id).await {
+        Ok(_) => HttpResponse::Ok().json(json!({"success": true})),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// `?target_id=...&limit=50`, newest first.
+#[derive(Debug, Deserialize)]
+pub struct AuditLogParams {
+    target_id: Option<Uuid>,
+    limit: Option<usize>,
+}
+
+#[get("/admin/audit", wrap = "RequireRole::new(&[UserRole::Admin])")]
+pub async fn list_audit_log(
+    db: web::Data<dyn Store>,
+    params: web::Query<AuditLogParams>,
+) -> impl Responder {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_USERS_PAGE_SIZE)
+        .clamp(1, MAX_USERS_PAGE_SIZE);
+    let res = admin::list_audit_log(db.get_ref(), params.target_id.as_ref(), limit).await;
+    result_match!(res)
+}
@@ -0,0 +1,108 @@
+use crate::api::guard::AuthUser;
+use crate::common::attachment::{try_download_attachment, try_upload_attachment, AttachmentError};
+use crate::common::store::Store;
+use crate::common::{AttachmentStorage, ATTACHMENT_MAX_SIZE};
+use actix_web::http::header::{self, ContentDisposition, DispositionParam, DispositionType};
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder, ResponseError};
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+impl ResponseError for AttachmentError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AttachmentError::Invalid(_) => StatusCode::BAD_REQUEST,
+            AttachmentError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            AttachmentError::UnsupportedType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AttachmentError::NotFound => StatusCode::NOT_FOUND,
+            AttachmentError::Forbidden => StatusCode::FORBIDDEN,
+            AttachmentError::Store(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({"error": self.to_string()}))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UploadQuery {
+    pub file_name: String,
+}
+
+/// Reads the request body, giving up as soon as it's over the size limit.
+async fn read_body(mut payload: web::Payload) -> Result<Vec<u8>, AttachmentError> {
+    let mut data = Vec::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| AttachmentError::Invalid(e.to_string()))?;
+        if data.len() + chunk.len() > ATTACHMENT_MAX_SIZE {
+            return Err(AttachmentError::TooLarge);
+        }
+        data.extend_from_slice(&chunk);
+    }
+
+    Ok(data)
+}
+
+/// Uploads the request body as a file, e.g.
+/// `POST /attachments?file_name=worksheet.pdf` with `Content-Type:
+/// application/pdf`. Returns the attachment to reference in messages and
+/// lessons.
+#[post("/attachments")]
+pub async fn upload_attachment(
+    db: web::Data<dyn Store>,
+    storage: web::Data<dyn AttachmentStorage>,
+    user: AuthUser,
+    req: HttpRequest,
+    query: web::Query<UploadQuery>,
+    payload: web::Payload,
+) -> impl Responder {
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let data = match read_body(payload).await {
+        Ok(data) => data,
+        Err(e) => return e.error_response(),
+    };
+
+    let res = try_upload_attachment(
+        db.get_ref(),
+        storage.get_ref(),
+        &user,
+        &query.file_name,
+        &content_type,
+        data,
+    )
+    .await;
+    match res {
+        Ok(a) => HttpResponse::Created().json(a),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// The file, to its owner and whoever can see a message or lesson it's
+/// attached to.
+#[get("/attachments/{attachment_id}")]
+pub async fn download_attachment(
+    db: web::Data<dyn Store>,
+    storage: web::Data<dyn AttachmentStorage>,
+    user: AuthUser,
+    path: web::Path<Uuid>,
+) -> impl Responder {
+    match try_download_attachment(db.get_ref(), storage.get_ref(), &user, &path).await {
+        Ok((attachment, data)) => HttpResponse::Ok()
+            .content_type(attachment.content_type)
+            .insert_header(ContentDisposition {
+                disposition: DispositionType::Attachment,
+                parameters: vec![DispositionParam::Filename(attachment.file_name)],
+            })
+            .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+            .body(data),
+        Err(e) => e.error_response(),
+    }
+}
@@ -1,26 +1,30 @@
+use crate::common::store::Store;
+use crate::common::token::{issue_tokens, refresh_tokens, revoke_tokens};
 use crate::common::user::{make_user, save_user_to_db, try_find_user};
 use crate::common::{User, UserRole, UserWithPasswordStudents};
-use actix_web::web::{Data, Json};
+use actix_web::web::{Data, Json, ReqData};
 use actix_web::{get, post, HttpResponse, Responder};
 use actix_web_httpauth::extractors::basic::BasicAuth;
 use argonautica::Verifier;
-use firestore::FirestoreDb;
-use hmac::{Hmac, Mac};
-use jwt::SignWithKey;
 use log::debug;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use sha2::Sha256;
 use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct TokenClaims {
     pub id: Uuid,
+    // token id, used to revoke it before `exp`
+    pub jti: Uuid,
+    pub iat: i64,
+    pub exp: i64,
 }
 
 #[derive(Serialize, Clone)]
 pub struct AuthResponse {
     token: String,
+    refresh_token: String,
+    expires_in: i64,
     user: User,
 }
 
@@ -29,13 +33,7 @@ pub struct DeviceIdInfo {
     pub device_id: Option<String>,
 }
 #[get("/auth")]
-pub async fn login(db: Data<FirestoreDb>, credentials: BasicAuth) -> impl Responder {
-    let jwt_secret: Hmac<Sha256> = Hmac::new_from_slice(
-        std::env::var("JWT_SECRET")
-            .expect("JWT_SECRET must be set!")
-            .as_bytes(),
-    )
-    .unwrap();
+pub async fn login(db: Data<dyn Store>, credentials: BasicAuth) -> impl Responder {
     let username = credentials.user_id();
     let password = credentials.password();
 
@@ -44,7 +42,7 @@ pub async fn login(db: Data<FirestoreDb>, credentials: BasicAuth) -> impl Respon
     match password {
         None => HttpResponse::Unauthorized().json("Must provide username and password"),
         Some(pass) => {
-            let user_data = try_find_user(&db, username).await;
+            let user_data = try_find_user(db.get_ref(), username).await;
 
             match user_data {
                 Ok(Some(user)) => {
@@ -58,19 +56,27 @@ pub async fn login(db: Data<FirestoreDb>, credentials: BasicAuth) -> impl Respon
                         .verify()
                         .unwrap();
 
-                    if is_valid {
-                        let claims = TokenClaims { id: user.uid };
-                        let token_str = claims.sign_with_key(&jwt_secret).unwrap();
-                        HttpResponse::Ok().json(AuthResponse {
-                            user: User {
-                                role: user.role,
-                                email: user.email,
-                                name: user.name,
-                                uid: user.uid,
-                                devices: user.devices,
-                            },
-                            token: token_str,
-                        })
+                    if is_valid && user.deactivated_at.is_some() {
+                        HttpResponse::Forbidden().json(json!({"error": "account deactivated"}))
+                    } else if is_valid {
+                        match issue_tokens(db.get_ref(), &user.uid).await {
+                            Ok(pair) => HttpResponse::Ok().json(AuthResponse {
+                                user: User {
+                                    role: user.role,
+                                    email: user.email,
+                                    name: user.name,
+                                    uid: user.uid,
+                                    devices: user.devices,
+                                    preferences: user.preferences,
+                                    deactivated_at: user.deactivated_at,
+                                },
+                                token: pair.token,
+                                refresh_token: pair.refresh_token,
+                                expires_in: pair.expires_in,
+                            }),
+                            Err(e) => HttpResponse::InternalServerError()
+                                .json(json!({"error": format!("{:?}", e)})),
+                        }
                     } else {
                         HttpResponse::Unauthorized()
                             .json(json!({"error": "incorrect username or password"}))
@@ -93,12 +99,16 @@ pub struct RegisterUserBody {
 }
 
 #[post("/auth/register")]
-pub async fn register_user(db: Data<FirestoreDb>, info: Json<RegisterUserBody>) -> impl Responder {
+pub async fn register_user(db: Data<dyn Store>, info: Json<RegisterUserBody>) -> impl Responder {
     if info.password != info.confirm_password {
         return HttpResponse::NotAcceptable().json(json!({"error": "password do not match"}));
     }
+    // admins are only made by other admins, through `POST /admin/users`
+    if matches!(info.role, UserRole::Admin | UserRole::System) {
+        return HttpResponse::Forbidden().json(json!({"error": "role not allowed"}));
+    }
 
-    match try_find_user(&db, info.email.as_str()).await {
+    match try_find_user(db.get_ref(), info.email.as_str()).await {
         Ok(Some(_)) => {
             return HttpResponse::BadRequest().json(json!({"error": "user exists"}));
         }
@@ -109,7 +119,7 @@ pub async fn register_user(db: Data<FirestoreDb>, info: Json<RegisterUserBody>)
 
     let inner = info.into_inner();
     let next_user = make_user(
-        &db,
+        db.get_ref(),
         &UserWithPasswordStudents {
             password: inner.password,
             email: inner.email,
@@ -121,7 +131,7 @@ pub async fn register_user(db: Data<FirestoreDb>, info: Json<RegisterUserBody>)
     .await;
 
     match next_user {
-        Ok((user, kids)) => match save_user_to_db(&db, &user, kids).await {
+        Ok((user, kids)) => match save_user_to_db(db.get_ref(), &user, kids).await {
             Ok(_) => HttpResponse::Ok().json(user),
             Err(e) => {
                 HttpResponse::InternalServerError().json(json!({"error": format!("{:?}", e)}))
@@ -130,3 +140,143 @@ pub async fn register_user(db: Data<FirestoreDb>, info: Json<RegisterUserBody>)
         Err(e) => HttpResponse::InternalServerError().json(json!({"error": format!("{:?}", e)})),
     }
 }
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshBody {
+    refresh_token: String,
+}
+
+#[post("/auth/refresh")]
+pub async fn refresh(db: Data<dyn Store>, body: Json<RefreshBody>) -> impl Responder {
+    match refresh_tokens(db.get_ref(), &body.refresh_token).await {
+        Ok(Some(pair)) => HttpResponse::Ok().json(pair),
+        Ok(None) => HttpResponse::Unauthorized().json(json!({"error": "invalid refresh token"})),
+        Err(e) => HttpResponse::InternalServerError().json(json!({"error": format!("{:?}", e)})),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogoutBody {
+    refresh_token: Option<String>,
+}
+
+#[post("/auth/logout")]
+pub async fn logout(
+    db: Data<dyn Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Option<Json<LogoutBody>>,
+) -> impl Responder {
+    match req_user {
+        Some(claims) => {
+            let refresh_token = body.as_ref().and_then(|b| b.refresh_token.as_deref());
+            match revoke_tokens(db.get_ref(), &claims, refresh_token).await {
+                Ok(_) => HttpResponse::Ok().json(json!({"success": true})),
+                Err(e) => {
+                    HttpResponse::InternalServerError().json(json!({"error": format!("{:?}", e)}))
+                }
+            }
+        }
+        _ => HttpResponse::Unauthorized().json(json!({"error": "unable to verify identity"})),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::auth::register_user;
+    use crate::common::store::{MemoryStore, Store};
+    use crate::common::user::try_find_user;
+    use crate::common::{UserRole, UserWithPassword};
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App};
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_register_privileged_role_async() {
+        std::env::set_var("HASH_SECRET", "test-secret");
+        let store: Arc<dyn Store> = Arc::new(MemoryStore::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(store.clone()))
+                .service(register_user),
+        )
+        .await;
+
+        for role in ["admin", "system", "teacher"] {
+            let email = format!("{}@example.com", role);
+            let req = test::TestRequest::post()
+                .uri("/auth/register")
+                .set_json(json!({
+                    "name": role,
+                    "email": email,
+                    "password": "secret",
+                    "confirm_password": "secret",
+                    "role": role,
+                }))
+                .to_request();
+            let status = test::call_service(&app, req).await.status();
+            let user = store.find_user(&email).await.unwrap();
+            match role {
+                "teacher" => {
+                    assert_eq!(status, StatusCode::OK);
+                    assert!(user.is_some());
+                }
+                _ => {
+                    assert_eq!(status, StatusCode::FORBIDDEN);
+                    assert!(user.is_none());
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register_email_case_async() {
+        std::env::set_var("HASH_SECRET", "test-secret");
+        let store: Arc<dyn Store> = Arc::new(MemoryStore::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(store.clone()))
+                .service(register_user),
+        )
+        .await;
+
+        for (email, expected) in [
+            (" Foo@Example.com", StatusCode::OK),
+            ("foo@example.com", StatusCode::BAD_REQUEST),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/auth/register")
+                .set_json(json!({
+                    "name": "foo",
+                    "email": email,
+                    "password": "secret",
+                    "confirm_password": "secret",
+                    "role": "teacher",
+                }))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), expected);
+        }
+        let user = try_find_user(store.as_ref(), "FOO@example.com")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.email, "foo@example.com");
+
+        // stored before emails were normalized
+        let legacy = UserWithPassword {
+            uid: uuid::Uuid::new_v4(),
+            email: "Old@Example.com".to_string(),
+            password: "!".to_string(),
+            role: UserRole::Parent,
+            name: "old".to_string(),
+            devices: Vec::new(),
+            preferences: Default::default(),
+            deactivated_at: None,
+        };
+        store.insert_user(&legacy).await.unwrap();
+        let found = try_find_user(store.as_ref(), "Old@Example.com")
+            .await
+            .unwrap();
+        assert_eq!(found.map(|u| u.uid), Some(legacy.uid));
+    }
+}
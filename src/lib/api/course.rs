@@ -1,43 +1,126 @@
-use crate::api::auth::TokenClaims;
-use crate::common::{course, UserRole};
-use crate::{check_user, result_match, result_option_match};
-use actix_web::web::ReqData;
-use actix_web::{get, web, HttpResponse, Responder};
-use firestore::FirestoreDb;
+use crate::api::guard::{AuthUser, RequireRole};
+use crate::common::course::CourseError;
+use crate::common::store::Store;
+use crate::common::{course, Notifier, UserRole};
+use crate::{result_match, result_option_match};
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, post, put, web, HttpResponse, Responder, ResponseError};
+use serde::{Deserialize, Deserializer};
 use serde_json::json;
+use uuid::Uuid;
 
-#[get("/courses")]
-pub async fn list_courses(
-    db: web::Data<FirestoreDb>,
-    req_user: Option<ReqData<TokenClaims>>,
-) -> impl Responder {
-    check_user!(req_user, db, u, {
-        let res = course::list_courses(&db, &u).await;
-        result_match!(res)
-    })
+#[derive(Debug, Deserialize)]
+pub struct CourseBody {
+    pub title: String,
+    pub content: String,
+    // admins pick the teacher, teachers always teach their own courses
+    #[serde(default)]
+    pub teacher_id: Option<Uuid>,
+    // seats for students, `null` for unlimited; updates keep the capacity
+    // when left out
+    #[serde(default, deserialize_with = "nullable")]
+    pub capacity: Option<Option<usize>>,
+    // enrollments wait for the teacher's approval and a parent's consent,
+    // off for new courses and unchanged by updates when left out
+    #[serde(default)]
+    pub teacher_approval: Option<bool>,
+    #[serde(default)]
+    pub parent_consent: Option<bool>,
 }
 
-#[get("/courses/my")]
-pub async fn list_my_courses(
-    db: web::Data<FirestoreDb>,
-    req_user: Option<ReqData<TokenClaims>>,
-) -> impl Responder {
-    check_user!(req_user, db, u, {
-        if u.role != UserRole::Teacher {
-            return HttpResponse::Unauthorized().json(json!({"error": "unauthorized"}));
+// tells a `null` (`Some(None)`) apart from a field left out (`None`)
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+impl ResponseError for CourseError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            CourseError::Invalid(_) => StatusCode::BAD_REQUEST,
+            CourseError::NotFound(_) => StatusCode::NOT_FOUND,
+            CourseError::Forbidden => StatusCode::FORBIDDEN,
+            CourseError::Archived | CourseError::Conflict(_) => StatusCode::CONFLICT,
+            CourseError::Store(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
-        let res = course::list_my_courses(&db, &u).await;
-        result_match!(res)
-    })
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({"error": self.to_string()}))
+    }
+}
+
+#[get("/courses")]
+pub async fn list_courses(db: web::Data<dyn Store>, user: AuthUser) -> impl Responder {
+    let res = course::list_courses(db.get_ref(), &user).await;
+    result_match!(res)
+}
+
+#[get("/courses/my", wrap = "RequireRole::new(&[UserRole::Teacher])")]
+pub async fn list_my_courses(db: web::Data<dyn Store>, user: AuthUser) -> impl Responder {
+    let res = course::list_my_courses(db.get_ref(), &user).await;
+    result_match!(res)
 }
 #[get("/courses/{course_id}")]
 pub async fn get_course(
-    db: web::Data<FirestoreDb>,
-    req_user: Option<ReqData<TokenClaims>>,
+    db: web::Data<dyn Store>,
+    user: AuthUser,
     path: web::Path<String>,
 ) -> impl Responder {
-    check_user!(req_user, db, u, {
-        let res = course::get_course(&db, &u, path.as_str()).await;
-        result_option_match!(res)
-    })
+    let res = course::get_course(db.get_ref(), &user, path.as_str()).await;
+    result_option_match!(res)
+}
+
+#[post(
+    "/courses",
+    wrap = "RequireRole::new(&[UserRole::Teacher, UserRole::Admin])"
+)]
+pub async fn create_course(
+    db: web::Data<dyn Store>,
+    user: AuthUser,
+    body: web::Json<CourseBody>,
+) -> impl Responder {
+    match course::try_create_course(db.get_ref(), &user, body.into_inner()).await {
+        Ok(c) => HttpResponse::Created().json(c),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[put(
+    "/courses/{course_id}",
+    wrap = "RequireRole::new(&[UserRole::Teacher, UserRole::Admin])"
+)]
+pub async fn update_course(
+    db: web::Data<dyn Store>,
+    notifier: web::Data<Notifier>,
+    user: AuthUser,
+    path: web::Path<Uuid>,
+    body: web::Json<CourseBody>,
+) -> impl Responder {
+    let res =
+        course::try_update_course(db.get_ref(), &notifier, &user, &path, body.into_inner()).await;
+    match res {
+        Ok(c) => HttpResponse::Ok().json(c),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// Archives the course, it isn't deleted.
+#[delete(
+    "/courses/{course_id}",
+    wrap = "RequireRole::new(&[UserRole::Teacher, UserRole::Admin])"
+)]
+pub async fn archive_course(
+    db: web::Data<dyn Store>,
+    notifier: web::Data<Notifier>,
+    user: AuthUser,
+    path: web::Path<Uuid>,
+) -> impl Responder {
+    match course::try_archive_course(db.get_ref(), &notifier, &user, &path).await {
+        Ok(c) => HttpResponse::Ok().json(c),
+        Err(e) => e.error_response(),
+    }
 }
@@ -1,17 +1,10 @@
-use crate::api::auth::TokenClaims;
-use crate::api::message::MessageBody;
-use crate::check_user;
-use crate::common::course::get_teacher;
+use crate::api::guard::{AuthUser, RequireRole};
 use crate::common::enrollment;
-use crate::common::message::try_send_messages;
-use crate::common::user::{get_system_user, try_get_student_parents};
-use crate::common::{Enrollment, UserRole};
-use actix_web::web::ReqData;
-use actix_web::{post, web, HttpResponse, Responder};
-use firestore::FirestoreDb;
-use log::debug;
+use crate::common::store::Store;
+use crate::common::{EnrollmentStatus, Notifier, UserRole};
+use crate::result_match;
+use actix_web::{delete, get, post, web, HttpResponse, Responder, ResponseError};
 use serde::Deserialize;
-use serde_json::json;
 use uuid::Uuid;
 
 #[derive(Debug, Deserialize)]
@@ -19,73 +12,182 @@ pub struct EnrollmentBody {
     course_id: Uuid,
 }
 
-#[post("/enrollment")]
+#[post("/enrollment", wrap = "RequireRole::new(&[UserRole::Student])")]
 pub async fn enroll(
-    db: web::Data<FirestoreDb>,
-    http: web::Data<reqwest::Client>,
-    req_user: Option<ReqData<TokenClaims>>,
+    db: web::Data<dyn Store>,
+    notifier: web::Data<Notifier>,
+    u: AuthUser,
     data: web::Json<EnrollmentBody>,
 ) -> impl Responder {
-    check_user!(req_user, db, u, {
-        if u.role != UserRole::Student {
-            return HttpResponse::BadRequest()
-                .json(json!({"error": "only student can enroll for a course"}));
-        }
+    match enrollment::try_enroll(db.get_ref(), &notifier, &u, &data.course_id).await {
+        Ok(e) => HttpResponse::Ok().json(e),
+        Err(e) => e.error_response(),
+    }
+}
 
-        let data = Enrollment {
-            id: Uuid::new_v4(),
-            course_id: data.course_id,
-            student_id: u.uid,
-        };
+/// The calling student drops the course.
+#[delete(
+    "/enrollment/{course_id}",
+    wrap = "RequireRole::new(&[UserRole::Student])"
+)]
+pub async fn unenroll(
+    db: web::Data<dyn Store>,
+    notifier: web::Data<Notifier>,
+    u: AuthUser,
+    path: web::Path<Uuid>,
+) -> impl Responder {
+    match enrollment::try_drop_course(db.get_ref(), &notifier, &u, &path).await {
+        Ok(e) => HttpResponse::Ok().json(e),
+        Err(e) => e.error_response(),
+    }
+}
 
-        match enrollment::enroll(&db, &data).await {
-            Ok(_) => {
-                let parents = try_get_student_parents(&db, &u.uid).await;
-                let teacher = get_teacher(&db, &data.course_id).await;
+#[derive(Debug, Deserialize)]
+pub struct BulkEnrollmentBody {
+    emails: Vec<String>,
+}
 
-                match parents {
-                    Ok(p) => {
-                        let mut parents_email =
-                            p.iter().map(|pp| pp.email.to_string()).collect::<Vec<_>>();
+/// Enrolls students by email, e.g. `{"emails": ["ann@school.com"]}`, and
+/// reports what became of each of them.
+#[post(
+    "/courses/{course_id}/students",
+    wrap = "RequireRole::new(&[UserRole::Teacher, UserRole::Admin])"
+)]
+pub async fn bulk_enroll(
+    db: web::Data<dyn Store>,
+    notifier: web::Data<Notifier>,
+    u: AuthUser,
+    path: web::Path<Uuid>,
+    data: web::Json<BulkEnrollmentBody>,
+) -> impl Responder {
+    let res = enrollment::try_bulk_enroll(db.get_ref(), &notifier, &u, &path, &data.emails).await;
+    match res {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => e.error_response(),
+    }
+}
 
-                        if let Ok(t) = teacher {
-                            parents_email.extend_from_slice(&t.devices);
-                        }
+/// Like `bulk_enroll`, with the students in a CSV body that has an `email`
+/// column, or only emails.
+#[post(
+    "/courses/{course_id}/roster",
+    wrap = "RequireRole::new(&[UserRole::Teacher, UserRole::Admin])"
+)]
+pub async fn import_roster(
+    db: web::Data<dyn Store>,
+    notifier: web::Data<Notifier>,
+    u: AuthUser,
+    path: web::Path<Uuid>,
+    body: String,
+) -> impl Responder {
+    let emails = match enrollment::parse_roster(&body) {
+        Ok(e) => e,
+        Err(e) => return e.error_response(),
+    };
+    match enrollment::try_bulk_enroll(db.get_ref(), &notifier, &u, &path, &emails).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => e.error_response(),
+    }
+}
 
-                        let sys = get_system_user(&db).await;
-                        debug!("sys user {:?}", sys);
-                        match sys {
-                            Ok(s) => {
-                                let _send = try_send_messages(
-                                    &db,
-                                    &http,
-                                    &s,
-                                    MessageBody {
-                                        subject: Some("Enrollment".to_string()),
-                                        receiver_ids: parents_email,
-                                        content: format!(
-                                            "Your kid is enrolled in course {:?}",
-                                            &data
-                                        ),
-                                    },
-                                )
-                                .await;
-                            }
-                            Err(_e) => {
-                                //
-                            }
-                        }
-                    }
-                    _ => {
-                        debug!("failed to find parents for user {:?}", &u);
-                    }
-                }
-                //send_notification_to_emails(&db, &http, &);
-                HttpResponse::Ok().json(data)
-            }
-            Err(e) => {
-                HttpResponse::InternalServerError().json(json!({"error": format!("{:?}", e)}))
-            }
-        }
-    })
+#[delete(
+    "/courses/{course_id}/students/{student_id}",
+    wrap = "RequireRole::new(&[UserRole::Teacher, UserRole::Admin])"
+)]
+pub async fn remove_student(
+    db: web::Data<dyn Store>,
+    notifier: web::Data<Notifier>,
+    u: AuthUser,
+    path: web::Path<(Uuid, Uuid)>,
+) -> impl Responder {
+    let (course_id, student_id) = path.into_inner();
+    let res = enrollment::try_end_student_enrollment(
+        db.get_ref(),
+        &notifier,
+        &u,
+        &course_id,
+        &student_id,
+        EnrollmentStatus::Removed,
+    )
+    .await;
+    match res {
+        Ok(e) => HttpResponse::Ok().json(e),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post(
+    "/courses/{course_id}/students/{student_id}/complete",
+    wrap = "RequireRole::new(&[UserRole::Teacher, UserRole::Admin])"
+)]
+pub async fn complete_student(
+    db: web::Data<dyn Store>,
+    notifier: web::Data<Notifier>,
+    u: AuthUser,
+    path: web::Path<(Uuid, Uuid)>,
+) -> impl Responder {
+    let (course_id, student_id) = path.into_inner();
+    let res = enrollment::try_end_student_enrollment(
+        db.get_ref(),
+        &notifier,
+        &u,
+        &course_id,
+        &student_id,
+        EnrollmentStatus::Completed,
+    )
+    .await;
+    match res {
+        Ok(e) => HttpResponse::Ok().json(e),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// Pending enrollments waiting for the caller's approval.
+#[get(
+    "/enrollment/requests",
+    wrap = "RequireRole::new(&[UserRole::Teacher, UserRole::Admin, UserRole::Parent])"
+)]
+pub async fn list_enrollment_requests(db: web::Data<dyn Store>, u: AuthUser) -> impl Responder {
+    let res = enrollment::list_enrollment_requests(db.get_ref(), &u).await;
+    result_match!(res)
+}
+
+#[post(
+    "/enrollment/{course_id}/students/{student_id}/approve",
+    wrap = "RequireRole::new(&[UserRole::Teacher, UserRole::Admin, UserRole::Parent])"
+)]
+pub async fn approve_enrollment(
+    db: web::Data<dyn Store>,
+    notifier: web::Data<Notifier>,
+    u: AuthUser,
+    path: web::Path<(Uuid, Uuid)>,
+) -> impl Responder {
+    let (course_id, student_id) = path.into_inner();
+    let res =
+        enrollment::try_approve_enrollment(db.get_ref(), &notifier, &u, &course_id, &student_id)
+            .await;
+    match res {
+        Ok(e) => HttpResponse::Ok().json(e),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post(
+    "/enrollment/{course_id}/students/{student_id}/reject",
+    wrap = "RequireRole::new(&[UserRole::Teacher, UserRole::Admin, UserRole::Parent])"
+)]
+pub async fn reject_enrollment(
+    db: web::Data<dyn Store>,
+    notifier: web::Data<Notifier>,
+    u: AuthUser,
+    path: web::Path<(Uuid, Uuid)>,
+) -> impl Responder {
+    let (course_id, student_id) = path.into_inner();
+    let res =
+        enrollment::try_reject_enrollment(db.get_ref(), &notifier, &u, &course_id, &student_id)
+            .await;
+    match res {
+        Ok(e) => HttpResponse::Ok().json(e),
+        Err(e) => e.error_response(),
+    }
 }
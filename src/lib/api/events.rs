@@ -0,0 +1,33 @@
+// src/api/events.rs
+use crate::api::guard::AuthUser;
+use crate::common::{Notifier, EVENTS_KEEPALIVE_SECS};
+use actix_web::web::Bytes;
+use actix_web::{get, web, HttpResponse, Responder};
+use futures::stream::{self, StreamExt};
+use std::time::Duration;
+
+/// Server-Sent Events of the caller: new messages, message state changes
+/// and enrollments, one JSON object per `data:` line with its `type` also as
+/// the SSE event name.
+#[get("/events")]
+pub async fn stream_events(notifier: web::Data<Notifier>, user: AuthUser) -> impl Responder {
+    let events = Box::pin(notifier.events().subscribe(&user));
+    let keepalive = Duration::from_secs(EVENTS_KEEPALIVE_SECS);
+
+    let body = stream::unfold(events, move |mut events| async move {
+        let chunk = match tokio::time::timeout(keepalive, events.next()).await {
+            Ok(Some(event)) => match serde_json::to_string(&event) {
+                Ok(data) => format!("event: {}\ndata: {}\n\n", event.name(), data),
+                Err(_) => ": unserializable event\n\n".to_string(),
+            },
+            Ok(None) => return None,
+            Err(_) => ": keep-alive\n\n".to_string(),
+        };
+        Some((Ok::<_, actix_web::Error>(Bytes::from(chunk)), events))
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(body)
+}
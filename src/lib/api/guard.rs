@@ -0,0 +1,195 @@
+// src/api/guard.rs
+//
+// The bearer validator loads the caller's `User` once and stores it in the
+// request extensions. Handlers read it back with the `AuthUser` extractor and
+// routes restricted to some roles are wrapped in `RequireRole`.
+use crate::common::{User, UserRole};
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::StatusCode;
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use serde_json::json;
+use std::fmt;
+use std::ops::Deref;
+use std::rc::Rc;
+
+/// 401 when nobody is logged in, 403 when the caller lacks the role.
+#[derive(Debug)]
+pub enum AuthError {
+    Unauthorized,
+    Forbidden,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Unauthorized => write!(f, "unauthorized"),
+            AuthError::Forbidden => write!(f, "forbidden"),
+        }
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AuthError::Forbidden => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({"error": self.to_string()}))
+    }
+}
+
+/// The authenticated caller.
+#[derive(Debug, Clone)]
+pub struct AuthUser(pub User);
+
+impl Deref for AuthUser {
+    type Target = User;
+
+    fn deref(&self) -> &User {
+        &self.0
+    }
+}
+
+impl FromRequest for AuthUser {
+    type Error = AuthError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<User>()
+                .cloned()
+                .map(AuthUser)
+                .ok_or(AuthError::Unauthorized),
+        )
+    }
+}
+
+/// Rejects requests whose user has none of `roles`, e.g.
+/// `#[get("/kids", wrap = "RequireRole::new(&[UserRole::Parent])")]`.
+#[derive(Clone)]
+pub struct RequireRole {
+    roles: Rc<[UserRole]>,
+}
+
+impl RequireRole {
+    pub fn new(roles: &[UserRole]) -> Self {
+        RequireRole {
+            roles: Rc::from(roles),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireRole
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequireRoleMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireRoleMiddleware {
+            service,
+            roles: self.roles.clone(),
+        }))
+    }
+}
+
+pub struct RequireRoleMiddleware<S> {
+    service: S,
+    roles: Rc<[UserRole]>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireRoleMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let allowed = match req.extensions().get::<User>() {
+            None => Err(AuthError::Unauthorized),
+            Some(u) if self.roles.contains(&u.role) => Ok(()),
+            Some(_) => Err(AuthError::Forbidden),
+        };
+
+        match allowed {
+            Ok(_) => Box::pin(self.service.call(req)),
+            Err(e) => Box::pin(ready(Err(e.into()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::guard::{AuthUser, RequireRole};
+    use crate::common::{User, UserRole};
+    use actix_web::dev::Service;
+    use actix_web::http::StatusCode;
+    use actix_web::{get, test, App, HttpMessage, HttpResponse, Responder};
+    use uuid::Uuid;
+
+    #[get("/parents-only", wrap = "RequireRole::new(&[UserRole::Parent])")]
+    async fn parents_only(user: AuthUser) -> impl Responder {
+        HttpResponse::Ok().json(user.uid)
+    }
+
+    fn user(role: UserRole) -> User {
+        User {
+            uid: Uuid::new_v4(),
+            name: "guard".to_string(),
+            email: "guard@example.com".to_string(),
+            role,
+            devices: vec![],
+            preferences: Default::default(),
+            deactivated_at: None,
+        }
+    }
+
+    async fn status_for(user: Option<User>) -> StatusCode {
+        let app = test::init_service(
+            App::new()
+                .wrap_fn(move |req, srv| {
+                    if let Some(u) = user.clone() {
+                        req.extensions_mut().insert(u);
+                    }
+                    srv.call(req)
+                })
+                .service(parents_only),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/parents-only").to_request();
+        match test::try_call_service(&app, req).await {
+            Ok(res) => res.status(),
+            Err(e) => e.as_response_error().status_code(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_require_role_async() {
+        assert_eq!(status_for(None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status_for(Some(user(UserRole::Student))).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status_for(Some(user(UserRole::Parent))).await,
+            StatusCode::OK
+        );
+    }
+}
@@ -0,0 +1,241 @@
+use crate::api::guard::{AuthUser, RequireRole};
+use crate::common::store::Store;
+use crate::common::{lesson, LessonLink, Material, UserRole};
+use actix_web::{delete, post, put, web, HttpResponse, Responder, ResponseError};
+use serde::Deserialize;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize)]
+pub struct ModuleBody {
+    pub title: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LessonBody {
+    pub title: String,
+    // Markdown
+    #[serde(default)]
+    pub body: String,
+    #[serde(default)]
+    pub links: Vec<LessonLink>,
+    #[serde(default)]
+    pub materials: Vec<Material>,
+}
+
+/// Ids of modules or lessons in their new order.
+#[derive(Debug, Deserialize)]
+pub struct OrderBody {
+    pub ids: Vec<Uuid>,
+}
+
+#[post(
+    "/courses/{course_id}/modules",
+    wrap = "RequireRole::new(&[UserRole::Teacher, UserRole::Admin])"
+)]
+pub async fn create_module(
+    db: web::Data<dyn Store>,
+    user: AuthUser,
+    path: web::Path<Uuid>,
+    body: web::Json<ModuleBody>,
+) -> impl Responder {
+    match lesson::try_create_module(db.get_ref(), &user, &path, body.into_inner()).await {
+        Ok(m) => HttpResponse::Created().json(m),
+        Err(e) => e.error_response(),
+    }
+}
+
+// registered before `update_module`, which would take "order" for an id
+#[put(
+    "/courses/{course_id}/modules/order",
+    wrap = "RequireRole::new(&[UserRole::Teacher, UserRole::Admin])"
+)]
+pub async fn reorder_modules(
+    db: web::Data<dyn Store>,
+    user: AuthUser,
+    path: web::Path<Uuid>,
+    body: web::Json<OrderBody>,
+) -> impl Responder {
+    match lesson::try_reorder_modules(db.get_ref(), &user, &path, &body.ids).await {
+        Ok(m) => HttpResponse::Ok().json(m),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[put(
+    "/courses/{course_id}/modules/{module_id}",
+    wrap = "RequireRole::new(&[UserRole::Teacher, UserRole::Admin])"
+)]
+pub async fn update_module(
+    db: web::Data<dyn Store>,
+    user: AuthUser,
+    path: web::Path<(Uuid, Uuid)>,
+    body: web::Json<ModuleBody>,
+) -> impl Responder {
+    let (course_id, module_id) = path.into_inner();
+    let res = lesson::try_update_module(
+        db.get_ref(),
+        &user,
+        &course_id,
+        &module_id,
+        body.into_inner(),
+    )
+    .await;
+    match res {
+        Ok(m) => HttpResponse::Ok().json(m),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// Only empty modules can be deleted.
+#[delete(
+    "/courses/{course_id}/modules/{module_id}",
+    wrap = "RequireRole::new(&[UserRole::Teacher, UserRole::Admin])"
+)]
+pub async fn delete_module(
+    db: web::Data<dyn Store>,
+    user: AuthUser,
+    path: web::Path<(Uuid, Uuid)>,
+) -> impl Responder {
+    let (course_id, module_id) = path.into_inner();
+    match lesson::try_delete_module(db.get_ref(), &user, &course_id, &module_id).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// New lessons are unpublished.
+#[post(
+    "/courses/{course_id}/modules/{module_id}/lessons",
+    wrap = "RequireRole::new(&[UserRole::Teacher, UserRole::Admin])"
+)]
+pub async fn create_lesson(
+    db: web::Data<dyn Store>,
+    user: AuthUser,
+    path: web::Path<(Uuid, Uuid)>,
+    body: web::Json<LessonBody>,
+) -> impl Responder {
+    let (course_id, module_id) = path.into_inner();
+    let res = lesson::try_create_lesson(
+        db.get_ref(),
+        &user,
+        &course_id,
+        &module_id,
+        body.into_inner(),
+    )
+    .await;
+    match res {
+        Ok(l) => HttpResponse::Created().json(l),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// Also moves lessons of other modules listed in `ids` into this one.
+#[put(
+    "/courses/{course_id}/modules/{module_id}/lessons/order",
+    wrap = "RequireRole::new(&[UserRole::Teacher, UserRole::Admin])"
+)]
+pub async fn reorder_lessons(
+    db: web::Data<dyn Store>,
+    user: AuthUser,
+    path: web::Path<(Uuid, Uuid)>,
+    body: web::Json<OrderBody>,
+) -> impl Responder {
+    let (course_id, module_id) = path.into_inner();
+    match lesson::try_reorder_lessons(db.get_ref(), &user, &course_id, &module_id, &body.ids).await
+    {
+        Ok(l) => HttpResponse::Ok().json(l),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[put(
+    "/courses/{course_id}/lessons/{lesson_id}",
+    wrap = "RequireRole::new(&[UserRole::Teacher, UserRole::Admin])"
+)]
+pub async fn update_lesson(
+    db: web::Data<dyn Store>,
+    user: AuthUser,
+    path: web::Path<(Uuid, Uuid)>,
+    body: web::Json<LessonBody>,
+) -> impl Responder {
+    let (course_id, lesson_id) = path.into_inner();
+    let res = lesson::try_update_lesson(
+        db.get_ref(),
+        &user,
+        &course_id,
+        &lesson_id,
+        body.into_inner(),
+    )
+    .await;
+    match res {
+        Ok(l) => HttpResponse::Ok().json(l),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[delete(
+    "/courses/{course_id}/lessons/{lesson_id}",
+    wrap = "RequireRole::new(&[UserRole::Teacher, UserRole::Admin])"
+)]
+pub async fn delete_lesson(
+    db: web::Data<dyn Store>,
+    user: AuthUser,
+    path: web::Path<(Uuid, Uuid)>,
+) -> impl Responder {
+    let (course_id, lesson_id) = path.into_inner();
+    match lesson::try_delete_lesson(db.get_ref(), &user, &course_id, &lesson_id).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post(
+    "/courses/{course_id}/lessons/{lesson_id}/publish",
+    wrap = "RequireRole::new(&[UserRole::Teacher, UserRole::Admin])"
+)]
+pub async fn publish_lesson(
+    db: web::Data<dyn Store>,
+    user: AuthUser,
+    path: web::Path<(Uuid, Uuid)>,
+) -> impl Responder {
+    let (course_id, lesson_id) = path.into_inner();
+    match lesson::try_set_lesson_published(db.get_ref(), &user, &course_id, &lesson_id, true).await
+    {
+        Ok(l) => HttpResponse::Ok().json(l),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post(
+    "/courses/{course_id}/lessons/{lesson_id}/unpublish",
+    wrap = "RequireRole::new(&[UserRole::Teacher, UserRole::Admin])"
+)]
+pub async fn unpublish_lesson(
+    db: web::Data<dyn Store>,
+    user: AuthUser,
+    path: web::Path<(Uuid, Uuid)>,
+) -> impl Responder {
+    let (course_id, lesson_id) = path.into_inner();
+    match lesson::try_set_lesson_published(db.get_ref(), &user, &course_id, &lesson_id, false).await
+    {
+        Ok(l) => HttpResponse::Ok().json(l),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// Marks the lesson as done by the student calling it.
+#[post(
+    "/courses/{course_id}/lessons/{lesson_id}/complete",
+    wrap = "RequireRole::new(&[UserRole::Student])"
+)]
+pub async fn complete_lesson(
+    db: web::Data<dyn Store>,
+    user: AuthUser,
+    path: web::Path<(Uuid, Uuid)>,
+) -> impl Responder {
+    let (course_id, lesson_id) = path.into_inner();
+    match lesson::try_complete_lesson(db.get_ref(), &user, &course_id, &lesson_id).await {
+        Ok(c) => HttpResponse::Ok().json(c),
+        Err(e) => e.error_response(),
+    }
+}
@@ -1,19 +1,24 @@
 // src/api/message.rs
 
-use actix_web::web::ReqData;
-use actix_web::{get, post, web, HttpResponse, Responder};
-
-use firestore::struct_path::paths;
-use firestore::{FirestoreDb, FirestoreResult};
+use actix_web::{get, post, web, HttpResponse, Responder, ResponseError};
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use uuid::Uuid;
 
-use crate::api::auth::TokenClaims;
-use crate::common::message::{try_list_messages, try_send_messages, MessageType};
-use crate::common::user::get_user_by_id;
-use crate::common::{Message, MessageState, MESSAGES_COLLECTION};
-use crate::{check_user, result_option_match};
+use crate::api::guard::{AuthError, AuthUser};
+use crate::common::attachment::check_message_attachments;
+use crate::common::message::{
+    can_set_message_state, try_count_unread, try_get_message, try_list_messages, try_list_thread,
+    try_reply_message, try_send_messages, try_set_message_state, MessageCursor, MessageFilter,
+    MessageQuery, MessageType,
+};
+use crate::common::store::Store;
+use crate::common::{
+    MessageState, Notifier, User, DEFAULT_MESSAGES_PAGE_SIZE, MAX_MESSAGES_PAGE_SIZE,
+};
+use crate::result_option_match;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MessageBody {
@@ -21,40 +26,90 @@ pub struct MessageBody {
     pub receiver_ids: Vec<String>,
     pub subject: Option<String>,
     pub content: String,
+    // uploaded by the sender, see `POST /attachments`
+    #[serde(default)]
+    pub attachments: Vec<Uuid>,
 }
 
 #[post("/messages")]
 pub async fn send_message(
-    db: web::Data<FirestoreDb>,
-    http: web::Data<reqwest::Client>,
-    req_user: Option<ReqData<TokenClaims>>,
+    db: web::Data<dyn Store>,
+    notifier: web::Data<Notifier>,
+    user: AuthUser,
     message: web::Json<MessageBody>,
 ) -> impl Responder {
-    match req_user {
-        Some(user) => match get_user_by_id(&db, &user.id).await {
-            Ok(Some(user_data)) => {
-                let msg: MessageBody = message.into_inner();
-                match try_send_messages(&db, &http, &user_data, msg).await {
-                    Ok(_) => HttpResponse::Ok().into(),
-                    Err(e) => HttpResponse::InternalServerError()
-                        .json(json!({"error": format!("{:?}", e)})),
+    let msg: MessageBody = message.into_inner();
+    if let Err(e) = check_message_attachments(db.get_ref(), &user, &msg.attachments).await {
+        return e.error_response();
+    }
+    match try_send_messages(db.get_ref(), &notifier, &user, msg).await {
+        Ok(sent) => HttpResponse::Ok().json(sent),
+        Err(e) => HttpResponse::InternalServerError().json(json!({"error": format!("{:?}", e)})),
+    }
+}
+
+#[get("/messages/{message_id}")]
+pub async fn get_message(
+    db: web::Data<dyn Store>,
+    user: AuthUser,
+    path: web::Path<String>,
+) -> impl Responder {
+    let data = try_get_message(db.get_ref(), &user, path.as_str()).await;
+
+    result_option_match!(data)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplyBody {
+    pub subject: Option<String>,
+    pub content: String,
+    #[serde(default)]
+    pub attachments: Vec<Uuid>,
+}
+
+#[post("/messages/{message_id}/reply")]
+pub async fn reply_message(
+    db: web::Data<dyn Store>,
+    notifier: web::Data<Notifier>,
+    user: AuthUser,
+    path: web::Path<String>,
+    body: web::Json<ReplyBody>,
+) -> impl Responder {
+    let body = body.into_inner();
+    if let Err(e) = check_message_attachments(db.get_ref(), &user, &body.attachments).await {
+        return e.error_response();
+    }
+    match try_get_message(db.get_ref(), &user, path.as_str()).await {
+        Ok(Some(original)) => {
+            match try_reply_message(
+                db.get_ref(),
+                &notifier,
+                &user,
+                &original,
+                body.subject,
+                body.content,
+                body.attachments,
+            )
+            .await
+            {
+                Ok(reply) => HttpResponse::Ok().json(reply),
+                Err(e) => {
+                    HttpResponse::InternalServerError().json(json!({"error": format!("{:?}", e)}))
                 }
             }
-            _ => HttpResponse::Unauthorized().json(json!({"error": "unable to verify identity"})),
-        },
-        _ => HttpResponse::Unauthorized().json(json!({"error": "unable to verify identity"})),
+        }
+        Ok(None) => HttpResponse::NotFound().json(json!({"error": "not found"})),
+        Err(e) => HttpResponse::InternalServerError().json(json!({"error": format!("{:?}", e)})),
     }
 }
 
-#[get("/messages/{message_id}")]
-pub async fn get_message(db: web::Data<FirestoreDb>, path: web::Path<String>) -> impl Responder {
-    let data: FirestoreResult<Option<Message>> = db
-        .fluent()
-        .select()
-        .by_id_in(MESSAGES_COLLECTION)
-        .obj()
-        .one(path.as_ref())
-        .await;
+#[get("/messages/{message_id}/thread")]
+pub async fn get_thread(
+    db: web::Data<dyn Store>,
+    user: AuthUser,
+    path: web::Path<String>,
+) -> impl Responder {
+    let data = try_list_thread(db.get_ref(), &user, path.as_str()).await;
 
     result_option_match!(data)
 }
@@ -66,80 +121,108 @@ pub struct UpdateMessageStateBody {
 
 #[post("/messages/{message_id}/state")]
 pub async fn update_message_state(
-    db: web::Data<FirestoreDb>,
+    db: web::Data<dyn Store>,
+    notifier: web::Data<Notifier>,
+    user: AuthUser,
     path: web::Path<String>,
     body: web::Json<UpdateMessageStateBody>,
 ) -> impl Responder {
-    let obj_by_id: FirestoreResult<Option<Message>> = db
-        .fluent()
-        .select()
-        .by_id_in(MESSAGES_COLLECTION)
-        .obj()
-        .one(path.as_ref())
-        .await;
+    let obj_by_id = try_get_message(db.get_ref(), &user, path.as_str()).await;
+    let state = body.into_inner().state;
 
     match obj_by_id {
-        Ok(Some(m)) => {
-            let update: FirestoreResult<Message> = db
-                .fluent()
-                .update()
-                .fields(paths!(Message::{state}))
-                .in_col(MESSAGES_COLLECTION)
-                .document_id(path.as_ref())
-                .object(&Message {
-                    state: body.into_inner().state,
-                    ..m.clone()
-                })
-                .execute()
-                .await;
-            match update {
-                Ok(_) => HttpResponse::Ok().into(),
-                Err(e) => {
-                    HttpResponse::InternalServerError().json(json!({"error": format!("{:?}", e)}))
-                }
-            }
+        Ok(Some(m)) if !can_set_message_state(&m, &user, &state) => {
+            AuthError::Forbidden.error_response()
         }
+        Ok(Some(m)) => match try_set_message_state(db.get_ref(), &notifier, &user, &m, state).await
+        {
+            Ok(_) => HttpResponse::Ok().into(),
+            Err(e) => {
+                HttpResponse::InternalServerError().json(json!({"error": format!("{:?}", e)}))
+            }
+        },
         Ok(None) => HttpResponse::NotFound().json(json!({"error": "not found"})),
         Err(e) => HttpResponse::InternalServerError().json(json!({"error": format!("{:?}", e)})),
     }
 }
 
+#[get("/messages/unread/count")]
+pub async fn count_unread(db: web::Data<dyn Store>, user: AuthUser) -> impl Responder {
+    match try_count_unread(db.get_ref(), &user).await {
+        Ok(unread) => HttpResponse::Ok().json(json!({ "unread": unread })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({"error": format!("{:?}", e)})),
+    }
+}
+
+/// Query string of the message listings, e.g.
+/// `?limit=20&state=sent&subject=homework&cursor=...`.
+#[derive(Debug, Deserialize)]
+pub struct ListMessagesParams {
+    cursor: Option<String>,
+    limit: Option<usize>,
+    state: Option<MessageState>,
+    sender_id: Option<Uuid>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    subject: Option<String>,
+}
+
 #[get("/messages/list/inbox")]
 pub async fn list_inbox(
-    db: web::Data<FirestoreDb>,
-    req_user: Option<ReqData<TokenClaims>>,
+    db: web::Data<dyn Store>,
+    user: AuthUser,
+    params: web::Query<ListMessagesParams>,
 ) -> impl Responder {
-    list_messages(&db, req_user, MessageType::Received).await
+    list_messages(&db, &user, MessageType::Received, params.into_inner()).await
 }
 
 #[get("/messages/list/sent")]
 pub async fn list_sent(
-    db: web::Data<FirestoreDb>,
-    req_user: Option<ReqData<TokenClaims>>,
+    db: web::Data<dyn Store>,
+    user: AuthUser,
+    params: web::Query<ListMessagesParams>,
 ) -> impl Responder {
-    list_messages(&db, req_user, MessageType::Sent).await
+    list_messages(&db, &user, MessageType::Sent, params.into_inner()).await
 }
 
 #[get("/messages/list/all")]
 pub async fn list_all(
-    db: web::Data<FirestoreDb>,
-    req_user: Option<ReqData<TokenClaims>>,
+    db: web::Data<dyn Store>,
+    user: AuthUser,
+    params: web::Query<ListMessagesParams>,
 ) -> impl Responder {
-    list_messages(&db, req_user, MessageType::All).await
+    list_messages(&db, &user, MessageType::All, params.into_inner()).await
 }
 
 async fn list_messages(
-    db: &FirestoreDb,
-    req_user: Option<ReqData<TokenClaims>>,
+    db: &web::Data<dyn Store>,
+    user: &User,
     message_type: MessageType,
-) -> impl Responder {
-    check_user!(req_user, db, u, {
-        let list = try_list_messages(db, &u, message_type).await;
-        match list {
-            Ok(messages) => HttpResponse::Ok().json(messages),
-            Err(e) => {
-                HttpResponse::InternalServerError().json(json!({"error": format!("{:?}", e)}))
-            }
-        }
-    })
+    params: ListMessagesParams,
+) -> HttpResponse {
+    let after = match params.cursor.as_deref().map(MessageCursor::decode) {
+        Some(None) => return HttpResponse::BadRequest().json(json!({"error": "invalid cursor"})),
+        Some(cursor) => cursor,
+        None => None,
+    };
+    let query = MessageQuery {
+        filter: MessageFilter {
+            state: params.state,
+            sender_id: params.sender_id,
+            from: params.from,
+            to: params.to,
+            subject: params.subject,
+        },
+        after,
+        limit: params
+            .limit
+            .unwrap_or(DEFAULT_MESSAGES_PAGE_SIZE)
+            .clamp(1, MAX_MESSAGES_PAGE_SIZE),
+    };
+
+    let list = try_list_messages(db.get_ref(), user, message_type, &query).await;
+    match list {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(e) => HttpResponse::InternalServerError().json(json!({"error": format!("{:?}", e)})),
+    }
 }
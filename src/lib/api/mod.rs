@@ -1,7 +1,12 @@
+pub mod admin;
+pub mod attachment;
 pub mod auth;
 pub mod course;
 pub mod enrollment;
+pub mod events;
+pub mod guard;
 pub mod kid;
+pub mod lesson;
 pub mod message;
 pub mod teacher;
 pub mod user;
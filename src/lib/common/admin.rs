@@ -0,0 +1,867 @@
+use crate::api::admin::CreateUserBody;
+use crate::common::store::{Store, StoreError, StoreResult};
+use crate::common::user::{
+    email_lookup, hash_password, make_user, normalize_email, save_user_to_db, try_find_user,
+    UserPage, UserQuery,
+};
+use crate::common::{
+    AuditAction, AuditEntry, ImportOutcome, ImportReport, ImportRow, NotificationPreferences, User,
+    UserRole, UserWithPassword, UserWithPasswordStudents, USER_IMPORT_MAX_ROWS,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use uuid::Uuid;
+
+/// Why an admin's change to an account was refused.
+#[derive(Debug)]
+pub enum AdminError {
+    Invalid(String),
+    // what wasn't found, e.g. "user"
+    NotFound(&'static str),
+    // the change doesn't fit the account, e.g. deactivating it twice
+    Conflict(String),
+    Store(StoreError),
+}
+
+impl fmt::Display for AdminError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminError::Invalid(reason) => write!(f, "{}", reason),
+            AdminError::NotFound(what) => write!(f, "{} not found", what),
+            AdminError::Conflict(reason) => write!(f, "{}", reason),
+            AdminError::Store(e) => write!(f, "{:?}", e),
+        }
+    }
+}
+
+impl From<StoreError> for AdminError {
+    fn from(e: StoreError) -> Self {
+        AdminError::Store(e)
+    }
+}
+
+/// An account created by an admin, with the password to hand over when it
+/// was generated.
+#[derive(Debug, Clone, Serialize)]
+pub struct CreatedUser {
+    pub user: User,
+    pub temporary_password: Option<String>,
+}
+
+/// A password to hand over once, the user is expected to change it.
+pub fn temporary_password() -> String {
+    Uuid::new_v4().simple().to_string()[..16].to_string()
+}
+
+async fn audit(
+    db: &dyn Store,
+    admin: &User,
+    action: AuditAction,
+    target_id: &Uuid,
+    details: String,
+) -> StoreResult<()> {
+    db.insert_audit_entry(&AuditEntry {
+        id: Uuid::new_v4(),
+        actor_id: admin.uid,
+        action,
+        target_id: *target_id,
+        details,
+        created_at: Utc::now(),
+    })
+    .await
+}
+
+/// The account an admin may change: not the system user, nor their own.
+async fn account(db: &dyn Store, admin: &User, id: &Uuid) -> Result<User, AdminError> {
+    let user = db.get_user(id).await?.ok_or(AdminError::NotFound("user"))?;
+    if user.role == UserRole::System {
+        return Err(AdminError::Conflict(
+            "the system user can't be changed".to_string(),
+        ));
+    }
+    if user.uid == admin.uid {
+        return Err(AdminError::Conflict(
+            "admins can't change their own account".to_string(),
+        ));
+    }
+
+    Ok(user)
+}
+
+async fn find_role(db: &dyn Store, id: &Uuid, role: UserRole) -> Result<User, AdminError> {
+    match db.get_user(id).await? {
+        Some(u) if u.role == role => Ok(u),
+        Some(u) => Err(AdminError::Invalid(format!(
+            "{} is not a {}",
+            u.email,
+            role_name(role)
+        ))),
+        None => Err(AdminError::NotFound("user")),
+    }
+}
+
+fn role_name(role: UserRole) -> String {
+    serde_json::to_value(role)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+pub async fn try_list_users(db: &dyn Store, query: &UserQuery) -> StoreResult<UserPage> {
+    db.list_users(query).await
+}
+
+/// Creates an account of any role but the system's. Without a password one
+/// is generated and returned once. Parents can be linked to their students
+/// right away.
+pub async fn try_create_user(
+    db: &dyn Store,
+    admin: &User,
+    body: CreateUserBody,
+) -> Result<CreatedUser, AdminError> {
+    let email = normalize_email(&body.email);
+    let name = body.name.trim().to_string();
+    if !email.contains('@') {
+        return Err(AdminError::Invalid("invalid email".to_string()));
+    }
+    if name.is_empty() {
+        return Err(AdminError::Invalid("the name is empty".to_string()));
+    }
+    if body.role == UserRole::System {
+        return Err(AdminError::Invalid(
+            "there is only one system user".to_string(),
+        ));
+    }
+    if body.password.as_ref().is_some_and(|p| p.is_empty()) {
+        return Err(AdminError::Invalid("the password is empty".to_string()));
+    }
+    let students = body.students.unwrap_or_default();
+    if !students.is_empty() && body.role != UserRole::Parent {
+        return Err(AdminError::Invalid(
+            "only parents are linked to students".to_string(),
+        ));
+    }
+    if try_find_user(db, &body.email).await?.is_some() {
+        return Err(AdminError::Conflict("user exists".to_string()));
+    }
+    let mut emails = Vec::with_capacity(students.len());
+    for id in &students {
+        emails.push(find_role(db, id, UserRole::Student).await?.email);
+    }
+
+    let temporary = match body.password {
+        Some(_) => None,
+        None => Some(temporary_password()),
+    };
+    let password = body.password.or(temporary.clone()).unwrap_or_default();
+    let user = UserWithPassword {
+        uid: Uuid::new_v4(),
+        email,
+        password: hash_password(&password).await?,
+        role: body.role,
+        name,
+        devices: Vec::new(),
+        preferences: NotificationPreferences::default(),
+        deactivated_at: None,
+    };
+    db.insert_user(&user).await?;
+    audit(
+        db,
+        admin,
+        AuditAction::CreateUser,
+        &user.uid,
+        format!("{} as {}", user.email, role_name(user.role)),
+    )
+    .await?;
+
+    if !students.is_empty() {
+        db.link_students(&user.uid, &students).await?;
+        for email in emails {
+            audit(
+                db,
+                admin,
+                AuditAction::LinkStudent,
+                &user.uid,
+                format!("student {}", email),
+            )
+            .await?;
+        }
+    }
+
+    Ok(CreatedUser {
+        user: User::from(user),
+        temporary_password: temporary,
+    })
+}
+
+/// Moves the user to another role. Users still linked to parents or
+/// students, enrolled in or teaching courses keep theirs until that's
+/// undone.
+pub async fn try_change_role(
+    db: &dyn Store,
+    admin: &User,
+    id: &Uuid,
+    role: UserRole,
+) -> Result<User, AdminError> {
+    let user = account(db, admin, id).await?;
+    if role == UserRole::System {
+        return Err(AdminError::Invalid(
+            "there is only one system user".to_string(),
+        ));
+    }
+    if user.role == role {
+        return Ok(user);
+    }
+
+    let busy = match user.role {
+        UserRole::Student => {
+            !db.list_parents_of(id).await?.is_empty()
+                || db
+                    .list_student_enrollments(id)
+                    .await?
+                    .iter()
+                    .any(|e| e.is_active() || e.is_waitlisted() || e.is_pending())
+        }
+        UserRole::Parent => !db.list_students_of(id).await?.is_empty(),
+        UserRole::Teacher => db
+            .list_teacher_courses(id)
+            .await?
+            .iter()
+            .any(|c| c.archived_at.is_none()),
+        _ => false,
+    };
+    if busy {
+        return Err(AdminError::Conflict(format!(
+            "the {} still has links, enrollments or courses",
+            role_name(user.role)
+        )));
+    }
+
+    let from = user.role;
+    let updated = User { role, ..user };
+    db.update_account(&updated).await?;
+    audit(
+        db,
+        admin,
+        AuditAction::ChangeRole,
+        id,
+        format!("{} -> {}", role_name(from), role_name(role)),
+    )
+    .await?;
+
+    Ok(updated)
+}
+
+/// Sets a new password, a generated one when none is given, and ends the
+/// user's sessions. Returns the generated password.
+pub async fn try_reset_password(
+    db: &dyn Store,
+    admin: &User,
+    id: &Uuid,
+    password: Option<String>,
+) -> Result<Option<String>, AdminError> {
+    let user = account(db, admin, id).await?;
+    if password.as_ref().is_some_and(|p| p.is_empty()) {
+        return Err(AdminError::Invalid("the password is empty".to_string()));
+    }
+
+    let temporary = match password {
+        Some(_) => None,
+        None => Some(temporary_password()),
+    };
+    let password = password.or(temporary.clone()).unwrap_or_default();
+    db.set_password(&user.uid, &hash_password(&password).await?)
+        .await?;
+    db.revoke_user_refresh_tokens(&user.uid).await?;
+    let details = match temporary {
+        Some(_) => "temporary password",
+        None => "new password",
+    };
+    audit(
+        db,
+        admin,
+        AuditAction::ResetPassword,
+        id,
+        details.to_string(),
+    )
+    .await?;
+
+    Ok(temporary)
+}
+
+/// Deactivated users can't log in, their sessions end and their access
+/// tokens stop working. Reactivating lets them log in again.
+pub async fn try_set_active(
+    db: &dyn Store,
+    admin: &User,
+    id: &Uuid,
+    active: bool,
+) -> Result<User, AdminError> {
+    let user = account(db, admin, id).await?;
+    if user.is_active() == active {
+        return Err(AdminError::Conflict(match active {
+            true => "the account is active".to_string(),
+            false => "the account is deactivated already".to_string(),
+        }));
+    }
+
+    let updated = User {
+        deactivated_at: match active {
+            true => None,
+            false => Some(Utc::now()),
+        },
+        ..user
+    };
+    db.update_account(&updated).await?;
+    let action = match active {
+        true => AuditAction::Reactivate,
+        false => {
+            db.revoke_user_refresh_tokens(id).await?;
+            AuditAction::Deactivate
+        }
+    };
+    audit(db, admin, action, id, updated.email.clone()).await?;
+
+    Ok(updated)
+}
+
+pub async fn try_link_student(
+    db: &dyn Store,
+    admin: &User,
+    parent_id: &Uuid,
+    student_id: &Uuid,
+) -> Result<(), AdminError> {
+    find_role(db, parent_id, UserRole::Parent).await?;
+    let student = find_role(db, student_id, UserRole::Student).await?;
+    let linked = db
+        .list_students_of(parent_id)
+        .await?
+        .iter()
+        .any(|sp| sp.student_id == *student_id);
+    if linked {
+        return Err(AdminError::Conflict("already linked".to_string()));
+    }
+
+    db.link_students(parent_id, &[*student_id]).await?;
+    audit(
+        db,
+        admin,
+        AuditAction::LinkStudent,
+        parent_id,
+        format!("student {}", student.email),
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn try_unlink_student(
+    db: &dyn Store,
+    admin: &User,
+    parent_id: &Uuid,
+    student_id: &Uuid,
+) -> Result<(), AdminError> {
+    if !db.unlink_student(parent_id, student_id).await? {
+        return Err(AdminError::NotFound("link"));
+    }
+
+    let student = db.get_user(student_id).await?;
+    audit(
+        db,
+        admin,
+        AuditAction::UnlinkStudent,
+        parent_id,
+        format!(
+            "student {}",
+            student.map_or(student_id.to_string(), |s| s.email)
+        ),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// The latest changes, to the account `target_id` if given.
+pub async fn list_audit_log(
+    db: &dyn Store,
+    target_id: Option<&Uuid>,
+    limit: usize,
+) -> StoreResult<Vec<AuditEntry>> {
+    db.list_audit_entries(target_id, limit).await
+}
+
+/// One account of an import. The role is checked per row, so a typo only
+/// skips its own row.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportUser {
+    pub name: String,
+    pub email: String,
+    pub role: String,
+    // generated and reported once when left out
+    #[serde(default)]
+    pub password: Option<String>,
+    // emails of a parent's students, in the import or signed up before
+    #[serde(default)]
+    pub students: Vec<String>,
+}
+
+/// Reads an import from CSV with `name`, `email` and `role` columns, and
+/// optionally `password` and `students`, the latter separated by `;` or
+/// spaces.
+pub fn parse_user_import(data: &str) -> Result<Vec<ImportUser>, AdminError> {
+    let invalid = |e: csv::Error| AdminError::Invalid(format!("invalid import: {}", e));
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(data.as_bytes());
+    let headers = reader.headers().map_err(invalid)?.clone();
+    let column = |name: &str| headers.iter().position(|h| h.eq_ignore_ascii_case(name));
+    let (name, email, role) = match (column("name"), column("email"), column("role")) {
+        (Some(n), Some(e), Some(r)) => (n, e, r),
+        _ => {
+            return Err(AdminError::Invalid(
+                "the import needs name, email and role columns".to_string(),
+            ))
+        }
+    };
+    let (password, students) = (column("password"), column("students"));
+
+    let mut users = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(invalid)?;
+        let field = |c: Option<usize>| c.and_then(|c| record.get(c)).unwrap_or_default();
+        users.push(ImportUser {
+            name: field(Some(name)).to_string(),
+            email: field(Some(email)).to_string(),
+            role: field(Some(role)).to_string(),
+            password: Some(field(password).to_string()).filter(|p| !p.is_empty()),
+            students: field(students)
+                .split(|c: char| c == ';' || c.is_whitespace())
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+                .collect(),
+        });
+    }
+
+    Ok(users)
+}
+
+/// Why the row can't be imported, judged by itself.
+fn check_import_row(user: &ImportUser, email: &str, role: Option<UserRole>) -> Option<String> {
+    match role {
+        _ if !email.contains('@') => Some("invalid email".to_string()),
+        _ if user.name.trim().is_empty() => Some("the name is empty".to_string()),
+        None | Some(UserRole::System) => Some(format!("unknown role {}", user.role)),
+        _ if user.password.as_ref().is_some_and(|p| p.is_empty()) => {
+            Some("the password is empty".to_string())
+        }
+        Some(r) if r != UserRole::Parent && !user.students.is_empty() => {
+            Some("only parents are linked to students".to_string())
+        }
+        _ => None,
+    }
+}
+
+fn parse_role(role: &str) -> Option<UserRole> {
+    serde_json::from_value(serde_json::Value::String(role.trim().to_lowercase())).ok()
+}
+
+/// Creates the accounts of an import with `make_user`, skipping rows that
+/// are invalid or exist already, and links parents to their students by
+/// email, compared in lower case. Students are created first, so parents can
+/// name students from the same import. A dry run checks every row the same
+/// way and writes nothing.
+pub async fn try_import_users(
+    db: &dyn Store,
+    admin: &User,
+    users: &[ImportUser],
+    dry_run: bool,
+) -> Result<ImportReport, AdminError> {
+    if users.len() > USER_IMPORT_MAX_ROWS {
+        return Err(AdminError::Invalid(format!(
+            "an import has at most {} rows",
+            USER_IMPORT_MAX_ROWS
+        )));
+    }
+
+    let emails = users
+        .iter()
+        .map(|u| normalize_email(&u.email))
+        .collect::<Vec<_>>();
+    let lookup = users
+        .iter()
+        .flat_map(|u| std::iter::once(&u.email).chain(&u.students))
+        .filter(|e| e.contains('@'))
+        .collect::<Vec<_>>();
+    let existing = db.get_users_by_emails(&email_lookup(&lookup)).await?;
+    let find_existing = |email: &str| existing.iter().find(|u| normalize_email(&u.email) == email);
+
+    let roles = users
+        .iter()
+        .map(|u| parse_role(&u.role))
+        .collect::<Vec<_>>();
+    let errors = users
+        .iter()
+        .zip(&emails)
+        .zip(&roles)
+        .map(|((user, email), role)| check_import_row(user, email, *role))
+        .collect::<Vec<_>>();
+    // students created along, so a parent can name students of the import
+    let imported = (0..users.len())
+        .filter(|&i| roles[i] == Some(UserRole::Student) && errors[i].is_none())
+        .filter(|&i| find_existing(&emails[i]).is_none())
+        .map(|i| emails[i].as_str())
+        .collect::<Vec<_>>();
+
+    let mut rows: Vec<ImportRow> = Vec::with_capacity(users.len());
+    // the stored emails of each row's students
+    let mut links = Vec::with_capacity(users.len());
+    for (i, (user, email)) in users.iter().zip(&emails).enumerate() {
+        let mut students = Vec::with_capacity(user.students.len());
+        let mut unknown = None;
+        for student in user.students.iter().map(|s| normalize_email(s)) {
+            match find_existing(&student) {
+                Some(u) if u.role == UserRole::Student => students.push(u.email.clone()),
+                None if imported.contains(&student.as_str()) => students.push(student),
+                _ => {
+                    unknown.get_or_insert(student);
+                }
+            }
+        }
+
+        let error = errors[i]
+            .clone()
+            .or(unknown.map(|s| format!("unknown student {}", s)));
+        let outcome = match error {
+            Some(_) => ImportOutcome::Invalid,
+            None if rows
+                .iter()
+                .any(|r| r.outcome == ImportOutcome::Valid && r.email == *email) =>
+            {
+                ImportOutcome::Duplicate
+            }
+            None if find_existing(email).is_some() => ImportOutcome::Exists,
+            None => ImportOutcome::Valid,
+        };
+        rows.push(ImportRow {
+            row: i + 1,
+            email: email.clone(),
+            outcome,
+            error,
+            user: None,
+            temporary_password: None,
+        });
+        links.push(students);
+    }
+
+    if !dry_run {
+        let mut order = (0..rows.len())
+            .filter(|&i| rows[i].outcome == ImportOutcome::Valid)
+            .collect::<Vec<_>>();
+        order.sort_by_key(|&i| roles[i] == Some(UserRole::Parent));
+        for i in order {
+            let row = &mut rows[i];
+            let temporary = match users[i].password {
+                Some(_) => None,
+                None => Some(temporary_password()),
+            };
+            let password = users[i].password.clone().or(temporary.clone());
+            let user = UserWithPasswordStudents {
+                email: row.email.clone(),
+                password: password.unwrap_or_default(),
+                role: roles[i].unwrap_or(UserRole::Student),
+                name: users[i].name.trim().to_string(),
+                students: (roles[i] == Some(UserRole::Parent)).then(|| links[i].clone()),
+            };
+            match create_imported(db, admin, &user).await {
+                Ok(created) => {
+                    row.outcome = ImportOutcome::Created;
+                    row.user = Some(created);
+                    row.temporary_password = temporary;
+                }
+                Err(e) => {
+                    row.outcome = ImportOutcome::Failed;
+                    row.error = Some(format!("{:?}", e));
+                }
+            }
+        }
+    }
+
+    let created = rows
+        .iter()
+        .filter(|r| matches!(r.outcome, ImportOutcome::Created | ImportOutcome::Valid))
+        .count();
+    Ok(ImportReport {
+        dry_run,
+        created,
+        skipped: rows.len() - created,
+        rows,
+    })
+}
+
+async fn create_imported(
+    db: &dyn Store,
+    admin: &User,
+    user: &UserWithPasswordStudents,
+) -> StoreResult<User> {
+    let (created, kids) = make_user(db, user).await?;
+    save_user_to_db(db, &created, kids).await?;
+    audit(
+        db,
+        admin,
+        AuditAction::CreateUser,
+        &created.uid,
+        format!("{} as {}, imported", created.email, role_name(created.role)),
+    )
+    .await?;
+    for email in user.students.iter().flatten() {
+        audit(
+            db,
+            admin,
+            AuditAction::LinkStudent,
+            &created.uid,
+            format!("student {}", email),
+        )
+        .await?;
+    }
+
+    Ok(User::from(created))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::admin::CreateUserBody;
+    use crate::common::admin::{
+        list_audit_log, parse_user_import, try_change_role, try_create_user, try_import_users,
+        try_link_student, try_list_users, try_reset_password, try_set_active, try_unlink_student,
+        AdminError,
+    };
+    use crate::common::store::{Fixtures, MemoryStore, StudentParentStore, UserStore};
+    use crate::common::token::{issue_tokens, refresh_tokens};
+    use crate::common::user::{get_user_by_id, UserQuery};
+    use crate::common::{AuditAction, ImportOutcome, UserRole, UserWithPassword};
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_admin_user_management_async() {
+        std::env::set_var("HASH_SECRET", "test-secret");
+        std::env::set_var("JWT_SECRET", "test-secret");
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+        let hl_id = Fixtures::id("student");
+        let teacher_id = Fixtures::id("teacher");
+        let admin = UserWithPassword {
+            uid: Uuid::new_v4(),
+            email: "admin@admin.com".to_string(),
+            password: "!".to_string(),
+            role: UserRole::Admin,
+            name: "admin".to_string(),
+            devices: Vec::new(),
+            preferences: Default::default(),
+            deactivated_at: None,
+        };
+        db.insert_user(&admin).await.unwrap();
+        let admin = get_user_by_id(&db, &admin.uid).await.unwrap().unwrap();
+
+        let body = || CreateUserBody {
+            name: "dad".to_string(),
+            email: "dad@dad.com".to_string(),
+            password: None,
+            role: UserRole::Parent,
+            students: Some(vec![hl_id]),
+        };
+        let created = try_create_user(&db, &admin, body()).await.unwrap();
+        assert!(created.temporary_password.is_some());
+        let dad = created.user;
+        assert_eq!(dad.role, UserRole::Parent);
+        assert_eq!(db.list_students_of(&dad.uid).await.unwrap().len(), 1);
+        assert!(matches!(
+            try_create_user(&db, &admin, body()).await,
+            Err(AdminError::Conflict(_))
+        ));
+        let shouted = CreateUserBody {
+            email: " Dad@DAD.com".to_string(),
+            ..body()
+        };
+        assert!(matches!(
+            try_create_user(&db, &admin, shouted).await,
+            Err(AdminError::Conflict(_))
+        ));
+
+        let query = UserQuery {
+            search: Some("DAD".to_string()),
+            ..UserQuery::default()
+        };
+        let page = try_list_users(&db, &query).await.unwrap();
+        assert_eq!(page.users.len(), 1);
+        let query = UserQuery {
+            limit: 2,
+            ..UserQuery::default()
+        };
+        let first = try_list_users(&db, &query).await.unwrap();
+        let emails = first
+            .users
+            .iter()
+            .map(|u| u.email.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(emails, vec!["admin@admin.com", "dad@dad.com"]);
+        let query = UserQuery {
+            after: first.next_cursor,
+            ..query
+        };
+        let second = try_list_users(&db, &query).await.unwrap();
+        assert_eq!(second.users[0].email, "hl@hl.com");
+
+        // linked, enrolled or teaching users keep their role
+        for id in [&hl_id, &teacher_id, &dad.uid] {
+            assert!(matches!(
+                try_change_role(&db, &admin, id, UserRole::Admin).await,
+                Err(AdminError::Conflict(_))
+            ));
+        }
+        assert!(matches!(
+            try_change_role(&db, &admin, &admin.uid, UserRole::Teacher).await,
+            Err(AdminError::Conflict(_))
+        ));
+        try_unlink_student(&db, &admin, &dad.uid, &hl_id)
+            .await
+            .unwrap();
+        assert!(matches!(
+            try_unlink_student(&db, &admin, &dad.uid, &hl_id).await,
+            Err(AdminError::NotFound(_))
+        ));
+        let teacher = try_change_role(&db, &admin, &dad.uid, UserRole::Teacher)
+            .await
+            .unwrap();
+        assert_eq!(teacher.role, UserRole::Teacher);
+        assert!(matches!(
+            try_link_student(&db, &admin, &dad.uid, &hl_id).await,
+            Err(AdminError::Invalid(_))
+        ));
+
+        // deactivating ends the sessions
+        let pair = issue_tokens(&db, &hl_id).await.unwrap();
+        let hl = try_set_active(&db, &admin, &hl_id, false).await.unwrap();
+        assert!(!hl.is_active());
+        assert!(refresh_tokens(&db, &pair.refresh_token)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            try_set_active(&db, &admin, &hl_id, false).await,
+            Err(AdminError::Conflict(_))
+        ));
+        let query = UserQuery {
+            active: Some(false),
+            ..UserQuery::default()
+        };
+        let page = try_list_users(&db, &query).await.unwrap();
+        assert_eq!(page.users[0].uid, hl_id);
+        try_set_active(&db, &admin, &hl_id, true).await.unwrap();
+
+        let before = db.find_user("hl@hl.com").await.unwrap().unwrap().password;
+        let temporary = try_reset_password(&db, &admin, &hl_id, None).await.unwrap();
+        assert!(temporary.is_some());
+        let after = db.find_user("hl@hl.com").await.unwrap().unwrap().password;
+        assert_ne!(before, after);
+
+        let actions = list_audit_log(&db, Some(&dad.uid), 10)
+            .await
+            .unwrap()
+            .iter()
+            .map(|e| e.action)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            actions,
+            vec![
+                AuditAction::ChangeRole,
+                AuditAction::UnlinkStudent,
+                AuditAction::LinkStudent,
+                AuditAction::CreateUser
+            ]
+        );
+        let latest = list_audit_log(&db, None, 1).await.unwrap();
+        assert_eq!(latest[0].action, AuditAction::ResetPassword);
+        assert_eq!(latest[0].actor_id, admin.uid);
+    }
+
+    #[test]
+    fn test_parse_user_import() {
+        let data = "Email,Name,Role,Students\n\
+                    ann@school.com, Ann ,student,\n\
+                    dad@dad.com,Dad,parent,ann@school.com;hl@hl.com\n";
+        let users = parse_user_import(data).unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[0].name, "Ann");
+        assert!(users[0].students.is_empty());
+        assert!(users[0].password.is_none());
+        assert_eq!(users[1].students, vec!["ann@school.com", "hl@hl.com"]);
+        assert!(matches!(
+            parse_user_import("email,name\nann@school.com,Ann\n"),
+            Err(AdminError::Invalid(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_import_users_async() {
+        std::env::set_var("HASH_SECRET", "test-secret");
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+        let admin = db.get_system_user().await.unwrap().unwrap();
+        let data = "name,email,role,password,students\n\
+                    Dad,dad@dad.com,parent,,ann@school.com hl@hl.com\n\
+                    Ann,ann@school.com,Student,,\n\
+                    Tom,tom@school.com,teacher,secret,\n\
+                    Ann again, ANN@School.com ,student,,\n\
+                    Mom,Mom@Mom.com,parent,,\n\
+                    Bob,bob@school.com,janitor,,\n\
+                    Eve,eve@school.com,parent,,t1@t1.com\n\
+                    Joe,joe@school.com,teacher,,ann@school.com\n\
+                    Bob,Bob@School.com,student,,\n";
+        let users = parse_user_import(data).unwrap();
+
+        let report = try_import_users(&db, &admin, &users, true).await.unwrap();
+        let outcomes = report.rows.iter().map(|r| r.outcome).collect::<Vec<_>>();
+        assert_eq!(
+            outcomes,
+            vec![
+                ImportOutcome::Valid,
+                ImportOutcome::Valid,
+                ImportOutcome::Valid,
+                ImportOutcome::Duplicate,
+                ImportOutcome::Exists,
+                ImportOutcome::Invalid,
+                ImportOutcome::Invalid,
+                ImportOutcome::Invalid,
+                // only valid rows count as earlier ones
+                ImportOutcome::Valid,
+            ]
+        );
+        assert_eq!(report.rows[3].email, "ann@school.com");
+        assert_eq!(
+            report.rows[6].error.as_deref(),
+            Some("unknown student t1@t1.com")
+        );
+        assert_eq!((report.created, report.skipped), (4, 5));
+        assert!(db.find_user("dad@dad.com").await.unwrap().is_none());
+
+        let report = try_import_users(&db, &admin, &users, false).await.unwrap();
+        assert!(!report.dry_run);
+        assert_eq!(report.created, 4);
+        assert_eq!(report.rows[0].outcome, ImportOutcome::Created);
+        assert!(report.rows[0].temporary_password.is_some());
+        assert!(report.rows[2].temporary_password.is_none());
+        let dad = report.rows[0].user.clone().unwrap();
+        assert_eq!(dad.role, UserRole::Parent);
+        assert_eq!(db.list_students_of(&dad.uid).await.unwrap().len(), 2);
+        let ann = db.find_user("ann@school.com").await.unwrap().unwrap();
+        assert_eq!(ann.role, UserRole::Student);
+        assert!(db.find_user("bob@school.com").await.unwrap().is_some());
+        let entries = list_audit_log(&db, Some(&dad.uid), 10).await.unwrap();
+        assert_eq!(entries.len(), 3);
+
+        // running it again creates no one twice
+        let report = try_import_users(&db, &admin, &users, false).await.unwrap();
+        assert_eq!(report.created, 0);
+        assert_eq!(report.rows[0].outcome, ImportOutcome::Exists);
+    }
+}
@@ -0,0 +1,422 @@
+use crate::common::course::CourseError;
+use crate::common::message::can_read_message;
+use crate::common::store::{Store, StoreError, StoreResult};
+use crate::common::{
+    Attachment, AttachmentRef, AttachmentStorage, User, UserRole, ATTACHMENT_CONTENT_TYPES,
+    ATTACHMENT_MAX_SIZE, ATTACHMENT_NAME_MAX_LEN, MESSAGE_MAX_ATTACHMENTS,
+};
+use chrono::Utc;
+use log::warn;
+use std::fmt;
+use uuid::Uuid;
+
+/// Why a file couldn't be uploaded, attached or downloaded.
+#[derive(Debug)]
+pub enum AttachmentError {
+    Invalid(String),
+    TooLarge,
+    UnsupportedType(String),
+    // missing, or not visible to the caller
+    NotFound,
+    // attaching a file someone else uploaded
+    Forbidden,
+    Store(StoreError),
+}
+
+impl fmt::Display for AttachmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttachmentError::Invalid(reason) => write!(f, "{}", reason),
+            AttachmentError::TooLarge => {
+                write!(
+                    f,
+                    "attachments are limited to {} bytes",
+                    ATTACHMENT_MAX_SIZE
+                )
+            }
+            AttachmentError::UnsupportedType(t) => write!(f, "unsupported content type {}", t),
+            AttachmentError::NotFound => write!(f, "attachment not found"),
+            AttachmentError::Forbidden => write!(f, "forbidden"),
+            AttachmentError::Store(e) => write!(f, "{:?}", e),
+        }
+    }
+}
+
+impl From<StoreError> for AttachmentError {
+    fn from(e: StoreError) -> Self {
+        AttachmentError::Store(e)
+    }
+}
+
+impl From<AttachmentError> for CourseError {
+    fn from(e: AttachmentError) -> Self {
+        match e {
+            AttachmentError::NotFound => CourseError::NotFound("attachment"),
+            AttachmentError::Forbidden => CourseError::Forbidden,
+            AttachmentError::Store(e) => CourseError::Store(e),
+            e => CourseError::Invalid(e.to_string()),
+        }
+    }
+}
+
+/// The file name without any directory, trimmed.
+fn validate_file_name(file_name: &str) -> Result<String, AttachmentError> {
+    let name = file_name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .trim();
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(AttachmentError::Invalid(
+            "file name is required".to_string(),
+        ));
+    }
+    if name.chars().count() > ATTACHMENT_NAME_MAX_LEN || name.chars().any(char::is_control) {
+        return Err(AttachmentError::Invalid(format!(
+            "file name must be at most {} printable characters",
+            ATTACHMENT_NAME_MAX_LEN
+        )));
+    }
+
+    Ok(name.to_string())
+}
+
+/// The MIME type without parameters, if it's one we accept.
+fn validate_content_type(content_type: &str) -> Result<String, AttachmentError> {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    match ATTACHMENT_CONTENT_TYPES.contains(&mime.as_str()) {
+        true => Ok(mime),
+        false => Err(AttachmentError::UnsupportedType(mime)),
+    }
+}
+
+/// Stores an uploaded file, owned by `user` until it's attached somewhere.
+pub async fn try_upload_attachment(
+    db: &dyn Store,
+    storage: &dyn AttachmentStorage,
+    user: &User,
+    file_name: &str,
+    content_type: &str,
+    data: Vec<u8>,
+) -> Result<Attachment, AttachmentError> {
+    let file_name = validate_file_name(file_name)?;
+    let content_type = validate_content_type(content_type)?;
+    if data.is_empty() {
+        return Err(AttachmentError::Invalid("file is empty".to_string()));
+    }
+    if data.len() > ATTACHMENT_MAX_SIZE {
+        return Err(AttachmentError::TooLarge);
+    }
+
+    let attachment = Attachment {
+        id: Uuid::new_v4(),
+        owner_id: user.uid,
+        file_name,
+        size: data.len() as u64,
+        content_type,
+        created_at: Utc::now(),
+        refs: Vec::new(),
+    };
+    let key = attachment.id.to_string();
+    storage.put(&key, &attachment.content_type, data).await?;
+    if let Err(e) = db.insert_attachment(&attachment).await {
+        // nothing refers to the file without its metadata
+        if let Err(e) = storage.delete(&key).await {
+            warn!("failed to delete orphaned attachment {}: {:?}", key, e);
+        }
+        return Err(e.into());
+    }
+
+    Ok(attachment)
+}
+
+/// The attachments `ids` if `user` may attach them: they uploaded them, or
+/// are an admin.
+pub async fn attachable(
+    db: &dyn Store,
+    user: &User,
+    ids: &[Uuid],
+) -> Result<Vec<Attachment>, AttachmentError> {
+    let mut attachments = Vec::with_capacity(ids.len());
+    for id in ids {
+        let attachment = db
+            .get_attachment(id)
+            .await?
+            .ok_or(AttachmentError::NotFound)?;
+        if attachment.owner_id != user.uid && user.role != UserRole::Admin {
+            return Err(AttachmentError::Forbidden);
+        }
+        attachments.push(attachment);
+    }
+
+    Ok(attachments)
+}
+
+/// Checks the attachments of a message `user` is about to send.
+pub async fn check_message_attachments(
+    db: &dyn Store,
+    user: &User,
+    ids: &[Uuid],
+) -> Result<(), AttachmentError> {
+    if ids.len() > MESSAGE_MAX_ATTACHMENTS {
+        return Err(AttachmentError::Invalid(format!(
+            "a message has at most {} attachments",
+            MESSAGE_MAX_ATTACHMENTS
+        )));
+    }
+
+    attachable(db, user, ids).await.map(|_| ())
+}
+
+/// Records that the attachments `ids` are used by `by`, so the people who
+/// can see it may download them.
+pub async fn link_attachments(db: &dyn Store, ids: &[Uuid], by: AttachmentRef) -> StoreResult<()> {
+    for id in ids {
+        let mut attachment = match db.get_attachment(id).await? {
+            Some(a) => a,
+            None => continue,
+        };
+        if !attachment.refs.contains(&by) {
+            attachment.refs.push(by.clone());
+            db.update_attachment(&attachment).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `user` can see a lesson of `course_id` that uses the attachment:
+/// the course's teacher and admins always, enrolled students and their
+/// parents once it's published.
+async fn can_see_lesson_attachment(
+    db: &dyn Store,
+    user: &User,
+    attachment: &Attachment,
+    course_id: &Uuid,
+    lesson_id: &Uuid,
+) -> StoreResult<bool> {
+    let lesson = match db.get_lesson(lesson_id).await? {
+        Some(l) if l.course_id == *course_id => l,
+        _ => return Ok(false),
+    };
+    if !lesson
+        .materials
+        .iter()
+        .any(|m| m.attachment_id == Some(attachment.id))
+    {
+        return Ok(false);
+    }
+    let course = match db.get_course(course_id).await? {
+        Some(c) => c,
+        None => return Ok(false),
+    };
+    if course.teacher_id == user.uid || user.role == UserRole::Admin {
+        return Ok(true);
+    }
+    if !lesson.published {
+        return Ok(false);
+    }
+
+    match user.role {
+        UserRole::Student => Ok(db
+            .find_enrollment(&user.uid, course_id)
+            .await?
+            .is_some_and(|e| e.is_active())),
+        UserRole::Parent => {
+            for kid in db.list_students_of(&user.uid).await? {
+                if db
+                    .find_enrollment(&kid.student_id, course_id)
+                    .await?
+                    .is_some_and(|e| e.is_active())
+                {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Owners can always download their files, others if they can read a
+/// message or see a lesson it's still attached to.
+pub async fn can_download(
+    db: &dyn Store,
+    user: &User,
+    attachment: &Attachment,
+) -> StoreResult<bool> {
+    if attachment.owner_id == user.uid {
+        return Ok(true);
+    }
+
+    for r in &attachment.refs {
+        let allowed = match r {
+            AttachmentRef::Message { message_id } => {
+                db.get_message(message_id).await?.is_some_and(|m| {
+                    m.attachments.contains(&attachment.id) && can_read_message(&m, user)
+                })
+            }
+            AttachmentRef::Lesson {
+                course_id,
+                lesson_id,
+            } => can_see_lesson_attachment(db, user, attachment, course_id, lesson_id).await?,
+        };
+        if allowed {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// The attachment and its bytes, if `user` may download it.
+pub async fn try_download_attachment(
+    db: &dyn Store,
+    storage: &dyn AttachmentStorage,
+    user: &User,
+    id: &Uuid,
+) -> Result<(Attachment, Vec<u8>), AttachmentError> {
+    let attachment = db
+        .get_attachment(id)
+        .await?
+        .ok_or(AttachmentError::NotFound)?;
+    if !can_download(db, user, &attachment).await? {
+        return Err(AttachmentError::NotFound);
+    }
+    let data = storage
+        .get(&attachment.id.to_string())
+        .await?
+        .ok_or(AttachmentError::NotFound)?;
+
+    Ok((attachment, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::lesson::{LessonBody, ModuleBody};
+    use crate::api::message::MessageBody;
+    use crate::common::attachment::{
+        check_message_attachments, try_download_attachment, try_upload_attachment, AttachmentError,
+    };
+    use crate::common::lesson::{try_create_lesson, try_create_module, try_set_lesson_published};
+    use crate::common::message::try_send_messages;
+    use crate::common::store::{Fixtures, MemoryStore};
+    use crate::common::{LocalStorage, Material, Notifier, ATTACHMENT_MAX_SIZE};
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_attachment_permissions_async() {
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+        let root = std::env::temp_dir().join(format!("edclass-{}", Uuid::new_v4()));
+        let storage = LocalStorage::new(&root);
+        let notifier = Notifier::default();
+        let math_id = Fixtures::id("math");
+        let teacher = db.fixture_user("teacher").await;
+        let parent = db.fixture_user("parent").await;
+        let student = db.fixture_user("student").await;
+        let pdf = || b"%PDF-1.4 worksheet".to_vec();
+
+        assert!(matches!(
+            try_upload_attachment(&db, &storage, &teacher, "run.sh", "text/x-sh", pdf()).await,
+            Err(AttachmentError::UnsupportedType(_))
+        ));
+        let huge = vec![0; ATTACHMENT_MAX_SIZE + 1];
+        assert!(matches!(
+            try_upload_attachment(&db, &storage, &teacher, "a.pdf", "application/pdf", huge).await,
+            Err(AttachmentError::TooLarge)
+        ));
+        let sheet = try_upload_attachment(
+            &db,
+            &storage,
+            &teacher,
+            "../../sheet.pdf",
+            "application/PDF; charset=binary",
+            pdf(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(sheet.file_name, "sheet.pdf");
+        assert_eq!(sheet.content_type, "application/pdf");
+
+        // only its owner sees an attachment nobody was sent
+        assert!(matches!(
+            try_download_attachment(&db, &storage, &parent, &sheet.id).await,
+            Err(AttachmentError::NotFound)
+        ));
+        assert!(matches!(
+            check_message_attachments(&db, &parent, &[sheet.id]).await,
+            Err(AttachmentError::Forbidden)
+        ));
+
+        check_message_attachments(&db, &teacher, &[sheet.id])
+            .await
+            .unwrap();
+        try_send_messages(
+            &db,
+            &notifier,
+            &teacher,
+            MessageBody {
+                receiver_ids: vec![parent.email.clone()],
+                subject: None,
+                content: "homework attached".to_string(),
+                attachments: vec![sheet.id],
+            },
+        )
+        .await
+        .unwrap();
+        let (_, data) = try_download_attachment(&db, &storage, &parent, &sheet.id)
+            .await
+            .unwrap();
+        assert_eq!(data, pdf());
+        assert!(matches!(
+            try_download_attachment(&db, &storage, &student, &sheet.id).await,
+            Err(AttachmentError::NotFound)
+        ));
+
+        // the enrolled student gets it once the lesson is published
+        let module = try_create_module(
+            &db,
+            &teacher,
+            &math_id,
+            ModuleBody {
+                title: "Algebra".to_string(),
+            },
+        )
+        .await
+        .unwrap();
+        let lesson = try_create_lesson(
+            &db,
+            &teacher,
+            &math_id,
+            &module.id,
+            LessonBody {
+                title: "Fractions".to_string(),
+                body: String::new(),
+                links: vec![],
+                materials: vec![Material {
+                    title: "Worksheet".to_string(),
+                    url: None,
+                    attachment_id: Some(sheet.id),
+                }],
+            },
+        )
+        .await
+        .unwrap();
+        assert!(try_download_attachment(&db, &storage, &student, &sheet.id)
+            .await
+            .is_err());
+        try_set_lesson_published(&db, &teacher, &math_id, &lesson.id, true)
+            .await
+            .unwrap();
+        assert!(try_download_attachment(&db, &storage, &student, &sheet.id)
+            .await
+            .is_ok());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -3,6 +3,94 @@ pub const USERS_COLLECTION: &str = "users";
 pub const STUDENTS_PARENTS_COLLECTION: &str = "students-parents";
 pub const ENROLLMENTS_COLLECTION: &str = "enrollment";
 pub const COURSES_COLLECTION: &str = "courses";
-pub const FCM_URL: &str = "https://fcm.googleapis.com/fcm/send";
+pub const REFRESH_TOKENS_COLLECTION: &str = "refresh-tokens";
+pub const REVOKED_TOKENS_COLLECTION: &str = "revoked-tokens";
+pub const OUTBOX_COLLECTION: &str = "outbox";
+pub const DIGESTS_COLLECTION: &str = "digests";
+pub const COURSE_MODULES_COLLECTION: &str = "course-modules";
+pub const LESSONS_COLLECTION: &str = "lessons";
+pub const LESSON_COMPLETIONS_COLLECTION: &str = "lesson-completions";
+pub const ATTACHMENTS_COLLECTION: &str = "attachments";
+pub const COURSE_SEATS_COLLECTION: &str = "course-seats";
+pub const AUDIT_LOG_COLLECTION: &str = "audit-log";
+pub const EMAIL_DEFAULT_SUBJECT: &str = "New message on edclass";
+pub const FCM_BASE_URL: &str = "https://fcm.googleapis.com";
+pub const FCM_SCOPE: &str = "https://www.googleapis.com/auth/firebase.messaging";
 
-pub const MAX_FCM_TOKENS_PER_REQUEST: usize = 1000;
+// HTTP v1 takes one device token per request
+pub const FCM_SEND_CONCURRENCY: usize = 16;
+pub const FCM_TOKEN_REFRESH_MARGIN_SECS: i64 = 60;
+
+pub const OUTBOX_MAX_ATTEMPTS: u32 = 8;
+// doubles per attempt: 30s, 1m, 2m, ... capped at an hour
+pub const OUTBOX_RETRY_BASE_SECS: i64 = 30;
+pub const OUTBOX_RETRY_MAX_SECS: i64 = 60 * 60;
+// how long a claimed entry is left alone before another worker may retry it
+pub const OUTBOX_LEASE_SECS: i64 = 5 * 60;
+pub const OUTBOX_POLL_INTERVAL_SECS: u64 = 10;
+pub const OUTBOX_BATCH_SIZE: usize = 50;
+
+pub const DIGEST_POLL_INTERVAL_SECS: u64 = 5 * 60;
+pub const DIGEST_BATCH_SIZE: usize = 50;
+pub const DIGEST_LEASE_SECS: i64 = 10 * 60;
+// a digest that couldn't be sent is tried again this much later
+pub const DIGEST_RETRY_SECS: i64 = 15 * 60;
+// per section, the rest is only counted
+pub const DIGEST_MAX_ITEMS: usize = 20;
+
+// events a subscriber may fall behind before it misses some
+pub const EVENT_BUS_CAPACITY: usize = 256;
+// comment line sent on idle event streams so proxies keep them open
+pub const EVENTS_KEEPALIVE_SECS: u64 = 15;
+
+// in characters
+pub const COURSE_TITLE_MAX_LEN: usize = 200;
+pub const COURSE_CONTENT_MAX_LEN: usize = 100_000;
+pub const LESSON_BODY_MAX_LEN: usize = 200_000;
+pub const LESSON_MAX_LINKS: usize = 50;
+pub const ATTACHMENT_NAME_MAX_LEN: usize = 255;
+
+// students added in one go, a few classes' worth
+pub const ROSTER_MAX_ROWS: usize = 1000;
+// documents checked and written in one Firestore transaction or batch,
+// well under its write limit
+pub const FIRESTORE_BATCH_SIZE: usize = 250;
+// values Firestore takes in one `in` filter
+pub const FIRESTORE_IN_MAX_VALUES: usize = 30;
+// accounts created in one import, a school's worth
+pub const USER_IMPORT_MAX_ROWS: usize = 5000;
+
+// in bytes
+pub const ATTACHMENT_MAX_SIZE: usize = 10 * 1024 * 1024;
+pub const MESSAGE_MAX_ATTACHMENTS: usize = 10;
+// worksheets, pictures and office documents
+pub const ATTACHMENT_CONTENT_TYPES: &[&str] = &[
+    "application/pdf",
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "text/plain",
+    "text/csv",
+    "application/msword",
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    "application/vnd.ms-excel",
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    "application/vnd.ms-powerpoint",
+    "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+    "application/vnd.oasis.opendocument.text",
+    "application/vnd.oasis.opendocument.spreadsheet",
+];
+// region used to sign object store requests when none is configured
+pub const OBJECT_STORE_DEFAULT_REGION: &str = "us-east-1";
+
+pub const DEFAULT_MESSAGES_PAGE_SIZE: usize = 20;
+pub const MAX_MESSAGES_PAGE_SIZE: usize = 100;
+pub const DEFAULT_USERS_PAGE_SIZE: usize = 50;
+pub const MAX_USERS_PAGE_SIZE: usize = 200;
+// users read for one page of a Firestore listing at most, a page cut
+// short by it still ends with a cursor
+pub const USERS_SCAN_LIMIT: usize = 1000;
+
+pub const ACCESS_TOKEN_TTL_SECS: i64 = 15 * 60;
+pub const REFRESH_TOKEN_TTL_SECS: i64 = 30 * 24 * 60 * 60;
@@ -1,51 +1,68 @@
-use crate::common::enrollment::list_user_enrolled_in;
-use crate::common::user::get_user_by_id;
+use crate::api::course::CourseBody;
+use crate::api::message::MessageBody;
+use crate::common::enrollment::{
+    admit_approved, list_user_enrolled_in, list_waitlist, promote_waitlist,
+};
+use crate::common::lesson::course_outline;
+use crate::common::message::try_send_notice;
+use crate::common::store::{Store, StoreError, StoreResult};
+use crate::common::user::{get_user_by_id, try_get_student_parents};
 use crate::common::{
-    Course, CourseEnrollment, CourseResponse, Enrollment, EnrollmentCounter, MyCourse, User,
-    UserRole, COURSES_COLLECTION, ENROLLMENTS_COLLECTION, USERS_COLLECTION,
+    Course, CourseEnrollment, CourseResponse, MyCourse, NotificationCategory, Notifier, User,
+    UserRole, COURSE_CONTENT_MAX_LEN, COURSE_TITLE_MAX_LEN,
 };
-use firestore::struct_path::path;
-use firestore::{FirestoreDb, FirestoreResult};
-use futures::stream::BoxStream;
-use futures::StreamExt;
+use chrono::Utc;
+use log::warn;
+use std::fmt;
 use uuid::Uuid;
 
-pub async fn list_courses(
-    db: &FirestoreDb,
-    user: &User,
-) -> Result<Vec<CourseEnrollment>, Box<dyn std::error::Error>> {
-    let mut box_courses: BoxStream<FirestoreResult<Course>> = db
-        .fluent()
-        .list()
-        .from(COURSES_COLLECTION)
-        .obj()
-        .stream_all_with_errors()
-        .await?;
+/// Why a course couldn't be created or changed.
+#[derive(Debug)]
+pub enum CourseError {
+    Invalid(String),
+    // what wasn't found, e.g. "course"
+    NotFound(&'static str),
+    // not the teacher of the course, nor an admin
+    Forbidden,
+    Archived,
+    // the change doesn't fit the current state, e.g. deleting a module
+    // that still has lessons
+    Conflict(String),
+    Store(StoreError),
+}
+
+impl fmt::Display for CourseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CourseError::Invalid(reason) => write!(f, "{}", reason),
+            CourseError::NotFound(what) => write!(f, "{} not found", what),
+            CourseError::Forbidden => write!(f, "forbidden"),
+            CourseError::Archived => write!(f, "course is archived"),
+            CourseError::Conflict(reason) => write!(f, "{}", reason),
+            CourseError::Store(e) => write!(f, "{:?}", e),
+        }
+    }
+}
+
+impl From<StoreError> for CourseError {
+    fn from(e: StoreError) -> Self {
+        CourseError::Store(e)
+    }
+}
 
+/// Archived courses are left out, see `list_my_courses` for a teacher's own.
+pub async fn list_courses(db: &dyn Store, user: &User) -> StoreResult<Vec<CourseEnrollment>> {
     let mut courses: Vec<CourseEnrollment> = Vec::new();
-    while let Some(Ok(c)) = box_courses.next().await {
+    for c in db.list_courses().await? {
+        if c.archived_at.is_some() {
+            continue;
+        }
         if user.role == UserRole::Student {
-            let enrollment: Vec<Enrollment> = db
-                .fluent()
-                .select()
-                .from(ENROLLMENTS_COLLECTION)
-                .filter(|q| {
-                    // filter query
-                    q.for_all([
-                        // query enroll id
-                        q.field(path!(Enrollment::course_id)).eq(&c.id.to_string()),
-                        q.field(path!(Enrollment::student_id))
-                            .eq(&user.uid.to_string()),
-                    ])
-                })
-                .limit(1)
-                .obj()
-                .query()
-                .await?;
+            let enrollment = db.find_enrollment(&user.uid, &c.id).await?;
 
             courses.push(CourseEnrollment {
                 course: c,
-                enrolled: enrollment.iter().next().is_some(),
+                enrolled: enrollment.is_some_and(|e| e.is_active()),
             })
         } else {
             courses.push(CourseEnrollment {
@@ -54,56 +71,17 @@ pub async fn list_courses(
             })
         }
     }
-    //box_courses.map(|c: Course| {}).try_collect().await?;
-    //let courses = box_courses.try_collect().await?;
 
     Ok(courses)
 }
 
-pub async fn list_my_courses(
-    db: &FirestoreDb,
-    user: &User,
-) -> Result<Vec<MyCourse>, Box<dyn std::error::Error>> {
-    let mut box_courses: BoxStream<FirestoreResult<Course>> = db
-        .fluent()
-        .select()
-        .from(COURSES_COLLECTION)
-        .filter(|q| {
-            // filter course
-            q.for_all([q.field(path!(Course::teacher_id)).eq(&user.uid)])
-        })
-        .obj()
-        .stream_query_with_errors()
-        .await?;
-
+pub async fn list_my_courses(db: &dyn Store, user: &User) -> StoreResult<Vec<MyCourse>> {
     let mut courses: Vec<MyCourse> = Vec::new();
-    while let Some(Ok(c)) = box_courses.next().await {
-        let student_counts: Vec<EnrollmentCounter> = db
-            .fluent()
-            .select()
-            .from(ENROLLMENTS_COLLECTION)
-            .filter(|q| {
-                // filter field
-                q.for_all([
-                    // filter all
-                    q.field(path!(Enrollment::course_id)).eq(&c.id),
-                ])
-            })
-            .aggregate(|a| {
-                // aggregate count
-                a.fields([a.field(path!(EnrollmentCounter::students)).count()])
-            })
-            .obj()
-            .query()
-            .await?;
-
-        let count = student_counts.iter().next();
+    for c in db.list_teacher_courses(&user.uid).await? {
+        let students = db.count_course_enrollments(&c.id).await?;
         courses.push(MyCourse {
             course: c,
-            students: match count {
-                Some(num) => num.students,
-                _ => 0,
-            },
+            students,
         })
     }
 
@@ -111,21 +89,14 @@ pub async fn list_my_courses(
 }
 
 pub async fn get_course(
-    db: &FirestoreDb,
+    db: &dyn Store,
     user: &User,
     id: &str,
-) -> Result<Option<CourseResponse>, Box<dyn std::error::Error + Send + Sync>> {
-    let select_course: Vec<Course> = db
-        .fluent()
-        .select()
-        .from(COURSES_COLLECTION)
-        .filter(|q| q.for_any([q.field(path!(Course::id)).eq(id)]))
-        .limit(1)
-        .obj()
-        .query()
-        .await?;
-
-    let course = select_course.into_iter().next();
+) -> StoreResult<Option<CourseResponse>> {
+    let course = match Uuid::parse_str(id) {
+        Ok(uuid) => db.get_course(&uuid).await?,
+        _ => None,
+    };
 
     match course {
         Some(c) => {
@@ -134,20 +105,42 @@ pub async fn get_course(
 
             match teacher {
                 Some(t) => {
+                    let outline = course_outline(db, user, &c).await?;
+                    let waitlisted = list_waitlist(db, &c.id).await?;
                     if user.role == UserRole::Student {
                         let enrolled = students.iter().find(|s| s.uid == user.uid).is_some();
+                        let waitlist_position = waitlisted
+                            .iter()
+                            .position(|e| e.student_id == user.uid)
+                            .map(|p| p + 1);
                         Ok(Some(CourseResponse {
                             course: c,
                             teacher: t,
                             students,
                             enrolled,
+                            waitlist_position,
+                            waitlist: Vec::new(),
+                            outline,
                         }))
                     } else {
+                        let mut waitlist = Vec::new();
+                        if user.role == UserRole::Admin || c.teacher_id == user.uid {
+                            let ids = waitlisted.iter().map(|e| e.student_id).collect::<Vec<_>>();
+                            let users = db.get_users(&ids).await?;
+                            // in waitlist order
+                            waitlist = ids
+                                .iter()
+                                .filter_map(|id| users.iter().find(|u| u.uid == *id).cloned())
+                                .collect();
+                        }
                         Ok(Some(CourseResponse {
                             course: c,
                             teacher: t,
                             students,
                             enrolled: false,
+                            waitlist_position: None,
+                            waitlist,
+                            outline,
                         }))
                     }
                 }
@@ -158,29 +151,12 @@ pub async fn get_course(
     }
 }
 
-pub async fn get_teacher(
-    db: &FirestoreDb,
-    course_id: &Uuid,
-) -> Result<User, Box<dyn std::error::Error>> {
-    let course_str = course_id.to_string();
-    let course: Option<Course> = db
-        .fluent()
-        .select()
-        .by_id_in(COURSES_COLLECTION)
-        .obj()
-        .one(&course_str)
-        .await?;
+pub async fn get_teacher(db: &dyn Store, course_id: &Uuid) -> StoreResult<User> {
+    let course = db.get_course(course_id).await?;
 
     match course {
         Some(c) => {
-            let teacher_id_str = c.teacher_id.to_string();
-            let teacher = db
-                .fluent()
-                .select()
-                .by_id_in(USERS_COLLECTION)
-                .obj()
-                .one(&teacher_id_str)
-                .await?;
+            let teacher = db.get_user(&c.teacher_id).await?;
 
             match teacher {
                 Some(t) => Ok(t),
@@ -197,21 +173,257 @@ pub async fn get_teacher(
     }
 }
 
+/// Checks the title and content a teacher sent, returns them trimmed.
+pub fn validate_course(body: &CourseBody) -> Result<(String, String), CourseError> {
+    let title = body.title.trim();
+    let content = body.content.trim();
+    if title.is_empty() {
+        return Err(CourseError::Invalid("title is required".to_string()));
+    }
+    if title.chars().count() > COURSE_TITLE_MAX_LEN {
+        return Err(CourseError::Invalid(format!(
+            "title is longer than {} characters",
+            COURSE_TITLE_MAX_LEN
+        )));
+    }
+    if content.is_empty() {
+        return Err(CourseError::Invalid("content is required".to_string()));
+    }
+    if content.chars().count() > COURSE_CONTENT_MAX_LEN {
+        return Err(CourseError::Invalid(format!(
+            "content is longer than {} characters",
+            COURSE_CONTENT_MAX_LEN
+        )));
+    }
+    if body.capacity == Some(Some(0)) {
+        return Err(CourseError::Invalid(
+            "capacity must be at least 1".to_string(),
+        ));
+    }
+
+    Ok((title.to_string(), content.to_string()))
+}
+
+/// Teachers teach their own courses, admins name the teacher.
+async fn course_teacher(
+    db: &dyn Store,
+    user: &User,
+    teacher_id: Option<Uuid>,
+) -> Result<Uuid, CourseError> {
+    match (user.role, teacher_id) {
+        (UserRole::Teacher, None) => Ok(user.uid),
+        (UserRole::Teacher, Some(id)) if id == user.uid => Ok(id),
+        (UserRole::Admin, Some(id)) => match get_user_by_id(db, &id).await? {
+            Some(t) if t.role == UserRole::Teacher => Ok(id),
+            _ => Err(CourseError::Invalid(
+                "teacher_id is not a teacher".to_string(),
+            )),
+        },
+        (UserRole::Admin, None) => Err(CourseError::Invalid("teacher_id is required".to_string())),
+        _ => Err(CourseError::Forbidden),
+    }
+}
+
+/// The course `id` if `user` may change it: its teacher or an admin.
+pub(crate) async fn editable_course(
+    db: &dyn Store,
+    user: &User,
+    id: &Uuid,
+) -> Result<Course, CourseError> {
+    let course = db
+        .get_course(id)
+        .await?
+        .ok_or(CourseError::NotFound("course"))?;
+    if user.role != UserRole::Admin && course.teacher_id != user.uid {
+        return Err(CourseError::Forbidden);
+    }
+    if course.archived_at.is_some() {
+        return Err(CourseError::Archived);
+    }
+
+    Ok(course)
+}
+
+pub async fn try_create_course(
+    db: &dyn Store,
+    user: &User,
+    body: CourseBody,
+) -> Result<Course, CourseError> {
+    let (title, content) = validate_course(&body)?;
+    let course = Course {
+        id: Uuid::new_v4(),
+        title,
+        content,
+        teacher_id: course_teacher(db, user, body.teacher_id).await?,
+        archived_at: None,
+        capacity: body.capacity.flatten(),
+        teacher_approval: body.teacher_approval.unwrap_or_default(),
+        parent_consent: body.parent_consent.unwrap_or_default(),
+    };
+    db.insert_course(&course).await?;
+
+    Ok(course)
+}
+
+/// Replaces title and content, and the teacher when an admin names another
+/// one. The capacity and approval settings are kept when the body leaves
+/// them out. Students and their parents are told what changed.
+pub async fn try_update_course(
+    db: &dyn Store,
+    notifier: &Notifier,
+    user: &User,
+    id: &Uuid,
+    body: CourseBody,
+) -> Result<Course, CourseError> {
+    let old = editable_course(db, user, id).await?;
+    let (title, content) = validate_course(&body)?;
+    let teacher_id = match body.teacher_id {
+        Some(t) if t != old.teacher_id => course_teacher(db, user, Some(t)).await?,
+        _ => old.teacher_id,
+    };
+    let course = Course {
+        title,
+        content,
+        teacher_id,
+        capacity: body.capacity.unwrap_or(old.capacity),
+        teacher_approval: body.teacher_approval.unwrap_or(old.teacher_approval),
+        parent_consent: body.parent_consent.unwrap_or(old.parent_consent),
+        ..old.clone()
+    };
+    db.update_course(&course).await?;
+
+    // requests may have all the approvals still asked for
+    if (old.teacher_approval && !course.teacher_approval)
+        || (old.parent_consent && !course.parent_consent)
+    {
+        if let Err(e) = admit_approved(db, notifier, &course).await {
+            warn!("failed to admit the requests of {}: {:?}", course.id, e);
+        }
+    }
+
+    // new seats go to the waitlist
+    if course.capacity != old.capacity {
+        if let Err(e) = promote_waitlist(db, notifier, &course).await {
+            warn!("failed to promote the waitlist of {}: {:?}", course.id, e);
+        }
+    }
+
+    let mut changes = Vec::new();
+    if course.title != old.title {
+        changes.push(format!("It was renamed from \"{}\".", old.title));
+    }
+    if course.content != old.content {
+        changes.push("Its description was updated.".to_string());
+    }
+    if course.teacher_id != old.teacher_id {
+        if let Some(t) = get_user_by_id(db, &course.teacher_id).await? {
+            changes.push(format!("It is now taught by {}.", t.name));
+        }
+    }
+    if !changes.is_empty() {
+        notify_course_change(
+            db,
+            notifier,
+            user,
+            &course,
+            format!("Course updated: {}", course.title),
+            changes.join("\n"),
+        )
+        .await;
+    }
+
+    Ok(course)
+}
+
+/// Soft-deletes the course: it stays readable by id but can't be changed or
+/// enrolled in anymore.
+pub async fn try_archive_course(
+    db: &dyn Store,
+    notifier: &Notifier,
+    user: &User,
+    id: &Uuid,
+) -> Result<Course, CourseError> {
+    let course = Course {
+        archived_at: Some(Utc::now()),
+        ..editable_course(db, user, id).await?
+    };
+    db.update_course(&course).await?;
+
+    notify_course_change(
+        db,
+        notifier,
+        user,
+        &course,
+        format!("Course archived: {}", course.title),
+        format!("The course \"{}\" has been archived.", course.title),
+    )
+    .await;
+
+    Ok(course)
+}
+
+/// Emails of the students of the course and their parents.
+pub async fn course_audience(db: &dyn Store, course_id: &Uuid) -> StoreResult<Vec<String>> {
+    let mut receivers = Vec::new();
+    for student in list_user_enrolled_in(db, course_id).await? {
+        for parent in try_get_student_parents(db, &student.uid).await? {
+            if !receivers.contains(&parent.email) {
+                receivers.push(parent.email);
+            }
+        }
+        receivers.push(student.email);
+    }
+
+    Ok(receivers)
+}
+
+/// Sends an announcement from `user` to the `course_audience`. The change is
+/// already stored, so failures are only logged.
+async fn notify_course_change(
+    db: &dyn Store,
+    notifier: &Notifier,
+    user: &User,
+    course: &Course,
+    subject: String,
+    content: String,
+) {
+    let receivers = match course_audience(db, &course.id).await {
+        Ok(r) if r.is_empty() => return,
+        Ok(r) => r,
+        Err(e) => {
+            warn!("failed to notify about course {}: {:?}", course.id, e);
+            return;
+        }
+    };
+
+    let body = MessageBody {
+        receiver_ids: receivers,
+        subject: Some(subject),
+        content,
+        attachments: vec![],
+    };
+    let category = NotificationCategory::Announcements;
+    if let Err(e) = try_send_notice(db, notifier, user, body, category).await {
+        warn!("failed to notify about course {}: {:?}", course.id, e);
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::common::course::{get_course, get_teacher, list_courses, list_my_courses};
-    use crate::common::{config_env_var, User, UserRole};
-    use dotenv::dotenv;
-    use firestore::FirestoreDb;
+    use crate::api::course::CourseBody;
+    use crate::common::course::{
+        get_course, get_teacher, list_courses, list_my_courses, try_archive_course,
+        try_create_course, try_update_course, CourseError,
+    };
+    use crate::common::message::{try_list_messages, MessageQuery, MessageType};
+    use crate::common::store::{Fixtures, MemoryStore, UserStore};
+    use crate::common::user::get_user_by_id;
+    use crate::common::{NotificationCategory, Notifier, User, UserRole};
     use uuid::Uuid;
 
     #[tokio::test]
     async fn test_get_teacher_async() {
-        dotenv().unwrap();
-        let project_id = config_env_var("PROJECT_ID").expect("failed to load project id");
-        let db = FirestoreDb::new(&project_id)
-            .await
-            .expect("failed to load firestore db");
+        let db = MemoryStore::from_fixtures(Fixtures::test());
 
         let teacher = get_teacher(
             &db,
@@ -223,11 +435,7 @@ mod tests {
     }
     #[tokio::test]
     async fn test_get_course_async() {
-        dotenv().unwrap();
-        let project_id = config_env_var("PROJECT_ID").expect("failed to load project id");
-        let db = FirestoreDb::new(&project_id)
-            .await
-            .expect("failed to load firestore db");
+        let db = MemoryStore::from_fixtures(Fixtures::test());
 
         let course = get_course(
             &db,
@@ -238,6 +446,8 @@ mod tests {
                 email: "hl@hl.com".to_string(),
                 name: "hl".to_string(),
                 role: UserRole::Student,
+                preferences: Default::default(),
+                deactivated_at: None,
             },
             "893a39bd-17d3-4a59-9b08-f2a1572fdd88",
         )
@@ -249,11 +459,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_list_course_async() {
-        dotenv().unwrap();
-        let project_id = config_env_var("PROJECT_ID").expect("failed to load project id");
-        let db = FirestoreDb::new(&project_id)
-            .await
-            .expect("failed to load firestore db");
+        let db = MemoryStore::from_fixtures(Fixtures::test());
 
         let courses = list_courses(
             &db,
@@ -264,6 +470,8 @@ mod tests {
                 email: "hl@hl.com".to_string(),
                 name: "hl".to_string(),
                 role: UserRole::Student,
+                preferences: Default::default(),
+                deactivated_at: None,
             },
         )
         .await
@@ -273,11 +481,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_list_my_courses_async() {
-        dotenv().unwrap();
-        let project_id = config_env_var("PROJECT_ID").expect("failed to load project id");
-        let db = FirestoreDb::new(&project_id)
-            .await
-            .expect("failed to load firestore db");
+        let db = MemoryStore::from_fixtures(Fixtures::test());
 
         let result = list_my_courses(
             &db,
@@ -288,10 +492,89 @@ mod tests {
                 email: "t1@t1.com".to_string(),
                 name: "t1".to_string(),
                 role: UserRole::Teacher,
+                preferences: Default::default(),
+                deactivated_at: None,
             },
         )
         .await;
 
         println!("my courses {:#?}", result)
     }
+
+    #[tokio::test]
+    async fn test_course_changes_async() {
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+        let notifier = Notifier::default();
+        let math_id = Fixtures::id("math");
+        let teacher = db.fixture_user("teacher").await;
+        let parent = db.fixture_user("parent").await;
+        let body = |title: &str| CourseBody {
+            title: title.to_string(),
+            content: "Fractions and decimals".to_string(),
+            teacher_id: None,
+            capacity: None,
+            teacher_approval: None,
+            parent_consent: None,
+        };
+
+        assert!(matches!(
+            try_create_course(&db, &teacher, body("  ")).await,
+            Err(CourseError::Invalid(_))
+        ));
+        assert!(matches!(
+            try_create_course(&db, &parent, body("Art")).await,
+            Err(CourseError::Forbidden)
+        ));
+        let art = try_create_course(&db, &teacher, body(" Art "))
+            .await
+            .unwrap();
+        assert_eq!(art.title, "Art");
+        assert_eq!(art.teacher_id, teacher.uid);
+
+        // another teacher can't touch it
+        let mut other = db.find_user("t1@t1.com").await.unwrap().unwrap();
+        other.uid = Uuid::new_v4();
+        other.email = "t2@t2.com".to_string();
+        db.insert_user(&other).await.unwrap();
+        let other = get_user_by_id(&db, &other.uid).await.unwrap().unwrap();
+        assert!(matches!(
+            try_update_course(&db, &notifier, &other, &math_id, body("Maths")).await,
+            Err(CourseError::Forbidden)
+        ));
+
+        let math = try_update_course(&db, &notifier, &teacher, &math_id, body("Maths"))
+            .await
+            .unwrap();
+        assert_eq!(math.title, "Maths");
+        // the enrolled student's parent is told
+        let inbox = try_list_messages(
+            &db,
+            &parent,
+            MessageType::Received,
+            &MessageQuery::default(),
+        )
+        .await
+        .unwrap();
+        let notice = &inbox.messages[0];
+        assert_eq!(notice.subject.as_deref(), Some("Course updated: Maths"));
+        assert_eq!(notice.category, NotificationCategory::Announcements);
+        assert!(notice.receiver_ids.contains(&"hl@hl.com".to_string()));
+
+        try_archive_course(&db, &notifier, &teacher, &math_id)
+            .await
+            .unwrap();
+        assert!(matches!(
+            try_update_course(&db, &notifier, &teacher, &math_id, body("Math")).await,
+            Err(CourseError::Archived)
+        ));
+        let listed = list_courses(&db, &parent).await.unwrap();
+        assert!(listed.iter().all(|c| c.course.id != math_id));
+        assert!(listed.iter().any(|c| c.course.id == art.id));
+        // still readable by id
+        let math = get_course(&db, &parent, &math_id.to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(math.course.archived_at.is_some());
+    }
 }
@@ -0,0 +1,464 @@
+use crate::common::message::{
+    try_count_unread, try_list_messages, MessageFilter, MessageQuery, MessageType,
+};
+use crate::common::store::{Store, StoreResult};
+use crate::common::user::{get_kids, get_user_by_id};
+use crate::common::{
+    Course, DigestFrequency, DigestSchedule, Kid, Message, MessageState, Notification,
+    NotificationCategory, NotificationChannel, Notifier, SendOutcome, User, DIGEST_BATCH_SIZE,
+    DIGEST_LEASE_SECS, DIGEST_MAX_ITEMS, DIGEST_POLL_INTERVAL_SECS, DIGEST_RETRY_SECS,
+    MAX_MESSAGES_PAGE_SIZE,
+};
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, warn};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// What happened since the previous digest of a user.
+#[derive(Debug, Clone, Default)]
+pub struct Digest {
+    // received since and still unread
+    pub unread: Vec<Message>,
+    // every unread received message, also older ones
+    pub unread_total: usize,
+    // kid name and the course they were enrolled in
+    pub enrollments: Vec<(String, Course)>,
+    // to the user or one of their kids
+    pub announcements: Vec<Message>,
+}
+
+impl Digest {
+    pub fn is_empty(&self) -> bool {
+        self.unread.is_empty() && self.enrollments.is_empty() && self.announcements.is_empty()
+    }
+
+    pub fn to_notification(&self, frequency: DigestFrequency) -> Notification {
+        let mut sections = Vec::new();
+        if !self.unread.is_empty() {
+            sections.push(section(
+                &format!("Unread messages ({} in total):", self.unread_total),
+                self.unread.iter().map(message_line),
+            ));
+        }
+        if !self.enrollments.is_empty() {
+            sections.push(section(
+                "New enrollments:",
+                self.enrollments
+                    .iter()
+                    .map(|(kid, course)| format!("{}: {}", kid, course.title)),
+            ));
+        }
+        if !self.announcements.is_empty() {
+            sections.push(section(
+                "Announcements:",
+                self.announcements.iter().map(message_line),
+            ));
+        }
+
+        let period = match frequency {
+            DigestFrequency::Daily => "daily",
+            DigestFrequency::Weekly => "weekly",
+        };
+        Notification {
+            category: NotificationCategory::Digest,
+            title: Some(format!("Your {} summary on edclass", period)),
+            body: sections.join("\n\n"),
+        }
+    }
+}
+
+/// A heading and one `- ` line per item, at most `DIGEST_MAX_ITEMS`.
+fn section<I: ExactSizeIterator<Item = String>>(heading: &str, items: I) -> String {
+    let more = items.len().saturating_sub(DIGEST_MAX_ITEMS);
+    let mut lines = vec![heading.to_string()];
+    lines.extend(items.take(DIGEST_MAX_ITEMS).map(|i| format!("- {}", i)));
+    if more > 0 {
+        lines.push(format!("and {} more", more));
+    }
+    lines.join("\n")
+}
+
+/// The subject, or the start of the content for messages without one.
+fn message_line(message: &Message) -> String {
+    match &message.subject {
+        Some(subject) => subject.clone(),
+        None => {
+            let first = message.content.lines().next().unwrap_or_default();
+            match first.char_indices().nth(80) {
+                Some((end, _)) => format!("{}...", &first[..end]),
+                None => first.to_string(),
+            }
+        }
+    }
+}
+
+/// `student_id:course_id` of every course of `kids`.
+fn enrollment_keys(kids: &[Kid]) -> Vec<String> {
+    kids.iter()
+        .flat_map(|k| {
+            k.courses
+                .iter()
+                .map(move |c| format!("{}:{}", k.user.uid, c.id))
+        })
+        .collect()
+}
+
+async fn received_since(
+    db: &dyn Store,
+    user: &User,
+    since: DateTime<Utc>,
+) -> StoreResult<Vec<Message>> {
+    let query = MessageQuery {
+        filter: MessageFilter {
+            from: Some(since),
+            ..MessageFilter::default()
+        },
+        after: None,
+        limit: MAX_MESSAGES_PAGE_SIZE,
+    };
+    Ok(try_list_messages(db, user, MessageType::Received, &query)
+        .await?
+        .messages)
+}
+
+/// Gathers the digest of `user` since `since`, leaving out the enrollments in
+/// `seen`. Also returns the enrollments to remember for the next one.
+pub async fn gather_digest(
+    db: &dyn Store,
+    user: &User,
+    since: DateTime<Utc>,
+    seen: &[String],
+) -> StoreResult<(Digest, Vec<String>)> {
+    let mut digest = Digest::default();
+    let mut announced = HashSet::new();
+    for message in received_since(db, user, since).await? {
+        match message.category {
+            NotificationCategory::Announcements => {
+                announced.insert(message.id);
+                digest.announcements.push(message);
+            }
+            NotificationCategory::Digest => {}
+            _ if message.state != MessageState::Read => digest.unread.push(message),
+            _ => {}
+        }
+    }
+    if !digest.unread.is_empty() {
+        digest.unread_total = try_count_unread(db, user).await?;
+    }
+
+    let kids = get_kids(db, user).await?;
+    for kid in kids.iter() {
+        for message in received_since(db, &kid.user, since).await? {
+            if message.category == NotificationCategory::Announcements
+                && announced.insert(message.id)
+            {
+                digest.announcements.push(message);
+            }
+        }
+        for course in kid.courses.iter() {
+            if !seen.contains(&format!("{}:{}", kid.user.uid, course.id)) {
+                digest
+                    .enrollments
+                    .push((kid.user.name.clone(), course.clone()));
+            }
+        }
+    }
+    // newest first, like the listings they come from
+    digest
+        .announcements
+        .sort_by_key(|m| std::cmp::Reverse(m.created_at));
+
+    Ok((digest, enrollment_keys(&kids)))
+}
+
+/// Creates, updates or removes the schedule of `user` to match `frequency`.
+/// A new schedule starts from now, so the first digest only reports what
+/// happens afterwards.
+pub async fn sync_digest_schedule(
+    db: &dyn Store,
+    user: &User,
+    frequency: Option<DigestFrequency>,
+) -> StoreResult<()> {
+    let frequency = match frequency {
+        Some(f) => f,
+        None => return db.delete_digest_schedule(&user.uid).await,
+    };
+
+    let schedule = match db.get_digest_schedule(&user.uid).await? {
+        Some(s) if s.frequency == frequency => return Ok(()),
+        Some(s) => DigestSchedule {
+            frequency,
+            next_digest_at: s.last_digest_at + frequency.period(),
+            ..s
+        },
+        None => {
+            let now = Utc::now();
+            DigestSchedule {
+                user_id: user.uid,
+                frequency,
+                next_digest_at: now + frequency.period(),
+                last_digest_at: now,
+                enrollments: enrollment_keys(&get_kids(db, user).await?),
+            }
+        }
+    };
+    db.set_digest_schedule(&schedule).await
+}
+
+/// Email when the user can get it, they asked for a summary in their inbox,
+/// otherwise whatever [`Notifier::channel_for`] picks.
+fn digest_channel<'a>(
+    notifier: &'a Notifier,
+    user: &User,
+) -> Option<(&'a dyn NotificationChannel, Vec<String>)> {
+    notifier
+        .channels()
+        .iter()
+        .filter(|c| c.name() == "email" && user.preferences.channel_enabled(c.name()))
+        .find_map(|c| {
+            let targets = c.targets(user);
+            match targets.is_empty() {
+                true => None,
+                false => Some((c.as_ref(), targets)),
+            }
+        })
+        .or_else(|| notifier.channel_for(user))
+}
+
+/// Sends the digest `schedule` is due for and moves it to the next period.
+/// Nothing is sent when nothing happened, during the user's quiet hours the
+/// digest waits for them to end and failed sends are retried after
+/// `DIGEST_RETRY_SECS`. Returns whether a digest went out.
+pub async fn send_digest(
+    db: &dyn Store,
+    notifier: &Notifier,
+    schedule: &mut DigestSchedule,
+) -> StoreResult<bool> {
+    let user = match get_user_by_id(db, &schedule.user_id).await? {
+        Some(user) => user,
+        None => {
+            db.delete_digest_schedule(&schedule.user_id).await?;
+            return Ok(false);
+        }
+    };
+
+    let now = Utc::now();
+    if let Some(until) = user.preferences.quiet_until(now) {
+        schedule.next_digest_at = until;
+        db.set_digest_schedule(schedule).await?;
+        return Ok(false);
+    }
+
+    let (digest, enrollments) =
+        gather_digest(db, &user, schedule.last_digest_at, &schedule.enrollments).await?;
+    let mut sent = false;
+    if let Some((channel, targets)) = digest_channel(notifier, &user).filter(|_| !digest.is_empty())
+    {
+        let outcomes = match channel
+            .send(&targets, &digest.to_notification(schedule.frequency))
+            .await
+        {
+            Ok(outcomes) => outcomes,
+            Err(e) => vec![(String::new(), SendOutcome::Failed(format!("{:?}", e)))],
+        };
+
+        let mut invalid = Vec::new();
+        let mut failure = None;
+        for (target, outcome) in outcomes {
+            match outcome {
+                SendOutcome::Sent => sent = true,
+                SendOutcome::InvalidTarget => invalid.push(target),
+                SendOutcome::Failed(e) => failure = Some(e),
+            }
+        }
+        if !invalid.is_empty() {
+            channel.prune(db, &user, &invalid).await?;
+        }
+        // one target reached is enough, the others would get it twice
+        if let Some(e) = failure.filter(|_| !sent) {
+            warn!("digest for {} failed: {}", user.email, e);
+            schedule.next_digest_at = now + Duration::seconds(DIGEST_RETRY_SECS);
+            db.set_digest_schedule(schedule).await?;
+            return Ok(false);
+        }
+    }
+
+    schedule.last_digest_at = now;
+    schedule.next_digest_at = now + schedule.frequency.period();
+    schedule.enrollments = enrollments;
+    db.set_digest_schedule(schedule).await?;
+    Ok(sent)
+}
+
+/// Claims the schedules due now and sends each digest. Returns how many were
+/// claimed.
+pub async fn process_digests(db: &dyn Store, notifier: &Notifier) -> StoreResult<usize> {
+    let now = Utc::now();
+    let schedules = db
+        .claim_digest_schedules(
+            &now,
+            &(now + Duration::seconds(DIGEST_LEASE_SECS)),
+            DIGEST_BATCH_SIZE,
+        )
+        .await?;
+
+    let claimed = schedules.len();
+    for mut schedule in schedules {
+        send_digest(db, notifier, &mut schedule).await?;
+    }
+
+    Ok(claimed)
+}
+
+/// Background loop of the server binary, like `run_outbox_worker` but every
+/// `DIGEST_POLL_INTERVAL_SECS`.
+pub async fn run_digest_worker(db: Arc<dyn Store>, notifier: Notifier) {
+    let interval = std::time::Duration::from_secs(DIGEST_POLL_INTERVAL_SECS);
+    loop {
+        match process_digests(db.as_ref(), &notifier).await {
+            Ok(n) if n >= DIGEST_BATCH_SIZE => continue,
+            Ok(n) => debug!("digests: sent up to {}", n),
+            Err(e) => warn!("digest worker failed: {:?}", e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::message::MessageBody;
+    use crate::common::digest::process_digests;
+    use crate::common::message::try_send_notice;
+    use crate::common::store::{DigestStore, EnrollmentStore, Fixtures, MemoryStore, StoreResult};
+    use crate::common::user::try_set_preferences;
+    use crate::common::{
+        DigestFrequency, Enrollment, Notification, NotificationCategory, NotificationChannel,
+        NotificationPreferences, Notifier, SendOutcome, User,
+    };
+    use async_trait::async_trait;
+    use chrono::{Duration, Utc};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct RecordingEmail {
+        sent: Mutex<Vec<(String, Notification)>>,
+    }
+
+    #[async_trait]
+    impl NotificationChannel for RecordingEmail {
+        fn name(&self) -> &'static str {
+            "email"
+        }
+
+        fn targets(&self, user: &User) -> Vec<String> {
+            vec![user.email.clone()]
+        }
+
+        async fn send(
+            &self,
+            targets: &[String],
+            notification: &Notification,
+        ) -> StoreResult<Vec<(String, SendOutcome)>> {
+            let mut sent = self.sent.lock().unwrap();
+            sent.extend(targets.iter().map(|t| (t.clone(), notification.clone())));
+            Ok(targets
+                .iter()
+                .map(|t| (t.clone(), SendOutcome::Sent))
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_digest_async() {
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+        let channel = Arc::new(RecordingEmail::default());
+        let notifier = Notifier::new(vec![channel.clone()]);
+        let parent_id = Fixtures::id("parent");
+        let kid_id = Fixtures::id("student");
+        let science_id = Fixtures::id("science");
+        let teacher = db.fixture_user("teacher").await;
+        let parent = db.fixture_user("parent").await;
+
+        let preferences = NotificationPreferences {
+            digest: Some(DigestFrequency::Daily),
+            ..NotificationPreferences::default()
+        };
+        try_set_preferences(&db, &parent, &preferences)
+            .await
+            .unwrap();
+        let schedule = db.get_digest_schedule(&parent_id).await.unwrap().unwrap();
+        assert!(schedule.next_digest_at > Utc::now() + Duration::hours(23));
+        // mathematics was known before subscribing
+        assert_eq!(schedule.enrollments.len(), 1);
+
+        // nothing is due yet
+        assert_eq!(process_digests(&db, &notifier).await.unwrap(), 0);
+
+        db.insert_enrollment(&Enrollment::new(kid_id, science_id))
+            .await
+            .unwrap();
+        let body = |to: &str, subject: &str| MessageBody {
+            receiver_ids: vec![to.to_string()],
+            subject: Some(subject.to_string()),
+            content: "hello".to_string(),
+            attachments: vec![],
+        };
+        // no channels inline, only the digest reports them
+        let quiet = Notifier::default();
+        let category = NotificationCategory::Messages;
+        try_send_notice(
+            &db,
+            &quiet,
+            &teacher,
+            body("mom@mom.com", "Grades"),
+            category,
+        )
+        .await
+        .unwrap();
+        let category = NotificationCategory::Announcements;
+        try_send_notice(
+            &db,
+            &quiet,
+            &teacher,
+            body("hl@hl.com", "Field trip"),
+            category,
+        )
+        .await
+        .unwrap();
+
+        let mut schedule = db.get_digest_schedule(&parent_id).await.unwrap().unwrap();
+        schedule.next_digest_at = Utc::now();
+        db.set_digest_schedule(&schedule).await.unwrap();
+        assert_eq!(process_digests(&db, &notifier).await.unwrap(), 1);
+
+        {
+            let sent = channel.sent.lock().unwrap();
+            assert_eq!(sent.len(), 1);
+            let (to, digest) = &sent[0];
+            assert_eq!(to, "mom@mom.com");
+            assert_eq!(
+                digest.title.as_deref(),
+                Some("Your daily summary on edclass")
+            );
+            assert!(digest.body.contains("- Grades"));
+            assert!(digest.body.contains("- hl: Science"));
+            assert!(!digest.body.contains("Mathematics"));
+            assert!(digest.body.contains("Announcements:\n- Field trip"));
+        }
+        let schedule = db.get_digest_schedule(&parent_id).await.unwrap().unwrap();
+        assert!(schedule.next_digest_at > Utc::now() + Duration::hours(23));
+        assert_eq!(schedule.enrollments.len(), 2);
+
+        // nothing new since, so nothing is sent
+        let mut schedule = schedule;
+        schedule.next_digest_at = Utc::now();
+        db.set_digest_schedule(&schedule).await.unwrap();
+        assert_eq!(process_digests(&db, &notifier).await.unwrap(), 1);
+        assert_eq!(channel.sent.lock().unwrap().len(), 1);
+
+        try_set_preferences(&db, &parent, &NotificationPreferences::default())
+            .await
+            .unwrap();
+        assert!(db.get_digest_schedule(&parent_id).await.unwrap().is_none());
+    }
+}
@@ -1,64 +1,911 @@
-use crate::common::user::get_user_by_id;
-use crate::common::{Enrollment, User, ENROLLMENTS_COLLECTION};
-use firestore::{path, FirestoreDb};
+use crate::api::message::MessageBody;
+use crate::common::course::{editable_course, CourseError};
+use crate::common::message::try_send_notice;
+use crate::common::store::{Store, StoreResult};
+use crate::common::user::{
+    email_lookup, get_system_user, get_user_by_id, normalize_email, try_get_student_parents,
+};
+use crate::common::{
+    Approver, Course, Enrollment, EnrollmentRequest, EnrollmentStatus, Event, NotificationCategory,
+    Notifier, RosterOutcome, RosterReport, RosterRow, User, UserRole, ROSTER_MAX_ROWS,
+};
+use chrono::Utc;
+use log::warn;
 use uuid::Uuid;
 
-pub async fn enroll(
-    db: &FirestoreDb,
+/// Enrolls `student` in the course, again if they dropped it before. When
+/// every seat is taken they join the end of the waitlist instead, and when
+/// the course asks for approvals the enrollment waits for them. Students
+/// removed by the teacher can't come back on their own.
+pub async fn try_enroll(
+    db: &dyn Store,
+    notifier: &Notifier,
+    student: &User,
+    course_id: &Uuid,
+) -> Result<Enrollment, CourseError> {
+    // archived courses take no new students
+    let course = match db.get_course(course_id).await? {
+        Some(c) if c.archived_at.is_none() => c,
+        _ => return Err(CourseError::NotFound("course")),
+    };
+
+    let existing = db.find_enrollment(&student.uid, course_id).await?;
+    if let Some(e) = &existing {
+        match e.status {
+            EnrollmentStatus::Active => {
+                return Err(CourseError::Conflict("already enrolled".to_string()))
+            }
+            EnrollmentStatus::Waitlisted => {
+                return Err(CourseError::Conflict("already on the waitlist".to_string()))
+            }
+            EnrollmentStatus::Pending => {
+                return Err(CourseError::Conflict(
+                    "already waiting for approval".to_string(),
+                ))
+            }
+            EnrollmentStatus::Completed => {
+                return Err(CourseError::Conflict(
+                    "course already completed".to_string(),
+                ))
+            }
+            EnrollmentStatus::Removed | EnrollmentStatus::Rejected => {
+                return Err(CourseError::Forbidden)
+            }
+            EnrollmentStatus::Dropped => {}
+        }
+    }
+
+    let from = existing.as_ref().map(|e| e.status);
+    let enrollment = match existing {
+        Some(e) => Enrollment {
+            enrolled_at: Utc::now(),
+            ended_at: None,
+            approved_by: None,
+            consented_by: None,
+            ..e
+        },
+        None => Enrollment::new(student.uid, *course_id),
+    };
+
+    if course.teacher_approval || course.parent_consent {
+        if course.parent_consent && db.list_parents_of(&student.uid).await?.is_empty() {
+            return Err(CourseError::Conflict(
+                "the course needs a parent's consent, but no parent is linked".to_string(),
+            ));
+        }
+        let enrollment = Enrollment {
+            status: EnrollmentStatus::Pending,
+            ..enrollment
+        };
+        if !write_enrollment(db, &enrollment, from).await? {
+            // a concurrent request got there first
+            return Err(CourseError::Conflict("already enrolled".to_string()));
+        }
+
+        let content = format!(
+            "{} asks to enroll in {}, waiting for {}.",
+            student.name,
+            course.title,
+            awaited_approvals(&course, &enrollment)
+        );
+        notify_enrollment(db, notifier, Some(student), &course, &enrollment, content).await;
+        return Ok(enrollment);
+    }
+
+    match admit(
+        db,
+        notifier,
+        Some(student),
+        &course,
+        student,
+        enrollment,
+        from,
+    )
+    .await?
+    {
+        Some(e) => Ok(e),
+        // a concurrent request got there first
+        None => Err(CourseError::Conflict("already enrolled".to_string())),
+    }
+}
+
+/// Inserts the enrollment, or updates it if its stored status is still
+/// `from`. Returns `false` when nothing was written.
+async fn write_enrollment(
+    db: &dyn Store,
     enrollment: &Enrollment,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let existing_enrollment: Vec<Enrollment> = db
-        .fluent()
-        .select()
-        .from(ENROLLMENTS_COLLECTION)
-        .filter(|q| {
-            // filter
-            q.for_all([
-                // all match condition
-                q.field(path!(Enrollment::student_id))
-                    .eq(&enrollment.student_id.to_string()),
-                q.field(path!(Enrollment::course_id))
-                    .eq(&enrollment.course_id.to_string()),
-            ])
-        })
-        .obj()
-        .query()
-        .await?;
-
-    if existing_enrollment.is_empty() {
-        db.fluent()
-            .insert()
-            .into(ENROLLMENTS_COLLECTION)
-            .document_id(enrollment.id.to_string())
-            .object(enrollment)
-            .execute()
-            .await?;
+    from: Option<EnrollmentStatus>,
+) -> StoreResult<bool> {
+    match from {
+        Some(from) => db.update_enrollment(enrollment, from).await,
+        None => db.insert_enrollment(enrollment).await,
+    }
+}
+
+/// Gives `student` a seat in the course, or a place on its waitlist when
+/// it's full, and tells everyone but `by`. Returns `None` when the
+/// enrollment changed from `from` in the meantime.
+async fn admit(
+    db: &dyn Store,
+    notifier: &Notifier,
+    by: Option<&User>,
+    course: &Course,
+    student: &User,
+    enrollment: Enrollment,
+    from: Option<EnrollmentStatus>,
+) -> StoreResult<Option<Enrollment>> {
+    let seated = db.take_seat(&course.id, course.capacity).await?;
+    let enrollment = Enrollment {
+        status: if seated {
+            EnrollmentStatus::Active
+        } else {
+            EnrollmentStatus::Waitlisted
+        },
+        ..enrollment
+    };
+    let written = write_enrollment(db, &enrollment, from).await;
+    if !matches!(written, Ok(true)) {
+        if seated {
+            if let Err(e) = db.release_seat(&course.id).await {
+                warn!("failed to release a seat of {}: {:?}", course.id, e);
+            }
+        }
+        written?;
+        return Ok(None);
+    }
+
+    let content = if seated {
+        format!("{} is enrolled in {}.", student.name, course.title)
+    } else {
+        let position = list_waitlist(db, &course.id)
+            .await?
+            .iter()
+            .position(|e| e.id == enrollment.id)
+            .map_or(0, |p| p + 1);
+        format!(
+            "{} is on the waitlist for {}, at position {}.",
+            student.name, course.title, position
+        )
+    };
+    notify_enrollment(db, notifier, by, course, &enrollment, content).await;
+
+    Ok(Some(enrollment))
+}
+
+/// What a pending enrollment still waits for, e.g. "a parent's consent".
+fn awaited_approvals(course: &Course, enrollment: &Enrollment) -> String {
+    let mut awaited = Vec::new();
+    if course.teacher_approval && enrollment.approved_by.is_none() {
+        awaited.push("the teacher's approval");
+    }
+    if course.parent_consent && enrollment.consented_by.is_none() {
+        awaited.push("a parent's consent");
+    }
+    awaited.join(" and ")
+}
+
+/// The pending enrollment of `student_id` in the course, and what `user`
+/// may agree to on it: its teacher or an admin to the teacher's approval, a
+/// parent of the student to their consent, as far as the course asks for
+/// them.
+async fn pending_enrollment(
+    db: &dyn Store,
+    user: &User,
+    course_id: &Uuid,
+    student_id: &Uuid,
+) -> Result<(Course, User, Enrollment, Approver), CourseError> {
+    let course = db
+        .get_course(course_id)
+        .await?
+        .ok_or(CourseError::NotFound("course"))?;
+    if course.archived_at.is_some() {
+        return Err(CourseError::Archived);
+    }
+
+    let teaches = user.role == UserRole::Admin || course.teacher_id == user.uid;
+    let approver = if course.teacher_approval && teaches {
+        Approver::Teacher
+    } else if course.parent_consent
+        && user.role == UserRole::Parent
+        && db
+            .list_students_of(&user.uid)
+            .await?
+            .iter()
+            .any(|sp| sp.student_id == *student_id)
+    {
+        Approver::Parent
+    } else {
+        return Err(CourseError::Forbidden);
+    };
+
+    let enrollment = match db.find_enrollment(student_id, course_id).await? {
+        Some(e) if e.is_pending() => e,
+        _ => return Err(CourseError::NotFound("enrollment request")),
+    };
+    let student = get_user_by_id(db, student_id)
+        .await?
+        .ok_or(CourseError::NotFound("student"))?;
+
+    Ok((course, student, enrollment, approver))
+}
+
+/// Records `user`'s approval of a pending enrollment. Once every approval
+/// the course asks for is in, the student gets a seat or joins the
+/// waitlist.
+pub async fn try_approve_enrollment(
+    db: &dyn Store,
+    notifier: &Notifier,
+    user: &User,
+    course_id: &Uuid,
+    student_id: &Uuid,
+) -> Result<Enrollment, CourseError> {
+    let (course, student, enrollment, approver) =
+        pending_enrollment(db, user, course_id, student_id).await?;
+    if !db
+        .approve_enrollment(&enrollment, approver, &user.uid)
+        .await?
+    {
+        return Err(CourseError::NotFound("enrollment request"));
+    }
+
+    // re-read, the other approval may have come in meanwhile
+    let enrollment = db
+        .find_enrollment(student_id, course_id)
+        .await?
+        .ok_or(CourseError::NotFound("enrollment request"))?;
+    if !enrollment.is_pending() {
+        return Ok(enrollment);
+    }
+    if !course.approves(&enrollment) {
+        let content = format!(
+            "{} approved the enrollment of {} in {}, waiting for {}.",
+            user.name,
+            student.name,
+            course.title,
+            awaited_approvals(&course, &enrollment)
+        );
+        notify_enrollment(db, notifier, Some(user), &course, &enrollment, content).await;
+        return Ok(enrollment);
+    }
+
+    let enrollment = Enrollment {
+        enrolled_at: Utc::now(),
+        ..enrollment
+    };
+    let from = Some(EnrollmentStatus::Pending);
+    match admit(
+        db,
+        notifier,
+        Some(user),
+        &course,
+        &student,
+        enrollment,
+        from,
+    )
+    .await?
+    {
+        Some(e) => Ok(e),
+        // admitted by a concurrent approval
+        None => db
+            .find_enrollment(student_id, course_id)
+            .await?
+            .ok_or(CourseError::NotFound("enrollment request")),
+    }
+}
+
+/// Turns a pending enrollment down, the student can't ask again.
+pub async fn try_reject_enrollment(
+    db: &dyn Store,
+    notifier: &Notifier,
+    user: &User,
+    course_id: &Uuid,
+    student_id: &Uuid,
+) -> Result<Enrollment, CourseError> {
+    let (course, student, enrollment, _) =
+        pending_enrollment(db, user, course_id, student_id).await?;
+    let enrollment = Enrollment {
+        status: EnrollmentStatus::Rejected,
+        ended_at: Some(Utc::now()),
+        ..enrollment
+    };
+    if !db
+        .update_enrollment(&enrollment, EnrollmentStatus::Pending)
+        .await?
+    {
+        return Err(CourseError::NotFound("enrollment request"));
+    }
+
+    let content = format!(
+        "{} turned down the enrollment of {} in {}.",
+        user.name, student.name, course.title
+    );
+    notify_enrollment(db, notifier, Some(user), &course, &enrollment, content).await;
+
+    Ok(enrollment)
+}
+
+/// The pending enrollments waiting for `user`: in the courses they teach,
+/// or any course for admins, and those of their kids for parents. Oldest
+/// first.
+pub async fn list_enrollment_requests(
+    db: &dyn Store,
+    user: &User,
+) -> StoreResult<Vec<EnrollmentRequest>> {
+    let mut requests = Vec::new();
+    match user.role {
+        UserRole::Teacher | UserRole::Admin => {
+            let courses = match user.role {
+                UserRole::Admin => db.list_courses().await?,
+                _ => db.list_teacher_courses(&user.uid).await?,
+            };
+            for course in courses {
+                if !course.teacher_approval || course.archived_at.is_some() {
+                    continue;
+                }
+                for e in db.list_course_enrollments(&course.id).await? {
+                    if !e.is_pending() || e.approved_by.is_some() {
+                        continue;
+                    }
+                    if let Some(student) = get_user_by_id(db, &e.student_id).await? {
+                        requests.push(EnrollmentRequest {
+                            enrollment: e,
+                            course: course.clone(),
+                            student,
+                        });
+                    }
+                }
+            }
+        }
+        UserRole::Parent => {
+            for sp in db.list_students_of(&user.uid).await? {
+                let student = match get_user_by_id(db, &sp.student_id).await? {
+                    Some(s) => s,
+                    None => continue,
+                };
+                for e in db.list_student_enrollments(&student.uid).await? {
+                    if !e.is_pending() || e.consented_by.is_some() {
+                        continue;
+                    }
+                    match db.get_course(&e.course_id).await? {
+                        Some(course) if course.parent_consent && course.archived_at.is_none() => {
+                            requests.push(EnrollmentRequest {
+                                enrollment: e,
+                                course,
+                                student: student.clone(),
+                            })
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+    requests.sort_by_key(|r| r.enrollment.enrolled_at);
+
+    Ok(requests)
+}
+
+/// Admits the pending enrollments of the course that have every approval it
+/// asks for now, after its policy was relaxed.
+pub(crate) async fn admit_approved(
+    db: &dyn Store,
+    notifier: &Notifier,
+    course: &Course,
+) -> StoreResult<()> {
+    if course.archived_at.is_some() {
+        return Ok(());
+    }
+
+    let mut pending = db
+        .list_course_enrollments(&course.id)
+        .await?
+        .into_iter()
+        .filter(|e| e.is_pending() && course.approves(e))
+        .collect::<Vec<_>>();
+    // first come first seated
+    pending.sort_by_key(|e| (e.enrolled_at, e.id));
+    for enrollment in pending {
+        let student = match get_user_by_id(db, &enrollment.student_id).await? {
+            Some(s) => s,
+            None => continue,
+        };
+        let enrollment = Enrollment {
+            enrolled_at: Utc::now(),
+            ..enrollment
+        };
+        let from = Some(EnrollmentStatus::Pending);
+        admit(db, notifier, None, course, &student, enrollment, from).await?;
     }
 
     Ok(())
 }
 
-pub async fn list_user_enrolled_in(
-    db: &FirestoreDb,
+/// The emails of a CSV roster, from its `email` column, or from the first
+/// column when there's no header. Blank lines are skipped.
+pub fn parse_roster(data: &str) -> Result<Vec<String>, CourseError> {
+    let invalid = |e: csv::Error| CourseError::Invalid(format!("invalid roster: {}", e));
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(data.as_bytes());
+    let mut records = reader.records();
+
+    let mut emails = Vec::new();
+    let header = match records.next() {
+        Some(r) => r.map_err(invalid)?,
+        None => return Ok(emails),
+    };
+    let column = match header.iter().position(|h| h.eq_ignore_ascii_case("email")) {
+        Some(c) => c,
+        None if header.get(0).is_some_and(|f| f.contains('@')) => {
+            emails.push(header[0].to_string());
+            0
+        }
+        None => {
+            return Err(CourseError::Invalid(
+                "the roster has no email column".to_string(),
+            ))
+        }
+    };
+    for record in records {
+        let record = record.map_err(invalid)?;
+        emails.push(record.get(column).unwrap_or_default().to_string());
+    }
+
+    Ok(emails)
+}
+
+/// The teacher of the course, or an admin, enrolls the students with the
+/// given emails, as far as seats allow and the rest on the waitlist. Adding
+/// them counts as the teacher's approval, a parent's consent is still
+/// waited for when the course asks for it. Every email gets a row in the
+/// report, in the order given.
+pub async fn try_bulk_enroll(
+    db: &dyn Store,
+    notifier: &Notifier,
+    user: &User,
     course_id: &Uuid,
-) -> Result<Vec<User>, Box<dyn ::std::error::Error + Send + Sync>> {
-    let enrollments: Vec<Enrollment> = db
-        .fluent()
-        .select()
-        .from(ENROLLMENTS_COLLECTION)
-        .filter(|q| {
-            q.for_any([
-                // filter by course id
-                q.field(path!(Enrollment::course_id)).eq(&course_id),
-            ])
-        })
-        .obj()
-        .query()
-        .await?;
+    emails: &[String],
+) -> Result<RosterReport, CourseError> {
+    if emails.len() > ROSTER_MAX_ROWS {
+        return Err(CourseError::Invalid(format!(
+            "a roster has at most {} rows",
+            ROSTER_MAX_ROWS
+        )));
+    }
+    let course = editable_course(db, user, course_id).await?;
+
+    let lookup = emails
+        .iter()
+        .filter(|e| e.contains('@'))
+        .collect::<Vec<_>>();
+    let students = db.get_users_by_emails(&email_lookup(&lookup)).await?;
+    let emails = emails
+        .iter()
+        .map(|e| normalize_email(e))
+        .collect::<Vec<_>>();
+    let enrollments = db.list_course_enrollments(course_id).await?;
+
+    let mut rows: Vec<RosterRow> = Vec::with_capacity(emails.len());
+    // the row, the student and their ended enrollment if any
+    let mut admitted = Vec::new();
+    for (i, email) in emails.into_iter().enumerate() {
+        let student = students.iter().find(|u| normalize_email(&u.email) == email);
+        let existing = student.and_then(|s| enrollments.iter().find(|e| e.student_id == s.uid));
+        let (outcome, enrollment) = match (student, existing) {
+            _ if !email.contains('@') => (RosterOutcome::InvalidEmail, None),
+            _ if rows.iter().any(|r| r.email == email) => (RosterOutcome::Duplicate, None),
+            (None, _) => (RosterOutcome::UnknownEmail, None),
+            (Some(s), _) if s.role != UserRole::Student => (RosterOutcome::NotAStudent, None),
+            (Some(_), Some(e)) if e.is_active() || e.is_waitlisted() || e.is_pending() => {
+                (RosterOutcome::AlreadyEnrolled, Some(e.clone()))
+            }
+            (Some(_), Some(e)) if e.status == EnrollmentStatus::Completed => {
+                (RosterOutcome::AlreadyCompleted, Some(e.clone()))
+            }
+            (Some(s), _)
+                if course.parent_consent && db.list_parents_of(&s.uid).await?.is_empty() =>
+            {
+                (RosterOutcome::NoParent, None)
+            }
+            (Some(s), e) => {
+                admitted.push((i, s.clone(), e.cloned()));
+                (RosterOutcome::Enrolled, None)
+            }
+        };
+        rows.push(RosterRow {
+            row: i + 1,
+            email,
+            outcome,
+            enrollment,
+        });
+    }
+
+    let mut planned = Vec::with_capacity(admitted.len());
+    let mut held = 0;
+    for (i, student, existing) in admitted {
+        let from = existing.as_ref().map(|e| e.status);
+        let enrollment = match existing {
+            Some(e) => Enrollment {
+                enrolled_at: Utc::now(),
+                ended_at: None,
+                consented_by: None,
+                ..e
+            },
+            None => Enrollment::new(student.uid, course.id),
+        };
+        let status = if course.parent_consent {
+            EnrollmentStatus::Pending
+        } else {
+            match db.take_seat(&course.id, course.capacity).await {
+                Ok(true) => EnrollmentStatus::Active,
+                Ok(false) => EnrollmentStatus::Waitlisted,
+                Err(e) => {
+                    release_seats(db, &course.id, held).await;
+                    return Err(e.into());
+                }
+            }
+        };
+        if status == EnrollmentStatus::Active {
+            held += 1;
+        }
+        let enrollment = Enrollment {
+            status,
+            approved_by: course.teacher_approval.then_some(user.uid),
+            ..enrollment
+        };
+        planned.push((i, student, enrollment, from));
+    }
+
+    let written = match write_roster(db, &planned, &mut held).await {
+        Ok(w) => w,
+        Err(e) => {
+            release_seats(db, &course.id, held).await;
+            return Err(e.into());
+        }
+    };
+
+    let waitlist = list_waitlist(db, &course.id).await?;
+    for (i, student, enrollment, _) in planned {
+        let row = &mut rows[i];
+        if !written.contains(&enrollment.id) {
+            // enrolled by a concurrent request
+            row.outcome = RosterOutcome::AlreadyEnrolled;
+            continue;
+        }
+
+        let content = match enrollment.status {
+            EnrollmentStatus::Active => {
+                row.outcome = RosterOutcome::Enrolled;
+                format!(
+                    "{} enrolled {} in {}.",
+                    user.name, student.name, course.title
+                )
+            }
+            EnrollmentStatus::Waitlisted => {
+                row.outcome = RosterOutcome::Waitlisted;
+                let position = waitlist
+                    .iter()
+                    .position(|e| e.id == enrollment.id)
+                    .map_or(0, |p| p + 1);
+                format!(
+                    "{} put {} on the waitlist for {}, at position {}.",
+                    user.name, student.name, course.title, position
+                )
+            }
+            _ => {
+                row.outcome = RosterOutcome::Pending;
+                format!(
+                    "{} enrolled {} in {}, waiting for {}.",
+                    user.name,
+                    student.name,
+                    course.title,
+                    awaited_approvals(&course, &enrollment)
+                )
+            }
+        };
+        notify_enrollment(db, notifier, Some(user), &course, &enrollment, content).await;
+        row.enrollment = Some(enrollment);
+    }
+
+    let count = |outcome| rows.iter().filter(|r| r.outcome == outcome).count();
+    let (enrolled, waitlisted, pending) = (
+        count(RosterOutcome::Enrolled),
+        count(RosterOutcome::Waitlisted),
+        count(RosterOutcome::Pending),
+    );
+    Ok(RosterReport {
+        course_id: course.id,
+        enrolled,
+        waitlisted,
+        pending,
+        skipped: rows.len() - enrolled - waitlisted - pending,
+        rows,
+    })
+}
+
+/// Stores the enrollments planned for a roster, the new ones in one batch.
+/// Seats of those that couldn't be written are given back and counted off
+/// `held`. Returns the ids of the written ones.
+async fn write_roster(
+    db: &dyn Store,
+    planned: &[(usize, User, Enrollment, Option<EnrollmentStatus>)],
+    held: &mut usize,
+) -> StoreResult<Vec<Uuid>> {
+    let mut written = Vec::new();
+    let mut fresh = Vec::new();
+    for (_, _, enrollment, from) in planned {
+        match from {
+            Some(from) => {
+                if db.update_enrollment(enrollment, *from).await? {
+                    written.push(enrollment.id);
+                } else if enrollment.is_active() {
+                    db.release_seat(&enrollment.course_id).await?;
+                }
+                if enrollment.is_active() {
+                    *held -= 1;
+                }
+            }
+            None => fresh.push(enrollment.clone()),
+        }
+    }
+
+    let inserted = db.insert_enrollments(&fresh).await?;
+    *held -= fresh.iter().filter(|e| e.is_active()).count();
+    for enrollment in fresh.iter().filter(|e| e.is_active()) {
+        if !inserted.iter().any(|e| e.id == enrollment.id) {
+            db.release_seat(&enrollment.course_id).await?;
+        }
+    }
+    written.extend(inserted.iter().map(|e| e.id));
+
+    Ok(written)
+}
+
+/// Gives back seats taken for enrollments that weren't written after all.
+async fn release_seats(db: &dyn Store, course_id: &Uuid, count: usize) {
+    for _ in 0..count {
+        if let Err(e) = db.release_seat(course_id).await {
+            warn!("failed to release a seat of {}: {:?}", course_id, e);
+        }
+    }
+}
+
+/// The students waiting for a seat in the course, first come first.
+pub async fn list_waitlist(db: &dyn Store, course_id: &Uuid) -> StoreResult<Vec<Enrollment>> {
+    let mut waitlist = db
+        .list_course_enrollments(course_id)
+        .await?
+        .into_iter()
+        .filter(|e| e.is_waitlisted())
+        .collect::<Vec<_>>();
+    waitlist.sort_by_key(|e| (e.enrolled_at, e.id));
+
+    Ok(waitlist)
+}
+
+/// Gives free seats of the course to the students at the top of its
+/// waitlist, telling each of them and their parents.
+pub(crate) async fn promote_waitlist(
+    db: &dyn Store,
+    notifier: &Notifier,
+    course: &Course,
+) -> StoreResult<()> {
+    if course.archived_at.is_some() {
+        return Ok(());
+    }
+
+    for next in list_waitlist(db, &course.id).await? {
+        if !db.take_seat(&course.id, course.capacity).await? {
+            break;
+        }
+        let promoted = Enrollment {
+            status: EnrollmentStatus::Active,
+            enrolled_at: Utc::now(),
+            ..next
+        };
+        if !db
+            .update_enrollment(&promoted, EnrollmentStatus::Waitlisted)
+            .await?
+        {
+            // left the waitlist in the meantime
+            db.release_seat(&course.id).await?;
+            continue;
+        }
+
+        let name = match get_user_by_id(db, &promoted.student_id).await? {
+            Some(s) => s.name,
+            None => "A student".to_string(),
+        };
+        let content = format!(
+            "A seat opened up: {} is now enrolled in {}.",
+            name, course.title
+        );
+        notify_enrollment(db, notifier, None, course, &promoted, content).await;
+    }
+
+    Ok(())
+}
+
+/// Ends the enrollment of `student_id` in the course with `status`, and
+/// hands a seat it held to the waitlist. Waitlisted students, and those
+/// waiting for approval, can only drop out or be removed.
+async fn end_enrollment(
+    db: &dyn Store,
+    notifier: &Notifier,
+    course: &Course,
+    student_id: &Uuid,
+    status: EnrollmentStatus,
+) -> Result<Enrollment, CourseError> {
+    let enrollment = match db.find_enrollment(student_id, &course.id).await? {
+        Some(e) if e.is_active() => e,
+        Some(e)
+            if (e.is_waitlisted() || e.is_pending()) && status != EnrollmentStatus::Completed =>
+        {
+            e
+        }
+        _ => return Err(CourseError::NotFound("enrollment")),
+    };
+    let from = enrollment.status;
+    let enrollment = Enrollment {
+        status,
+        ended_at: Some(Utc::now()),
+        ..enrollment
+    };
+    if !db.update_enrollment(&enrollment, from).await? {
+        // ended by a concurrent request
+        return Err(CourseError::NotFound("enrollment"));
+    }
+
+    if from == EnrollmentStatus::Active {
+        db.release_seat(&course.id).await?;
+        if let Err(e) = promote_waitlist(db, notifier, course).await {
+            warn!("failed to promote the waitlist of {}: {:?}", course.id, e);
+        }
+    }
+
+    Ok(enrollment)
+}
+
+/// The student leaves the course, its waitlist or their enrollment request.
+pub async fn try_drop_course(
+    db: &dyn Store,
+    notifier: &Notifier,
+    student: &User,
+    course_id: &Uuid,
+) -> Result<Enrollment, CourseError> {
+    let course = db
+        .get_course(course_id)
+        .await?
+        .ok_or(CourseError::NotFound("course"))?;
+    let enrollment = end_enrollment(
+        db,
+        notifier,
+        &course,
+        &student.uid,
+        EnrollmentStatus::Dropped,
+    )
+    .await?;
+
+    notify_enrollment(
+        db,
+        notifier,
+        Some(student),
+        &course,
+        &enrollment,
+        format!("{} dropped {}.", student.name, course.title),
+    )
+    .await;
+
+    Ok(enrollment)
+}
+
+/// The teacher of the course, or an admin, takes a student out of it or
+/// marks them done with it, `status` being `Removed` or `Completed`.
+pub async fn try_end_student_enrollment(
+    db: &dyn Store,
+    notifier: &Notifier,
+    user: &User,
+    course_id: &Uuid,
+    student_id: &Uuid,
+    status: EnrollmentStatus,
+) -> Result<Enrollment, CourseError> {
+    let course = editable_course(db, user, course_id).await?;
+    let student = get_user_by_id(db, student_id)
+        .await?
+        .ok_or(CourseError::NotFound("student"))?;
+    let enrollment = end_enrollment(db, notifier, &course, student_id, status).await?;
+
+    let content = match status {
+        EnrollmentStatus::Completed => format!("{} completed {}.", student.name, course.title),
+        _ => format!("{} was removed from {}.", student.name, course.title),
+    };
+    notify_enrollment(db, notifier, Some(user), &course, &enrollment, content).await;
+
+    Ok(enrollment)
+}
+
+/// Publishes the enrollment event and tells the student, their parents and
+/// the teacher about the change, except for `by` who made it. The change is
+/// already stored, so failures are only logged.
+async fn notify_enrollment(
+    db: &dyn Store,
+    notifier: &Notifier,
+    by: Option<&User>,
+    course: &Course,
+    enrollment: &Enrollment,
+    content: String,
+) {
+    let student = get_user_by_id(db, &enrollment.student_id).await;
+    let parents = try_get_student_parents(db, &enrollment.student_id).await;
+    let teacher = get_user_by_id(db, &course.teacher_id).await;
+
+    let mut audience = vec![enrollment.student_id.to_string()];
+    let mut receivers = Vec::new();
+    if let Ok(Some(s)) = &student {
+        receivers.push(s.email.clone());
+    }
+    match &parents {
+        Ok(p) => {
+            audience.extend(p.iter().map(|pp| pp.email.clone()));
+            receivers.extend(p.iter().map(|pp| pp.email.clone()));
+        }
+        Err(e) => warn!(
+            "failed to find parents of {}: {:?}",
+            enrollment.student_id, e
+        ),
+    }
+    if let Ok(Some(t)) = &teacher {
+        audience.push(t.uid.to_string());
+        receivers.push(t.email.clone());
+    }
+    notifier.events().publish(
+        audience,
+        Event::Enrollment {
+            enrollment: enrollment.clone(),
+        },
+    );
+
+    if let Some(by) = by {
+        receivers.retain(|r| *r != by.email);
+    }
+    if receivers.is_empty() {
+        return;
+    }
+    let sys = match get_system_user(db).await {
+        Ok(s) => s,
+        Err(e) => {
+            warn!(
+                "failed to notify about enrollment {}: {:?}",
+                enrollment.id, e
+            );
+            return;
+        }
+    };
+    let body = MessageBody {
+        subject: Some("Enrollment".to_string()),
+        receiver_ids: receivers,
+        content,
+        attachments: vec![],
+    };
+    let category = NotificationCategory::Enrollment;
+    if let Err(e) = try_send_notice(db, notifier, &sys, body, category).await {
+        warn!(
+            "failed to notify about enrollment {}: {:?}",
+            enrollment.id, e
+        );
+    }
+}
+
+/// The students currently enrolled in the course.
+pub async fn list_user_enrolled_in(db: &dyn Store, course_id: &Uuid) -> StoreResult<Vec<User>> {
+    let enrollments = db.list_course_enrollments(course_id).await?;
 
     let mut students = Vec::new();
 
-    for e in enrollments {
+    for e in enrollments.iter().filter(|e| e.is_active()) {
         if let Some(u) = get_user_by_id(db, &e.student_id).await? {
             students.push(u);
         };
@@ -66,3 +913,487 @@ pub async fn list_user_enrolled_in(
 
     Ok(students)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::api::course::CourseBody;
+    use crate::common::course::{get_course, try_update_course, CourseError};
+    use crate::common::enrollment::{
+        list_enrollment_requests, parse_roster, try_approve_enrollment, try_bulk_enroll,
+        try_drop_course, try_end_student_enrollment, try_enroll, try_reject_enrollment,
+    };
+    use crate::common::message::{try_list_messages, MessageQuery, MessageType};
+    use crate::common::store::{
+        EnrollmentStore, Fixtures, MemoryStore, StudentParentStore, UserStore,
+    };
+    use crate::common::user::{get_kids, get_user_by_id};
+    use crate::common::{
+        EnrollmentStatus, Notifier, RosterOutcome, User, UserRole, UserWithPassword,
+    };
+    use uuid::Uuid;
+
+    async fn student(db: &MemoryStore, name: &str) -> User {
+        let user = UserWithPassword {
+            uid: Uuid::new_v4(),
+            email: format!("{}@{}.com", name, name),
+            password: "!".to_string(),
+            role: UserRole::Student,
+            name: name.to_string(),
+            devices: Vec::new(),
+            preferences: Default::default(),
+            deactivated_at: None,
+        };
+        db.insert_user(&user).await.unwrap();
+        get_user_by_id(db, &user.uid).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_enrollment_lifecycle_async() {
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+        let notifier = Notifier::default();
+        let student_id = Fixtures::id("student");
+        let math_id = Fixtures::id("math");
+        let science_id = Fixtures::id("science");
+        let teacher = db.fixture_user("teacher").await;
+        let student = db.fixture_user("student").await;
+        let parent = db.fixture_user("parent").await;
+
+        assert!(matches!(
+            try_enroll(&db, &notifier, &student, &math_id).await,
+            Err(CourseError::Conflict(_))
+        ));
+        let dropped = try_drop_course(&db, &notifier, &student, &math_id)
+            .await
+            .unwrap();
+        assert_eq!(dropped.status, EnrollmentStatus::Dropped);
+        assert!(dropped.ended_at.is_some());
+        assert_eq!(db.count_course_enrollments(&math_id).await.unwrap(), 0);
+        assert!(matches!(
+            try_drop_course(&db, &notifier, &student, &math_id).await,
+            Err(CourseError::NotFound(_))
+        ));
+
+        // the parent is told and sees the course as past
+        let inbox = try_list_messages(
+            &db,
+            &parent,
+            MessageType::Received,
+            &MessageQuery::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(inbox.messages[0].content, "hl dropped Mathematics.");
+        let kids = get_kids(&db, &parent).await.unwrap();
+        assert!(kids[0].courses.is_empty());
+        assert_eq!(kids[0].past_courses[0].course.id, math_id);
+        assert_eq!(kids[0].past_courses[0].status, EnrollmentStatus::Dropped);
+
+        // dropping isn't final
+        let again = try_enroll(&db, &notifier, &student, &math_id)
+            .await
+            .unwrap();
+        assert_eq!(again.id, dropped.id);
+        assert!(again.is_active() && again.ended_at.is_none());
+
+        // only the teacher takes students out, and removed students stay out
+        assert!(matches!(
+            try_end_student_enrollment(
+                &db,
+                &notifier,
+                &parent,
+                &math_id,
+                &student_id,
+                EnrollmentStatus::Removed
+            )
+            .await,
+            Err(CourseError::Forbidden)
+        ));
+        try_end_student_enrollment(
+            &db,
+            &notifier,
+            &teacher,
+            &math_id,
+            &student_id,
+            EnrollmentStatus::Removed,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            try_enroll(&db, &notifier, &student, &math_id).await,
+            Err(CourseError::Forbidden)
+        ));
+
+        try_enroll(&db, &notifier, &student, &science_id)
+            .await
+            .unwrap();
+        try_end_student_enrollment(
+            &db,
+            &notifier,
+            &teacher,
+            &science_id,
+            &student_id,
+            EnrollmentStatus::Completed,
+        )
+        .await
+        .unwrap();
+        let kids = get_kids(&db, &parent).await.unwrap();
+        let statuses = kids[0]
+            .past_courses
+            .iter()
+            .map(|p| p.status)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            vec![EnrollmentStatus::Completed, EnrollmentStatus::Removed]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_waitlist_async() {
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+        let notifier = Notifier::default();
+        let parent_id = Fixtures::id("parent");
+        let science_id = Fixtures::id("science");
+        let teacher = db.fixture_user("teacher").await;
+        let hl = db.fixture_user("student").await;
+        let parent = db.fixture_user("parent").await;
+        let ann = student(&db, "ann").await;
+        let bob = student(&db, "bob").await;
+        db.link_students(&parent_id, &[ann.uid]).await.unwrap();
+
+        let body = |capacity: usize| CourseBody {
+            title: "Science".to_string(),
+            content: "Introduction to physics and chemistry".to_string(),
+            teacher_id: None,
+            capacity: Some(Some(capacity)),
+            teacher_approval: None,
+            parent_consent: None,
+        };
+        assert!(matches!(
+            try_update_course(&db, &notifier, &teacher, &science_id, body(0)).await,
+            Err(CourseError::Invalid(_))
+        ));
+        try_update_course(&db, &notifier, &teacher, &science_id, body(1))
+            .await
+            .unwrap();
+
+        let seated = try_enroll(&db, &notifier, &hl, &science_id).await.unwrap();
+        assert!(seated.is_active());
+        for s in [&ann, &bob] {
+            let waiting = try_enroll(&db, &notifier, s, &science_id).await.unwrap();
+            assert_eq!(waiting.status, EnrollmentStatus::Waitlisted);
+        }
+        assert!(matches!(
+            try_enroll(&db, &notifier, &bob, &science_id).await,
+            Err(CourseError::Conflict(_))
+        ));
+        assert_eq!(db.count_course_enrollments(&science_id).await.unwrap(), 1);
+
+        let id = science_id.to_string();
+        let course = get_course(&db, &bob, &id).await.unwrap().unwrap();
+        assert_eq!(course.waitlist_position, Some(2));
+        let course = get_course(&db, &teacher, &id).await.unwrap().unwrap();
+        let waitlist = course.waitlist.iter().map(|u| u.uid).collect::<Vec<_>>();
+        assert_eq!(waitlist, vec![ann.uid, bob.uid]);
+
+        // editing the title alone keeps the seats and the waitlist
+        let renamed = CourseBody {
+            title: "Physics".to_string(),
+            capacity: None,
+            ..body(1)
+        };
+        let course = try_update_course(&db, &notifier, &teacher, &science_id, renamed)
+            .await
+            .unwrap();
+        assert_eq!(course.capacity, Some(1));
+        let course = get_course(&db, &teacher, &id).await.unwrap().unwrap();
+        assert_eq!(course.waitlist.len(), 2);
+        assert_eq!(db.count_course_enrollments(&science_id).await.unwrap(), 1);
+        try_update_course(&db, &notifier, &teacher, &science_id, body(1))
+            .await
+            .unwrap();
+
+        // the first in line gets the seat, and their parent hears about it
+        try_drop_course(&db, &notifier, &hl, &science_id)
+            .await
+            .unwrap();
+        let ann_enrollment = db
+            .find_enrollment(&ann.uid, &science_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(ann_enrollment.is_active());
+        assert_eq!(db.count_course_enrollments(&science_id).await.unwrap(), 1);
+        let inbox = try_list_messages(
+            &db,
+            &parent,
+            MessageType::Received,
+            &MessageQuery::default(),
+        )
+        .await
+        .unwrap();
+        let promoted = inbox
+            .messages
+            .iter()
+            .find(|m| m.content == "A seat opened up: ann is now enrolled in Science.")
+            .unwrap();
+        assert!(promoted.receiver_ids.contains(&"ann@ann.com".to_string()));
+
+        // dropping out of the waitlist frees nothing, more seats promote
+        let course = get_course(&db, &bob, &id).await.unwrap().unwrap();
+        assert_eq!(course.waitlist_position, Some(1));
+        try_update_course(&db, &notifier, &teacher, &science_id, body(3))
+            .await
+            .unwrap();
+        let bob_enrollment = db
+            .find_enrollment(&bob.uid, &science_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(bob_enrollment.is_active());
+        assert_eq!(db.count_course_enrollments(&science_id).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_enrollment_approval_async() {
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+        let notifier = Notifier::default();
+        let teacher_id = Fixtures::id("teacher");
+        let hl_id = Fixtures::id("student");
+        let parent_id = Fixtures::id("parent");
+        let science_id = Fixtures::id("science");
+        let teacher = db.fixture_user("teacher").await;
+        let hl = db.fixture_user("student").await;
+        let parent = db.fixture_user("parent").await;
+        let ann = student(&db, "ann").await;
+        let bob = student(&db, "bob").await;
+        db.link_students(&parent_id, &[ann.uid]).await.unwrap();
+
+        let body = |teacher_approval: bool, parent_consent: bool| CourseBody {
+            title: "Science".to_string(),
+            content: "Introduction to physics and chemistry".to_string(),
+            teacher_id: None,
+            capacity: None,
+            teacher_approval: Some(teacher_approval),
+            parent_consent: Some(parent_consent),
+        };
+        try_update_course(&db, &notifier, &teacher, &science_id, body(true, true))
+            .await
+            .unwrap();
+
+        let pending = try_enroll(&db, &notifier, &hl, &science_id).await.unwrap();
+        assert_eq!(pending.status, EnrollmentStatus::Pending);
+        assert_eq!(db.count_course_enrollments(&science_id).await.unwrap(), 0);
+        // nobody could consent for bob
+        assert!(matches!(
+            try_enroll(&db, &notifier, &bob, &science_id).await,
+            Err(CourseError::Conflict(_))
+        ));
+        for approver in [&teacher, &parent] {
+            let requests = list_enrollment_requests(&db, approver).await.unwrap();
+            assert_eq!(requests.len(), 1);
+            assert_eq!(requests[0].student.uid, hl_id);
+        }
+        assert!(matches!(
+            try_approve_enrollment(&db, &notifier, &bob, &science_id, &hl_id).await,
+            Err(CourseError::Forbidden)
+        ));
+
+        // active only once both agreed
+        let approved = try_approve_enrollment(&db, &notifier, &teacher, &science_id, &hl_id)
+            .await
+            .unwrap();
+        assert_eq!(approved.status, EnrollmentStatus::Pending);
+        assert_eq!(approved.approved_by, Some(teacher_id));
+        assert!(list_enrollment_requests(&db, &teacher)
+            .await
+            .unwrap()
+            .is_empty());
+        // an update leaving the settings out admits nobody
+        let renamed = CourseBody {
+            title: "Physics".to_string(),
+            teacher_approval: None,
+            parent_consent: None,
+            ..body(true, true)
+        };
+        let course = try_update_course(&db, &notifier, &teacher, &science_id, renamed)
+            .await
+            .unwrap();
+        assert!(course.teacher_approval && course.parent_consent);
+        let still = db
+            .find_enrollment(&hl_id, &science_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(still.status, EnrollmentStatus::Pending);
+        let consented = try_approve_enrollment(&db, &notifier, &parent, &science_id, &hl_id)
+            .await
+            .unwrap();
+        assert!(consented.is_active());
+        assert_eq!(consented.consented_by, Some(parent_id));
+        assert_eq!(db.count_course_enrollments(&science_id).await.unwrap(), 1);
+
+        try_enroll(&db, &notifier, &ann, &science_id).await.unwrap();
+        let rejected = try_reject_enrollment(&db, &notifier, &parent, &science_id, &ann.uid)
+            .await
+            .unwrap();
+        assert_eq!(rejected.status, EnrollmentStatus::Rejected);
+        assert!(matches!(
+            try_enroll(&db, &notifier, &ann, &science_id).await,
+            Err(CourseError::Forbidden)
+        ));
+
+        // dropping the requirements lets waiting requests in
+        let cat = student(&db, "cat").await;
+        db.link_students(&parent_id, &[cat.uid]).await.unwrap();
+        try_enroll(&db, &notifier, &cat, &science_id).await.unwrap();
+        let kids = get_kids(&db, &parent).await.unwrap();
+        let cat_kid = kids.iter().find(|k| k.user.uid == cat.uid).unwrap();
+        assert_eq!(cat_kid.pending_courses[0].id, science_id);
+        try_update_course(&db, &notifier, &teacher, &science_id, body(false, false))
+            .await
+            .unwrap();
+        let admitted = db
+            .find_enrollment(&cat.uid, &science_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(admitted.is_active());
+    }
+
+    #[test]
+    fn test_parse_roster() {
+        let csv = "Name,Email\nann,ann@ann.com\n\nbob, bob@bob.com \ncat\n";
+        assert_eq!(
+            parse_roster(csv).unwrap(),
+            vec!["ann@ann.com", "bob@bob.com", ""]
+        );
+        assert_eq!(
+            parse_roster("ann@ann.com\nbob@bob.com").unwrap(),
+            vec!["ann@ann.com", "bob@bob.com"]
+        );
+        assert!(parse_roster("").unwrap().is_empty());
+        assert!(matches!(
+            parse_roster("name\nann"),
+            Err(CourseError::Invalid(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_bulk_enroll_async() {
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+        let notifier = Notifier::default();
+        let teacher_id = Fixtures::id("teacher");
+        let science_id = Fixtures::id("science");
+        let teacher = db.fixture_user("teacher").await;
+        let parent = db.fixture_user("parent").await;
+        let ann = student(&db, "ann").await;
+        let bob = student(&db, "bob").await;
+        try_enroll(&db, &notifier, &bob, &science_id).await.unwrap();
+        try_drop_course(&db, &notifier, &bob, &science_id)
+            .await
+            .unwrap();
+
+        let body = CourseBody {
+            title: "Science".to_string(),
+            content: "Introduction to physics and chemistry".to_string(),
+            teacher_id: None,
+            capacity: Some(Some(2)),
+            teacher_approval: Some(true),
+            parent_consent: Some(false),
+        };
+        try_update_course(&db, &notifier, &teacher, &science_id, body)
+            .await
+            .unwrap();
+
+        let emails = [
+            "hl@hl.com",
+            " ann@ann.com",
+            "mom@mom.com",
+            "nobody@nowhere.com",
+            "ann@ann.com",
+            "bob@bob.com",
+            "ann",
+        ]
+        .map(String::from);
+        assert!(matches!(
+            try_bulk_enroll(&db, &notifier, &parent, &science_id, &emails).await,
+            Err(CourseError::Forbidden)
+        ));
+        let report = try_bulk_enroll(&db, &notifier, &teacher, &science_id, &emails)
+            .await
+            .unwrap();
+        let outcomes = report.rows.iter().map(|r| r.outcome).collect::<Vec<_>>();
+        assert_eq!(
+            outcomes,
+            vec![
+                RosterOutcome::Enrolled,
+                RosterOutcome::Enrolled,
+                RosterOutcome::NotAStudent,
+                RosterOutcome::UnknownEmail,
+                RosterOutcome::Duplicate,
+                RosterOutcome::Waitlisted,
+                RosterOutcome::InvalidEmail,
+            ]
+        );
+        assert_eq!(report.rows[1].email, "ann@ann.com");
+        let ann_enrollment = report.rows[1].enrollment.as_ref().unwrap();
+        assert_eq!(ann_enrollment.student_id, ann.uid);
+        assert_eq!(
+            (report.enrolled, report.waitlisted, report.skipped),
+            (2, 1, 4)
+        );
+        // the dropped enrollment is taken up again
+        let bob_enrollment = report.rows[5].enrollment.clone().unwrap();
+        assert_eq!(bob_enrollment.status, EnrollmentStatus::Waitlisted);
+        assert_eq!(bob_enrollment.approved_by, Some(teacher_id));
+        assert_eq!(db.count_course_enrollments(&science_id).await.unwrap(), 2);
+
+        // students and parents hear about it
+        let inbox = try_list_messages(
+            &db,
+            &parent,
+            MessageType::Received,
+            &MessageQuery::default(),
+        )
+        .await
+        .unwrap();
+        assert!(inbox
+            .messages
+            .iter()
+            .any(|m| m.content == "t1 enrolled hl in Science."));
+
+        let report = try_bulk_enroll(&db, &notifier, &teacher, &science_id, &emails[..2])
+            .await
+            .unwrap();
+        assert!(report
+            .rows
+            .iter()
+            .all(|r| r.outcome == RosterOutcome::AlreadyEnrolled));
+        assert_eq!(report.skipped, 2);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_enroll_large_roster_async() {
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+        let notifier = Notifier::default();
+        let science_id = Fixtures::id("science");
+        let teacher = db.fixture_user("teacher").await;
+        let mut emails = Vec::new();
+        for i in 0..40 {
+            let name = format!("s{}", i);
+            student(&db, &name).await;
+            emails.push(format!("{}@{}.COM", name.to_uppercase(), name));
+        }
+
+        let report = try_bulk_enroll(&db, &notifier, &teacher, &science_id, &emails)
+            .await
+            .unwrap();
+        assert!(report
+            .rows
+            .iter()
+            .all(|r| r.outcome == RosterOutcome::Enrolled));
+        assert_eq!(report.rows[0].email, "s0@s0.com");
+        assert_eq!(report.enrolled, 40);
+    }
+}
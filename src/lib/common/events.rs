@@ -0,0 +1,213 @@
+use crate::common::message::message_view;
+use crate::common::{Enrollment, Message, MessageState, User, EVENT_BUS_CAPACITY};
+use futures::stream::{self, Stream};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+/// Something connected clients are told about as it happens.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A message was sent to the user, as they see it.
+    Message {
+        message: Message,
+    },
+    /// The delivery state of a message, or with `receiver` the receipt of
+    /// one of its receivers, changed.
+    MessageState {
+        message_id: Uuid,
+        receiver: Option<String>,
+        state: MessageState,
+    },
+    Enrollment {
+        enrollment: Enrollment,
+    },
+    /// The client fell behind and `missed` events were dropped, it should
+    /// reload what it shows.
+    Lagged {
+        missed: u64,
+    },
+}
+
+impl Event {
+    /// The `type` it is serialized with.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Message { .. } => "message",
+            Event::MessageState { .. } => "message_state",
+            Event::Enrollment { .. } => "enrollment",
+            Event::Lagged { .. } => "lagged",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Published {
+    // emails or uids, like `Message::receiver_ids`
+    audience: Vec<String>,
+    event: Event,
+}
+
+impl Published {
+    fn is_for(&self, user: &User) -> bool {
+        self.audience
+            .iter()
+            .any(|a| *a == user.email || *a == user.uid.to_string())
+    }
+}
+
+/// In-process fan-out of events to the subscribed clients. Only reaches the
+/// clients of this server, events published without subscribers are dropped.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Arc<Published>>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus::new(EVENT_BUS_CAPACITY)
+    }
+}
+
+impl EventBus {
+    /// Subscribers more than `capacity` events behind get `Event::Lagged`.
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        EventBus { sender }
+    }
+
+    pub fn publish(&self, audience: Vec<String>, event: Event) {
+        // fails only when nobody is subscribed
+        let _ = self.sender.send(Arc::new(Published { audience, event }));
+    }
+
+    /// The events for `user` from now on.
+    pub fn subscribe(&self, user: &User) -> impl Stream<Item = Event> {
+        let receiver = self.sender.subscribe();
+        stream::unfold(
+            (receiver, user.clone()),
+            |(mut receiver, user)| async move {
+                loop {
+                    let event = match receiver.recv().await {
+                        Ok(p) if p.is_for(&user) => match &p.event {
+                            Event::Message { message } => Event::Message {
+                                message: message_view(message.clone(), &user),
+                            },
+                            event => event.clone(),
+                        },
+                        Ok(_) => continue,
+                        Err(RecvError::Lagged(missed)) => Event::Lagged { missed },
+                        Err(RecvError::Closed) => return None,
+                    };
+                    return Some((event, (receiver, user)));
+                }
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::message::MessageBody;
+    use crate::common::message::{try_send_messages, try_set_message_state};
+    use crate::common::store::{Fixtures, MemoryStore};
+    use crate::common::{Event, EventBus, MessageState, Notifier};
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn test_events_async() {
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+        let notifier = Notifier::default();
+        let teacher = db.fixture_user("teacher").await;
+        let parent = db.fixture_user("parent").await;
+        let student = db.fixture_user("student").await;
+
+        let mut teacher_events = Box::pin(notifier.events().subscribe(&teacher));
+        let mut parent_events = Box::pin(notifier.events().subscribe(&parent));
+        let mut student_events = Box::pin(notifier.events().subscribe(&student));
+
+        let sent = try_send_messages(
+            &db,
+            &notifier,
+            &teacher,
+            MessageBody {
+                receiver_ids: vec!["mom@mom.com".to_string()],
+                subject: None,
+                content: "hello".to_string(),
+                attachments: vec![],
+            },
+        )
+        .await
+        .unwrap();
+
+        match parent_events.next().await.unwrap() {
+            Event::Message { message } => {
+                assert_eq!(message.id, sent.message.id);
+                assert_eq!(message.receipts.len(), 1);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        // without channels the message is sent right away
+        match teacher_events.next().await.unwrap() {
+            Event::MessageState {
+                receiver: None,
+                state,
+                ..
+            } => assert_eq!(state, MessageState::Sent),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        try_set_message_state(&db, &notifier, &parent, &sent.message, MessageState::Read)
+            .await
+            .unwrap();
+        match teacher_events.next().await.unwrap() {
+            Event::MessageState {
+                message_id,
+                receiver,
+                state,
+            } => {
+                assert_eq!(message_id, sent.message.id);
+                assert_eq!(receiver.as_deref(), Some("mom@mom.com"));
+                assert_eq!(state, MessageState::Read);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // the student wasn't addressed by any of it
+        notifier
+            .events()
+            .publish(vec![student.email.clone()], Event::Lagged { missed: 0 });
+        assert!(matches!(
+            student_events.next().await.unwrap(),
+            Event::Lagged { missed: 0 }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscriber_lags_async() {
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+        let parent = db.fixture_user("parent").await;
+
+        let bus = EventBus::new(2);
+        let mut events = Box::pin(bus.subscribe(&parent));
+        for missed in 0..4 {
+            bus.publish(vec![parent.email.clone()], Event::Lagged { missed });
+        }
+        // the two oldest were dropped, then the retained ones follow
+        assert!(matches!(
+            events.next().await.unwrap(),
+            Event::Lagged { missed: 2 }
+        ));
+        assert!(matches!(
+            events.next().await.unwrap(),
+            Event::Lagged { missed: 2 }
+        ));
+        assert!(matches!(
+            events.next().await.unwrap(),
+            Event::Lagged { missed: 3 }
+        ));
+    }
+}
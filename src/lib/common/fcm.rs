@@ -1,66 +1,476 @@
-use crate::common::user::try_get_users_from_emails;
-use crate::common::{FCM_URL, MAX_FCM_TOKENS_PER_REQUEST};
-use firestore::FirestoreDb;
+use crate::common::store::{Store, StoreResult};
+use crate::common::{
+    config_env_var, Notification, NotificationChannel, SendOutcome, User, FCM_BASE_URL, FCM_SCOPE,
+    FCM_SEND_CONCURRENCY, FCM_TOKEN_REFRESH_MARGIN_SECS,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use futures::StreamExt;
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
 use log::debug;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::sync::Arc;
+use tokio::sync::Mutex;
 
-pub async fn send_notification_to_emails<I: AsRef<str> + Serialize>(
-    db: &FirestoreDb,
-    http: &reqwest::Client,
-    emails: &[I],
-    title: Option<&str>,
-    body: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let receivers = try_get_users_from_emails(db, emails).await?;
-    let fcm_key = std::env::var("FCM_SEVER_KEY").expect("FCM_SEVER_KEY must be set!");
-
-    debug!("receivers ok {:?}", receivers);
-
-    let devices: Vec<_> = receivers
-        .as_slice()
-        .iter()
-        .flat_map(|u| u.devices.clone())
-        .collect();
-
-    let token_batches: Vec<_> = devices.chunks(MAX_FCM_TOKENS_PER_REQUEST).collect();
-
-    for tokens in token_batches {
-        // Create a JSON payload for the FCM request with the current batch of tokens
-        let payload = json!({
-            "registration_ids": tokens,
-            "notification": {
-                "title": title,
-                "body": body,
-            },
-        });
-
-        // Send the FCM request
-        let res = http
-            .post(FCM_URL)
-            .header("Authorization", format!("key={}", fcm_key))
-            .header("Content-Type", "application/json")
-            .json(&payload)
+/// OAuth2 access tokens for the FCM HTTP v1 API.
+#[async_trait]
+pub trait AccessTokenProvider: Send + Sync {
+    async fn access_token(&self, http: &reqwest::Client) -> StoreResult<String>;
+}
+
+/// The fields of a service account JSON key we need.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServiceAccountKey {
+    pub project_id: String,
+    pub client_email: String,
+    pub private_key: String,
+    pub token_uri: String,
+}
+
+impl ServiceAccountKey {
+    pub fn from_file(path: &str) -> StoreResult<Self> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+}
+
+#[derive(Serialize)]
+struct JwtGrantClaims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: i64,
+}
+
+/// Exchanges a signed JWT for an access token (the OAuth2 JWT bearer grant)
+/// and reuses it until shortly before it expires.
+pub struct ServiceAccountTokenProvider {
+    key: ServiceAccountKey,
+    cached: Mutex<Option<(String, DateTime<Utc>)>>,
+}
+
+impl ServiceAccountTokenProvider {
+    pub fn new(key: ServiceAccountKey) -> Self {
+        ServiceAccountTokenProvider {
+            key,
+            cached: Mutex::new(None),
+        }
+    }
+
+    async fn fetch_token(&self, http: &reqwest::Client) -> StoreResult<(String, DateTime<Utc>)> {
+        let now = Utc::now();
+        let claims = JwtGrantClaims {
+            iss: &self.key.client_email,
+            scope: FCM_SCOPE,
+            aud: &self.key.token_uri,
+            iat: now.timestamp(),
+            exp: (now + Duration::hours(1)).timestamp(),
+        };
+        let assertion = encode(
+            &Header::new(Algorithm::RS256),
+            &claims,
+            &EncodingKey::from_rsa_pem(self.key.private_key.as_bytes())?,
+        )?;
+
+        let res: TokenResponse = http
+            .post(&self.key.token_uri)
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", assertion.as_str()),
+            ])
             .send()
-            .await;
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
 
-        match res {
-            Ok(r) => {
-                // Check if the request was successful (status code 200)
-                if r.status().is_success() {
-                    debug!("Notification sent successfully to batch!");
-                } else {
-                    debug!(
-                        "failed to send notification to batch. Status code: {}",
-                        r.status()
-                    );
-                }
-            }
+        Ok((res.access_token, now + Duration::seconds(res.expires_in)))
+    }
+}
+
+#[async_trait]
+impl AccessTokenProvider for ServiceAccountTokenProvider {
+    async fn access_token(&self, http: &reqwest::Client) -> StoreResult<String> {
+        // held across the fetch so concurrent sends share one refresh
+        let mut cached = self.cached.lock().await;
+        let margin = Duration::seconds(FCM_TOKEN_REFRESH_MARGIN_SECS);
+        match cached.as_ref() {
+            Some((token, expires_at)) if *expires_at - margin > Utc::now() => Ok(token.clone()),
             _ => {
-                debug!("failed to send notification");
+                let (token, expires_at) = self.fetch_token(http).await?;
+                *cached = Some((token.clone(), expires_at));
+                Ok(token)
+            }
+        }
+    }
+}
+
+struct FcmProject {
+    id: String,
+    tokens: Arc<dyn AccessTokenProvider>,
+}
+
+/// Sends push notifications through the FCM HTTP v1 API, one request per
+/// device token.
+#[derive(Clone)]
+pub struct FcmClient {
+    http: reqwest::Client,
+    base_url: String,
+    // `None` when no credentials are configured, notifications are skipped
+    project: Option<Arc<FcmProject>>,
+}
+
+impl FcmClient {
+    pub fn new(
+        http: reqwest::Client,
+        base_url: &str,
+        project_id: &str,
+        tokens: Arc<dyn AccessTokenProvider>,
+    ) -> Self {
+        FcmClient {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            project: Some(Arc::new(FcmProject {
+                id: project_id.to_string(),
+                tokens,
+            })),
+        }
+    }
+
+    pub fn disabled(http: reqwest::Client) -> Self {
+        FcmClient {
+            http,
+            base_url: FCM_BASE_URL.to_string(),
+            project: None,
+        }
+    }
+
+    /// Reads the service account key from `FCM_CREDENTIALS`, falling back to
+    /// `GOOGLE_APPLICATION_CREDENTIALS`. `FCM_PROJECT_ID` overrides the key's
+    /// project and `FCM_BASE_URL` the API host.
+    pub fn from_env(http: reqwest::Client) -> StoreResult<Self> {
+        let path = config_env_var("FCM_CREDENTIALS")
+            .or_else(|_| config_env_var("GOOGLE_APPLICATION_CREDENTIALS"))?;
+        let key = ServiceAccountKey::from_file(&path)?;
+        let project_id = config_env_var("FCM_PROJECT_ID").unwrap_or(key.project_id.clone());
+        let base_url = config_env_var("FCM_BASE_URL").unwrap_or(FCM_BASE_URL.to_string());
+
+        Ok(FcmClient::new(
+            http,
+            &base_url,
+            &project_id,
+            Arc::new(ServiceAccountTokenProvider::new(key)),
+        ))
+    }
+
+    /// Sends to every token concurrently and reports how each one went, so
+    /// one stale device doesn't fail the others.
+    pub async fn send_to_tokens(
+        &self,
+        tokens: &[String],
+        title: Option<&str>,
+        body: &str,
+    ) -> StoreResult<Vec<(String, SendOutcome)>> {
+        let project = match &self.project {
+            Some(p) => p,
+            None => {
+                debug!("FCM is not configured, skipping {} devices", tokens.len());
+                return Ok(Vec::new());
             }
+        };
+        if tokens.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let access_token = project.tokens.access_token(&self.http).await?;
+        let url = format!("{}/v1/projects/{}/messages:send", self.base_url, project.id);
+
+        let requests: Vec<_> = tokens
+            .iter()
+            .map(|token| {
+                let payload = json!({
+                    "message": {
+                        "token": token,
+                        "notification": {
+                            "title": title,
+                            "body": body,
+                        },
+                    },
+                });
+                let req = self
+                    .http
+                    .post(&url)
+                    .bearer_auth(&access_token)
+                    .json(&payload);
+
+                async move {
+                    let outcome = match req.send().await {
+                        Ok(r) if r.status().is_success() => SendOutcome::Sent,
+                        Ok(r) => {
+                            let status = r.status();
+                            let error = r.json::<FcmErrorResponse>().await.ok();
+                            outcome_from_error(status, error.map(|e| e.error))
+                        }
+                        Err(e) => SendOutcome::Failed(format!("{:?}", e)),
+                    };
+                    debug!("notification to {}: {:?}", token, outcome);
+                    (token.clone(), outcome)
+                }
+            })
+            .collect();
+
+        let outcomes = futures::stream::iter(requests)
+            .buffer_unordered(FCM_SEND_CONCURRENCY)
+            .collect()
+            .await;
+
+        Ok(outcomes)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct FcmErrorResponse {
+    error: FcmError,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct FcmError {
+    #[serde(default)]
+    message: String,
+    #[serde(default)]
+    details: Vec<FcmErrorDetail>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FcmErrorDetail {
+    #[serde(rename = "errorCode")]
+    error_code: Option<String>,
+}
+
+fn outcome_from_error(status: reqwest::StatusCode, error: Option<FcmError>) -> SendOutcome {
+    let error = error.unwrap_or_default();
+    let code = error.details.iter().find_map(|d| d.error_code.as_deref());
+    match code {
+        Some("UNREGISTERED") => SendOutcome::InvalidTarget,
+        // also raised for a bad payload, only the token one names it
+        Some("INVALID_ARGUMENT") if error.message.contains("registration token") => {
+            SendOutcome::InvalidTarget
+        }
+        _ if status == reqwest::StatusCode::NOT_FOUND => SendOutcome::InvalidTarget,
+        _ => SendOutcome::Failed(format!("{} {}", status, error.message)),
+    }
+}
+
+/// Push to the user's registered devices, FCM device tokens being the targets.
+#[async_trait]
+impl NotificationChannel for FcmClient {
+    fn name(&self) -> &'static str {
+        "push"
+    }
+
+    fn targets(&self, user: &User) -> Vec<String> {
+        match self.project {
+            Some(_) => user.devices.clone(),
+            None => Vec::new(),
+        }
+    }
+
+    async fn send(
+        &self,
+        targets: &[String],
+        notification: &Notification,
+    ) -> StoreResult<Vec<(String, SendOutcome)>> {
+        self.send_to_tokens(targets, notification.title.as_deref(), &notification.body)
+            .await
+    }
+
+    async fn prune(&self, db: &dyn Store, user: &User, invalid: &[String]) -> StoreResult<usize> {
+        let devices: Vec<String> = user
+            .devices
+            .iter()
+            .filter(|d| !invalid.contains(d))
+            .cloned()
+            .collect();
+        if devices.len() == user.devices.len() {
+            return Ok(0);
+        }
+
+        db.set_devices(&user.uid, &devices).await?;
+        Ok(user.devices.len() - devices.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::fcm::{FcmClient, ServiceAccountKey, ServiceAccountTokenProvider};
+    use crate::common::store::{Fixtures, MemoryStore, UserStore};
+    use crate::common::{
+        send_notification_to_emails, Notification, NotificationCategory, NotificationSummary,
+        Notifier,
+    };
+    use actix_web::dev::ServerHandle;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct MockFcm {
+        token_requests: Mutex<usize>,
+        // (authorization header, device token)
+        sends: Mutex<Vec<(String, String)>>,
+    }
+
+    async fn mock_token(state: web::Data<MockFcm>) -> HttpResponse {
+        *state.token_requests.lock().unwrap() += 1;
+        HttpResponse::Ok().json(json!({"access_token": "mock-token", "expires_in": 3600}))
+    }
+
+    async fn mock_send(
+        state: web::Data<MockFcm>,
+        req: HttpRequest,
+        body: web::Json<Value>,
+    ) -> HttpResponse {
+        let auth = req
+            .headers()
+            .get("authorization")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let token = body["message"]["token"].as_str().unwrap().to_string();
+        state.sends.lock().unwrap().push((auth, token.clone()));
+
+        let error = |status: &str, code: &str, message: &str| {
+            json!({"error": {
+                "status": status,
+                "message": message,
+                "details": [{
+                    "@type": "type.googleapis.com/google.firebase.fcm.v1.FcmError",
+                    "errorCode": code,
+                }],
+            }})
+        };
+        match token.as_str() {
+            "stale" => HttpResponse::NotFound().json(error(
+                "NOT_FOUND",
+                "UNREGISTERED",
+                "Requested entity was not found.",
+            )),
+            "garbage" => HttpResponse::BadRequest().json(error(
+                "INVALID_ARGUMENT",
+                "INVALID_ARGUMENT",
+                "The registration token is not a valid FCM registration token",
+            )),
+            "busy" => HttpResponse::ServiceUnavailable().json(error(
+                "UNAVAILABLE",
+                "UNAVAILABLE",
+                "The service is currently unavailable.",
+            )),
+            _ => HttpResponse::Ok().json(json!({"name": "projects/edclass-test/messages/1"})),
+        }
+    }
+
+    async fn mock_fcm() -> (web::Data<MockFcm>, Notifier, ServerHandle) {
+        let state = web::Data::new(MockFcm::default());
+        let server_state = state.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(server_state.clone())
+                .route("/token", web::post().to(mock_token))
+                .route(
+                    "/v1/projects/edclass-test/messages:send",
+                    web::post().to(mock_send),
+                )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let base_url = format!("http://{}", server.addrs()[0]);
+        let handle = server.run();
+        let server_handle = handle.handle();
+        tokio::spawn(handle);
+
+        let mut key: ServiceAccountKey =
+            serde_json::from_str(include_str!("../../../fixtures/fcm-service-account.json"))
+                .unwrap();
+        key.token_uri = format!("{}/token", base_url);
+        let fcm = FcmClient::new(
+            reqwest::Client::new(),
+            &base_url,
+            &key.project_id.clone(),
+            Arc::new(ServiceAccountTokenProvider::new(key)),
+        );
+
+        (state, Notifier::new(vec![Arc::new(fcm)]), server_handle)
+    }
+
+    fn notification(title: Option<&str>) -> Notification {
+        Notification {
+            category: NotificationCategory::Messages,
+            title: title.map(|t| t.to_string()),
+            body: "hello".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_through_v1_api_async() {
+        let (state, fcm, server_handle) = mock_fcm().await;
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+        let parent_id = Fixtures::id("parent");
+        let devices = vec!["device-a".to_string(), "device-b".to_string()];
+        db.set_devices(&parent_id, &devices).await.unwrap();
+
+        for _ in 0..2 {
+            send_notification_to_emails(&db, &fcm, &["mom@mom.com"], &notification(Some("hi")))
+                .await
+                .unwrap();
         }
+        server_handle.stop(false).await;
+
+        // the access token is fetched once and reused for the second send
+        assert_eq!(*state.token_requests.lock().unwrap(), 1);
+        let mut sends = state.sends.lock().unwrap().clone();
+        sends.sort();
+        assert_eq!(sends.len(), 4);
+        assert!(sends.iter().all(|(auth, _)| auth == "Bearer mock-token"));
+        assert_eq!(sends[0].1, "device-a");
+        assert_eq!(sends[3].1, "device-b");
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn test_prune_invalid_tokens_async() {
+        let (_state, fcm, server_handle) = mock_fcm().await;
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+        let parent_id = Fixtures::id("parent");
+        let devices: Vec<String> = ["device-a", "stale", "garbage", "busy"]
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        db.set_devices(&parent_id, &devices).await.unwrap();
+
+        let summary = send_notification_to_emails(&db, &fcm, &["mom@mom.com"], &notification(None))
+            .await
+            .unwrap();
+        server_handle.stop(false).await;
+
+        assert_eq!(
+            summary,
+            NotificationSummary {
+                sent: 1,
+                failed: 3,
+                removed: 2,
+                deferred: 0,
+                retry: vec!["push:busy".to_string()],
+                deferred_until: None,
+            }
+        );
+        let parent = db.get_user(&parent_id).await.unwrap().unwrap();
+        assert_eq!(parent.devices, vec!["device-a", "busy"]);
+    }
 }
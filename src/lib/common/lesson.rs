@@ -0,0 +1,623 @@
+use crate::api::lesson::{LessonBody, ModuleBody};
+use crate::common::attachment::{attachable, link_attachments};
+use crate::common::course::{editable_course, CourseError};
+use crate::common::store::{Store, StoreResult};
+use crate::common::{
+    AttachmentRef, Course, CourseModule, Lesson, LessonCompletion, LessonLink, LessonOutline,
+    Material, ModuleOutline, User, UserRole, COURSE_TITLE_MAX_LEN, LESSON_BODY_MAX_LEN,
+    LESSON_MAX_LINKS,
+};
+use chrono::Utc;
+use std::collections::HashSet;
+use uuid::Uuid;
+
+/// Checks a module or lesson title, returns it trimmed.
+fn validate_title(title: &str) -> Result<String, CourseError> {
+    let title = title.trim();
+    if title.is_empty() {
+        return Err(CourseError::Invalid("title is required".to_string()));
+    }
+    if title.chars().count() > COURSE_TITLE_MAX_LEN {
+        return Err(CourseError::Invalid(format!(
+            "title is longer than {} characters",
+            COURSE_TITLE_MAX_LEN
+        )));
+    }
+
+    Ok(title.to_string())
+}
+
+/// Links and materials are titled http(s) URLs, `what` names them in errors.
+fn validate_link(what: &str, title: &str, url: &str) -> Result<(String, String), CourseError> {
+    let title = title.trim();
+    let url = url.trim();
+    if title.is_empty() {
+        return Err(CourseError::Invalid(format!("{} title is required", what)));
+    }
+    let host = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"));
+    if host.is_none_or(|h| h.is_empty() || h.starts_with('/')) || url.contains(char::is_whitespace)
+    {
+        return Err(CourseError::Invalid(format!(
+            "{} url is not an http(s) URL: {}",
+            what, url
+        )));
+    }
+
+    Ok((title.to_string(), url.to_string()))
+}
+
+/// A material is either an uploaded attachment or a link.
+fn validate_material(material: &Material) -> Result<Material, CourseError> {
+    match (&material.url, material.attachment_id) {
+        (Some(url), None) => {
+            let (title, url) = validate_link("material", &material.title, url)?;
+            Ok(Material {
+                title,
+                url: Some(url),
+                attachment_id: None,
+            })
+        }
+        (None, Some(id)) => Ok(Material {
+            title: validate_title(&material.title)?,
+            url: None,
+            attachment_id: Some(id),
+        }),
+        _ => Err(CourseError::Invalid(
+            "a material has either a url or an attachment_id".to_string(),
+        )),
+    }
+}
+
+/// The attachments of `materials` not already used by `old`, which `user`
+/// must have uploaded.
+async fn new_attachments(
+    db: &dyn Store,
+    user: &User,
+    materials: &[Material],
+    old: Option<&Lesson>,
+) -> Result<Vec<Uuid>, CourseError> {
+    let ids: Vec<Uuid> = materials
+        .iter()
+        .filter_map(|m| m.attachment_id)
+        .filter(|id| old.is_none_or(|l| l.materials.iter().all(|m| m.attachment_id != Some(*id))))
+        .collect();
+    attachable(db, user, &ids).await?;
+
+    Ok(ids)
+}
+
+/// The lesson a teacher sent with its fields trimmed.
+pub fn validate_lesson(body: &LessonBody) -> Result<LessonBody, CourseError> {
+    let title = validate_title(&body.title)?;
+    let text = body.body.trim();
+    if text.chars().count() > LESSON_BODY_MAX_LEN {
+        return Err(CourseError::Invalid(format!(
+            "body is longer than {} characters",
+            LESSON_BODY_MAX_LEN
+        )));
+    }
+    if body.links.len() > LESSON_MAX_LINKS || body.materials.len() > LESSON_MAX_LINKS {
+        return Err(CourseError::Invalid(format!(
+            "a lesson has at most {} links and {} materials",
+            LESSON_MAX_LINKS, LESSON_MAX_LINKS
+        )));
+    }
+    let links = body
+        .links
+        .iter()
+        .map(|l| validate_link("link", &l.title, &l.url))
+        .map(|r| r.map(|(title, url)| LessonLink { title, url }))
+        .collect::<Result<Vec<_>, _>>()?;
+    let materials = body
+        .materials
+        .iter()
+        .map(validate_material)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(LessonBody {
+        title,
+        body: text.to_string(),
+        links,
+        materials,
+    })
+}
+
+/// Whether `ids` lists every one of `expected` exactly once, and nothing else.
+fn is_permutation(ids: &[Uuid], expected: &[Uuid]) -> bool {
+    let unique: HashSet<&Uuid> = ids.iter().collect();
+    unique.len() == ids.len()
+        && ids.len() == expected.len()
+        && expected.iter().all(|id| unique.contains(id))
+}
+
+async fn course_module(
+    db: &dyn Store,
+    course_id: &Uuid,
+    module_id: &Uuid,
+) -> Result<CourseModule, CourseError> {
+    db.list_course_modules(course_id)
+        .await?
+        .into_iter()
+        .find(|m| m.id == *module_id)
+        .ok_or(CourseError::NotFound("module"))
+}
+
+async fn course_lesson(
+    db: &dyn Store,
+    course_id: &Uuid,
+    lesson_id: &Uuid,
+) -> Result<Lesson, CourseError> {
+    db.get_lesson(lesson_id)
+        .await?
+        .filter(|l| l.course_id == *course_id)
+        .ok_or(CourseError::NotFound("lesson"))
+}
+
+/// Appends a module to the course.
+pub async fn try_create_module(
+    db: &dyn Store,
+    user: &User,
+    course_id: &Uuid,
+    body: ModuleBody,
+) -> Result<CourseModule, CourseError> {
+    let course = editable_course(db, user, course_id).await?;
+    let title = validate_title(&body.title)?;
+    let modules = db.list_course_modules(&course.id).await?;
+    let position = modules.iter().map(|m| m.position + 1).max().unwrap_or(0);
+    let module = CourseModule {
+        id: Uuid::new_v4(),
+        course_id: course.id,
+        title,
+        position,
+    };
+    db.insert_module(&module).await?;
+
+    Ok(module)
+}
+
+pub async fn try_update_module(
+    db: &dyn Store,
+    user: &User,
+    course_id: &Uuid,
+    module_id: &Uuid,
+    body: ModuleBody,
+) -> Result<CourseModule, CourseError> {
+    editable_course(db, user, course_id).await?;
+    let module = CourseModule {
+        title: validate_title(&body.title)?,
+        ..course_module(db, course_id, module_id).await?
+    };
+    db.update_module(&module).await?;
+
+    Ok(module)
+}
+
+/// Only empty modules can be deleted, so no lesson is lost by accident.
+pub async fn try_delete_module(
+    db: &dyn Store,
+    user: &User,
+    course_id: &Uuid,
+    module_id: &Uuid,
+) -> Result<(), CourseError> {
+    editable_course(db, user, course_id).await?;
+    let module = course_module(db, course_id, module_id).await?;
+    let lessons = db.list_course_lessons(course_id).await?;
+    if lessons.iter().any(|l| l.module_id == module.id) {
+        return Err(CourseError::Conflict(
+            "module still has lessons, move or delete them first".to_string(),
+        ));
+    }
+    db.delete_module(&module.id).await?;
+
+    Ok(())
+}
+
+/// `ids` must list every module of the course, in the new order.
+pub async fn try_reorder_modules(
+    db: &dyn Store,
+    user: &User,
+    course_id: &Uuid,
+    ids: &[Uuid],
+) -> Result<Vec<CourseModule>, CourseError> {
+    editable_course(db, user, course_id).await?;
+    let modules = db.list_course_modules(course_id).await?;
+    let expected: Vec<Uuid> = modules.iter().map(|m| m.id).collect();
+    if !is_permutation(ids, &expected) {
+        return Err(CourseError::Invalid(
+            "ids must list every module of the course once".to_string(),
+        ));
+    }
+    db.reorder_modules(course_id, ids).await?;
+
+    Ok(db.list_course_modules(course_id).await?)
+}
+
+async fn link_lesson_attachments(
+    db: &dyn Store,
+    lesson: &Lesson,
+    attachments: &[Uuid],
+) -> StoreResult<()> {
+    let by = AttachmentRef::Lesson {
+        course_id: lesson.course_id,
+        lesson_id: lesson.id,
+    };
+    link_attachments(db, attachments, by).await
+}
+
+/// Appends a lesson to the module. It stays hidden from students until it
+/// is published.
+pub async fn try_create_lesson(
+    db: &dyn Store,
+    user: &User,
+    course_id: &Uuid,
+    module_id: &Uuid,
+    body: LessonBody,
+) -> Result<Lesson, CourseError> {
+    editable_course(db, user, course_id).await?;
+    let module = course_module(db, course_id, module_id).await?;
+    let body = validate_lesson(&body)?;
+    let lessons = db.list_course_lessons(course_id).await?;
+    let position = lessons
+        .iter()
+        .filter(|l| l.module_id == module.id)
+        .map(|l| l.position + 1)
+        .max()
+        .unwrap_or(0);
+    let attachments = new_attachments(db, user, &body.materials, None).await?;
+    let lesson = Lesson {
+        id: Uuid::new_v4(),
+        course_id: *course_id,
+        module_id: module.id,
+        title: body.title,
+        body: body.body,
+        links: body.links,
+        materials: body.materials,
+        position,
+        published: false,
+        updated_at: Utc::now(),
+    };
+    db.insert_lesson(&lesson).await?;
+    link_lesson_attachments(db, &lesson, &attachments).await?;
+
+    Ok(lesson)
+}
+
+/// Replaces the content of the lesson, it keeps its place and publication.
+pub async fn try_update_lesson(
+    db: &dyn Store,
+    user: &User,
+    course_id: &Uuid,
+    lesson_id: &Uuid,
+    body: LessonBody,
+) -> Result<Lesson, CourseError> {
+    editable_course(db, user, course_id).await?;
+    let old = course_lesson(db, course_id, lesson_id).await?;
+    let body = validate_lesson(&body)?;
+    let attachments = new_attachments(db, user, &body.materials, Some(&old)).await?;
+    let lesson = Lesson {
+        title: body.title,
+        body: body.body,
+        links: body.links,
+        materials: body.materials,
+        updated_at: Utc::now(),
+        ..old
+    };
+    db.update_lesson(&lesson).await?;
+    link_lesson_attachments(db, &lesson, &attachments).await?;
+
+    Ok(lesson)
+}
+
+/// Deletes the lesson along with who completed it.
+pub async fn try_delete_lesson(
+    db: &dyn Store,
+    user: &User,
+    course_id: &Uuid,
+    lesson_id: &Uuid,
+) -> Result<(), CourseError> {
+    editable_course(db, user, course_id).await?;
+    let lesson = course_lesson(db, course_id, lesson_id).await?;
+    db.delete_lesson(&lesson.id).await?;
+
+    Ok(())
+}
+
+/// Sets the order of the lessons of a module. `ids` must list every lesson
+/// of the module, and may add lessons of other modules to move them here.
+pub async fn try_reorder_lessons(
+    db: &dyn Store,
+    user: &User,
+    course_id: &Uuid,
+    module_id: &Uuid,
+    ids: &[Uuid],
+) -> Result<Vec<Lesson>, CourseError> {
+    editable_course(db, user, course_id).await?;
+    let module = course_module(db, course_id, module_id).await?;
+    let lessons = db.list_course_lessons(course_id).await?;
+    let unique: HashSet<&Uuid> = ids.iter().collect();
+    if unique.len() != ids.len() {
+        return Err(CourseError::Invalid("ids must be unique".to_string()));
+    }
+    if let Some(id) = ids.iter().find(|id| lessons.iter().all(|l| l.id != **id)) {
+        return Err(CourseError::Invalid(format!(
+            "lesson {} is not part of the course",
+            id
+        )));
+    }
+    if lessons
+        .iter()
+        .any(|l| l.module_id == module.id && !unique.contains(&l.id))
+    {
+        return Err(CourseError::Invalid(
+            "ids must list every lesson of the module".to_string(),
+        ));
+    }
+    db.reorder_lessons(course_id, &module.id, ids).await?;
+
+    let mut lessons: Vec<Lesson> = db
+        .list_course_lessons(course_id)
+        .await?
+        .into_iter()
+        .filter(|l| l.module_id == module.id)
+        .collect();
+    lessons.sort_by_key(|l| l.position);
+    Ok(lessons)
+}
+
+/// Shows the lesson to students, or hides it again.
+pub async fn try_set_lesson_published(
+    db: &dyn Store,
+    user: &User,
+    course_id: &Uuid,
+    lesson_id: &Uuid,
+    published: bool,
+) -> Result<Lesson, CourseError> {
+    editable_course(db, user, course_id).await?;
+    let lesson = course_lesson(db, course_id, lesson_id).await?;
+    if lesson.published == published {
+        return Ok(lesson);
+    }
+    let lesson = Lesson {
+        published,
+        updated_at: Utc::now(),
+        ..lesson
+    };
+    db.update_lesson(&lesson).await?;
+
+    Ok(lesson)
+}
+
+/// Marks a published lesson as done by the enrolled student `user`. Doing
+/// it again returns the first completion.
+pub async fn try_complete_lesson(
+    db: &dyn Store,
+    user: &User,
+    course_id: &Uuid,
+    lesson_id: &Uuid,
+) -> Result<LessonCompletion, CourseError> {
+    if user.role != UserRole::Student {
+        return Err(CourseError::Forbidden);
+    }
+    let course = db
+        .get_course(course_id)
+        .await?
+        .ok_or(CourseError::NotFound("course"))?;
+    if course.archived_at.is_some() {
+        return Err(CourseError::Archived);
+    }
+    let enrollment = db.find_enrollment(&user.uid, course_id).await?;
+    if !enrollment.is_some_and(|e| e.is_active()) {
+        return Err(CourseError::Forbidden);
+    }
+    let lesson = course_lesson(db, course_id, lesson_id).await?;
+    if !lesson.published {
+        return Err(CourseError::NotFound("lesson"));
+    }
+
+    let completion = LessonCompletion {
+        lesson_id: lesson.id,
+        student_id: user.uid,
+        course_id: *course_id,
+        completed_at: Utc::now(),
+    };
+    if db.insert_lesson_completion(&completion).await? {
+        return Ok(completion);
+    }
+    let existing = db
+        .list_course_completions(course_id)
+        .await?
+        .into_iter()
+        .find(|c| c.lesson_id == lesson.id && c.student_id == user.uid);
+
+    Ok(existing.unwrap_or(completion))
+}
+
+/// The modules and lessons of the course as `user` sees them. Only its
+/// teacher and admins see unpublished lessons, and `completed_by` lists the
+/// students `user` may know about.
+pub async fn course_outline(
+    db: &dyn Store,
+    user: &User,
+    course: &Course,
+) -> StoreResult<Vec<ModuleOutline>> {
+    let editor = user.role == UserRole::Admin || course.teacher_id == user.uid;
+    let visible: Option<HashSet<Uuid>> = match user.role {
+        _ if editor => None,
+        UserRole::Parent => Some(
+            db.list_students_of(&user.uid)
+                .await?
+                .into_iter()
+                .map(|s| s.student_id)
+                .collect(),
+        ),
+        _ => Some(HashSet::from([user.uid])),
+    };
+
+    let completions = db.list_course_completions(&course.id).await?;
+    let mut lessons = db.list_course_lessons(&course.id).await?;
+    lessons.sort_by_key(|l| l.position);
+
+    let outline = db
+        .list_course_modules(&course.id)
+        .await?
+        .into_iter()
+        .map(|module| {
+            let lessons = lessons
+                .iter()
+                .filter(|l| l.module_id == module.id && (editor || l.published))
+                .map(|lesson| {
+                    let completed_by: Vec<Uuid> = completions
+                        .iter()
+                        .filter(|c| c.lesson_id == lesson.id)
+                        .map(|c| c.student_id)
+                        .filter(|s| visible.as_ref().is_none_or(|v| v.contains(s)))
+                        .collect();
+                    LessonOutline {
+                        lesson: lesson.clone(),
+                        completed: completed_by.contains(&user.uid),
+                        completed_by,
+                    }
+                })
+                .collect();
+            ModuleOutline { module, lessons }
+        })
+        .collect();
+
+    Ok(outline)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::lesson::{LessonBody, ModuleBody};
+    use crate::common::course::{get_course, CourseError};
+    use crate::common::lesson::{
+        try_complete_lesson, try_create_lesson, try_create_module, try_delete_module,
+        try_reorder_lessons, try_reorder_modules, try_set_lesson_published,
+    };
+    use crate::common::store::{Fixtures, MemoryStore};
+    use crate::common::{LessonLink, Material};
+
+    #[tokio::test]
+    async fn test_course_outline_async() {
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+        let math_id = Fixtures::id("math");
+        let teacher = db.fixture_user("teacher").await;
+        let parent = db.fixture_user("parent").await;
+        let student = db.fixture_user("student").await;
+        let module = |title: &str| ModuleBody {
+            title: title.to_string(),
+        };
+        let lesson = |title: &str| LessonBody {
+            title: title.to_string(),
+            body: "# Fractions\n\nHalves and *quarters*.".to_string(),
+            links: vec![LessonLink {
+                title: "Video".to_string(),
+                url: "https://example.com/fractions".to_string(),
+            }],
+            materials: vec![],
+        };
+
+        let algebra = try_create_module(&db, &teacher, &math_id, module("Algebra"))
+            .await
+            .unwrap();
+        let geometry = try_create_module(&db, &teacher, &math_id, module("Geometry"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            try_create_module(&db, &student, &math_id, module("Mine")).await,
+            Err(CourseError::Forbidden)
+        ));
+
+        let mut bad = lesson("Bad");
+        bad.materials.push(Material {
+            title: "Sheet".to_string(),
+            url: Some("javascript:alert(1)".to_string()),
+            attachment_id: None,
+        });
+        assert!(matches!(
+            try_create_lesson(&db, &teacher, &math_id, &algebra.id, bad).await,
+            Err(CourseError::Invalid(_))
+        ));
+        let first = try_create_lesson(&db, &teacher, &math_id, &algebra.id, lesson("First"))
+            .await
+            .unwrap();
+        let second = try_create_lesson(&db, &teacher, &math_id, &algebra.id, lesson("Second"))
+            .await
+            .unwrap();
+        assert!(!first.published);
+        assert_eq!(second.position, 1);
+
+        // unpublished lessons can't be completed nor seen by students
+        assert!(matches!(
+            try_complete_lesson(&db, &student, &math_id, &first.id).await,
+            Err(CourseError::NotFound("lesson"))
+        ));
+        try_set_lesson_published(&db, &teacher, &math_id, &first.id, true)
+            .await
+            .unwrap();
+        let course = get_course(&db, &student, &math_id.to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(course.outline.len(), 2);
+        assert_eq!(course.outline[0].lessons.len(), 1);
+        assert!(!course.outline[0].lessons[0].completed);
+
+        try_complete_lesson(&db, &student, &math_id, &first.id)
+            .await
+            .unwrap();
+        let again = try_complete_lesson(&db, &student, &math_id, &first.id)
+            .await
+            .unwrap();
+        assert_eq!(again.student_id, student.uid);
+        let course = get_course(&db, &student, &math_id.to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(course.outline[0].lessons[0].completed);
+        // the parent sees their kid's progress, the teacher every lesson
+        let course = get_course(&db, &parent, &math_id.to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!course.outline[0].lessons[0].completed);
+        assert_eq!(course.outline[0].lessons[0].completed_by, vec![student.uid]);
+        let course = get_course(&db, &teacher, &math_id.to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(course.outline[0].lessons.len(), 2);
+
+        // move the second lesson to geometry, then put geometry first
+        assert!(matches!(
+            try_delete_module(&db, &teacher, &math_id, &algebra.id).await,
+            Err(CourseError::Conflict(_))
+        ));
+        assert!(matches!(
+            try_reorder_lessons(&db, &teacher, &math_id, &algebra.id, &[second.id]).await,
+            Err(CourseError::Invalid(_))
+        ));
+        let moved = try_reorder_lessons(&db, &teacher, &math_id, &geometry.id, &[second.id])
+            .await
+            .unwrap();
+        assert_eq!(moved[0].module_id, geometry.id);
+        assert_eq!(moved[0].position, 0);
+        assert!(matches!(
+            try_reorder_modules(&db, &teacher, &math_id, &[geometry.id]).await,
+            Err(CourseError::Invalid(_))
+        ));
+        let modules = try_reorder_modules(&db, &teacher, &math_id, &[geometry.id, algebra.id])
+            .await
+            .unwrap();
+        assert_eq!(modules[0].id, geometry.id);
+        let course = get_course(&db, &teacher, &math_id.to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(course.outline[0].module.title, "Geometry");
+        assert_eq!(course.outline[0].lessons[0].lesson.id, second.id);
+        assert_eq!(course.outline[1].lessons[0].lesson.id, first.id);
+    }
+}
@@ -1,92 +1,595 @@
 use crate::api::message::MessageBody;
+use crate::common::attachment::link_attachments;
+use crate::common::outbox::{deliver_outbox_entry, new_outbox_entry};
+use crate::common::store::{Store, StoreResult};
 use crate::common::{
-    send_notification_to_emails, Message, MessageState, User, MESSAGES_COLLECTION,
+    AttachmentRef, Event, Message, MessageReceipt, MessageState, NotificationCategory,
+    NotificationSummary, Notifier, User, DEFAULT_MESSAGES_PAGE_SIZE,
 };
-use chrono::Utc;
-use firestore::{path, FirestoreDb, FirestoreQueryDirection, FirestoreResult};
-use futures::stream::BoxStream;
-use futures::TryStreamExt;
+use chrono::{DateTime, TimeZone, Utc};
 use log::debug;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+#[derive(Debug, Copy, Clone)]
 pub enum MessageType {
     Received,
     Sent,
     All,
 }
+
+/// Position of the last message of a page. Listings are ordered by
+/// `created_at` then id, both descending, and the next page starts right
+/// after the cursor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageCursor {
+    pub created_at: DateTime<Utc>,
+    pub id: Uuid,
+}
+
+impl MessageCursor {
+    pub fn of(message: &Message) -> Self {
+        MessageCursor {
+            created_at: message.created_at,
+            id: message.id,
+        }
+    }
+
+    /// Opaque to clients: `<created_at micros>_<id>`.
+    pub fn encode(&self) -> String {
+        format!("{}_{}", self.created_at.timestamp_micros(), self.id)
+    }
+
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let (micros, id) = cursor.split_once('_')?;
+        Some(MessageCursor {
+            created_at: Utc.timestamp_micros(micros.parse().ok()?).single()?,
+            id: Uuid::parse_str(id).ok()?,
+        })
+    }
+
+    /// Whether `message` sorts after the cursor, i.e. belongs to a later page.
+    pub fn precedes(&self, message: &Message) -> bool {
+        (message.created_at, message.id) < (self.created_at, self.id)
+    }
+}
+
+/// Optional restrictions on a listing. `state` applies to the state the
+/// caller sees, their own receipt for received messages.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MessageFilter {
+    pub state: Option<MessageState>,
+    pub sender_id: Option<Uuid>,
+    // inclusive
+    pub from: Option<DateTime<Utc>>,
+    // exclusive
+    pub to: Option<DateTime<Utc>>,
+    // case-insensitive substring of the subject
+    pub subject: Option<String>,
+}
+
+impl MessageFilter {
+    /// Checks `message` as seen by its reader, see `message_view`.
+    pub fn matches(&self, message: &Message) -> bool {
+        self.state.as_ref().is_none_or(|s| &message.state == s)
+            && self.sender_id.is_none_or(|id| message.sender_id == id)
+            && self.from.is_none_or(|from| message.created_at >= from)
+            && self.to.is_none_or(|to| message.created_at < to)
+            && self.subject.as_ref().is_none_or(|text| {
+                message
+                    .subject
+                    .as_ref()
+                    .is_some_and(|s| s.to_lowercase().contains(&text.to_lowercase()))
+            })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageQuery {
+    pub filter: MessageFilter,
+    pub after: Option<MessageCursor>,
+    pub limit: usize,
+}
+
+impl Default for MessageQuery {
+    fn default() -> Self {
+        MessageQuery {
+            filter: MessageFilter::default(),
+            after: None,
+            limit: DEFAULT_MESSAGES_PAGE_SIZE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MessagePage {
+    pub messages: Vec<Message>,
+    // pass back as `cursor` for the next page, `None` on the last one
+    pub next_cursor: Option<String>,
+}
+
+impl MessagePage {
+    /// Cuts `messages`, fetched with one extra row, down to `limit` and
+    /// points the cursor at the last one kept when more are left.
+    pub fn from_overfetch(mut messages: Vec<Message>, limit: usize) -> Self {
+        let next_cursor = match messages.len() > limit {
+            true => {
+                messages.truncate(limit);
+                messages.last().map(|m| MessageCursor::of(m).encode())
+            }
+            false => None,
+        };
+
+        MessagePage {
+            messages,
+            next_cursor,
+        }
+    }
+}
+
 pub async fn try_list_messages(
-    db: &FirestoreDb,
+    db: &dyn Store,
     user: &User,
     message_type: MessageType,
-) -> Result<Vec<Message>, Box<dyn std::error::Error + Send + Sync>> {
-    let objs_stream: BoxStream<FirestoreResult<Message>> = db
-        .fluent()
-        .select()
-        .from(MESSAGES_COLLECTION)
-        .filter(|q| match message_type {
-            MessageType::Received => q.for_all([q
-                .field("receiver_ids")
-                .array_contains(user.email.to_string())]),
-            MessageType::Sent => q.for_all([q.field("sender_id").eq(user.uid.to_string())]),
-            MessageType::All => q.for_any([
-                q.field("receiver_ids")
-                    .array_contains(user.email.to_string()),
-                q.field("sender_id").eq(user.uid.to_string()),
-            ]),
-        })
-        .order_by([(
-            path!(Message::created_at),
-            FirestoreQueryDirection::Descending,
-        )])
-        .obj()
-        .stream_query_with_errors()
-        .await?;
-    let as_vec: Vec<Message> = objs_stream.try_collect().await?;
-    Ok(as_vec)
+    query: &MessageQuery,
+) -> StoreResult<MessagePage> {
+    let page = db.list_messages(user, message_type, query).await?;
+    Ok(MessagePage {
+        messages: page
+            .messages
+            .into_iter()
+            .map(|m| message_view(m, user))
+            .collect(),
+        ..page
+    })
 }
 
-pub async fn try_send_messages(
-    db: &FirestoreDb,
-    http: &reqwest::Client,
+pub async fn try_count_unread(db: &dyn Store, user: &User) -> StoreResult<usize> {
+    db.count_unread_messages(user).await
+}
+
+/// Receivers are addressed by email, older messages may use the user uuid.
+fn is_user_address(receiver: &str, user: &User) -> bool {
+    receiver == user.email || receiver == user.uid.to_string()
+}
+
+pub fn is_message_receiver(message: &Message, user: &User) -> bool {
+    message
+        .receiver_ids
+        .iter()
+        .any(|r| is_user_address(r, user))
+}
+
+/// `user`'s receipt on `message`, if they are one of its receivers.
+pub fn receipt_for(message: &Message, user: &User) -> Option<MessageReceipt> {
+    let receiver = message
+        .receiver_ids
+        .iter()
+        .find(|r| is_user_address(r, user))?;
+    message.receipt(receiver)
+}
+
+/// What `user` gets to see of `message`: the sender sees every receipt, a
+/// receiver only their own, with `state` reflecting it.
+pub fn message_view(message: Message, user: &User) -> Message {
+    if message.sender_id == user.uid {
+        return message;
+    }
+
+    match receipt_for(&message, user) {
+        Some(receipt) => Message {
+            state: receipt.state.clone(),
+            receipts: vec![receipt],
+            ..message
+        },
+        None => message,
+    }
+}
+
+fn state_rank(state: &MessageState) -> u8 {
+    match state {
+        MessageState::Pending | MessageState::Failed => 0,
+        MessageState::Sent => 1,
+        MessageState::Received => 2,
+        MessageState::Read => 3,
+    }
+}
+
+pub fn can_read_message(message: &Message, user: &User) -> bool {
+    message.sender_id == user.uid || is_message_receiver(message, user)
+}
+
+/// Only receivers acknowledge a message, the delivery states belong to the
+/// sender.
+pub fn can_set_message_state(message: &Message, user: &User, state: &MessageState) -> bool {
+    match state {
+        MessageState::Received | MessageState::Read => is_message_receiver(message, user),
+        MessageState::Pending | MessageState::Failed | MessageState::Sent => {
+            message.sender_id == user.uid
+        }
+    }
+}
+
+/// Returns the message only if `user` may read it.
+pub async fn try_get_message(
+    db: &dyn Store,
     user: &User,
-    msg: MessageBody,
-) -> Result<(), Box<dyn ::std::error::Error>> {
-    let message_data = Message {
+    id: &str,
+) -> StoreResult<Option<Message>> {
+    let message = match Uuid::parse_str(id) {
+        Ok(uuid) => db.get_message(&uuid).await?,
+        _ => None,
+    };
+
+    Ok(message
+        .filter(|m| can_read_message(m, user))
+        .map(|m| message_view(m, user)))
+}
+
+/// Receivers acknowledge their own receipt, which never moves backwards, the
+/// sender sets the delivery state of the whole message. Callers check
+/// `can_set_message_state` first. Connected clients of the sender and the
+/// receivers concerned are told.
+pub async fn try_set_message_state(
+    db: &dyn Store,
+    notifier: &Notifier,
+    user: &User,
+    message: &Message,
+    state: MessageState,
+) -> StoreResult<()> {
+    match state {
+        MessageState::Received | MessageState::Read => {
+            let mut receipt = match receipt_for(message, user) {
+                Some(r) => r,
+                _ => {
+                    return Err(Box::new(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        "not a receiver of this message",
+                    )))
+                }
+            };
+            if state_rank(&state) <= state_rank(&receipt.state) {
+                return Ok(());
+            }
+
+            let now = Utc::now();
+            receipt.received_at.get_or_insert(now);
+            if state == MessageState::Read {
+                receipt.read_at = Some(now);
+            }
+            receipt.state = state.clone();
+            db.set_message_receipt(&message.id, &receipt).await?;
+            notifier.events().publish(
+                vec![message.sender_id.to_string(), receipt.receiver.clone()],
+                Event::MessageState {
+                    message_id: message.id,
+                    receiver: Some(receipt.receiver),
+                    state,
+                },
+            );
+        }
+        _ => {
+            db.set_message_state(&message.id, state.clone()).await?;
+            publish_message_state(notifier, message, state);
+        }
+    }
+
+    Ok(())
+}
+
+/// Tells the sender and receivers of `message` about its new delivery state.
+pub fn publish_message_state(notifier: &Notifier, message: &Message, state: MessageState) {
+    let mut audience = message.receiver_ids.clone();
+    audience.push(message.sender_id.to_string());
+    notifier.events().publish(
+        audience,
+        Event::MessageState {
+            message_id: message.id,
+            receiver: None,
+            state,
+        },
+    );
+}
+
+fn new_message(user: &User, msg: MessageBody, category: NotificationCategory) -> Message {
+    Message {
         id: Uuid::new_v4(),
         sender_id: user.uid.clone(),
+        receipts: msg
+            .receiver_ids
+            .iter()
+            .map(|r| MessageReceipt::pending(r))
+            .collect(),
         receiver_ids: msg.receiver_ids,
-        subject: msg.subject.to_owned(),
-        content: msg.content.to_owned(),
+        subject: msg.subject,
+        content: msg.content,
         state: MessageState::Pending,
         created_at: Utc::now(),
+        thread_id: None,
+        reply_to: None,
+        category,
+        attachments: msg.attachments,
+    }
+}
+
+/// A stored message and how its push notifications went.
+#[derive(Debug, Clone, Serialize)]
+pub struct SentMessage {
+    pub message: Message,
+    pub notifications: NotificationSummary,
+}
+
+pub async fn try_send_messages(
+    db: &dyn Store,
+    notifier: &Notifier,
+    user: &User,
+    msg: MessageBody,
+) -> StoreResult<SentMessage> {
+    try_send_notice(db, notifier, user, msg, NotificationCategory::Messages).await
+}
+
+/// Sends `msg` notifying about `category`, e.g. an enrollment notice from the
+/// system user, so receivers can mute it.
+pub async fn try_send_notice(
+    db: &dyn Store,
+    notifier: &Notifier,
+    user: &User,
+    msg: MessageBody,
+    category: NotificationCategory,
+) -> StoreResult<SentMessage> {
+    deliver_message(db, notifier, new_message(user, msg, category)).await
+}
+
+/// Answers `original` in its thread, addressed to its sender and the other
+/// receivers. `subject` defaults to "Re: " and the original subject.
+pub async fn try_reply_message(
+    db: &dyn Store,
+    notifier: &Notifier,
+    user: &User,
+    original: &Message,
+    subject: Option<String>,
+    content: String,
+    attachments: Vec<Uuid>,
+) -> StoreResult<SentMessage> {
+    let mut receiver_ids: Vec<String> = Vec::new();
+    if let Some(sender) = db.get_user(&original.sender_id).await? {
+        receiver_ids.push(sender.email);
+    }
+    receiver_ids.extend(original.receiver_ids.iter().cloned());
+    receiver_ids.retain(|r| !is_user_address(r, user));
+    let mut seen = std::collections::HashSet::new();
+    receiver_ids.retain(|r| seen.insert(r.clone()));
+
+    let subject = subject.or_else(|| {
+        original
+            .subject
+            .as_ref()
+            .map(|s| match s.starts_with("Re: ") {
+                true => s.clone(),
+                false => format!("Re: {}", s),
+            })
+    });
+
+    let message = Message {
+        thread_id: Some(original.thread_id.unwrap_or(original.id)),
+        reply_to: Some(original.id),
+        ..new_message(
+            user,
+            MessageBody {
+                receiver_ids,
+                subject,
+                content,
+                attachments,
+            },
+            NotificationCategory::Messages,
+        )
+    };
+    deliver_message(db, notifier, message).await
+}
+
+/// The conversation containing message `id`, oldest first, limited to what
+/// `user` may read.
+pub async fn try_list_thread(
+    db: &dyn Store,
+    user: &User,
+    id: &str,
+) -> StoreResult<Option<Vec<Message>>> {
+    let message = match try_get_message(db, user, id).await? {
+        Some(m) => m,
+        None => return Ok(None),
     };
 
+    let thread = db
+        .list_thread(&message.thread_id.unwrap_or(message.id))
+        .await?;
+    Ok(Some(
+        thread
+            .into_iter()
+            .filter(|m| can_read_message(m, user))
+            .map(|m| message_view(m, user))
+            .collect(),
+    ))
+}
+
+/// Stores `message` with its outbox entry and makes the first delivery
+/// attempt right away. Failed devices are retried by the outbox worker.
+/// Callers check the attachments with `check_message_attachments` first.
+async fn deliver_message(
+    db: &dyn Store,
+    notifier: &Notifier,
+    mut message_data: Message,
+) -> StoreResult<SentMessage> {
     debug!("message {:?}", message_data);
 
-    let req: FirestoreResult<Message> = db
-        .fluent()
-        .insert()
-        .into(MESSAGES_COLLECTION)
-        .document_id(message_data.id.to_string())
-        .object(&message_data)
-        .execute()
-        .await;
-
-    match req {
-        Ok(_) => {
-            let _send = send_notification_to_emails(
-                &db,
-                &http,
-                message_data.receiver_ids.as_slice(),
-                message_data.subject.as_deref(),
-                &message_data.content,
-            )
-            .await?;
-            Ok(())
-        }
-        Err(e) => Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Unsupported,
-            format!("{:?}", e),
-        ))),
+    let mut entry = new_outbox_entry(&message_data, Utc::now());
+    db.insert_message_with_outbox(&message_data, &entry).await?;
+    let attached = AttachmentRef::Message {
+        message_id: message_data.id,
+    };
+    link_attachments(db, &message_data.attachments, attached).await?;
+    notifier.events().publish(
+        message_data.receiver_ids.clone(),
+        Event::Message {
+            message: message_data.clone(),
+        },
+    );
+    let notifications = deliver_outbox_entry(db, notifier, &mut message_data, &mut entry).await?;
+
+    Ok(SentMessage {
+        message: message_data,
+        notifications,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::message::{
+        can_set_message_state, try_count_unread, try_get_message, try_list_messages,
+        try_list_thread, try_reply_message, try_set_message_state, MessageQuery, MessageType,
+    };
+    use crate::common::store::{Fixtures, MemoryStore, MessageStore};
+    use crate::common::{Message, MessageReceipt, MessageState, NotificationCategory, Notifier};
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_message_ownership_async() {
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+        let teacher = db.fixture_user("teacher").await;
+        let parent = db.fixture_user("parent").await;
+        let student = db.fixture_user("student").await;
+        let id = "3406beef-4a9d-436f-b8ae-e89c22cead5c";
+
+        assert!(try_get_message(&db, &student, id).await.unwrap().is_none());
+        assert!(try_get_message(&db, &teacher, id).await.unwrap().is_some());
+        let message = try_get_message(&db, &parent, id).await.unwrap().unwrap();
+
+        assert!(can_set_message_state(
+            &message,
+            &parent,
+            &MessageState::Read
+        ));
+        assert!(!can_set_message_state(
+            &message,
+            &teacher,
+            &MessageState::Read
+        ));
+        assert!(!can_set_message_state(
+            &message,
+            &student,
+            &MessageState::Received
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_per_receiver_state_async() {
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+        let teacher = db.fixture_user("teacher").await;
+        let parent = db.fixture_user("parent").await;
+        let student = db.fixture_user("student").await;
+        let notifier = Notifier::default();
+
+        let receivers = vec![parent.email.clone(), student.email.clone()];
+        let message = Message {
+            id: Uuid::new_v4(),
+            sender_id: teacher.uid,
+            receipts: receivers
+                .iter()
+                .map(|r| MessageReceipt::pending(r))
+                .collect(),
+            receiver_ids: receivers,
+            subject: None,
+            content: "homework".to_string(),
+            state: MessageState::Sent,
+            created_at: Utc::now(),
+            thread_id: None,
+            reply_to: None,
+            category: NotificationCategory::Messages,
+            attachments: vec![],
+        };
+        db.insert_message(&message).await.unwrap();
+        assert_eq!(try_count_unread(&db, &student).await.unwrap(), 1);
+
+        let id = message.id.to_string();
+        let seen = try_get_message(&db, &parent, &id).await.unwrap().unwrap();
+        try_set_message_state(&db, &notifier, &parent, &seen, MessageState::Read)
+            .await
+            .unwrap();
+        // marking it received afterwards doesn't undo the read
+        let seen = try_get_message(&db, &parent, &id).await.unwrap().unwrap();
+        try_set_message_state(&db, &notifier, &parent, &seen, MessageState::Received)
+            .await
+            .unwrap();
+
+        let parent_view = try_get_message(&db, &parent, &id).await.unwrap().unwrap();
+        assert_eq!(parent_view.state, MessageState::Read);
+        assert_eq!(parent_view.receipts.len(), 1);
+        assert!(parent_view.receipts[0].read_at.is_some());
+
+        let inbox = try_list_messages(
+            &db,
+            &student,
+            MessageType::Received,
+            &MessageQuery::default(),
+        )
+        .await
+        .unwrap()
+        .messages;
+        let student_view = inbox.iter().find(|m| m.id == message.id).unwrap();
+        assert_eq!(student_view.state, MessageState::Pending);
+        assert_eq!(try_count_unread(&db, &student).await.unwrap(), 1);
+
+        let sender_view = try_get_message(&db, &teacher, &id).await.unwrap().unwrap();
+        assert_eq!(sender_view.receipts.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_reply_thread_async() {
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+        let notifier = Notifier::default();
+        let teacher = db.fixture_user("teacher").await;
+        let parent = db.fixture_user("parent").await;
+        let student = db.fixture_user("student").await;
+        let id = "3406beef-4a9d-436f-b8ae-e89c22cead5c";
+
+        let original = try_get_message(&db, &parent, id).await.unwrap().unwrap();
+        let reply = try_reply_message(
+            &db,
+            &notifier,
+            &parent,
+            &original,
+            None,
+            "ok".to_string(),
+            vec![],
+        )
+        .await
+        .unwrap()
+        .message;
+        assert_eq!(reply.receiver_ids, vec![teacher.email.clone()]);
+        assert_eq!(reply.thread_id, Some(original.id));
+
+        let answer = try_reply_message(
+            &db,
+            &notifier,
+            &teacher,
+            &reply,
+            None,
+            "thanks".to_string(),
+            vec![],
+        )
+        .await
+        .unwrap()
+        .message;
+        assert_eq!(answer.receiver_ids, vec![parent.email.clone()]);
+        assert_eq!(answer.thread_id, Some(original.id));
+        assert_eq!(answer.reply_to, Some(reply.id));
+
+        let thread = try_list_thread(&db, &teacher, &answer.id.to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        let ids: Vec<Uuid> = thread.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![original.id, reply.id, answer.id]);
+        assert!(try_list_thread(&db, &student, id).await.unwrap().is_none());
     }
 }
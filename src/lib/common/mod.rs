@@ -1,15 +1,30 @@
+pub mod admin;
+pub mod attachment;
 mod constants;
 pub mod course;
+pub mod digest;
+mod email;
 pub mod enrollment;
+mod events;
 mod fcm;
+pub mod lesson;
 
 pub mod macros;
 pub mod message;
 mod model;
+mod notify;
+pub mod outbox;
+mod storage;
+pub mod store;
+pub mod token;
 pub mod user;
 mod util;
 
 pub use constants::*;
+pub use email::*;
+pub use events::*;
 pub use fcm::*;
 pub use model::*;
+pub use notify::*;
+pub use storage::*;
 pub use util::*;
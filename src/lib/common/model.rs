@@ -1,13 +1,8 @@
-use crate::common::{
-    COURSES_COLLECTION, ENROLLMENTS_COLLECTION, STUDENTS_PARENTS_COLLECTION, USERS_COLLECTION,
-};
-use chrono::{DateTime, Utc};
-use firestore::struct_path::path;
-use firestore::{FirestoreDb, FirestoreResult};
-use futures::stream::BoxStream;
-use futures::{StreamExt, TryStreamExt};
+use crate::common::store::{Store, StoreResult};
+use chrono::{DateTime, Duration, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::hash::{Hash, Hasher};
 use uuid::Uuid;
 
@@ -28,6 +23,124 @@ pub struct User {
     pub role: UserRole,
     pub name: String,
     pub devices: Vec<String>,
+    #[serde(default)]
+    pub preferences: NotificationPreferences,
+    // set while an admin has the account deactivated
+    #[serde(default)]
+    pub deactivated_at: Option<DateTime<Utc>>,
+}
+
+impl User {
+    /// Deactivated users can't log in and their tokens stop working.
+    pub fn is_active(&self) -> bool {
+        self.deactivated_at.is_none()
+    }
+}
+
+/// What a notification is about, so users can mute some kinds.
+#[derive(
+    Debug, Copy, Clone, Default, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationCategory {
+    #[default]
+    Messages,
+    Enrollment,
+    Announcements,
+    // the periodic summary, see `NotificationPreferences::digest`
+    Digest,
+}
+
+/// How often a user gets a summary of what happened instead of waiting for
+/// each notification.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestFrequency {
+    Daily,
+    Weekly,
+}
+
+impl DigestFrequency {
+    pub fn period(&self) -> Duration {
+        match self {
+            DigestFrequency::Daily => Duration::days(1),
+            DigestFrequency::Weekly => Duration::weeks(1),
+        }
+    }
+}
+
+/// A daily window, in the user's time zone, in which notifications wait.
+/// `end` before `start` spans midnight, e.g. 22:00 to 07:00.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct QuietHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    // IANA name, e.g. "Europe/Berlin"
+    pub time_zone: String,
+}
+
+impl QuietHours {
+    pub fn tz(&self) -> Option<Tz> {
+        self.time_zone.parse().ok()
+    }
+
+    /// When the window containing `now` ends, `None` outside of quiet hours.
+    pub fn until(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let tz = self.tz()?;
+        let local = now.with_timezone(&tz);
+        let time = local.time();
+        let quiet = match self.start <= self.end {
+            true => self.start <= time && time < self.end,
+            false => time >= self.start || time < self.end,
+        };
+        if !quiet {
+            return None;
+        }
+
+        let mut day = local.date_naive();
+        if time >= self.end {
+            day = day.succ_opt()?;
+        }
+        // `end` may not exist on a daylight saving day, an hour later does
+        let end = day.and_time(self.end);
+        tz.from_local_datetime(&end)
+            .earliest()
+            .or_else(|| {
+                tz.from_local_datetime(&(end + Duration::hours(1)))
+                    .earliest()
+            })
+            .map(|t| t.with_timezone(&Utc))
+    }
+}
+
+/// How a user wants to be notified. Channels and categories not listed are
+/// enabled.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub struct NotificationPreferences {
+    // by channel name, "push" or "email"
+    #[serde(default)]
+    pub channels: BTreeMap<String, bool>,
+    #[serde(default)]
+    pub categories: BTreeMap<NotificationCategory, bool>,
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+    // `None` for no digest
+    #[serde(default)]
+    pub digest: Option<DigestFrequency>,
+}
+
+impl NotificationPreferences {
+    pub fn channel_enabled(&self, channel: &str) -> bool {
+        self.channels.get(channel).copied().unwrap_or(true)
+    }
+
+    pub fn category_enabled(&self, category: NotificationCategory) -> bool {
+        self.categories.get(&category).copied().unwrap_or(true)
+    }
+
+    pub fn quiet_until(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.quiet_hours.as_ref().and_then(|q| q.until(now))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -43,6 +156,11 @@ pub struct UserWithPassword {
     pub role: UserRole,
     pub name: String,
     pub devices: Vec<String>,
+    #[serde(default)]
+    pub preferences: NotificationPreferences,
+    // set while an admin has the account deactivated
+    #[serde(default)]
+    pub deactivated_at: Option<DateTime<Utc>>,
 }
 
 impl From<UserWithPassword> for User {
@@ -53,6 +171,8 @@ impl From<UserWithPassword> for User {
             name: u.name,
             devices: u.devices,
             email: u.email,
+            preferences: u.preferences,
+            deactivated_at: u.deactivated_at,
         }
     }
 }
@@ -69,7 +189,22 @@ pub struct UserWithPasswordStudents {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Kid {
     pub user: User,
+    // the courses the kid is enrolled in now
     pub courses: Vec<Course>,
+    // full courses the kid waits for a seat in
+    pub waitlisted_courses: Vec<Course>,
+    // courses the kid asked to enroll in, waiting for approval
+    pub pending_courses: Vec<Course>,
+    pub past_courses: Vec<PastCourse>,
+}
+
+/// A course a kid was enrolled in, and how that ended.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PastCourse {
+    pub course: Course,
+    pub status: EnrollmentStatus,
+    pub enrolled_at: DateTime<Utc>,
+    pub ended_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
@@ -78,6 +213,104 @@ pub struct Course {
     pub title: String,
     pub content: String,
     pub teacher_id: Uuid,
+    // courses are archived instead of deleted, and left out of listings
+    #[serde(default)]
+    pub archived_at: Option<DateTime<Utc>>,
+    // seats for students, students enrolling beyond it are waitlisted;
+    // unlimited when missing
+    #[serde(default)]
+    pub capacity: Option<usize>,
+    // enrollments wait for the teacher's approval
+    #[serde(default)]
+    pub teacher_approval: bool,
+    // enrollments wait for the consent of one of the student's parents
+    #[serde(default)]
+    pub parent_consent: bool,
+}
+
+impl Course {
+    /// Whether the approvals the course asks for are all in.
+    pub fn approves(&self, enrollment: &Enrollment) -> bool {
+        (!self.teacher_approval || enrollment.approved_by.is_some())
+            && (!self.parent_consent || enrollment.consented_by.is_some())
+    }
+}
+
+/// A chapter of a course, holding lessons.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct CourseModule {
+    pub id: Uuid,
+    pub course_id: Uuid,
+    pub title: String,
+    // index among the modules of the course
+    pub position: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct LessonLink {
+    pub title: String,
+    pub url: String,
+}
+
+/// A worksheet or other file handed out with a lesson, either an uploaded
+/// attachment or a link to it.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Material {
+    pub title: String,
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub attachment_id: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Lesson {
+    pub id: Uuid,
+    pub course_id: Uuid,
+    pub module_id: Uuid,
+    pub title: String,
+    // Markdown
+    pub body: String,
+    pub links: Vec<LessonLink>,
+    pub materials: Vec<Material>,
+    // index among the lessons of the module
+    pub position: u32,
+    // only teachers see unpublished lessons
+    pub published: bool,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A student marked a lesson as done.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct LessonCompletion {
+    pub lesson_id: Uuid,
+    pub student_id: Uuid,
+    pub course_id: Uuid,
+    pub completed_at: DateTime<Utc>,
+}
+
+/// An uploaded file. Its bytes are kept in the `AttachmentStorage` under
+/// its id.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Attachment {
+    pub id: Uuid,
+    // the uploader
+    pub owner_id: Uuid,
+    pub file_name: String,
+    pub content_type: String,
+    pub size: u64,
+    pub created_at: DateTime<Utc>,
+    // where it was attached, checked against the message or lesson before
+    // letting anyone but the owner download it
+    #[serde(default)]
+    pub refs: Vec<AttachmentRef>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AttachmentRef {
+    Message { message_id: Uuid },
+    Lesson { course_id: Uuid, lesson_id: Uuid },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
@@ -97,18 +330,206 @@ impl Hash for Course {
         self.id.hash(state);
     }
 }
+/// Enrollments are kept once they end, so parents still see past courses.
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EnrollmentStatus {
+    #[default]
+    Active,
+    // waiting for a seat, in the order of `enrolled_at`
+    Waitlisted,
+    // waiting for the approvals the course asks for
+    Pending,
+    // turned down by the teacher or a parent
+    Rejected,
+    // left by the student
+    Dropped,
+    Completed,
+    // taken out by the teacher
+    Removed,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Enrollment {
     pub id: Uuid,
     pub course_id: Uuid,
     // user uuid -> role -> student
     pub student_id: Uuid,
+    #[serde(default)]
+    pub status: EnrollmentStatus,
+    // when the student got a seat, or asked for one while waitlisted; the
+    // epoch for enrollments older than their timestamps
+    #[serde(default)]
+    pub enrolled_at: DateTime<Utc>,
+    #[serde(default)]
+    pub ended_at: Option<DateTime<Utc>>,
+    // the teacher, or admin, who approved the enrollment
+    #[serde(default)]
+    pub approved_by: Option<Uuid>,
+    // the parent who consented to it
+    #[serde(default)]
+    pub consented_by: Option<Uuid>,
 }
+
+impl Enrollment {
+    pub fn new(student_id: Uuid, course_id: Uuid) -> Self {
+        Enrollment {
+            id: Uuid::new_v4(),
+            course_id,
+            student_id,
+            status: EnrollmentStatus::Active,
+            enrolled_at: Utc::now(),
+            ended_at: None,
+            approved_by: None,
+            consented_by: None,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.status == EnrollmentStatus::Active
+    }
+
+    pub fn is_waitlisted(&self) -> bool {
+        self.status == EnrollmentStatus::Waitlisted
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.status == EnrollmentStatus::Pending
+    }
+}
+
+/// Whose agreement a pending enrollment got.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Approver {
+    Teacher,
+    Parent,
+}
+
+/// A pending enrollment, for those who have to approve it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnrollmentRequest {
+    pub enrollment: Enrollment,
+    pub course: Course,
+    pub student: User,
+}
+
+/// What became of one row of a roster added by the teacher.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RosterOutcome {
+    Enrolled,
+    Waitlisted,
+    // waiting for a parent's consent
+    Pending,
+    // active, waitlisted or waiting for approval already
+    AlreadyEnrolled,
+    AlreadyCompleted,
+    UnknownEmail,
+    NotAStudent,
+    // the course asks for a parent's consent, but no parent is linked
+    NoParent,
+    InvalidEmail,
+    // the email is on an earlier row too
+    Duplicate,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RosterRow {
+    // 1-based, header excluded
+    pub row: usize,
+    pub email: String,
+    pub outcome: RosterOutcome,
+    pub enrollment: Option<Enrollment>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RosterReport {
+    pub course_id: Uuid,
+    pub enrolled: usize,
+    pub waitlisted: usize,
+    pub pending: usize,
+    pub skipped: usize,
+    pub rows: Vec<RosterRow>,
+}
+
+/// An admin's change to an account.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    CreateUser,
+    ChangeRole,
+    ResetPassword,
+    Deactivate,
+    Reactivate,
+    LinkStudent,
+    UnlinkStudent,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: Uuid,
+    // the admin who made the change
+    pub actor_id: Uuid,
+    pub action: AuditAction,
+    // the changed account, the parent for (un)links
+    pub target_id: Uuid,
+    // e.g. "student -> teacher"
+    pub details: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// What became of one row of a user import.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportOutcome {
+    Created,
+    // passed every check of a dry run, nothing was written
+    Valid,
+    // an account with the email exists already
+    Exists,
+    // the email is on an earlier row too
+    Duplicate,
+    // see `error`
+    Invalid,
+    // valid, but writing it failed, see `error`
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportRow {
+    // 1-based, header excluded
+    pub row: usize,
+    pub email: String,
+    pub outcome: ImportOutcome,
+    pub error: Option<String>,
+    pub user: Option<User>,
+    // generated for rows without a password, reported only here
+    pub temporary_password: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    // the rows created, or that would be without the dry run
+    pub created: usize,
+    pub skipped: usize,
+    pub rows: Vec<ImportRow>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EnrollmentCounter {
     pub students: usize,
 }
+
+/// How many seats of a course are taken, kept apart from the course so
+/// seats are counted atomically.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CourseSeats {
+    pub course_id: Uuid,
+    pub taken: usize,
+}
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum MessageState {
     Pending,
@@ -129,6 +550,127 @@ pub struct Message {
     pub content: String,
     pub state: MessageState,
     pub created_at: DateTime<Utc>,
+    // first message of the conversation, `None` on that message itself
+    #[serde(default)]
+    pub thread_id: Option<Uuid>,
+    #[serde(default)]
+    pub reply_to: Option<Uuid>,
+    #[serde(default)]
+    pub category: NotificationCategory,
+    // one per entry of `receiver_ids`, missing on messages sent before receipts
+    #[serde(default)]
+    pub receipts: Vec<MessageReceipt>,
+    #[serde(default)]
+    pub attachments: Vec<Uuid>,
+}
+
+impl Message {
+    /// Receipt of `receiver`, derived from `state` for messages sent before
+    /// receipts were tracked. `None` if `receiver` isn't addressed.
+    pub fn receipt(&self, receiver: &str) -> Option<MessageReceipt> {
+        if !self.receiver_ids.iter().any(|r| r == receiver) {
+            return None;
+        }
+
+        let receipt = self.receipts.iter().find(|r| r.receiver == receiver);
+        Some(match receipt {
+            Some(r) => r.clone(),
+            None => MessageReceipt {
+                state: self.state.clone(),
+                ..MessageReceipt::pending(receiver)
+            },
+        })
+    }
+
+    pub fn set_receipt(&mut self, receipt: MessageReceipt) {
+        match self
+            .receipts
+            .iter_mut()
+            .find(|r| r.receiver == receipt.receiver)
+        {
+            Some(r) => *r = receipt,
+            None => self.receipts.push(receipt),
+        }
+    }
+}
+
+/// Delivery and read state of a message for one of its receivers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageReceipt {
+    pub receiver: String,
+    pub state: MessageState,
+    pub sent_at: Option<DateTime<Utc>>,
+    pub received_at: Option<DateTime<Utc>>,
+    pub read_at: Option<DateTime<Utc>>,
+}
+
+impl MessageReceipt {
+    pub fn pending(receiver: &str) -> Self {
+        MessageReceipt {
+            receiver: receiver.to_string(),
+            state: MessageState::Pending,
+            sent_at: None,
+            received_at: None,
+            read_at: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutboxState {
+    Pending,
+    Delivered,
+    // gave up after `OUTBOX_MAX_ATTEMPTS`
+    Dead,
+}
+
+/// Push notifications owed for a message. Written together with the message
+/// and worked off by the outbox worker until delivered or dead-lettered.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub message_id: Uuid,
+    pub state: OutboxState,
+    pub attempts: u32,
+    // also pushed forward while an attempt is running, so a crashed attempt
+    // is picked up again once it lapses
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    // `channel:target`s still to notify, `None` for every receiver
+    pub retry_tokens: Option<Vec<String>>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// When the next digest of a user is due and what the previous one covered.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DigestSchedule {
+    pub user_id: Uuid,
+    pub frequency: DigestFrequency,
+    // also pushed forward while a digest is being sent, like
+    // `OutboxEntry::next_attempt_at`
+    pub next_digest_at: DateTime<Utc>,
+    // messages from then on are new to the next digest
+    pub last_digest_at: DateTime<Utc>,
+    // `student_id:course_id` of the kids' enrollments already reported
+    pub enrollments: Vec<String>,
+}
+
+/// A refresh token session. Only the SHA-256 of the token handed to the client
+/// is stored, as `id`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshToken {
+    pub id: String,
+    pub user_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked: bool,
+}
+
+/// An access token revoked before its expiry, kept until `expires_at`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevokedToken {
+    pub jti: Uuid,
+    pub expires_at: DateTime<Utc>,
 }
 /*
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -148,10 +690,7 @@ impl Teacher {
         &self.user
     }
 
-    pub async fn from_user<U: Into<User>>(
-        into_user: U,
-        db: &FirestoreDb,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
+    pub async fn from_user<U: Into<User>>(into_user: U, db: &dyn Store) -> StoreResult<Self> {
         let user = into_user.into();
         if user.role != UserRole::Teacher {
             return Err(Box::new(std::io::Error::new(
@@ -160,58 +699,19 @@ impl Teacher {
             )));
         }
 
-        let uuid_str = user.uid.to_string();
-        let box_courses = db
-            .fluent()
-            .select()
-            .from(COURSES_COLLECTION)
-            .filter(|q| q.for_any([q.field(path!(Course::teacher_id)).eq(&uuid_str)]))
-            .obj()
-            .stream_query_with_errors()
-            .await?;
-
-        let to_courses: Vec<Course> = box_courses.try_collect().await?;
+        let to_courses = db.list_teacher_courses(&user.uid).await?;
         let mut courses_map = HashMap::new();
 
         for course in to_courses {
-            let course_id = course.id.to_string();
-            let box_enroll: BoxStream<FirestoreResult<Enrollment>> = db
-                .fluent()
-                .select()
-                .from(ENROLLMENTS_COLLECTION)
-                .filter(|q| {
-                    q.for_any([
-                        //
-                        q.field(path!(Enrollment::course_id)).eq(&course_id),
-                    ])
-                })
-                .obj()
-                .stream_query_with_errors()
-                .await?;
-
-            let course_enrollments: Vec<Enrollment> = box_enroll.try_collect().await?;
-            let student_ids = course_enrollments
+            let student_ids = db
+                .list_course_enrollments(&course.id)
+                .await?
                 .into_iter()
-                .map(|ce| ce.student_id.to_string())
+                .filter(|ce| ce.is_active())
+                .map(|ce| ce.student_id)
                 .collect::<Vec<_>>();
 
-            let mut box_users: BoxStream<(String, Option<User>)> = db
-                .fluent()
-                .select()
-                .by_id_in(USERS_COLLECTION)
-                .obj()
-                .batch(&student_ids)
-                .await?;
-
-            let mut course_users = Vec::new();
-            while let Some((_i, u)) = box_users.next().await {
-                if let Some(su) = u {
-                    course_users.push(su);
-                }
-            }
-
-            //let course_users: Vec<User> = box_users.collect().await?;
-            //println!("course_users {:?}", course_users);
+            let course_users = db.get_users(&student_ids).await?;
             courses_map.insert(course, course_users);
         }
 
@@ -232,10 +732,7 @@ impl Parent {
         &self.user
     }
 
-    pub async fn from_user<U: Into<User>>(
-        into_user: U,
-        db: &FirestoreDb,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
+    pub async fn from_user<U: Into<User>>(into_user: U, db: &dyn Store) -> StoreResult<Self> {
         let user = into_user.into();
         if user.role != UserRole::Parent {
             return Err(Box::new(std::io::Error::new(
@@ -244,41 +741,14 @@ impl Parent {
             )));
         }
 
-        let box_children_uuid: BoxStream<FirestoreResult<StudentsParents>> = db
-            .fluent()
-            .select()
-            .from(STUDENTS_PARENTS_COLLECTION)
-            .filter(|q| {
-                q.for_any([
-                    //
-                    q.field(path!(StudentsParents::parent_id)).eq(&user.uid),
-                ])
-            })
-            .obj()
-            .stream_query_with_errors()
-            .await?;
-
-        let students_parents: Vec<StudentsParents> = box_children_uuid.try_collect().await?;
-        let children_uuid = students_parents
+        let children_uuid = db
+            .list_students_of(&user.uid)
+            .await?
             .into_iter()
             .map(|sp| sp.student_id)
             .collect::<Vec<_>>();
 
-        let box_users = db
-            .fluent()
-            .select()
-            .from(USERS_COLLECTION)
-            .filter(|q| {
-                q.for_any([
-                    // query by uid
-                    q.field(path!(User::uid)).is_in(children_uuid.as_slice()),
-                ])
-            })
-            .obj()
-            .stream_query_with_errors()
-            .await?;
-
-        let children = box_users.try_collect().await?;
+        let children = db.get_users(&children_uuid).await?;
 
         Ok(Parent { user, children })
     }
@@ -290,22 +760,41 @@ pub struct CourseResponse {
     pub teacher: User,
     pub students: Vec<User>,
     pub enrolled: bool,
+    // the calling student's place on the waitlist, from 1
+    pub waitlist_position: Option<usize>,
+    // the waitlisted students in order, for the teacher
+    pub waitlist: Vec<User>,
+    pub outline: Vec<ModuleOutline>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ModuleOutline {
+    #[serde(flatten)]
+    pub module: CourseModule,
+    pub lessons: Vec<LessonOutline>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LessonOutline {
+    #[serde(flatten)]
+    pub lesson: Lesson,
+    // by the caller
+    pub completed: bool,
+    // the students the caller may see who completed it: all of them for
+    // teachers, their kids for parents
+    pub completed_by: Vec<Uuid>,
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::common::store::{Fixtures, MemoryStore};
     use crate::common::user::try_find_user;
-    use crate::common::{config_env_var, Parent, Teacher};
-    use dotenv::dotenv;
-    use firestore::FirestoreDb;
+    use crate::common::{Parent, QuietHours, Teacher};
+    use chrono::{DateTime, NaiveTime, Utc};
 
     #[tokio::test]
     async fn test_parents_async() {
-        dotenv().unwrap();
-        let project_id = config_env_var("PROJECT_ID").expect("failed to load project id");
-        let db = FirestoreDb::new(&project_id)
-            .await
-            .expect("failed to load firestore db");
+        let db = MemoryStore::from_fixtures(Fixtures::test());
 
         let user = try_find_user(&db, "mom@mom.com").await.unwrap().unwrap();
         let user_id = user.uid.clone();
@@ -317,11 +806,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_teachers_async() {
-        dotenv().unwrap();
-        let project_id = config_env_var("PROJECT_ID").expect("failed to load project id");
-        let db = FirestoreDb::new(&project_id)
-            .await
-            .expect("failed to load firestore db");
+        let db = MemoryStore::from_fixtures(Fixtures::test());
 
         let user = try_find_user(&db, "t1@t1.com").await.unwrap().unwrap();
         let user_id = user.uid.clone();
@@ -330,4 +815,47 @@ mod tests {
         println!("teacher {:#?}", teacher);
         assert_eq!(user_id, teacher_id);
     }
+
+    #[test]
+    fn test_quiet_hours() {
+        let at = |raw: &str| raw.parse::<DateTime<Utc>>().unwrap();
+        let night = QuietHours {
+            start: NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(7, 0, 0).unwrap(),
+            time_zone: "Europe/Berlin".to_string(),
+        };
+
+        // 23:30 and 05:00 in Berlin, both end at 07:00 Berlin
+        assert_eq!(
+            night.until(at("2024-01-15T22:30:00Z")),
+            Some(at("2024-01-16T06:00:00Z"))
+        );
+        assert_eq!(
+            night.until(at("2024-01-16T04:00:00Z")),
+            Some(at("2024-01-16T06:00:00Z"))
+        );
+        assert_eq!(night.until(at("2024-01-16T12:00:00Z")), None);
+        // summer time
+        assert_eq!(
+            night.until(at("2024-07-01T21:00:00Z")),
+            Some(at("2024-07-02T05:00:00Z"))
+        );
+
+        let lunch = QuietHours {
+            start: NaiveTime::from_hms_opt(12, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(13, 0, 0).unwrap(),
+            time_zone: "UTC".to_string(),
+        };
+        assert_eq!(
+            lunch.until(at("2024-01-16T12:15:00Z")),
+            Some(at("2024-01-16T13:00:00Z"))
+        );
+        assert_eq!(lunch.until(at("2024-01-16T13:00:00Z")), None);
+
+        let unknown = QuietHours {
+            time_zone: "Mars/Olympus".to_string(),
+            ..lunch
+        };
+        assert_eq!(unknown.until(at("2024-01-16T12:15:00Z")), None);
+    }
 }
@@ -0,0 +1,226 @@
+use crate::common::store::{Store, StoreResult};
+use crate::common::user::try_get_users_from_emails;
+use crate::common::{EventBus, Message, NotificationCategory, User};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::debug;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// What a recipient is told, rendered by each channel in its own format.
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub category: NotificationCategory,
+    pub title: Option<String>,
+    pub body: String,
+}
+
+impl From<&Message> for Notification {
+    fn from(message: &Message) -> Self {
+        Notification {
+            category: message.category,
+            title: message.subject.clone(),
+            body: message.content.clone(),
+        }
+    }
+}
+
+/// How a send to one target went.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendOutcome {
+    Sent,
+    /// The target will never accept it, e.g. a device FCM no longer knows.
+    InvalidTarget,
+    Failed(String),
+}
+
+/// A way to reach users, e.g. push to their devices or email.
+#[async_trait]
+pub trait NotificationChannel: Send + Sync {
+    /// Prefix of this channel's targets in `NotificationSummary::retry`.
+    fn name(&self) -> &'static str;
+
+    /// Where `user` can be reached on this channel, empty if they can't.
+    fn targets(&self, user: &User) -> Vec<String>;
+
+    /// Sends to every target and reports how each one went, so one bad target
+    /// doesn't fail the others.
+    async fn send(
+        &self,
+        targets: &[String],
+        notification: &Notification,
+    ) -> StoreResult<Vec<(String, SendOutcome)>>;
+
+    /// Forgets the targets of `user` reported invalid, returns how many were
+    /// removed.
+    async fn prune(
+        &self,
+        _db: &dyn Store,
+        _user: &User,
+        _invalid: &[String],
+    ) -> StoreResult<usize> {
+        Ok(0)
+    }
+}
+
+/// Result of notifying a set of users, counted per target.
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct NotificationSummary {
+    pub sent: usize,
+    pub failed: usize,
+    // invalid targets removed from their user, e.g. unregistered devices
+    pub removed: usize,
+    // held back by the receivers' quiet hours
+    pub deferred: usize,
+    // `channel:target` that failed for a reason worth another try, or was
+    // deferred
+    #[serde(skip)]
+    pub retry: Vec<String>,
+    // when the first deferred receiver's quiet hours end
+    #[serde(skip)]
+    pub deferred_until: Option<DateTime<Utc>>,
+}
+
+impl NotificationSummary {
+    /// Whether something failed, as opposed to only waiting for quiet hours.
+    pub fn has_failures(&self) -> bool {
+        self.retry.len() > self.deferred
+    }
+}
+
+/// The configured channels, in order of preference, and the event bus of
+/// the clients connected right now.
+#[derive(Clone, Default)]
+pub struct Notifier {
+    channels: Vec<Arc<dyn NotificationChannel>>,
+    events: EventBus,
+}
+
+impl Notifier {
+    pub fn new(channels: Vec<Arc<dyn NotificationChannel>>) -> Self {
+        Notifier {
+            channels,
+            events: EventBus::default(),
+        }
+    }
+
+    pub fn channels(&self) -> &[Arc<dyn NotificationChannel>] {
+        &self.channels
+    }
+
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// The first channel `user` enabled that can reach them, so parents with
+    /// the app get a push and the others an email.
+    pub fn channel_for(&self, user: &User) -> Option<(&dyn NotificationChannel, Vec<String>)> {
+        self.channels
+            .iter()
+            .filter(|c| user.preferences.channel_enabled(c.name()))
+            .find_map(|c| {
+                let targets = c.targets(user);
+                match targets.is_empty() {
+                    true => None,
+                    false => Some((c.as_ref(), targets)),
+                }
+            })
+    }
+}
+
+type Route<'a> = (&'a dyn NotificationChannel, Vec<(&'a User, Vec<String>)>);
+
+/// Notifies the users behind `emails`, each on the channel picked by
+/// [`Notifier::channel_for`]. Users who muted the category are skipped and
+/// those in their quiet hours deferred.
+pub async fn send_notification_to_emails<I: AsRef<str>>(
+    db: &dyn Store,
+    notifier: &Notifier,
+    emails: &[I],
+    notification: &Notification,
+) -> StoreResult<NotificationSummary> {
+    send_notification(db, notifier, emails, None, notification).await
+}
+
+/// Like [`send_notification_to_emails`], but only to the `channel:target`s in
+/// `only` when given, e.g. the `retry` of an earlier attempt. Targets reported
+/// invalid are pruned from their user.
+pub async fn send_notification<I: AsRef<str>>(
+    db: &dyn Store,
+    notifier: &Notifier,
+    emails: &[I],
+    only: Option<&[String]>,
+    notification: &Notification,
+) -> StoreResult<NotificationSummary> {
+    let receivers = try_get_users_from_emails(db, emails).await?;
+
+    debug!("receivers ok {:?}", receivers);
+
+    // receivers and their targets, grouped by channel
+    let mut routes: HashMap<&str, Route> = HashMap::new();
+    let mut summary = NotificationSummary::default();
+    let now = Utc::now();
+    for user in receivers.iter() {
+        if !user.preferences.category_enabled(notification.category) {
+            continue;
+        }
+        let (channel, targets) = match notifier.channel_for(user) {
+            Some(route) => route,
+            None => continue,
+        };
+        let targets: Vec<String> = targets
+            .into_iter()
+            .filter(|t| only.is_none_or(|o| o.contains(&format!("{}:{}", channel.name(), t))))
+            .collect();
+        if targets.is_empty() {
+            continue;
+        }
+
+        if let Some(until) = user.preferences.quiet_until(now) {
+            summary.deferred += targets.len();
+            summary
+                .retry
+                .extend(targets.iter().map(|t| format!("{}:{}", channel.name(), t)));
+            summary.deferred_until = Some(summary.deferred_until.map_or(until, |d| d.min(until)));
+            continue;
+        }
+        routes
+            .entry(channel.name())
+            .or_insert_with(|| (channel, Vec::new()))
+            .1
+            .push((user, targets));
+    }
+
+    for (name, (channel, users)) in routes {
+        let targets: Vec<String> = users.iter().flat_map(|(_, t)| t.clone()).collect();
+        let outcomes = channel.send(&targets, notification).await?;
+
+        let mut invalid = HashSet::new();
+        for (target, outcome) in outcomes {
+            match outcome {
+                SendOutcome::Sent => summary.sent += 1,
+                SendOutcome::InvalidTarget => {
+                    invalid.insert(target);
+                }
+                SendOutcome::Failed(_) => {
+                    summary.failed += 1;
+                    summary.retry.push(format!("{}:{}", name, target));
+                }
+            }
+        }
+        summary.failed += invalid.len();
+
+        for (user, targets) in users {
+            let invalid: Vec<String> = targets
+                .into_iter()
+                .filter(|t| invalid.contains(t))
+                .collect();
+            if !invalid.is_empty() {
+                summary.removed += channel.prune(db, user, &invalid).await?;
+            }
+        }
+    }
+
+    Ok(summary)
+}
@@ -0,0 +1,374 @@
+use crate::common::message::publish_message_state;
+use crate::common::store::{Store, StoreResult};
+use crate::common::{
+    send_notification, Message, MessageReceipt, MessageState, Notification, NotificationSummary,
+    Notifier, OutboxEntry, OutboxState, OUTBOX_BATCH_SIZE, OUTBOX_LEASE_SECS, OUTBOX_MAX_ATTEMPTS,
+    OUTBOX_POLL_INTERVAL_SECS, OUTBOX_RETRY_BASE_SECS, OUTBOX_RETRY_MAX_SECS,
+};
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, warn};
+use std::sync::Arc;
+
+/// Wait before attempt `attempts + 1`, doubling from `OUTBOX_RETRY_BASE_SECS`
+/// up to `OUTBOX_RETRY_MAX_SECS`.
+pub fn retry_delay(attempts: u32) -> Duration {
+    let factor = 1i64 << attempts.saturating_sub(1).min(20);
+    Duration::seconds((OUTBOX_RETRY_BASE_SECS * factor).min(OUTBOX_RETRY_MAX_SECS))
+}
+
+/// A fresh entry for `message`, already leased to the caller so the worker
+/// leaves it alone while the first attempt runs inline.
+pub fn new_outbox_entry(message: &Message, now: DateTime<Utc>) -> OutboxEntry {
+    OutboxEntry {
+        message_id: message.id,
+        state: OutboxState::Pending,
+        attempts: 0,
+        next_attempt_at: now + Duration::seconds(OUTBOX_LEASE_SECS),
+        last_error: None,
+        retry_tokens: None,
+        created_at: now,
+    }
+}
+
+/// Sets the message and the receipts still pending to `state`.
+async fn mark_message(
+    db: &dyn Store,
+    notifier: &Notifier,
+    message: &mut Message,
+    state: MessageState,
+) -> StoreResult<()> {
+    let now = Utc::now();
+    for receiver in message.receiver_ids.clone() {
+        let receipt = message
+            .receipt(&receiver)
+            .unwrap_or_else(|| MessageReceipt::pending(&receiver));
+        if receipt.state != MessageState::Pending {
+            continue;
+        }
+        let receipt = MessageReceipt {
+            state: state.clone(),
+            sent_at: match state {
+                MessageState::Sent => Some(now),
+                _ => None,
+            },
+            ..receipt
+        };
+        db.set_message_receipt(&message.id, &receipt).await?;
+        message.set_receipt(receipt);
+    }
+
+    db.set_message_state(&message.id, state.clone()).await?;
+    publish_message_state(notifier, message, state.clone());
+    message.state = state;
+    Ok(())
+}
+
+/// Makes one delivery attempt for `entry`. The message becomes `Sent` once
+/// every receiver was reached and `Failed` when the entry is dead-lettered,
+/// otherwise the targets that failed are retried after `retry_delay`.
+/// Receivers in their quiet hours are tried again when those end, which
+/// doesn't count as a failed attempt.
+///
+/// Only store errors are returned, FCM errors are recorded on the entry.
+pub async fn deliver_outbox_entry(
+    db: &dyn Store,
+    notifier: &Notifier,
+    message: &mut Message,
+    entry: &mut OutboxEntry,
+) -> StoreResult<NotificationSummary> {
+    let result = send_notification(
+        db,
+        notifier,
+        message.receiver_ids.as_slice(),
+        entry.retry_tokens.as_deref(),
+        &Notification::from(&*message),
+    )
+    .await;
+
+    let summary = match result {
+        Ok(summary) if summary.retry.is_empty() => {
+            entry.state = OutboxState::Delivered;
+            entry.last_error = None;
+            entry.retry_tokens = None;
+            db.update_outbox_entry(entry).await?;
+            mark_message(db, notifier, message, MessageState::Sent).await?;
+            return Ok(summary);
+        }
+        Ok(summary) if !summary.has_failures() => {
+            entry.retry_tokens = Some(summary.retry.clone());
+            entry.next_attempt_at = summary
+                .deferred_until
+                .unwrap_or_else(|| Utc::now() + retry_delay(entry.attempts));
+            db.update_outbox_entry(entry).await?;
+            return Ok(summary);
+        }
+        Ok(summary) => {
+            entry.last_error = Some(format!("{} targets failed", summary.retry.len()));
+            entry.retry_tokens = Some(summary.retry.clone());
+            summary
+        }
+        Err(e) => {
+            entry.last_error = Some(format!("{:?}", e));
+            NotificationSummary::default()
+        }
+    };
+
+    entry.attempts += 1;
+    if entry.attempts >= OUTBOX_MAX_ATTEMPTS {
+        warn!(
+            "giving up on notifications for message {} after {} attempts: {:?}",
+            message.id, entry.attempts, entry.last_error
+        );
+        entry.state = OutboxState::Dead;
+        db.update_outbox_entry(entry).await?;
+        mark_message(db, notifier, message, MessageState::Failed).await?;
+    } else {
+        entry.next_attempt_at = Utc::now() + retry_delay(entry.attempts);
+        db.update_outbox_entry(entry).await?;
+    }
+
+    Ok(summary)
+}
+
+/// Claims the entries due now and attempts each once. Returns how many were
+/// attempted.
+pub async fn process_outbox(db: &dyn Store, notifier: &Notifier) -> StoreResult<usize> {
+    let now = Utc::now();
+    let entries = db
+        .claim_outbox_entries(
+            &now,
+            &(now + Duration::seconds(OUTBOX_LEASE_SECS)),
+            OUTBOX_BATCH_SIZE,
+        )
+        .await?;
+
+    let claimed = entries.len();
+    for mut entry in entries {
+        match db.get_message(&entry.message_id).await? {
+            Some(mut message) => {
+                deliver_outbox_entry(db, notifier, &mut message, &mut entry).await?;
+            }
+            None => {
+                entry.state = OutboxState::Dead;
+                entry.last_error = Some("message not found".to_string());
+                db.update_outbox_entry(&entry).await?;
+            }
+        }
+    }
+
+    Ok(claimed)
+}
+
+/// Background loop of the server binary, polls the outbox every
+/// `OUTBOX_POLL_INTERVAL_SECS` and right away while there is a backlog.
+pub async fn run_outbox_worker(db: Arc<dyn Store>, notifier: Notifier) {
+    let interval = std::time::Duration::from_secs(OUTBOX_POLL_INTERVAL_SECS);
+    loop {
+        match process_outbox(db.as_ref(), &notifier).await {
+            Ok(n) if n >= OUTBOX_BATCH_SIZE => continue,
+            Ok(n) => debug!("outbox: attempted {} entries", n),
+            Err(e) => warn!("outbox worker failed: {:?}", e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::message::MessageBody;
+    use crate::common::message::{try_send_messages, try_send_notice};
+    use crate::common::outbox::{process_outbox, retry_delay};
+    use crate::common::store::{
+        Fixtures, MemoryStore, MessageStore, OutboxStore, StoreResult, UserStore,
+    };
+    use crate::common::{
+        AccessTokenProvider, FcmClient, MessageState, Notification, NotificationCategory,
+        NotificationChannel, NotificationPreferences, Notifier, OutboxState, QuietHours,
+        SendOutcome, User, OUTBOX_MAX_ATTEMPTS,
+    };
+    use async_trait::async_trait;
+    use chrono::{Duration, Utc};
+    use std::sync::{Arc, Mutex};
+
+    struct StaticToken;
+
+    #[async_trait]
+    impl AccessTokenProvider for StaticToken {
+        async fn access_token(&self, _: &reqwest::Client) -> StoreResult<String> {
+            Ok("token".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outbox_retries_and_dead_letters_async() {
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+        // nothing listens there, every send fails
+        let down = Notifier::new(vec![Arc::new(FcmClient::new(
+            reqwest::Client::new(),
+            "http://127.0.0.1:9",
+            "edclass-test",
+            Arc::new(StaticToken),
+        ))]);
+        let parent_id = Fixtures::id("parent");
+        let teacher = db.fixture_user("teacher").await;
+        db.set_devices(&parent_id, &["device-a".to_string()])
+            .await
+            .unwrap();
+
+        let body = |content: &str| MessageBody {
+            receiver_ids: vec!["mom@mom.com".to_string()],
+            subject: None,
+            content: content.to_string(),
+            attachments: vec![],
+        };
+        let sent = try_send_messages(&db, &down, &teacher, body("outage"))
+            .await
+            .unwrap();
+        assert_eq!(sent.message.state, MessageState::Pending);
+        assert_eq!(sent.notifications.failed, 1);
+
+        let id = sent.message.id;
+        let entry = db.get_outbox_entry(&id).await.unwrap().unwrap();
+        assert_eq!(entry.attempts, 1);
+        assert_eq!(entry.retry_tokens, Some(vec!["push:device-a".to_string()]));
+        assert!(entry.next_attempt_at > Utc::now() + Duration::seconds(20));
+
+        // not due yet
+        assert_eq!(process_outbox(&db, &down).await.unwrap(), 0);
+
+        for attempt in 2..=OUTBOX_MAX_ATTEMPTS {
+            let mut entry = db.get_outbox_entry(&id).await.unwrap().unwrap();
+            entry.next_attempt_at = Utc::now();
+            db.update_outbox_entry(&entry).await.unwrap();
+            assert_eq!(process_outbox(&db, &down).await.unwrap(), 1);
+            assert_eq!(
+                db.get_outbox_entry(&id).await.unwrap().unwrap().attempts,
+                attempt
+            );
+        }
+
+        let entry = db.get_outbox_entry(&id).await.unwrap().unwrap();
+        assert_eq!(entry.state, OutboxState::Dead);
+        let message = db.get_message(&id).await.unwrap().unwrap();
+        assert_eq!(message.state, MessageState::Failed);
+
+        // with no channel able to reach the parent there's nothing to retry
+        let sent = try_send_messages(&db, &Notifier::default(), &teacher, body("fine"))
+            .await
+            .unwrap();
+        assert_eq!(sent.message.state, MessageState::Sent);
+        let entry = db
+            .get_outbox_entry(&sent.message.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.state, OutboxState::Delivered);
+
+        assert_eq!(retry_delay(1), Duration::seconds(30));
+        assert_eq!(retry_delay(3), Duration::seconds(120));
+        assert_eq!(retry_delay(30), Duration::hours(1));
+    }
+
+    /// Reaches every device and remembers what it sent.
+    #[derive(Default)]
+    struct RecordingChannel {
+        sent: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl NotificationChannel for RecordingChannel {
+        fn name(&self) -> &'static str {
+            "push"
+        }
+
+        fn targets(&self, user: &User) -> Vec<String> {
+            user.devices.clone()
+        }
+
+        async fn send(
+            &self,
+            targets: &[String],
+            _: &Notification,
+        ) -> StoreResult<Vec<(String, SendOutcome)>> {
+            self.sent.lock().unwrap().extend(targets.iter().cloned());
+            Ok(targets
+                .iter()
+                .map(|t| (t.clone(), SendOutcome::Sent))
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_preferences_async() {
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+        let channel = Arc::new(RecordingChannel::default());
+        let notifier = Notifier::new(vec![channel.clone()]);
+        let parent_id = Fixtures::id("parent");
+        let teacher = db.fixture_user("teacher").await;
+        db.set_devices(&parent_id, &["device-a".to_string()])
+            .await
+            .unwrap();
+        let body = || MessageBody {
+            receiver_ids: vec!["mom@mom.com".to_string()],
+            subject: None,
+            content: "hello".to_string(),
+            attachments: vec![],
+        };
+
+        // muted enrollment notices are dropped, messages still go out
+        let mut preferences = NotificationPreferences::default();
+        preferences
+            .categories
+            .insert(NotificationCategory::Enrollment, false);
+        db.set_preferences(&parent_id, &preferences).await.unwrap();
+        let notice = try_send_notice(
+            &db,
+            &notifier,
+            &teacher,
+            body(),
+            NotificationCategory::Enrollment,
+        )
+        .await
+        .unwrap();
+        assert_eq!(notice.notifications.sent, 0);
+        assert!(channel.sent.lock().unwrap().is_empty());
+        try_send_messages(&db, &notifier, &teacher, body())
+            .await
+            .unwrap();
+        assert_eq!(*channel.sent.lock().unwrap(), vec!["device-a"]);
+
+        // quiet hours from an hour ago to an hour from now
+        let now = Utc::now();
+        preferences.quiet_hours = Some(QuietHours {
+            start: (now - Duration::hours(1)).time(),
+            end: (now + Duration::hours(1)).time(),
+            time_zone: "UTC".to_string(),
+        });
+        db.set_preferences(&parent_id, &preferences).await.unwrap();
+        let sent = try_send_messages(&db, &notifier, &teacher, body())
+            .await
+            .unwrap();
+        assert_eq!(sent.notifications.deferred, 1);
+        assert_eq!(sent.message.state, MessageState::Pending);
+        assert_eq!(channel.sent.lock().unwrap().len(), 1);
+
+        let mut entry = db
+            .get_outbox_entry(&sent.message.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.attempts, 0);
+        assert_eq!(entry.retry_tokens, Some(vec!["push:device-a".to_string()]));
+        assert!(entry.next_attempt_at > now + Duration::minutes(59));
+
+        // delivered once the window is over
+        preferences.quiet_hours = None;
+        db.set_preferences(&parent_id, &preferences).await.unwrap();
+        entry.next_attempt_at = Utc::now();
+        db.update_outbox_entry(&entry).await.unwrap();
+        assert_eq!(process_outbox(&db, &notifier).await.unwrap(), 1);
+        assert_eq!(channel.sent.lock().unwrap().len(), 2);
+        let message = db.get_message(&sent.message.id).await.unwrap().unwrap();
+        assert_eq!(message.state, MessageState::Sent);
+    }
+}
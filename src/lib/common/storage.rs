@@ -0,0 +1,390 @@
+use crate::common::store::StoreResult;
+use crate::common::{config_env_var, OBJECT_STORE_DEFAULT_REGION};
+use async_trait::async_trait;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use log::debug;
+use sha2::{Digest, Sha256};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/// Where the bytes of attachments are kept, by key. The metadata is in the
+/// `Store`.
+#[async_trait]
+pub trait AttachmentStorage: Send + Sync {
+    async fn put(&self, key: &str, content_type: &str, data: Vec<u8>) -> StoreResult<()>;
+
+    /// `None` if nothing is stored under `key`.
+    async fn get(&self, key: &str) -> StoreResult<Option<Vec<u8>>>;
+
+    /// Deleting a missing key is not an error.
+    async fn delete(&self, key: &str) -> StoreResult<()>;
+}
+
+/// Keys become file or object names, only plain ones are accepted.
+fn check_key(key: &str) -> StoreResult<()> {
+    let plain = key
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    match plain && !key.is_empty() {
+        true => Ok(()),
+        false => Err(Box::new(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid storage key {:?}", key),
+        ))),
+    }
+}
+
+/// Files in a directory of the server, for single-server setups.
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        LocalStorage { root: root.into() }
+    }
+
+    /// Keeps the files in `ATTACHMENTS_DIR`, `attachments` by default.
+    pub fn from_env() -> Self {
+        LocalStorage::new(config_env_var("ATTACHMENTS_DIR").unwrap_or("attachments".to_string()))
+    }
+}
+
+#[async_trait]
+impl AttachmentStorage for LocalStorage {
+    async fn put(&self, key: &str, _content_type: &str, data: Vec<u8>) -> StoreResult<()> {
+        check_key(key)?;
+        tokio::fs::create_dir_all(&self.root).await?;
+        // readers never see a partly written file
+        let partial = self.root.join(format!("{}.partial", key));
+        tokio::fs::write(&partial, data).await?;
+        tokio::fs::rename(&partial, self.root.join(key)).await?;
+
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> StoreResult<Option<Vec<u8>>> {
+        check_key(key)?;
+        match tokio::fs::read(self.root.join(key)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> StoreResult<()> {
+        check_key(key)?;
+        match tokio::fs::remove_file(self.root.join(key)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(Box::new(e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// The AWS Signature Version 4 key for `date` (`YYYYMMDD`).
+fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac_sha256(format!("AWS4{}", secret_key).as_bytes(), date);
+    let key = hmac_sha256(&key, region);
+    let key = hmac_sha256(&key, service);
+    hmac_sha256(&key, "aws4_request")
+}
+
+/// A bucket of an S3 compatible object store, e.g. AWS S3, MinIO or Cloud
+/// Storage in interoperability mode. Objects are addressed path-style,
+/// `{endpoint}/{bucket}/{key}`, and requests signed with AWS Signature
+/// Version 4.
+#[derive(Clone)]
+pub struct ObjectStorage {
+    http: reqwest::Client,
+    endpoint: reqwest::Url,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+}
+
+impl ObjectStorage {
+    pub fn new(
+        http: reqwest::Client,
+        endpoint: &str,
+        bucket: &str,
+        region: &str,
+        access_key: &str,
+        secret_key: &str,
+    ) -> StoreResult<Self> {
+        Ok(ObjectStorage {
+            http,
+            endpoint: reqwest::Url::parse(endpoint)?,
+            bucket: bucket.to_string(),
+            region: region.to_string(),
+            access_key: access_key.to_string(),
+            secret_key: secret_key.to_string(),
+        })
+    }
+
+    /// Uses the bucket `OBJECT_STORE_BUCKET` at `OBJECT_STORE_URL` with the
+    /// credentials `OBJECT_STORE_ACCESS_KEY` and `OBJECT_STORE_SECRET_KEY`.
+    /// `OBJECT_STORE_REGION` defaults to us-east-1.
+    pub fn from_env(http: reqwest::Client) -> StoreResult<Self> {
+        let region = config_env_var("OBJECT_STORE_REGION")
+            .unwrap_or(OBJECT_STORE_DEFAULT_REGION.to_string());
+        ObjectStorage::new(
+            http,
+            &config_env_var("OBJECT_STORE_URL")?,
+            &config_env_var("OBJECT_STORE_BUCKET")?,
+            &region,
+            &config_env_var("OBJECT_STORE_ACCESS_KEY")?,
+            &config_env_var("OBJECT_STORE_SECRET_KEY")?,
+        )
+    }
+
+    /// A signed request for the object `key`.
+    fn request(
+        &self,
+        method: reqwest::Method,
+        key: &str,
+        body: Vec<u8>,
+    ) -> StoreResult<reqwest::RequestBuilder> {
+        check_key(key)?;
+        let mut url = self.endpoint.clone();
+        let path = format!(
+            "{}/{}/{}",
+            url.path().trim_end_matches('/'),
+            self.bucket,
+            key
+        );
+        url.set_path(&path);
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            _ => {
+                return Err(Box::new(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "object store URL has no host",
+                )))
+            }
+        };
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex(&Sha256::digest(&body));
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n\
+             host;x-amz-content-sha256;x-amz-date\n{}",
+            method,
+            url.path(),
+            host,
+            payload_hash,
+            amz_date,
+            payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let signature = hex(&hmac_sha256(
+            &signing_key(&self.secret_key, &date, &self.region, "s3"),
+            &string_to_sign,
+        ));
+
+        Ok(self
+            .http
+            .request(method, url)
+            .header("x-amz-date", amz_date)
+            .header("x-amz-content-sha256", payload_hash)
+            .header(
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, \
+                     SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+                    self.access_key, scope, signature
+                ),
+            )
+            .body(body))
+    }
+}
+
+#[async_trait]
+impl AttachmentStorage for ObjectStorage {
+    async fn put(&self, key: &str, content_type: &str, data: Vec<u8>) -> StoreResult<()> {
+        let response = self
+            .request(reqwest::Method::PUT, key, data)?
+            .header("content-type", content_type)
+            .send()
+            .await?;
+        debug!("object store put {}: {}", key, response.status());
+        response.error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> StoreResult<Option<Vec<u8>>> {
+        let response = self
+            .request(reqwest::Method::GET, key, Vec::new())?
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        Ok(Some(response.error_for_status()?.bytes().await?.to_vec()))
+    }
+
+    async fn delete(&self, key: &str) -> StoreResult<()> {
+        let response = self
+            .request(reqwest::Method::DELETE, key, Vec::new())?
+            .send()
+            .await?;
+        if response.status() != reqwest::StatusCode::NOT_FOUND {
+            response.error_for_status()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::storage::{
+        hex, signing_key, AttachmentStorage, LocalStorage, ObjectStorage,
+    };
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use sha2::{Digest, Sha256};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_signing_key() {
+        // the example of the AWS Signature Version 4 documentation
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex(&key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_local_storage_async() {
+        let root = std::env::temp_dir().join(format!("edclass-{}", Uuid::new_v4()));
+        let storage = LocalStorage::new(&root);
+        let key = Uuid::new_v4().to_string();
+
+        assert!(storage.get(&key).await.unwrap().is_none());
+        storage
+            .put(&key, "text/plain", b"worksheet".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(storage.get(&key).await.unwrap().unwrap(), b"worksheet");
+        assert!(storage.get("../etc/passwd").await.is_err());
+        storage.delete(&key).await.unwrap();
+        storage.delete(&key).await.unwrap();
+        assert!(storage.get(&key).await.unwrap().is_none());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    /// Stands in for an S3 bucket: keeps objects by path and rejects
+    /// unsigned requests or payloads not matching their hash.
+    async fn mock_object(
+        objects: web::Data<Mutex<HashMap<String, Vec<u8>>>>,
+        req: HttpRequest,
+        body: web::Bytes,
+    ) -> HttpResponse {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or_default()
+                .to_string()
+        };
+        if !header("authorization").starts_with("AWS4-HMAC-SHA256 Credential=test-key/")
+            || header("x-amz-content-sha256") != hex(&Sha256::digest(&body))
+        {
+            return HttpResponse::Forbidden().finish();
+        }
+
+        let path = req.path().to_string();
+        let mut objects = objects.lock().unwrap();
+        match req.method().as_str() {
+            "PUT" => {
+                objects.insert(path, body.to_vec());
+                HttpResponse::Ok().finish()
+            }
+            "GET" => match objects.get(&path) {
+                Some(data) => HttpResponse::Ok().body(data.clone()),
+                None => HttpResponse::NotFound().finish(),
+            },
+            "DELETE" => {
+                objects.remove(&path);
+                HttpResponse::NoContent().finish()
+            }
+            _ => HttpResponse::MethodNotAllowed().finish(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_object_storage_async() {
+        let objects = web::Data::new(Mutex::new(HashMap::<String, Vec<u8>>::new()));
+        let server_objects = objects.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(server_objects.clone())
+                .default_service(web::to(mock_object))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let endpoint = format!("http://{}", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        tokio::spawn(server);
+
+        let storage = ObjectStorage::new(
+            reqwest::Client::new(),
+            &endpoint,
+            "edclass",
+            "us-east-1",
+            "test-key",
+            "test-secret",
+        )
+        .unwrap();
+        let key = Uuid::new_v4().to_string();
+
+        assert!(storage.get(&key).await.unwrap().is_none());
+        storage
+            .put(&key, "application/pdf", b"%PDF-1.4".to_vec())
+            .await
+            .unwrap();
+        assert!(objects
+            .lock()
+            .unwrap()
+            .contains_key(&format!("/edclass/{}", key)));
+        assert_eq!(storage.get(&key).await.unwrap().unwrap(), b"%PDF-1.4");
+        storage.delete(&key).await.unwrap();
+        assert!(storage.get(&key).await.unwrap().is_none());
+
+        handle.stop(true).await;
+    }
+}
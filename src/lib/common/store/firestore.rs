@@ -0,0 +1,1876 @@
+use crate::common::message::{message_view, MessagePage, MessageQuery, MessageType};
+use crate::common::store::{
+    AttachmentStore, AuditStore, CourseStore, DigestStore, EnrollmentStore, LessonStore,
+    MessageStore, OutboxStore, StoreResult, StudentParentStore, TokenStore, UserStore,
+};
+use crate::common::user::{UserPage, UserQuery};
+use crate::common::{
+    Approver, Attachment, AuditEntry, Course, CourseModule, CourseSeats, DigestSchedule,
+    Enrollment, EnrollmentStatus, Lesson, LessonCompletion, Message, MessageReceipt, MessageState,
+    NotificationPreferences, OutboxEntry, OutboxState, RefreshToken, RevokedToken, StudentsParents,
+    User, UserRole, UserWithPassword, ATTACHMENTS_COLLECTION, AUDIT_LOG_COLLECTION,
+    COURSES_COLLECTION, COURSE_MODULES_COLLECTION, COURSE_SEATS_COLLECTION, DIGESTS_COLLECTION,
+    ENROLLMENTS_COLLECTION, FIRESTORE_BATCH_SIZE, FIRESTORE_IN_MAX_VALUES, LESSONS_COLLECTION,
+    LESSON_COMPLETIONS_COLLECTION, MESSAGES_COLLECTION, OUTBOX_COLLECTION,
+    REFRESH_TOKENS_COLLECTION, REVOKED_TOKENS_COLLECTION, STUDENTS_PARENTS_COLLECTION,
+    USERS_COLLECTION, USERS_SCAN_LIMIT,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use firestore::{
+    path, paths, FirestoreDb, FirestoreQueryDirection, FirestoreResult, FirestoreWritePrecondition,
+};
+use futures::stream::BoxStream;
+use futures::{FutureExt, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Clone)]
+pub struct FirestoreStore {
+    db: FirestoreDb,
+}
+
+impl FirestoreStore {
+    pub fn new(db: FirestoreDb) -> Self {
+        FirestoreStore { db }
+    }
+
+    pub fn db(&self) -> &FirestoreDb {
+        &self.db
+    }
+
+    /// Writes the fields `list_users` queries on to every user, for the ones
+    /// stored before there were any. Returns how many users were indexed.
+    pub async fn index_users(&self) -> StoreResult<usize> {
+        let objs_stream: BoxStream<FirestoreResult<User>> = self
+            .db
+            .fluent()
+            .select()
+            .from(USERS_COLLECTION)
+            .obj()
+            .stream_query_with_errors()
+            .await?;
+        let users: Vec<User> = objs_stream.try_collect().await?;
+
+        let batch_writer = self.db.create_simple_batch_writer().await?;
+        for chunk in users.chunks(FIRESTORE_BATCH_SIZE) {
+            let mut current_batch = batch_writer.new_batch();
+            for user in chunk {
+                self.db
+                    .fluent()
+                    .update()
+                    .fields(paths!(UserIndex::{active, search}))
+                    .in_col(USERS_COLLECTION)
+                    .document_id(&user.uid.to_string())
+                    .object(&UserIndex::of(&user.name, &user.email, user.deactivated_at))
+                    .add_to_batch(&mut current_batch)?;
+            }
+            current_batch.write().await?;
+        }
+
+        Ok(users.len())
+    }
+}
+
+/// Kept on user documents only for `list_users` to query on: whether the
+/// account is active, and the lowercased starts of the email and of each
+/// word of the name, one of which a search has to be.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UserIndex {
+    active: bool,
+    search: Vec<String>,
+}
+
+impl UserIndex {
+    fn of(name: &str, email: &str, deactivated_at: Option<DateTime<Utc>>) -> Self {
+        let mut search = Vec::new();
+        for word in std::iter::once(email).chain(name.split_whitespace()) {
+            let word = word.to_lowercase();
+            let ends = word.char_indices().skip(1).map(|(i, _)| i);
+            search.extend(ends.chain([word.len()]).map(|i| word[..i].to_string()));
+        }
+        search.sort();
+        search.dedup();
+
+        UserIndex {
+            active: deactivated_at.is_none(),
+            search,
+        }
+    }
+}
+
+// a new user as `insert_user` writes it, the index in the same write
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexedUser {
+    #[serde(flatten)]
+    user: UserWithPassword,
+    #[serde(flatten)]
+    index: UserIndex,
+}
+
+// the fields `update_account` replaces, and the index along with them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AccountFields {
+    name: String,
+    role: UserRole,
+    #[serde(default)]
+    deactivated_at: Option<DateTime<Utc>>,
+    active: bool,
+    search: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PasswordField {
+    password: String,
+}
+
+#[async_trait]
+impl UserStore for FirestoreStore {
+    async fn get_user(&self, id: &Uuid) -> StoreResult<Option<User>> {
+        let user: Option<User> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in(USERS_COLLECTION)
+            .obj()
+            .one(&id.to_string())
+            .await?;
+
+        Ok(user)
+    }
+
+    async fn find_user(&self, email_or_id: &str) -> StoreResult<Option<UserWithPassword>> {
+        let users: Vec<UserWithPassword> = self
+            .db
+            .fluent()
+            .select()
+            .from(USERS_COLLECTION)
+            .filter(|q| {
+                q.for_any([
+                    q.field(path!(UserWithPassword::email)).eq(email_or_id),
+                    q.field(path!(UserWithPassword::uid)).eq(email_or_id),
+                ])
+            })
+            .limit(1)
+            .obj()
+            .query()
+            .await?;
+
+        Ok(users.into_iter().next())
+    }
+
+    async fn insert_user(&self, user: &UserWithPassword) -> StoreResult<()> {
+        self.db
+            .fluent()
+            .insert()
+            .into(USERS_COLLECTION)
+            .document_id(&user.uid.to_string())
+            .object(&IndexedUser {
+                user: user.clone(),
+                index: UserIndex::of(&user.name, &user.email, user.deactivated_at),
+            })
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn set_devices(&self, id: &Uuid, devices: &[String]) -> StoreResult<()> {
+        let user = match self.get_user(id).await? {
+            Some(u) => u,
+            _ => {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "data not found",
+                )))
+            }
+        };
+
+        self.db
+            .fluent()
+            .update()
+            .fields(paths!(User::{devices}))
+            .in_col(USERS_COLLECTION)
+            .document_id(&id.to_string())
+            .object(&User {
+                devices: devices.to_vec(),
+                ..user
+            })
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn set_preferences(
+        &self,
+        id: &Uuid,
+        preferences: &NotificationPreferences,
+    ) -> StoreResult<()> {
+        let user = match self.get_user(id).await? {
+            Some(u) => u,
+            _ => {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "data not found",
+                )))
+            }
+        };
+
+        self.db
+            .fluent()
+            .update()
+            .fields(paths!(User::{preferences}))
+            .in_col(USERS_COLLECTION)
+            .document_id(&id.to_string())
+            .object(&User {
+                preferences: preferences.clone(),
+                ..user
+            })
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_users(&self, ids: &[Uuid]) -> StoreResult<Vec<User>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let ids = ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        let mut box_users: BoxStream<(String, Option<User>)> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in(USERS_COLLECTION)
+            .obj()
+            .batch(&ids)
+            .await?;
+
+        let mut users = Vec::new();
+        while let Some((_i, u)) = box_users.next().await {
+            if let Some(user) = u {
+                users.push(user);
+            }
+        }
+
+        Ok(users)
+    }
+
+    async fn get_users_by_emails(&self, emails: &[String]) -> StoreResult<Vec<User>> {
+        if emails.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // an `in` filter takes a limited number of values
+        let mut users = Vec::new();
+        for chunk in emails.chunks(FIRESTORE_IN_MAX_VALUES) {
+            let box_users: BoxStream<FirestoreResult<User>> = self
+                .db
+                .fluent()
+                .select()
+                .from(USERS_COLLECTION)
+                .filter(|q| q.for_any([q.field(path!(User::email)).is_in(chunk)]))
+                .obj()
+                .stream_query_with_errors()
+                .await?;
+            users.extend(box_users.try_collect::<Vec<User>>().await?);
+        }
+
+        Ok(users)
+    }
+
+    async fn get_system_user(&self) -> StoreResult<Option<User>> {
+        let obj_stream = self
+            .db
+            .fluent()
+            .select()
+            .from(USERS_COLLECTION)
+            .limit(1)
+            .filter(|q| q.for_all(q.field(path!(User::role)).eq(&UserRole::System)))
+            .obj()
+            .stream_query_with_errors()
+            .await?;
+
+        let to_vec: Vec<User> = obj_stream.try_collect().await?;
+        Ok(to_vec.into_iter().next())
+    }
+
+    async fn list_users(&self, query: &UserQuery) -> StoreResult<UserPage> {
+        // the index holds single words, the rest of the search is checked on
+        // the stream
+        let word = query
+            .search
+            .as_ref()
+            .and_then(|s| s.split_whitespace().next().map(str::to_lowercase));
+        let mut objs_stream: BoxStream<FirestoreResult<User>> = self
+            .db
+            .fluent()
+            .select()
+            .from(USERS_COLLECTION)
+            .filter(|q| {
+                q.for_all([
+                    query
+                        .role
+                        .as_ref()
+                        .and_then(|r| q.field(path!(User::role)).eq(r)),
+                    query
+                        .active
+                        .and_then(|a| q.field(path!(UserIndex::active)).eq(a)),
+                    word.as_ref()
+                        .and_then(|w| q.field(path!(UserIndex::search)).array_contains(w)),
+                    query
+                        .after
+                        .as_ref()
+                        .and_then(|a| q.field(path!(User::email)).greater_than(a)),
+                ])
+            })
+            .order_by([(path!(User::email), FirestoreQueryDirection::Ascending)])
+            .limit(USERS_SCAN_LIMIT as u32)
+            .obj()
+            .stream_query_with_errors()
+            .await?;
+
+        let (mut users, mut scanned, mut last) = (Vec::new(), 0, None);
+        while let Some(user) = objs_stream.try_next().await? {
+            scanned += 1;
+            last = Some(user.email.clone());
+            if query.matches(&user) {
+                users.push(user);
+                if users.len() > query.limit {
+                    break;
+                }
+            }
+        }
+
+        let mut page = UserPage::from_overfetch(users, query.limit);
+        // a page cut short by the scan limit goes on where the scan stopped
+        if page.next_cursor.is_none() && scanned == USERS_SCAN_LIMIT {
+            page.next_cursor = last;
+        }
+        Ok(page)
+    }
+
+    async fn update_account(&self, user: &User) -> StoreResult<()> {
+        let index = UserIndex::of(&user.name, &user.email, user.deactivated_at);
+        self.db
+            .fluent()
+            .update()
+            .fields(paths!(AccountFields::{name, role, deactivated_at, active, search}))
+            .in_col(USERS_COLLECTION)
+            .document_id(&user.uid.to_string())
+            .object(&AccountFields {
+                name: user.name.clone(),
+                role: user.role,
+                deactivated_at: user.deactivated_at,
+                active: index.active,
+                search: index.search,
+            })
+            .precondition(FirestoreWritePrecondition::Exists(true))
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn set_password(&self, id: &Uuid, password: &str) -> StoreResult<()> {
+        self.db
+            .fluent()
+            .update()
+            .fields(paths!(PasswordField::{password}))
+            .in_col(USERS_COLLECTION)
+            .document_id(&id.to_string())
+            .object(&PasswordField {
+                password: password.to_string(),
+            })
+            .precondition(FirestoreWritePrecondition::Exists(true))
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StudentParentStore for FirestoreStore {
+    async fn link_students(&self, parent_id: &Uuid, student_ids: &[Uuid]) -> StoreResult<()> {
+        if student_ids.is_empty() {
+            return Ok(());
+        }
+
+        let batch_writer = self.db.create_simple_batch_writer().await?;
+        let mut current_batch = batch_writer.new_batch();
+        for id in student_ids {
+            let s_p = StudentsParents {
+                student_id: *id,
+                parent_id: *parent_id,
+            };
+
+            self.db
+                .fluent()
+                .update()
+                .in_col(STUDENTS_PARENTS_COLLECTION)
+                .document_id(&format!("{}_{}", &s_p.student_id, &s_p.parent_id))
+                .object(&s_p)
+                .add_to_batch(&mut current_batch)?;
+        }
+
+        current_batch.write().await?;
+        Ok(())
+    }
+
+    async fn list_parents_of(&self, student_id: &Uuid) -> StoreResult<Vec<StudentsParents>> {
+        let box_students_parents: BoxStream<FirestoreResult<StudentsParents>> = self
+            .db
+            .fluent()
+            .select()
+            .from(STUDENTS_PARENTS_COLLECTION)
+            .filter(|q| {
+                q.for_all([
+                    //
+                    q.field(path!(StudentsParents::student_id))
+                        .eq(&student_id.to_string()),
+                ])
+            })
+            .obj()
+            .stream_query_with_errors()
+            .await?;
+
+        let students_parents = box_students_parents.try_collect().await?;
+        Ok(students_parents)
+    }
+
+    async fn list_students_of(&self, parent_id: &Uuid) -> StoreResult<Vec<StudentsParents>> {
+        let box_students_parents: BoxStream<FirestoreResult<StudentsParents>> = self
+            .db
+            .fluent()
+            .select()
+            .from(STUDENTS_PARENTS_COLLECTION)
+            .filter(|q| {
+                q.for_all([
+                    //
+                    q.field(path!(StudentsParents::parent_id)).eq(parent_id),
+                ])
+            })
+            .obj()
+            .stream_query_with_errors()
+            .await?;
+
+        let students_parents = box_students_parents.try_collect().await?;
+        Ok(students_parents)
+    }
+
+    async fn unlink_student(&self, parent_id: &Uuid, student_id: &Uuid) -> StoreResult<bool> {
+        let linked = self
+            .list_students_of(parent_id)
+            .await?
+            .iter()
+            .any(|sp| sp.student_id == *student_id);
+        if !linked {
+            return Ok(false);
+        }
+
+        self.db
+            .fluent()
+            .delete()
+            .from(STUDENTS_PARENTS_COLLECTION)
+            .document_id(&format!("{}_{}", student_id, parent_id))
+            .execute()
+            .await?;
+
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl CourseStore for FirestoreStore {
+    async fn list_courses(&self) -> StoreResult<Vec<Course>> {
+        let box_courses: BoxStream<FirestoreResult<Course>> = self
+            .db
+            .fluent()
+            .list()
+            .from(COURSES_COLLECTION)
+            .obj()
+            .stream_all_with_errors()
+            .await?;
+
+        let courses = box_courses.try_collect().await?;
+        Ok(courses)
+    }
+
+    async fn get_course(&self, id: &Uuid) -> StoreResult<Option<Course>> {
+        let course: Option<Course> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in(COURSES_COLLECTION)
+            .obj()
+            .one(&id.to_string())
+            .await?;
+
+        Ok(course)
+    }
+
+    async fn get_courses(&self, ids: &[Uuid]) -> StoreResult<Vec<Course>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // an `in` filter takes a limited number of values
+        let mut courses = Vec::new();
+        for chunk in ids.chunks(FIRESTORE_IN_MAX_VALUES) {
+            let chunk_courses: Vec<Course> = self
+                .db
+                .fluent()
+                .select()
+                .from(COURSES_COLLECTION)
+                .filter(|q| {
+                    q.for_any([
+                        // enrollment course
+                        q.field(path!(Course::id)).is_in(chunk),
+                    ])
+                })
+                .obj()
+                .query()
+                .await?;
+            courses.extend(chunk_courses);
+        }
+
+        Ok(courses)
+    }
+
+    async fn list_teacher_courses(&self, teacher_id: &Uuid) -> StoreResult<Vec<Course>> {
+        let box_courses: BoxStream<FirestoreResult<Course>> = self
+            .db
+            .fluent()
+            .select()
+            .from(COURSES_COLLECTION)
+            .filter(|q| {
+                // filter course
+                q.for_all([q.field(path!(Course::teacher_id)).eq(teacher_id)])
+            })
+            .obj()
+            .stream_query_with_errors()
+            .await?;
+
+        let courses = box_courses.try_collect().await?;
+        Ok(courses)
+    }
+
+    async fn insert_course(&self, course: &Course) -> StoreResult<()> {
+        self.db
+            .fluent()
+            .insert()
+            .into(COURSES_COLLECTION)
+            .document_id(&course.id.to_string())
+            .object(course)
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn update_course(&self, course: &Course) -> StoreResult<()> {
+        self.db
+            .fluent()
+            .update()
+            .in_col(COURSES_COLLECTION)
+            .document_id(course.id.to_string())
+            .object(course)
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl LessonStore for FirestoreStore {
+    async fn list_course_modules(&self, course_id: &Uuid) -> StoreResult<Vec<CourseModule>> {
+        let box_modules: BoxStream<FirestoreResult<CourseModule>> = self
+            .db
+            .fluent()
+            .select()
+            .from(COURSE_MODULES_COLLECTION)
+            .filter(|q| q.for_all([q.field(path!(CourseModule::course_id)).eq(course_id)]))
+            .order_by([(
+                path!(CourseModule::position),
+                FirestoreQueryDirection::Ascending,
+            )])
+            .obj()
+            .stream_query_with_errors()
+            .await?;
+
+        let modules = box_modules.try_collect().await?;
+        Ok(modules)
+    }
+
+    async fn insert_module(&self, module: &CourseModule) -> StoreResult<()> {
+        self.db
+            .fluent()
+            .insert()
+            .into(COURSE_MODULES_COLLECTION)
+            .document_id(&module.id.to_string())
+            .object(module)
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn update_module(&self, module: &CourseModule) -> StoreResult<()> {
+        self.db
+            .fluent()
+            .update()
+            .in_col(COURSE_MODULES_COLLECTION)
+            .document_id(module.id.to_string())
+            .object(module)
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_module(&self, id: &Uuid) -> StoreResult<()> {
+        self.db
+            .fluent()
+            .delete()
+            .from(COURSE_MODULES_COLLECTION)
+            .document_id(id.to_string())
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn reorder_modules(&self, course_id: &Uuid, ids: &[Uuid]) -> StoreResult<()> {
+        let modules = self.list_course_modules(course_id).await?;
+
+        let batch_writer = self.db.create_simple_batch_writer().await?;
+        let mut current_batch = batch_writer.new_batch();
+        for module in modules {
+            let position = match ids.iter().position(|id| *id == module.id) {
+                Some(p) => p as u32,
+                None => continue,
+            };
+            self.db
+                .fluent()
+                .update()
+                .in_col(COURSE_MODULES_COLLECTION)
+                .document_id(module.id.to_string())
+                .object(&CourseModule { position, ..module })
+                .add_to_batch(&mut current_batch)?;
+        }
+
+        current_batch.write().await?;
+        Ok(())
+    }
+
+    async fn list_course_lessons(&self, course_id: &Uuid) -> StoreResult<Vec<Lesson>> {
+        let box_lessons: BoxStream<FirestoreResult<Lesson>> = self
+            .db
+            .fluent()
+            .select()
+            .from(LESSONS_COLLECTION)
+            .filter(|q| q.for_all([q.field(path!(Lesson::course_id)).eq(course_id)]))
+            .order_by([(path!(Lesson::position), FirestoreQueryDirection::Ascending)])
+            .obj()
+            .stream_query_with_errors()
+            .await?;
+
+        let lessons = box_lessons.try_collect().await?;
+        Ok(lessons)
+    }
+
+    async fn get_lesson(&self, id: &Uuid) -> StoreResult<Option<Lesson>> {
+        let lesson: Option<Lesson> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in(LESSONS_COLLECTION)
+            .obj()
+            .one(&id.to_string())
+            .await?;
+
+        Ok(lesson)
+    }
+
+    async fn insert_lesson(&self, lesson: &Lesson) -> StoreResult<()> {
+        self.db
+            .fluent()
+            .insert()
+            .into(LESSONS_COLLECTION)
+            .document_id(&lesson.id.to_string())
+            .object(lesson)
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn update_lesson(&self, lesson: &Lesson) -> StoreResult<()> {
+        self.db
+            .fluent()
+            .update()
+            .in_col(LESSONS_COLLECTION)
+            .document_id(lesson.id.to_string())
+            .object(lesson)
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_lesson(&self, id: &Uuid) -> StoreResult<()> {
+        let completions: Vec<LessonCompletion> = self
+            .db
+            .fluent()
+            .select()
+            .from(LESSON_COMPLETIONS_COLLECTION)
+            .filter(|q| q.for_all([q.field(path!(LessonCompletion::lesson_id)).eq(id)]))
+            .obj()
+            .query()
+            .await?;
+
+        let batch_writer = self.db.create_simple_batch_writer().await?;
+        let mut current_batch = batch_writer.new_batch();
+        for c in completions {
+            self.db
+                .fluent()
+                .delete()
+                .from(LESSON_COMPLETIONS_COLLECTION)
+                .document_id(format!("{}_{}", c.lesson_id, c.student_id))
+                .add_to_batch(&mut current_batch)?;
+        }
+        self.db
+            .fluent()
+            .delete()
+            .from(LESSONS_COLLECTION)
+            .document_id(id.to_string())
+            .add_to_batch(&mut current_batch)?;
+
+        current_batch.write().await?;
+        Ok(())
+    }
+
+    async fn reorder_lessons(
+        &self,
+        course_id: &Uuid,
+        module_id: &Uuid,
+        ids: &[Uuid],
+    ) -> StoreResult<()> {
+        let lessons = self.list_course_lessons(course_id).await?;
+
+        let batch_writer = self.db.create_simple_batch_writer().await?;
+        let mut current_batch = batch_writer.new_batch();
+        for lesson in lessons {
+            let position = match ids.iter().position(|id| *id == lesson.id) {
+                Some(p) => p as u32,
+                None => continue,
+            };
+            self.db
+                .fluent()
+                .update()
+                .in_col(LESSONS_COLLECTION)
+                .document_id(lesson.id.to_string())
+                .object(&Lesson {
+                    module_id: *module_id,
+                    position,
+                    ..lesson
+                })
+                .add_to_batch(&mut current_batch)?;
+        }
+
+        current_batch.write().await?;
+        Ok(())
+    }
+
+    async fn insert_lesson_completion(&self, completion: &LessonCompletion) -> StoreResult<bool> {
+        let id = format!("{}_{}", completion.lesson_id, completion.student_id);
+        let existing: Option<LessonCompletion> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in(LESSON_COMPLETIONS_COLLECTION)
+            .obj()
+            .one(&id)
+            .await?;
+        if existing.is_some() {
+            return Ok(false);
+        }
+
+        self.db
+            .fluent()
+            .insert()
+            .into(LESSON_COMPLETIONS_COLLECTION)
+            .document_id(&id)
+            .object(completion)
+            .execute()
+            .await?;
+
+        Ok(true)
+    }
+
+    async fn list_course_completions(
+        &self,
+        course_id: &Uuid,
+    ) -> StoreResult<Vec<LessonCompletion>> {
+        let completions: Vec<LessonCompletion> = self
+            .db
+            .fluent()
+            .select()
+            .from(LESSON_COMPLETIONS_COLLECTION)
+            .filter(|q| q.for_all([q.field(path!(LessonCompletion::course_id)).eq(course_id)]))
+            .obj()
+            .query()
+            .await?;
+
+        Ok(completions)
+    }
+}
+
+// enrollments are keyed by student and course, so a student can't be
+// enrolled twice even when two requests race
+fn enrollment_key(student_id: &Uuid, course_id: &Uuid) -> String {
+    format!("{}_{}", student_id, course_id)
+}
+
+/// Reads the enrollment with its document id, at its key or, when it was
+/// stored before enrollments were keyed, at its own id.
+async fn read_enrollment(
+    db: &FirestoreDb,
+    enrollment: &Enrollment,
+) -> FirestoreResult<Option<(String, Enrollment)>> {
+    let key = enrollment_key(&enrollment.student_id, &enrollment.course_id);
+    for id in [key, enrollment.id.to_string()] {
+        let stored: Option<Enrollment> = db
+            .fluent()
+            .select()
+            .by_id_in(ENROLLMENTS_COLLECTION)
+            .obj()
+            .one(&id)
+            .await?;
+        if let Some(e) = stored {
+            return Ok(Some((id, e)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Finds the enrollment of the student in the course, also among the ones
+/// stored before enrollments were keyed.
+async fn query_enrollment(
+    db: &FirestoreDb,
+    student_id: &Uuid,
+    course_id: &Uuid,
+) -> FirestoreResult<Option<Enrollment>> {
+    let stored: Option<Enrollment> = db
+        .fluent()
+        .select()
+        .by_id_in(ENROLLMENTS_COLLECTION)
+        .obj()
+        .one(&enrollment_key(student_id, course_id))
+        .await?;
+    if stored.is_some() {
+        return Ok(stored);
+    }
+
+    let enrollments: Vec<Enrollment> = db
+        .fluent()
+        .select()
+        .from(ENROLLMENTS_COLLECTION)
+        .filter(|q| {
+            // filter
+            q.for_all([
+                // all match condition
+                q.field(path!(Enrollment::student_id))
+                    .eq(&student_id.to_string()),
+                q.field(path!(Enrollment::course_id))
+                    .eq(&course_id.to_string()),
+            ])
+        })
+        .limit(1)
+        .obj()
+        .query()
+        .await?;
+
+    Ok(enrollments.into_iter().next())
+}
+
+async fn query_course_enrollments(
+    db: &FirestoreDb,
+    course_id: &Uuid,
+) -> FirestoreResult<Vec<Enrollment>> {
+    db.fluent()
+        .select()
+        .from(ENROLLMENTS_COLLECTION)
+        .filter(|q| {
+            q.for_any([
+                // filter by course id
+                q.field(path!(Enrollment::course_id)).eq(course_id),
+            ])
+        })
+        .obj()
+        .query()
+        .await
+}
+
+#[async_trait]
+impl EnrollmentStore for FirestoreStore {
+    async fn find_enrollment(
+        &self,
+        student_id: &Uuid,
+        course_id: &Uuid,
+    ) -> StoreResult<Option<Enrollment>> {
+        Ok(query_enrollment(&self.db, student_id, course_id).await?)
+    }
+
+    async fn insert_enrollment(&self, enrollment: &Enrollment) -> StoreResult<bool> {
+        let enrollment = enrollment.clone();
+        let inserted = self
+            .db
+            .run_transaction(move |db, transaction| {
+                let enrollment = enrollment.clone();
+                async move {
+                    // read in the transaction, so of two racing inserts the
+                    // second finds the first
+                    if query_enrollment(&db, &enrollment.student_id, &enrollment.course_id)
+                        .await?
+                        .is_some()
+                    {
+                        return Ok(false);
+                    }
+
+                    db.fluent()
+                        .update()
+                        .in_col(ENROLLMENTS_COLLECTION)
+                        .document_id(enrollment_key(
+                            &enrollment.student_id,
+                            &enrollment.course_id,
+                        ))
+                        .object(&enrollment)
+                        .add_to_transaction(transaction)?;
+                    Ok(true)
+                }
+                .boxed()
+            })
+            .await?;
+
+        Ok(inserted)
+    }
+
+    async fn insert_enrollments(&self, enrollments: &[Enrollment]) -> StoreResult<Vec<Enrollment>> {
+        let mut written: Vec<Enrollment> = Vec::new();
+        // each chunk is checked and written in one transaction, like
+        // `insert_enrollment`, within the transaction write limit
+        for chunk in enrollments.chunks(FIRESTORE_BATCH_SIZE) {
+            let chunk = chunk.to_vec();
+            let inserted = self
+                .db
+                .run_transaction(move |db, transaction| {
+                    let chunk = chunk.clone();
+                    async move {
+                        let mut inserted: Vec<Enrollment> = Vec::new();
+                        for enrollment in chunk {
+                            let twice = inserted.iter().any(|e| {
+                                e.student_id == enrollment.student_id
+                                    && e.course_id == enrollment.course_id
+                            });
+                            if twice
+                                || query_enrollment(
+                                    &db,
+                                    &enrollment.student_id,
+                                    &enrollment.course_id,
+                                )
+                                .await?
+                                .is_some()
+                            {
+                                continue;
+                            }
+                            inserted.push(enrollment);
+                        }
+
+                        for enrollment in &inserted {
+                            db.fluent()
+                                .update()
+                                .in_col(ENROLLMENTS_COLLECTION)
+                                .document_id(enrollment_key(
+                                    &enrollment.student_id,
+                                    &enrollment.course_id,
+                                ))
+                                .object(enrollment)
+                                .add_to_transaction(transaction)?;
+                        }
+                        Ok(inserted)
+                    }
+                    .boxed()
+                })
+                .await?;
+            written.extend(inserted);
+        }
+
+        Ok(written)
+    }
+
+    async fn update_enrollment(
+        &self,
+        enrollment: &Enrollment,
+        from: EnrollmentStatus,
+    ) -> StoreResult<bool> {
+        let enrollment = enrollment.clone();
+        let updated = self
+            .db
+            .run_transaction(move |db, transaction| {
+                let enrollment = enrollment.clone();
+                async move {
+                    let id = match read_enrollment(&db, &enrollment).await? {
+                        Some((id, e)) if e.status == from => id,
+                        _ => return Ok(false),
+                    };
+
+                    db.fluent()
+                        .update()
+                        .in_col(ENROLLMENTS_COLLECTION)
+                        .document_id(&id)
+                        .object(&enrollment)
+                        .add_to_transaction(transaction)?;
+                    Ok(true)
+                }
+                .boxed()
+            })
+            .await?;
+
+        Ok(updated)
+    }
+
+    async fn approve_enrollment(
+        &self,
+        enrollment: &Enrollment,
+        approver: Approver,
+        by: &Uuid,
+    ) -> StoreResult<bool> {
+        let (enrollment, by) = (enrollment.clone(), *by);
+        let approved = self
+            .db
+            .run_transaction(move |db, transaction| {
+                let enrollment = enrollment.clone();
+                async move {
+                    let (id, mut enrollment) = match read_enrollment(&db, &enrollment).await? {
+                        Some((id, e)) if e.is_pending() => (id, e),
+                        _ => return Ok(false),
+                    };
+
+                    // only the one field, so the teacher and a parent
+                    // approving at the same time don't undo each other
+                    let fields = match approver {
+                        Approver::Teacher => {
+                            enrollment.approved_by = Some(by);
+                            paths!(Enrollment::{approved_by})
+                        }
+                        Approver::Parent => {
+                            enrollment.consented_by = Some(by);
+                            paths!(Enrollment::{consented_by})
+                        }
+                    };
+                    db.fluent()
+                        .update()
+                        .fields(fields)
+                        .in_col(ENROLLMENTS_COLLECTION)
+                        .document_id(&id)
+                        .object(&enrollment)
+                        .add_to_transaction(transaction)?;
+                    Ok(true)
+                }
+                .boxed()
+            })
+            .await?;
+
+        Ok(approved)
+    }
+
+    async fn list_course_enrollments(&self, course_id: &Uuid) -> StoreResult<Vec<Enrollment>> {
+        Ok(query_course_enrollments(&self.db, course_id).await?)
+    }
+
+    async fn list_student_enrollments(&self, student_id: &Uuid) -> StoreResult<Vec<Enrollment>> {
+        let enrollments: Vec<Enrollment> = self
+            .db
+            .fluent()
+            .select()
+            .from(ENROLLMENTS_COLLECTION)
+            .filter(|q| {
+                q.for_all([
+                    //
+                    q.field(path!(Enrollment::student_id)).eq(student_id),
+                ])
+            })
+            .obj()
+            .query()
+            .await?;
+
+        Ok(enrollments)
+    }
+
+    async fn count_course_enrollments(&self, course_id: &Uuid) -> StoreResult<usize> {
+        // enrollments written before they had a status count as active, which
+        // a query on the status field would miss
+        let enrollments = self.list_course_enrollments(course_id).await?;
+
+        Ok(enrollments.iter().filter(|e| e.is_active()).count())
+    }
+
+    async fn take_seat(&self, course_id: &Uuid, capacity: Option<usize>) -> StoreResult<bool> {
+        let (id, course_id) = (course_id.to_string(), *course_id);
+        let taken = self
+            .db
+            .run_transaction(move |db, transaction| {
+                let id = id.clone();
+                async move {
+                    let seats: Option<CourseSeats> = db
+                        .fluent()
+                        .select()
+                        .by_id_in(COURSE_SEATS_COLLECTION)
+                        .obj()
+                        .one(&id)
+                        .await?;
+                    // courses enrolled in before seats were counted start from
+                    // their active enrollments, read in the transaction so two
+                    // first seats can't both start from the same count
+                    let mut seats = match seats {
+                        Some(seats) => seats,
+                        None => CourseSeats {
+                            course_id,
+                            taken: query_course_enrollments(&db, &course_id)
+                                .await?
+                                .iter()
+                                .filter(|e| e.is_active())
+                                .count(),
+                        },
+                    };
+                    if capacity.is_some_and(|c| seats.taken >= c) {
+                        return Ok(false);
+                    }
+
+                    seats.taken += 1;
+                    db.fluent()
+                        .update()
+                        .in_col(COURSE_SEATS_COLLECTION)
+                        .document_id(&id)
+                        .object(&seats)
+                        .add_to_transaction(transaction)?;
+                    Ok(true)
+                }
+                .boxed()
+            })
+            .await?;
+
+        Ok(taken)
+    }
+
+    async fn release_seat(&self, course_id: &Uuid) -> StoreResult<()> {
+        let id = course_id.to_string();
+        self.db
+            .run_transaction(move |db, transaction| {
+                let id = id.clone();
+                async move {
+                    let seats: Option<CourseSeats> = db
+                        .fluent()
+                        .select()
+                        .by_id_in(COURSE_SEATS_COLLECTION)
+                        .obj()
+                        .one(&id)
+                        .await?;
+                    if let Some(mut seats) = seats {
+                        seats.taken = seats.taken.saturating_sub(1);
+                        db.fluent()
+                            .update()
+                            .in_col(COURSE_SEATS_COLLECTION)
+                            .document_id(&id)
+                            .object(&seats)
+                            .add_to_transaction(transaction)?;
+                    }
+                    Ok(())
+                }
+                .boxed()
+            })
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageStore for FirestoreStore {
+    async fn list_messages(
+        &self,
+        user: &User,
+        message_type: MessageType,
+        query: &MessageQuery,
+    ) -> StoreResult<MessagePage> {
+        let filter = &query.filter;
+        let objs_stream: BoxStream<FirestoreResult<Message>> = self
+            .db
+            .fluent()
+            .select()
+            .from(MESSAGES_COLLECTION)
+            .filter(|q| {
+                q.for_all([
+                    match message_type {
+                        MessageType::Received => q
+                            .field("receiver_ids")
+                            .array_contains(user.email.to_string()),
+                        MessageType::Sent => q.field("sender_id").eq(user.uid.to_string()),
+                        MessageType::All => q.for_any([
+                            q.field("receiver_ids")
+                                .array_contains(user.email.to_string()),
+                            q.field("sender_id").eq(user.uid.to_string()),
+                        ]),
+                    },
+                    filter
+                        .sender_id
+                        .and_then(|id| q.field("sender_id").eq(id.to_string())),
+                    filter.from.and_then(|from| {
+                        q.field(path!(Message::created_at))
+                            .greater_than_or_equal(from)
+                    }),
+                    filter
+                        .to
+                        .and_then(|to| q.field(path!(Message::created_at)).less_than(to)),
+                    query.after.as_ref().and_then(|c| {
+                        q.field(path!(Message::created_at))
+                            .less_than_or_equal(c.created_at)
+                    }),
+                ])
+            })
+            .order_by([
+                (
+                    path!(Message::created_at),
+                    FirestoreQueryDirection::Descending,
+                ),
+                (path!(Message::id), FirestoreQueryDirection::Descending),
+            ])
+            .obj()
+            .stream_query_with_errors()
+            .await?;
+
+        // state and subject can't be queried, so the rest of the filter runs
+        // on the stream, which is only read as far as the page needs
+        let messages: Vec<Message> = objs_stream
+            .try_filter(|m| {
+                futures::future::ready(
+                    query.after.as_ref().is_none_or(|c| c.precedes(m))
+                        && filter.matches(&message_view(m.clone(), user)),
+                )
+            })
+            .map_ok(|m| message_view(m, user))
+            .take(query.limit + 1)
+            .try_collect()
+            .await?;
+
+        Ok(MessagePage::from_overfetch(messages, query.limit))
+    }
+
+    async fn get_message(&self, id: &Uuid) -> StoreResult<Option<Message>> {
+        let message: Option<Message> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in(MESSAGES_COLLECTION)
+            .obj()
+            .one(&id.to_string())
+            .await?;
+
+        Ok(message)
+    }
+
+    async fn insert_message(&self, message: &Message) -> StoreResult<()> {
+        self.db
+            .fluent()
+            .insert()
+            .into(MESSAGES_COLLECTION)
+            .document_id(message.id.to_string())
+            .object(message)
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn set_message_state(&self, id: &Uuid, state: MessageState) -> StoreResult<()> {
+        let message = match self.get_message(id).await? {
+            Some(m) => m,
+            _ => {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "message not found",
+                )))
+            }
+        };
+
+        self.db
+            .fluent()
+            .update()
+            .fields(paths!(Message::{state}))
+            .in_col(MESSAGES_COLLECTION)
+            .document_id(&id.to_string())
+            .object(&Message { state, ..message })
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn list_thread(&self, thread_id: &Uuid) -> StoreResult<Vec<Message>> {
+        let objs_stream: BoxStream<FirestoreResult<Message>> = self
+            .db
+            .fluent()
+            .select()
+            .from(MESSAGES_COLLECTION)
+            .filter(|q| q.for_all([q.field("thread_id").eq(thread_id.to_string())]))
+            .order_by([(
+                path!(Message::created_at),
+                FirestoreQueryDirection::Ascending,
+            )])
+            .obj()
+            .stream_query_with_errors()
+            .await?;
+
+        let replies: Vec<Message> = objs_stream.try_collect().await?;
+        let mut thread: Vec<Message> = self.get_message(thread_id).await?.into_iter().collect();
+        thread.extend(replies);
+        Ok(thread)
+    }
+
+    async fn set_message_receipt(&self, id: &Uuid, receipt: &MessageReceipt) -> StoreResult<()> {
+        let (id, receipt) = (id.to_string(), receipt.clone());
+        // the receipts array is rewritten whole, in a transaction so receivers
+        // updating theirs at once don't drop each other's
+        let found = self
+            .db
+            .run_transaction(move |db, transaction| {
+                let (id, receipt) = (id.clone(), receipt.clone());
+                async move {
+                    let message: Option<Message> = db
+                        .fluent()
+                        .select()
+                        .by_id_in(MESSAGES_COLLECTION)
+                        .obj()
+                        .one(&id)
+                        .await?;
+                    let mut message = match message {
+                        Some(m) => m,
+                        None => return Ok(false),
+                    };
+                    message.set_receipt(receipt);
+
+                    db.fluent()
+                        .update()
+                        .fields(paths!(Message::{receipts}))
+                        .in_col(MESSAGES_COLLECTION)
+                        .document_id(&id)
+                        .object(&message)
+                        .add_to_transaction(transaction)?;
+                    Ok(true)
+                }
+                .boxed()
+            })
+            .await?;
+
+        match found {
+            true => Ok(()),
+            false => Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "message not found",
+            ))),
+        }
+    }
+
+    async fn count_unread_messages(&self, user: &User) -> StoreResult<usize> {
+        // receipts is an array of maps, which Firestore can't filter on
+        let objs_stream: BoxStream<FirestoreResult<Message>> = self
+            .db
+            .fluent()
+            .select()
+            .from(MESSAGES_COLLECTION)
+            .filter(|q| {
+                q.for_all([q
+                    .field("receiver_ids")
+                    .array_contains(user.email.to_string())])
+            })
+            .obj()
+            .stream_query_with_errors()
+            .await?;
+
+        let received: Vec<Message> = objs_stream.try_collect().await?;
+        Ok(received
+            .iter()
+            .filter_map(|m| m.receipt(&user.email))
+            .filter(|r| r.state != MessageState::Read)
+            .count())
+    }
+}
+
+#[async_trait]
+impl TokenStore for FirestoreStore {
+    async fn insert_refresh_token(&self, token: &RefreshToken) -> StoreResult<()> {
+        self.db
+            .fluent()
+            .insert()
+            .into(REFRESH_TOKENS_COLLECTION)
+            .document_id(&token.id)
+            .object(token)
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_refresh_token(&self, id: &str) -> StoreResult<Option<RefreshToken>> {
+        let token: Option<RefreshToken> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in(REFRESH_TOKENS_COLLECTION)
+            .obj()
+            .one(id)
+            .await?;
+
+        Ok(token)
+    }
+
+    async fn revoke_refresh_token(&self, id: &str) -> StoreResult<bool> {
+        let id = id.to_string();
+        // read and written in one transaction, so of two refreshes with the
+        // same token only one finds it unspent
+        let revoked = self
+            .db
+            .run_transaction(move |db, transaction| {
+                let id = id.clone();
+                async move {
+                    let token: Option<RefreshToken> = db
+                        .fluent()
+                        .select()
+                        .by_id_in(REFRESH_TOKENS_COLLECTION)
+                        .obj()
+                        .one(&id)
+                        .await?;
+                    let token = match token {
+                        Some(t) if !t.revoked => t,
+                        _ => return Ok(false),
+                    };
+
+                    db.fluent()
+                        .update()
+                        .fields(paths!(RefreshToken::{revoked}))
+                        .in_col(REFRESH_TOKENS_COLLECTION)
+                        .document_id(&id)
+                        .object(&RefreshToken {
+                            revoked: true,
+                            ..token
+                        })
+                        .add_to_transaction(transaction)?;
+                    Ok(true)
+                }
+                .boxed()
+            })
+            .await?;
+
+        Ok(revoked)
+    }
+
+    async fn revoke_access_token(&self, token: &RevokedToken) -> StoreResult<()> {
+        self.db
+            .fluent()
+            .update()
+            .in_col(REVOKED_TOKENS_COLLECTION)
+            .document_id(&token.jti.to_string())
+            .object(token)
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn is_access_token_revoked(&self, jti: &Uuid) -> StoreResult<bool> {
+        let token: Option<RevokedToken> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in(REVOKED_TOKENS_COLLECTION)
+            .obj()
+            .one(&jti.to_string())
+            .await?;
+
+        Ok(token.is_some())
+    }
+
+    async fn revoke_user_refresh_tokens(&self, user_id: &Uuid) -> StoreResult<()> {
+        let tokens: Vec<RefreshToken> = self
+            .db
+            .fluent()
+            .select()
+            .from(REFRESH_TOKENS_COLLECTION)
+            .filter(|q| {
+                q.for_all([
+                    q.field(path!(RefreshToken::user_id))
+                        .eq(user_id.to_string()),
+                    q.field(path!(RefreshToken::revoked)).eq(false),
+                ])
+            })
+            .obj()
+            .query()
+            .await?;
+        if tokens.is_empty() {
+            return Ok(());
+        }
+
+        let batch_writer = self.db.create_simple_batch_writer().await?;
+        let mut current_batch = batch_writer.new_batch();
+        for token in tokens {
+            self.db
+                .fluent()
+                .update()
+                .fields(paths!(RefreshToken::{revoked}))
+                .in_col(REFRESH_TOKENS_COLLECTION)
+                .document_id(&token.id)
+                .object(&RefreshToken {
+                    revoked: true,
+                    ..token.clone()
+                })
+                .add_to_batch(&mut current_batch)?;
+        }
+
+        current_batch.write().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl OutboxStore for FirestoreStore {
+    async fn insert_message_with_outbox(
+        &self,
+        message: &Message,
+        entry: &OutboxEntry,
+    ) -> StoreResult<()> {
+        // a batch is applied atomically
+        let batch_writer = self.db.create_simple_batch_writer().await?;
+        let mut current_batch = batch_writer.new_batch();
+
+        self.db
+            .fluent()
+            .update()
+            .in_col(MESSAGES_COLLECTION)
+            .document_id(message.id.to_string())
+            .object(message)
+            .add_to_batch(&mut current_batch)?;
+        self.db
+            .fluent()
+            .update()
+            .in_col(OUTBOX_COLLECTION)
+            .document_id(entry.message_id.to_string())
+            .object(entry)
+            .add_to_batch(&mut current_batch)?;
+
+        current_batch.write().await?;
+        Ok(())
+    }
+
+    async fn claim_outbox_entries(
+        &self,
+        now: &DateTime<Utc>,
+        lease_until: &DateTime<Utc>,
+        limit: usize,
+    ) -> StoreResult<Vec<OutboxEntry>> {
+        let objs_stream: BoxStream<FirestoreResult<OutboxEntry>> = self
+            .db
+            .fluent()
+            .select()
+            .from(OUTBOX_COLLECTION)
+            .filter(|q| {
+                q.for_all([
+                    q.field(path!(OutboxEntry::state)).eq(&OutboxState::Pending),
+                    q.field(path!(OutboxEntry::next_attempt_at))
+                        .less_than_or_equal(*now),
+                ])
+            })
+            .order_by([(
+                path!(OutboxEntry::next_attempt_at),
+                FirestoreQueryDirection::Ascending,
+            )])
+            .limit(limit as u32)
+            .obj()
+            .stream_query_with_errors()
+            .await?;
+
+        let due: Vec<OutboxEntry> = objs_stream.try_collect().await?;
+
+        // each is claimed in a transaction checking it's still due, so of
+        // workers querying at once only one gets it
+        let mut claimed = Vec::with_capacity(due.len());
+        for entry in due {
+            let (id, now, lease_until) = (entry.message_id.to_string(), *now, *lease_until);
+            let entry = self
+                .db
+                .run_transaction(move |db, transaction| {
+                    let id = id.clone();
+                    async move {
+                        let entry: Option<OutboxEntry> = db
+                            .fluent()
+                            .select()
+                            .by_id_in(OUTBOX_COLLECTION)
+                            .obj()
+                            .one(&id)
+                            .await?;
+                        let entry = match entry {
+                            Some(e)
+                                if e.state == OutboxState::Pending && e.next_attempt_at <= now =>
+                            {
+                                OutboxEntry {
+                                    next_attempt_at: lease_until,
+                                    ..e
+                                }
+                            }
+                            _ => return Ok(None),
+                        };
+
+                        db.fluent()
+                            .update()
+                            .in_col(OUTBOX_COLLECTION)
+                            .document_id(&id)
+                            .object(&entry)
+                            .add_to_transaction(transaction)?;
+                        Ok(Some(entry))
+                    }
+                    .boxed()
+                })
+                .await?;
+            claimed.extend(entry);
+        }
+
+        Ok(claimed)
+    }
+
+    async fn update_outbox_entry(&self, entry: &OutboxEntry) -> StoreResult<()> {
+        self.db
+            .fluent()
+            .update()
+            .in_col(OUTBOX_COLLECTION)
+            .document_id(entry.message_id.to_string())
+            .object(entry)
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_outbox_entry(&self, message_id: &Uuid) -> StoreResult<Option<OutboxEntry>> {
+        let entry: Option<OutboxEntry> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in(OUTBOX_COLLECTION)
+            .obj()
+            .one(&message_id.to_string())
+            .await?;
+
+        Ok(entry)
+    }
+}
+
+#[async_trait]
+impl DigestStore for FirestoreStore {
+    async fn get_digest_schedule(&self, user_id: &Uuid) -> StoreResult<Option<DigestSchedule>> {
+        let schedule: Option<DigestSchedule> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in(DIGESTS_COLLECTION)
+            .obj()
+            .one(&user_id.to_string())
+            .await?;
+
+        Ok(schedule)
+    }
+
+    async fn set_digest_schedule(&self, schedule: &DigestSchedule) -> StoreResult<()> {
+        self.db
+            .fluent()
+            .update()
+            .in_col(DIGESTS_COLLECTION)
+            .document_id(schedule.user_id.to_string())
+            .object(schedule)
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_digest_schedule(&self, user_id: &Uuid) -> StoreResult<()> {
+        self.db
+            .fluent()
+            .delete()
+            .from(DIGESTS_COLLECTION)
+            .document_id(user_id.to_string())
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn claim_digest_schedules(
+        &self,
+        now: &DateTime<Utc>,
+        lease_until: &DateTime<Utc>,
+        limit: usize,
+    ) -> StoreResult<Vec<DigestSchedule>> {
+        let objs_stream: BoxStream<FirestoreResult<DigestSchedule>> = self
+            .db
+            .fluent()
+            .select()
+            .from(DIGESTS_COLLECTION)
+            .filter(|q| {
+                q.for_all([q
+                    .field(path!(DigestSchedule::next_digest_at))
+                    .less_than_or_equal(*now)])
+            })
+            .order_by([(
+                path!(DigestSchedule::next_digest_at),
+                FirestoreQueryDirection::Ascending,
+            )])
+            .limit(limit as u32)
+            .obj()
+            .stream_query_with_errors()
+            .await?;
+
+        let due: Vec<DigestSchedule> = objs_stream.try_collect().await?;
+
+        // claimed one by one in transactions, like `claim_outbox_entries`
+        let mut claimed = Vec::with_capacity(due.len());
+        for schedule in due {
+            let (id, now, lease_until) = (schedule.user_id.to_string(), *now, *lease_until);
+            let schedule = self
+                .db
+                .run_transaction(move |db, transaction| {
+                    let id = id.clone();
+                    async move {
+                        let schedule: Option<DigestSchedule> = db
+                            .fluent()
+                            .select()
+                            .by_id_in(DIGESTS_COLLECTION)
+                            .obj()
+                            .one(&id)
+                            .await?;
+                        let schedule = match schedule {
+                            Some(s) if s.next_digest_at <= now => DigestSchedule {
+                                next_digest_at: lease_until,
+                                ..s
+                            },
+                            _ => return Ok(None),
+                        };
+
+                        db.fluent()
+                            .update()
+                            .in_col(DIGESTS_COLLECTION)
+                            .document_id(&id)
+                            .object(&schedule)
+                            .add_to_transaction(transaction)?;
+                        Ok(Some(schedule))
+                    }
+                    .boxed()
+                })
+                .await?;
+            claimed.extend(schedule);
+        }
+
+        Ok(claimed)
+    }
+}
+
+#[async_trait]
+impl AttachmentStore for FirestoreStore {
+    async fn insert_attachment(&self, attachment: &Attachment) -> StoreResult<()> {
+        self.db
+            .fluent()
+            .insert()
+            .into(ATTACHMENTS_COLLECTION)
+            .document_id(&attachment.id.to_string())
+            .object(attachment)
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_attachment(&self, id: &Uuid) -> StoreResult<Option<Attachment>> {
+        let attachment: Option<Attachment> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in(ATTACHMENTS_COLLECTION)
+            .obj()
+            .one(&id.to_string())
+            .await?;
+
+        Ok(attachment)
+    }
+
+    async fn update_attachment(&self, attachment: &Attachment) -> StoreResult<()> {
+        self.db
+            .fluent()
+            .update()
+            .in_col(ATTACHMENTS_COLLECTION)
+            .document_id(attachment.id.to_string())
+            .object(attachment)
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AuditStore for FirestoreStore {
+    async fn insert_audit_entry(&self, entry: &AuditEntry) -> StoreResult<()> {
+        self.db
+            .fluent()
+            .insert()
+            .into(AUDIT_LOG_COLLECTION)
+            .document_id(&entry.id.to_string())
+            .object(entry)
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn list_audit_entries(
+        &self,
+        target_id: Option<&Uuid>,
+        limit: usize,
+    ) -> StoreResult<Vec<AuditEntry>> {
+        let entries: Vec<AuditEntry> = self
+            .db
+            .fluent()
+            .select()
+            .from(AUDIT_LOG_COLLECTION)
+            .filter(|q| {
+                q.for_all([target_id
+                    .and_then(|id| q.field(path!(AuditEntry::target_id)).eq(id.to_string()))])
+            })
+            .order_by([
+                (
+                    path!(AuditEntry::created_at),
+                    FirestoreQueryDirection::Descending,
+                ),
+                (path!(AuditEntry::id), FirestoreQueryDirection::Descending),
+            ])
+            .limit(limit as u32)
+            .obj()
+            .query()
+            .await?;
+
+        Ok(entries)
+    }
+}
@@ -0,0 +1,915 @@
+use crate::common::message::{message_view, MessagePage, MessageQuery, MessageType};
+use crate::common::store::{
+    AttachmentStore, AuditStore, CourseStore, DigestStore, EnrollmentStore, LessonStore,
+    MessageStore, OutboxStore, StoreResult, StudentParentStore, TokenStore, UserStore,
+};
+use crate::common::user::{UserPage, UserQuery};
+use crate::common::{
+    Approver, Attachment, AuditEntry, Course, CourseModule, DigestSchedule, Enrollment,
+    EnrollmentStatus, Lesson, LessonCompletion, Message, MessageReceipt, MessageState,
+    NotificationPreferences, OutboxEntry, OutboxState, RefreshToken, RevokedToken, StudentsParents,
+    User, UserRole, UserWithPassword,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use uuid::Uuid;
+
+/// Seed data for [`MemoryStore`], keyed by the Firestore collection names so
+/// an export of the real collections can be loaded as-is.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Fixtures {
+    #[serde(default)]
+    pub users: Vec<UserWithPassword>,
+    #[serde(default, rename = "students-parents")]
+    pub students_parents: Vec<StudentsParents>,
+    #[serde(default)]
+    pub courses: Vec<Course>,
+    #[serde(default, rename = "enrollment")]
+    pub enrollments: Vec<Enrollment>,
+    #[serde(default)]
+    pub messages: Vec<Message>,
+}
+
+impl Fixtures {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> StoreResult<Self> {
+        let raw = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&raw)?)
+    }
+
+    /// The fixture set shipped in `fixtures/test.json`, used by unit tests.
+    #[cfg(test)]
+    pub fn test() -> Self {
+        serde_json::from_str(include_str!("../../../../fixtures/test.json"))
+            .expect("failed to parse test fixtures")
+    }
+
+    /// The id of a user or course in `test()` by name: `system`, `teacher`,
+    /// `student` and `parent`, or the `math` and `science` courses.
+    #[cfg(test)]
+    pub fn id(name: &str) -> Uuid {
+        let id = match name {
+            "system" => "d829962e-3fbf-4d28-99ea-a5ae9893ce1a",
+            "teacher" => "30e0ebf1-da41-4686-b38d-76ca74ecd523",
+            "student" => "21857bd9-f520-4842-b608-e07d858e9518",
+            "parent" => "7a53967f-54d3-49a5-96c7-0a9842a1ce3f",
+            "math" => "893a39bd-17d3-4a59-9b08-f2a1572fdd88",
+            "science" => "521cf41a-045f-47bf-a544-c9ccc069b816",
+            _ => panic!("no test fixture named {}", name),
+        };
+        Uuid::parse_str(id).expect("failed to parse uuid")
+    }
+}
+
+#[derive(Default)]
+struct MemoryData {
+    users: HashMap<Uuid, UserWithPassword>,
+    students_parents: Vec<StudentsParents>,
+    courses: HashMap<Uuid, Course>,
+    modules: HashMap<Uuid, CourseModule>,
+    lessons: HashMap<Uuid, Lesson>,
+    completions: Vec<LessonCompletion>,
+    enrollments: HashMap<Uuid, Enrollment>,
+    messages: HashMap<Uuid, Message>,
+    refresh_tokens: HashMap<String, RefreshToken>,
+    revoked_tokens: HashMap<Uuid, RevokedToken>,
+    outbox: HashMap<Uuid, OutboxEntry>,
+    digests: HashMap<Uuid, DigestSchedule>,
+    attachments: HashMap<Uuid, Attachment>,
+    // taken seats by course
+    seats: HashMap<Uuid, usize>,
+    audit_log: Vec<AuditEntry>,
+}
+
+/// Process-local backend for tests and offline development. Nothing is
+/// persisted, every restart begins from the fixtures again.
+#[derive(Default)]
+pub struct MemoryStore {
+    data: RwLock<MemoryData>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    pub fn from_fixtures(fixtures: Fixtures) -> Self {
+        let mut seats = HashMap::new();
+        for e in fixtures.enrollments.iter().filter(|e| e.is_active()) {
+            *seats.entry(e.course_id).or_insert(0) += 1;
+        }
+        let data = MemoryData {
+            users: fixtures.users.into_iter().map(|u| (u.uid, u)).collect(),
+            students_parents: fixtures.students_parents,
+            courses: fixtures.courses.into_iter().map(|c| (c.id, c)).collect(),
+            enrollments: fixtures
+                .enrollments
+                .into_iter()
+                .map(|e| (e.id, e))
+                .collect(),
+            messages: fixtures.messages.into_iter().map(|m| (m.id, m)).collect(),
+            seats,
+            ..MemoryData::default()
+        };
+
+        MemoryStore {
+            data: RwLock::new(data),
+        }
+    }
+
+    pub fn from_fixtures_file<P: AsRef<Path>>(path: P) -> StoreResult<Self> {
+        Ok(MemoryStore::from_fixtures(Fixtures::from_file(path)?))
+    }
+
+    /// A user of the test fixtures by name, see `Fixtures::id`.
+    #[cfg(test)]
+    pub async fn fixture_user(&self, name: &str) -> User {
+        self.get_user(&Fixtures::id(name))
+            .await
+            .expect("failed to load user")
+            .expect("test fixture user missing")
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, MemoryData> {
+        self.data.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, MemoryData> {
+        self.data.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn not_found(what: &str) -> Box<dyn std::error::Error + Send + Sync> {
+    Box::new(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("{} not found", what),
+    ))
+}
+
+#[async_trait]
+impl UserStore for MemoryStore {
+    async fn get_user(&self, id: &Uuid) -> StoreResult<Option<User>> {
+        Ok(self.read().users.get(id).cloned().map(User::from))
+    }
+
+    async fn find_user(&self, email_or_id: &str) -> StoreResult<Option<UserWithPassword>> {
+        Ok(self
+            .read()
+            .users
+            .values()
+            .find(|u| u.email == email_or_id || u.uid.to_string() == email_or_id)
+            .cloned())
+    }
+
+    async fn insert_user(&self, user: &UserWithPassword) -> StoreResult<()> {
+        self.write().users.insert(user.uid, user.clone());
+        Ok(())
+    }
+
+    async fn set_devices(&self, id: &Uuid, devices: &[String]) -> StoreResult<()> {
+        match self.write().users.get_mut(id) {
+            Some(u) => {
+                u.devices = devices.to_vec();
+                Ok(())
+            }
+            _ => Err(not_found("user")),
+        }
+    }
+
+    async fn set_preferences(
+        &self,
+        id: &Uuid,
+        preferences: &NotificationPreferences,
+    ) -> StoreResult<()> {
+        match self.write().users.get_mut(id) {
+            Some(u) => {
+                u.preferences = preferences.clone();
+                Ok(())
+            }
+            _ => Err(not_found("user")),
+        }
+    }
+
+    async fn get_users(&self, ids: &[Uuid]) -> StoreResult<Vec<User>> {
+        let data = self.read();
+        Ok(ids
+            .iter()
+            .filter_map(|id| data.users.get(id).cloned().map(User::from))
+            .collect())
+    }
+
+    async fn get_users_by_emails(&self, emails: &[String]) -> StoreResult<Vec<User>> {
+        Ok(self
+            .read()
+            .users
+            .values()
+            .filter(|u| emails.contains(&u.email))
+            .cloned()
+            .map(User::from)
+            .collect())
+    }
+
+    async fn get_system_user(&self) -> StoreResult<Option<User>> {
+        Ok(self
+            .read()
+            .users
+            .values()
+            .find(|u| u.role == UserRole::System)
+            .cloned()
+            .map(User::from))
+    }
+
+    async fn list_users(&self, query: &UserQuery) -> StoreResult<UserPage> {
+        let mut users = self
+            .read()
+            .users
+            .values()
+            .cloned()
+            .map(User::from)
+            .filter(|u| query.matches(u))
+            .filter(|u| query.after.as_ref().is_none_or(|after| u.email > *after))
+            .collect::<Vec<_>>();
+        users.sort_by(|a, b| a.email.cmp(&b.email));
+        users.truncate(query.limit + 1);
+
+        Ok(UserPage::from_overfetch(users, query.limit))
+    }
+
+    async fn update_account(&self, user: &User) -> StoreResult<()> {
+        match self.write().users.get_mut(&user.uid) {
+            Some(u) => {
+                u.name = user.name.clone();
+                u.role = user.role;
+                u.deactivated_at = user.deactivated_at;
+                Ok(())
+            }
+            _ => Err(not_found("user")),
+        }
+    }
+
+    async fn set_password(&self, id: &Uuid, password: &str) -> StoreResult<()> {
+        match self.write().users.get_mut(id) {
+            Some(u) => {
+                u.password = password.to_string();
+                Ok(())
+            }
+            _ => Err(not_found("user")),
+        }
+    }
+}
+
+#[async_trait]
+impl StudentParentStore for MemoryStore {
+    async fn link_students(&self, parent_id: &Uuid, student_ids: &[Uuid]) -> StoreResult<()> {
+        let mut data = self.write();
+        for id in student_ids {
+            let exists = data
+                .students_parents
+                .iter()
+                .any(|sp| sp.student_id == *id && sp.parent_id == *parent_id);
+            if !exists {
+                data.students_parents.push(StudentsParents {
+                    student_id: *id,
+                    parent_id: *parent_id,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn list_parents_of(&self, student_id: &Uuid) -> StoreResult<Vec<StudentsParents>> {
+        Ok(self
+            .read()
+            .students_parents
+            .iter()
+            .filter(|sp| sp.student_id == *student_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn list_students_of(&self, parent_id: &Uuid) -> StoreResult<Vec<StudentsParents>> {
+        Ok(self
+            .read()
+            .students_parents
+            .iter()
+            .filter(|sp| sp.parent_id == *parent_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn unlink_student(&self, parent_id: &Uuid, student_id: &Uuid) -> StoreResult<bool> {
+        let mut data = self.write();
+        let before = data.students_parents.len();
+        data.students_parents
+            .retain(|sp| sp.student_id != *student_id || sp.parent_id != *parent_id);
+        Ok(data.students_parents.len() < before)
+    }
+}
+
+#[async_trait]
+impl CourseStore for MemoryStore {
+    async fn list_courses(&self) -> StoreResult<Vec<Course>> {
+        Ok(self.read().courses.values().cloned().collect())
+    }
+
+    async fn get_course(&self, id: &Uuid) -> StoreResult<Option<Course>> {
+        Ok(self.read().courses.get(id).cloned())
+    }
+
+    async fn get_courses(&self, ids: &[Uuid]) -> StoreResult<Vec<Course>> {
+        let data = self.read();
+        Ok(ids
+            .iter()
+            .filter_map(|id| data.courses.get(id).cloned())
+            .collect())
+    }
+
+    async fn list_teacher_courses(&self, teacher_id: &Uuid) -> StoreResult<Vec<Course>> {
+        Ok(self
+            .read()
+            .courses
+            .values()
+            .filter(|c| c.teacher_id == *teacher_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn insert_course(&self, course: &Course) -> StoreResult<()> {
+        self.write().courses.insert(course.id, course.clone());
+        Ok(())
+    }
+
+    async fn update_course(&self, course: &Course) -> StoreResult<()> {
+        match self.write().courses.get_mut(&course.id) {
+            Some(c) => {
+                *c = course.clone();
+                Ok(())
+            }
+            _ => Err(not_found("course")),
+        }
+    }
+}
+
+#[async_trait]
+impl LessonStore for MemoryStore {
+    async fn list_course_modules(&self, course_id: &Uuid) -> StoreResult<Vec<CourseModule>> {
+        let mut modules: Vec<CourseModule> = self
+            .read()
+            .modules
+            .values()
+            .filter(|m| m.course_id == *course_id)
+            .cloned()
+            .collect();
+        modules.sort_by_key(|m| m.position);
+        Ok(modules)
+    }
+
+    async fn insert_module(&self, module: &CourseModule) -> StoreResult<()> {
+        self.write().modules.insert(module.id, module.clone());
+        Ok(())
+    }
+
+    async fn update_module(&self, module: &CourseModule) -> StoreResult<()> {
+        match self.write().modules.get_mut(&module.id) {
+            Some(m) => {
+                *m = module.clone();
+                Ok(())
+            }
+            _ => Err(not_found("module")),
+        }
+    }
+
+    async fn delete_module(&self, id: &Uuid) -> StoreResult<()> {
+        self.write().modules.remove(id);
+        Ok(())
+    }
+
+    async fn reorder_modules(&self, course_id: &Uuid, ids: &[Uuid]) -> StoreResult<()> {
+        let mut data = self.write();
+        for (position, id) in ids.iter().enumerate() {
+            if let Some(m) = data
+                .modules
+                .get_mut(id)
+                .filter(|m| m.course_id == *course_id)
+            {
+                m.position = position as u32;
+            }
+        }
+        Ok(())
+    }
+
+    async fn list_course_lessons(&self, course_id: &Uuid) -> StoreResult<Vec<Lesson>> {
+        let mut lessons: Vec<Lesson> = self
+            .read()
+            .lessons
+            .values()
+            .filter(|l| l.course_id == *course_id)
+            .cloned()
+            .collect();
+        lessons.sort_by_key(|l| l.position);
+        Ok(lessons)
+    }
+
+    async fn get_lesson(&self, id: &Uuid) -> StoreResult<Option<Lesson>> {
+        Ok(self.read().lessons.get(id).cloned())
+    }
+
+    async fn insert_lesson(&self, lesson: &Lesson) -> StoreResult<()> {
+        self.write().lessons.insert(lesson.id, lesson.clone());
+        Ok(())
+    }
+
+    async fn update_lesson(&self, lesson: &Lesson) -> StoreResult<()> {
+        match self.write().lessons.get_mut(&lesson.id) {
+            Some(l) => {
+                *l = lesson.clone();
+                Ok(())
+            }
+            _ => Err(not_found("lesson")),
+        }
+    }
+
+    async fn delete_lesson(&self, id: &Uuid) -> StoreResult<()> {
+        let mut data = self.write();
+        data.lessons.remove(id);
+        data.completions.retain(|c| c.lesson_id != *id);
+        Ok(())
+    }
+
+    async fn reorder_lessons(
+        &self,
+        course_id: &Uuid,
+        module_id: &Uuid,
+        ids: &[Uuid],
+    ) -> StoreResult<()> {
+        let mut data = self.write();
+        for (position, id) in ids.iter().enumerate() {
+            if let Some(l) = data
+                .lessons
+                .get_mut(id)
+                .filter(|l| l.course_id == *course_id)
+            {
+                l.module_id = *module_id;
+                l.position = position as u32;
+            }
+        }
+        Ok(())
+    }
+
+    async fn insert_lesson_completion(&self, completion: &LessonCompletion) -> StoreResult<bool> {
+        let mut data = self.write();
+        let done = data
+            .completions
+            .iter()
+            .any(|c| c.lesson_id == completion.lesson_id && c.student_id == completion.student_id);
+        if !done {
+            data.completions.push(completion.clone());
+        }
+        Ok(!done)
+    }
+
+    async fn list_course_completions(
+        &self,
+        course_id: &Uuid,
+    ) -> StoreResult<Vec<LessonCompletion>> {
+        Ok(self
+            .read()
+            .completions
+            .iter()
+            .filter(|c| c.course_id == *course_id)
+            .cloned()
+            .collect())
+    }
+}
+
+#[async_trait]
+impl EnrollmentStore for MemoryStore {
+    async fn find_enrollment(
+        &self,
+        student_id: &Uuid,
+        course_id: &Uuid,
+    ) -> StoreResult<Option<Enrollment>> {
+        Ok(self
+            .read()
+            .enrollments
+            .values()
+            .find(|e| e.student_id == *student_id && e.course_id == *course_id)
+            .cloned())
+    }
+
+    async fn insert_enrollment(&self, enrollment: &Enrollment) -> StoreResult<bool> {
+        let mut data = self.write();
+        let exists = data
+            .enrollments
+            .values()
+            .any(|e| e.student_id == enrollment.student_id && e.course_id == enrollment.course_id);
+        if exists {
+            return Ok(false);
+        }
+
+        data.enrollments.insert(enrollment.id, enrollment.clone());
+        Ok(true)
+    }
+
+    async fn insert_enrollments(&self, enrollments: &[Enrollment]) -> StoreResult<Vec<Enrollment>> {
+        let mut data = self.write();
+        let mut written = Vec::new();
+        for enrollment in enrollments {
+            let exists = data.enrollments.values().any(|e| {
+                e.student_id == enrollment.student_id && e.course_id == enrollment.course_id
+            });
+            if !exists {
+                data.enrollments.insert(enrollment.id, enrollment.clone());
+                written.push(enrollment.clone());
+            }
+        }
+        Ok(written)
+    }
+
+    async fn update_enrollment(
+        &self,
+        enrollment: &Enrollment,
+        from: EnrollmentStatus,
+    ) -> StoreResult<bool> {
+        match self.write().enrollments.get_mut(&enrollment.id) {
+            Some(e) if e.status == from => {
+                *e = enrollment.clone();
+                Ok(true)
+            }
+            Some(_) => Ok(false),
+            None => Err(not_found("enrollment")),
+        }
+    }
+
+    async fn approve_enrollment(
+        &self,
+        enrollment: &Enrollment,
+        approver: Approver,
+        by: &Uuid,
+    ) -> StoreResult<bool> {
+        match self.write().enrollments.get_mut(&enrollment.id) {
+            Some(e) if e.is_pending() => {
+                match approver {
+                    Approver::Teacher => e.approved_by = Some(*by),
+                    Approver::Parent => e.consented_by = Some(*by),
+                }
+                Ok(true)
+            }
+            Some(_) => Ok(false),
+            None => Err(not_found("enrollment")),
+        }
+    }
+
+    async fn list_course_enrollments(&self, course_id: &Uuid) -> StoreResult<Vec<Enrollment>> {
+        Ok(self
+            .read()
+            .enrollments
+            .values()
+            .filter(|e| e.course_id == *course_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn list_student_enrollments(&self, student_id: &Uuid) -> StoreResult<Vec<Enrollment>> {
+        Ok(self
+            .read()
+            .enrollments
+            .values()
+            .filter(|e| e.student_id == *student_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn count_course_enrollments(&self, course_id: &Uuid) -> StoreResult<usize> {
+        Ok(self
+            .read()
+            .enrollments
+            .values()
+            .filter(|e| e.course_id == *course_id && e.is_active())
+            .count())
+    }
+
+    async fn take_seat(&self, course_id: &Uuid, capacity: Option<usize>) -> StoreResult<bool> {
+        let mut data = self.write();
+        let taken = data.seats.entry(*course_id).or_insert(0);
+        if capacity.is_some_and(|c| *taken >= c) {
+            return Ok(false);
+        }
+
+        *taken += 1;
+        Ok(true)
+    }
+
+    async fn release_seat(&self, course_id: &Uuid) -> StoreResult<()> {
+        if let Some(taken) = self.write().seats.get_mut(course_id) {
+            *taken = taken.saturating_sub(1);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageStore for MemoryStore {
+    async fn list_messages(
+        &self,
+        user: &User,
+        message_type: MessageType,
+        query: &MessageQuery,
+    ) -> StoreResult<MessagePage> {
+        let received = |m: &Message| m.receiver_ids.contains(&user.email);
+        let sent = |m: &Message| m.sender_id == user.uid;
+
+        let mut messages: Vec<Message> = self
+            .read()
+            .messages
+            .values()
+            .filter(|m| match message_type {
+                MessageType::Received => received(m),
+                MessageType::Sent => sent(m),
+                MessageType::All => received(m) || sent(m),
+            })
+            .filter(|m| query.after.as_ref().is_none_or(|c| c.precedes(m)))
+            .map(|m| message_view(m.clone(), user))
+            .filter(|m| query.filter.matches(m))
+            .collect();
+
+        messages.sort_by_key(|m| std::cmp::Reverse((m.created_at, m.id)));
+        messages.truncate(query.limit + 1);
+        Ok(MessagePage::from_overfetch(messages, query.limit))
+    }
+
+    async fn get_message(&self, id: &Uuid) -> StoreResult<Option<Message>> {
+        Ok(self.read().messages.get(id).cloned())
+    }
+
+    async fn insert_message(&self, message: &Message) -> StoreResult<()> {
+        self.write().messages.insert(message.id, message.clone());
+        Ok(())
+    }
+
+    async fn set_message_state(&self, id: &Uuid, state: MessageState) -> StoreResult<()> {
+        match self.write().messages.get_mut(id) {
+            Some(m) => {
+                m.state = state;
+                Ok(())
+            }
+            _ => Err(not_found("message")),
+        }
+    }
+
+    async fn list_thread(&self, thread_id: &Uuid) -> StoreResult<Vec<Message>> {
+        let mut messages: Vec<Message> = self
+            .read()
+            .messages
+            .values()
+            .filter(|m| m.id == *thread_id || m.thread_id == Some(*thread_id))
+            .cloned()
+            .collect();
+
+        messages.sort_by_key(|m| m.created_at);
+        Ok(messages)
+    }
+
+    async fn set_message_receipt(&self, id: &Uuid, receipt: &MessageReceipt) -> StoreResult<()> {
+        match self.write().messages.get_mut(id) {
+            Some(m) => {
+                m.set_receipt(receipt.clone());
+                Ok(())
+            }
+            _ => Err(not_found("message")),
+        }
+    }
+
+    async fn count_unread_messages(&self, user: &User) -> StoreResult<usize> {
+        Ok(self
+            .read()
+            .messages
+            .values()
+            .filter_map(|m| m.receipt(&user.email))
+            .filter(|r| r.state != MessageState::Read)
+            .count())
+    }
+}
+
+#[async_trait]
+impl TokenStore for MemoryStore {
+    async fn insert_refresh_token(&self, token: &RefreshToken) -> StoreResult<()> {
+        self.write()
+            .refresh_tokens
+            .insert(token.id.clone(), token.clone());
+        Ok(())
+    }
+
+    async fn get_refresh_token(&self, id: &str) -> StoreResult<Option<RefreshToken>> {
+        Ok(self.read().refresh_tokens.get(id).cloned())
+    }
+
+    async fn revoke_refresh_token(&self, id: &str) -> StoreResult<bool> {
+        match self.write().refresh_tokens.get_mut(id) {
+            Some(t) if !t.revoked => {
+                t.revoked = true;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn revoke_access_token(&self, token: &RevokedToken) -> StoreResult<()> {
+        self.write().revoked_tokens.insert(token.jti, token.clone());
+        Ok(())
+    }
+
+    async fn is_access_token_revoked(&self, jti: &Uuid) -> StoreResult<bool> {
+        Ok(self.read().revoked_tokens.contains_key(jti))
+    }
+
+    async fn revoke_user_refresh_tokens(&self, user_id: &Uuid) -> StoreResult<()> {
+        for t in self.write().refresh_tokens.values_mut() {
+            if t.user_id == *user_id {
+                t.revoked = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl OutboxStore for MemoryStore {
+    async fn insert_message_with_outbox(
+        &self,
+        message: &Message,
+        entry: &OutboxEntry,
+    ) -> StoreResult<()> {
+        let mut data = self.write();
+        data.messages.insert(message.id, message.clone());
+        data.outbox.insert(entry.message_id, entry.clone());
+        Ok(())
+    }
+
+    async fn claim_outbox_entries(
+        &self,
+        now: &DateTime<Utc>,
+        lease_until: &DateTime<Utc>,
+        limit: usize,
+    ) -> StoreResult<Vec<OutboxEntry>> {
+        let mut data = self.write();
+        let mut due: Vec<&mut OutboxEntry> = data
+            .outbox
+            .values_mut()
+            .filter(|e| e.state == OutboxState::Pending && e.next_attempt_at <= *now)
+            .collect();
+        due.sort_by_key(|e| e.next_attempt_at);
+
+        Ok(due
+            .into_iter()
+            .take(limit)
+            .map(|e| {
+                e.next_attempt_at = *lease_until;
+                e.clone()
+            })
+            .collect())
+    }
+
+    async fn update_outbox_entry(&self, entry: &OutboxEntry) -> StoreResult<()> {
+        match self.write().outbox.get_mut(&entry.message_id) {
+            Some(e) => {
+                *e = entry.clone();
+                Ok(())
+            }
+            _ => Err(not_found("outbox entry")),
+        }
+    }
+
+    async fn get_outbox_entry(&self, message_id: &Uuid) -> StoreResult<Option<OutboxEntry>> {
+        Ok(self.read().outbox.get(message_id).cloned())
+    }
+}
+
+#[async_trait]
+impl DigestStore for MemoryStore {
+    async fn get_digest_schedule(&self, user_id: &Uuid) -> StoreResult<Option<DigestSchedule>> {
+        Ok(self.read().digests.get(user_id).cloned())
+    }
+
+    async fn set_digest_schedule(&self, schedule: &DigestSchedule) -> StoreResult<()> {
+        self.write()
+            .digests
+            .insert(schedule.user_id, schedule.clone());
+        Ok(())
+    }
+
+    async fn delete_digest_schedule(&self, user_id: &Uuid) -> StoreResult<()> {
+        self.write().digests.remove(user_id);
+        Ok(())
+    }
+
+    async fn claim_digest_schedules(
+        &self,
+        now: &DateTime<Utc>,
+        lease_until: &DateTime<Utc>,
+        limit: usize,
+    ) -> StoreResult<Vec<DigestSchedule>> {
+        let mut data = self.write();
+        let mut due: Vec<&mut DigestSchedule> = data
+            .digests
+            .values_mut()
+            .filter(|d| d.next_digest_at <= *now)
+            .collect();
+        due.sort_by_key(|d| d.next_digest_at);
+
+        Ok(due
+            .into_iter()
+            .take(limit)
+            .map(|d| {
+                d.next_digest_at = *lease_until;
+                d.clone()
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl AttachmentStore for MemoryStore {
+    async fn insert_attachment(&self, attachment: &Attachment) -> StoreResult<()> {
+        self.write()
+            .attachments
+            .insert(attachment.id, attachment.clone());
+        Ok(())
+    }
+
+    async fn get_attachment(&self, id: &Uuid) -> StoreResult<Option<Attachment>> {
+        Ok(self.read().attachments.get(id).cloned())
+    }
+
+    async fn update_attachment(&self, attachment: &Attachment) -> StoreResult<()> {
+        match self.write().attachments.get_mut(&attachment.id) {
+            Some(a) => {
+                *a = attachment.clone();
+                Ok(())
+            }
+            _ => Err(not_found("attachment")),
+        }
+    }
+}
+
+#[async_trait]
+impl AuditStore for MemoryStore {
+    async fn insert_audit_entry(&self, entry: &AuditEntry) -> StoreResult<()> {
+        self.write().audit_log.push(entry.clone());
+        Ok(())
+    }
+
+    async fn list_audit_entries(
+        &self,
+        target_id: Option<&Uuid>,
+        limit: usize,
+    ) -> StoreResult<Vec<AuditEntry>> {
+        let mut entries = self
+            .read()
+            .audit_log
+            .iter()
+            .filter(|e| target_id.is_none_or(|id| e.target_id == *id))
+            .cloned()
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|e| std::cmp::Reverse((e.created_at, e.id)));
+        entries.truncate(limit);
+
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::store::{EnrollmentStore, Fixtures, MemoryStore, UserStore};
+    use crate::common::{Enrollment, UserRole};
+
+    #[tokio::test]
+    async fn test_fixtures_load_async() {
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+
+        let sys = db.get_system_user().await.unwrap().unwrap();
+        assert_eq!(sys.role, UserRole::System);
+
+        let teacher = db.find_user("t1@t1.com").await.unwrap().unwrap();
+        assert_eq!(teacher.role, UserRole::Teacher);
+    }
+
+    #[tokio::test]
+    async fn test_enrollment_is_unique_async() {
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+        let student = db.find_user("hl@hl.com").await.unwrap().unwrap();
+        let course_id = Fixtures::id("math");
+        let before = db.count_course_enrollments(&course_id).await.unwrap();
+
+        let enrollment = Enrollment::new(student.uid, course_id);
+        assert!(!db.insert_enrollment(&enrollment).await.unwrap());
+        assert_eq!(
+            db.count_course_enrollments(&course_id).await.unwrap(),
+            before
+        );
+    }
+}
@@ -0,0 +1,327 @@
+// src/lib/common/store
+//
+// Repository traits for every collection the API touches. Handlers and the
+// business logic in `common::*` only ever see `dyn Store`, the concrete
+// backend is picked once in the server binary.
+mod firestore;
+mod memory;
+mod sql;
+
+pub use self::firestore::FirestoreStore;
+pub use self::memory::{Fixtures, MemoryStore};
+pub use self::sql::SqlStore;
+
+use crate::common::message::{MessagePage, MessageQuery, MessageType};
+use crate::common::user::{UserPage, UserQuery};
+use crate::common::{
+    Approver, Attachment, AuditEntry, Course, CourseModule, DigestSchedule, Enrollment,
+    EnrollmentStatus, Lesson, LessonCompletion, Message, MessageReceipt, MessageState,
+    NotificationPreferences, OutboxEntry, RefreshToken, RevokedToken, StudentsParents, User,
+    UserWithPassword,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+pub type StoreError = Box<dyn std::error::Error + Send + Sync>;
+pub type StoreResult<T> = Result<T, StoreError>;
+
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    async fn get_user(&self, id: &Uuid) -> StoreResult<Option<User>>;
+
+    /// Looks a user up by email or by uid, including the password hash.
+    async fn find_user(&self, email_or_id: &str) -> StoreResult<Option<UserWithPassword>>;
+
+    async fn insert_user(&self, user: &UserWithPassword) -> StoreResult<()>;
+
+    async fn set_devices(&self, id: &Uuid, devices: &[String]) -> StoreResult<()>;
+
+    async fn set_preferences(
+        &self,
+        id: &Uuid,
+        preferences: &NotificationPreferences,
+    ) -> StoreResult<()>;
+
+    async fn get_users(&self, ids: &[Uuid]) -> StoreResult<Vec<User>>;
+
+    /// Callers may pass any number of emails, backends split them as their
+    /// queries need.
+    async fn get_users_by_emails(&self, emails: &[String]) -> StoreResult<Vec<User>>;
+
+    async fn get_system_user(&self) -> StoreResult<Option<User>>;
+
+    /// One page of the users matching `query`, by email.
+    async fn list_users(&self, query: &UserQuery) -> StoreResult<UserPage>;
+
+    /// Replaces the name, role and deactivation of the stored user.
+    async fn update_account(&self, user: &User) -> StoreResult<()>;
+
+    /// Replaces the password hash of the user.
+    async fn set_password(&self, id: &Uuid, password: &str) -> StoreResult<()>;
+}
+
+#[async_trait]
+pub trait StudentParentStore: Send + Sync {
+    async fn link_students(&self, parent_id: &Uuid, student_ids: &[Uuid]) -> StoreResult<()>;
+
+    async fn list_parents_of(&self, student_id: &Uuid) -> StoreResult<Vec<StudentsParents>>;
+
+    async fn list_students_of(&self, parent_id: &Uuid) -> StoreResult<Vec<StudentsParents>>;
+
+    /// Returns `false` when the two weren't linked.
+    async fn unlink_student(&self, parent_id: &Uuid, student_id: &Uuid) -> StoreResult<bool>;
+}
+
+#[async_trait]
+pub trait CourseStore: Send + Sync {
+    async fn list_courses(&self) -> StoreResult<Vec<Course>>;
+
+    async fn get_course(&self, id: &Uuid) -> StoreResult<Option<Course>>;
+
+    /// Takes any number of ids, like `get_users_by_emails`.
+    async fn get_courses(&self, ids: &[Uuid]) -> StoreResult<Vec<Course>>;
+
+    async fn list_teacher_courses(&self, teacher_id: &Uuid) -> StoreResult<Vec<Course>>;
+
+    async fn insert_course(&self, course: &Course) -> StoreResult<()>;
+
+    /// Replaces the stored course with the same id.
+    async fn update_course(&self, course: &Course) -> StoreResult<()>;
+}
+
+/// Modules and lessons of courses, and which students completed them.
+#[async_trait]
+pub trait LessonStore: Send + Sync {
+    /// Ordered by `position`.
+    async fn list_course_modules(&self, course_id: &Uuid) -> StoreResult<Vec<CourseModule>>;
+
+    async fn insert_module(&self, module: &CourseModule) -> StoreResult<()>;
+
+    async fn update_module(&self, module: &CourseModule) -> StoreResult<()>;
+
+    async fn delete_module(&self, id: &Uuid) -> StoreResult<()>;
+
+    /// Sets the `position` of each module to its index in `ids`.
+    async fn reorder_modules(&self, course_id: &Uuid, ids: &[Uuid]) -> StoreResult<()>;
+
+    /// Every lesson of the course, ordered by `position` within each module.
+    async fn list_course_lessons(&self, course_id: &Uuid) -> StoreResult<Vec<Lesson>>;
+
+    async fn get_lesson(&self, id: &Uuid) -> StoreResult<Option<Lesson>>;
+
+    async fn insert_lesson(&self, lesson: &Lesson) -> StoreResult<()>;
+
+    async fn update_lesson(&self, lesson: &Lesson) -> StoreResult<()>;
+
+    /// Deletes the lesson and its completions.
+    async fn delete_lesson(&self, id: &Uuid) -> StoreResult<()>;
+
+    /// Moves the lessons `ids` of the course into `module_id`, each at its
+    /// index in `ids`.
+    async fn reorder_lessons(
+        &self,
+        course_id: &Uuid,
+        module_id: &Uuid,
+        ids: &[Uuid],
+    ) -> StoreResult<()>;
+
+    /// Returns `false` if the student already completed the lesson.
+    async fn insert_lesson_completion(&self, completion: &LessonCompletion) -> StoreResult<bool>;
+
+    async fn list_course_completions(&self, course_id: &Uuid)
+        -> StoreResult<Vec<LessonCompletion>>;
+}
+
+#[async_trait]
+pub trait EnrollmentStore: Send + Sync {
+    async fn find_enrollment(
+        &self,
+        student_id: &Uuid,
+        course_id: &Uuid,
+    ) -> StoreResult<Option<Enrollment>>;
+
+    /// Inserts the enrollment unless the student is already enrolled in the
+    /// course. Returns `false` when nothing was written.
+    async fn insert_enrollment(&self, enrollment: &Enrollment) -> StoreResult<bool>;
+
+    /// Inserts the enrollments, skipping students already enrolled in their
+    /// course, also when they enroll meanwhile. Returns the ones written.
+    async fn insert_enrollments(&self, enrollments: &[Enrollment]) -> StoreResult<Vec<Enrollment>>;
+
+    /// Replaces the status, timestamps and approvals of the enrollment if its
+    /// stored status is still `from`. Returns `false` when it changed in
+    /// between.
+    async fn update_enrollment(
+        &self,
+        enrollment: &Enrollment,
+        from: EnrollmentStatus,
+    ) -> StoreResult<bool>;
+
+    /// Records the agreement of `by` to a pending enrollment, leaving the
+    /// other approval as it is. Returns `false` when it's no longer pending.
+    async fn approve_enrollment(
+        &self,
+        enrollment: &Enrollment,
+        approver: Approver,
+        by: &Uuid,
+    ) -> StoreResult<bool>;
+
+    /// All enrollments of the course, ended ones included.
+    async fn list_course_enrollments(&self, course_id: &Uuid) -> StoreResult<Vec<Enrollment>>;
+
+    /// All enrollments of the student, ended ones included.
+    async fn list_student_enrollments(&self, student_id: &Uuid) -> StoreResult<Vec<Enrollment>>;
+
+    /// Counts the students currently enrolled in the course.
+    async fn count_course_enrollments(&self, course_id: &Uuid) -> StoreResult<usize>;
+
+    /// Takes a seat in the course if fewer than `capacity` are taken, any
+    /// number without a capacity. Returns `false` when the course is full.
+    async fn take_seat(&self, course_id: &Uuid, capacity: Option<usize>) -> StoreResult<bool>;
+
+    /// Gives back a seat taken with `take_seat`.
+    async fn release_seat(&self, course_id: &Uuid) -> StoreResult<()>;
+}
+
+#[async_trait]
+pub trait MessageStore: Send + Sync {
+    /// One page of the messages visible to `user`, newest first.
+    async fn list_messages(
+        &self,
+        user: &User,
+        message_type: MessageType,
+        query: &MessageQuery,
+    ) -> StoreResult<MessagePage>;
+
+    async fn get_message(&self, id: &Uuid) -> StoreResult<Option<Message>>;
+
+    async fn insert_message(&self, message: &Message) -> StoreResult<()>;
+
+    async fn set_message_state(&self, id: &Uuid, state: MessageState) -> StoreResult<()>;
+
+    /// The root message `thread_id` and its replies, oldest first.
+    async fn list_thread(&self, thread_id: &Uuid) -> StoreResult<Vec<Message>>;
+
+    /// Replaces the receipt of `receipt.receiver` on message `id`.
+    async fn set_message_receipt(&self, id: &Uuid, receipt: &MessageReceipt) -> StoreResult<()>;
+
+    /// Received messages `user` hasn't read yet.
+    async fn count_unread_messages(&self, user: &User) -> StoreResult<usize>;
+}
+
+#[async_trait]
+pub trait TokenStore: Send + Sync {
+    async fn insert_refresh_token(&self, token: &RefreshToken) -> StoreResult<()>;
+
+    async fn get_refresh_token(&self, id: &str) -> StoreResult<Option<RefreshToken>>;
+
+    /// Marks the refresh token revoked. Returns `false` if it was unknown or
+    /// already revoked, so a token can only be spent once.
+    async fn revoke_refresh_token(&self, id: &str) -> StoreResult<bool>;
+
+    async fn revoke_access_token(&self, token: &RevokedToken) -> StoreResult<()>;
+
+    async fn is_access_token_revoked(&self, jti: &Uuid) -> StoreResult<bool>;
+
+    /// Revokes every refresh token of the user, ending all their sessions.
+    async fn revoke_user_refresh_tokens(&self, user_id: &Uuid) -> StoreResult<()>;
+}
+
+#[async_trait]
+pub trait OutboxStore: Send + Sync {
+    /// Inserts `message` and its outbox entry in one write, so no message is
+    /// stored without the notifications it owes.
+    async fn insert_message_with_outbox(
+        &self,
+        message: &Message,
+        entry: &OutboxEntry,
+    ) -> StoreResult<()>;
+
+    /// Up to `limit` pending entries due at `now`, oldest first. Their
+    /// `next_attempt_at` is moved to `lease_until` so other workers skip them
+    /// meanwhile.
+    async fn claim_outbox_entries(
+        &self,
+        now: &DateTime<Utc>,
+        lease_until: &DateTime<Utc>,
+        limit: usize,
+    ) -> StoreResult<Vec<OutboxEntry>>;
+
+    async fn update_outbox_entry(&self, entry: &OutboxEntry) -> StoreResult<()>;
+
+    async fn get_outbox_entry(&self, message_id: &Uuid) -> StoreResult<Option<OutboxEntry>>;
+}
+
+#[async_trait]
+pub trait DigestStore: Send + Sync {
+    async fn get_digest_schedule(&self, user_id: &Uuid) -> StoreResult<Option<DigestSchedule>>;
+
+    /// Inserts or replaces the schedule of `schedule.user_id`.
+    async fn set_digest_schedule(&self, schedule: &DigestSchedule) -> StoreResult<()>;
+
+    async fn delete_digest_schedule(&self, user_id: &Uuid) -> StoreResult<()>;
+
+    /// Up to `limit` schedules due at `now`, their `next_digest_at` moved to
+    /// `lease_until` like [`OutboxStore::claim_outbox_entries`].
+    async fn claim_digest_schedules(
+        &self,
+        now: &DateTime<Utc>,
+        lease_until: &DateTime<Utc>,
+        limit: usize,
+    ) -> StoreResult<Vec<DigestSchedule>>;
+}
+
+/// Metadata of uploaded files, their bytes are in an `AttachmentStorage`.
+#[async_trait]
+pub trait AttachmentStore: Send + Sync {
+    async fn insert_attachment(&self, attachment: &Attachment) -> StoreResult<()>;
+
+    async fn get_attachment(&self, id: &Uuid) -> StoreResult<Option<Attachment>>;
+
+    async fn update_attachment(&self, attachment: &Attachment) -> StoreResult<()>;
+}
+
+/// Who changed which account, for admins to look back on.
+#[async_trait]
+pub trait AuditStore: Send + Sync {
+    async fn insert_audit_entry(&self, entry: &AuditEntry) -> StoreResult<()>;
+
+    /// Up to `limit` entries, about `target_id` if given, newest first.
+    async fn list_audit_entries(
+        &self,
+        target_id: Option<&Uuid>,
+        limit: usize,
+    ) -> StoreResult<Vec<AuditEntry>>;
+}
+
+/// Everything the API needs from a backend, injected as `web::Data<dyn Store>`.
+pub trait Store:
+    UserStore
+    + StudentParentStore
+    + CourseStore
+    + LessonStore
+    + EnrollmentStore
+    + MessageStore
+    + TokenStore
+    + OutboxStore
+    + DigestStore
+    + AttachmentStore
+    + AuditStore
+{
+}
+
+impl<T> Store for T where
+    T: UserStore
+        + StudentParentStore
+        + CourseStore
+        + LessonStore
+        + EnrollmentStore
+        + MessageStore
+        + TokenStore
+        + OutboxStore
+        + DigestStore
+        + AttachmentStore
+        + AuditStore
+{
+}
@@ -0,0 +1,2336 @@
+use crate::common::message::{MessagePage, MessageQuery, MessageType};
+use crate::common::store::{
+    AttachmentStore, AuditStore, CourseStore, DigestStore, EnrollmentStore, LessonStore,
+    MessageStore, OutboxStore, StoreResult, StudentParentStore, TokenStore, UserStore,
+};
+use crate::common::user::{UserPage, UserQuery};
+use crate::common::{
+    Approver, Attachment, AuditEntry, Course, CourseModule, DigestSchedule, Enrollment,
+    EnrollmentStatus, Lesson, LessonCompletion, Message, MessageReceipt, MessageState,
+    NotificationPreferences, OutboxEntry, OutboxState, RefreshToken, RevokedToken, StudentsParents,
+    User, UserRole, UserWithPassword,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sqlx::any::{AnyPoolOptions, AnyRow};
+use sqlx::migrate::Migrator;
+use sqlx::{AnyConnection, AnyPool, Row};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// SQL backend over sqlx's `Any` driver, so the same queries serve SQLite
+/// (`sqlite://edclass.db?mode=rwc`) and Postgres (`postgres://...`).
+///
+/// Every value is stored as TEXT or INTEGER to stay portable between the two:
+/// ids as hyphenated uuids, enums by their serde name and timestamps as
+/// RFC 3339 in UTC, which sorts lexicographically.
+#[derive(Clone)]
+pub struct SqlStore {
+    pool: AnyPool,
+}
+
+impl SqlStore {
+    /// Connects to `url` and brings the schema up to date.
+    pub async fn connect(url: &str) -> StoreResult<Self> {
+        sqlx::any::install_default_drivers();
+
+        let mut options = AnyPoolOptions::new();
+        if url.starts_with("sqlite::memory:") {
+            // every connection to an in-memory database gets its own copy
+            options = options
+                .max_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None);
+        }
+
+        let pool = options.connect(url).await?;
+        MIGRATOR.run(&pool).await?;
+
+        Ok(SqlStore { pool })
+    }
+
+    pub fn pool(&self) -> &AnyPool {
+        &self.pool
+    }
+
+    async fn query_users(
+        &self,
+        filter: &str,
+        args: Vec<String>,
+    ) -> StoreResult<Vec<UserWithPassword>> {
+        let sql = format!(
+            "SELECT uid, email, password, role, name, preferences, deactivated_at FROM users \
+             WHERE {}",
+            filter
+        );
+        let mut query = sqlx::query(&sql);
+        for arg in args {
+            query = query.bind(arg);
+        }
+        let rows = query.fetch_all(&self.pool).await?;
+
+        let ids = rows
+            .iter()
+            .map(|r| r.try_get::<String, _>("uid"))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut devices = self.query_devices(&ids).await?;
+
+        rows.iter()
+            .map(|row| {
+                let uid: String = row.try_get("uid")?;
+                let preferences: Option<String> = row.try_get("preferences")?;
+                Ok(UserWithPassword {
+                    uid: Uuid::parse_str(&uid)?,
+                    email: row.try_get("email")?,
+                    password: row.try_get("password")?,
+                    role: from_sql_enum(&row.try_get::<String, _>("role")?)?,
+                    name: row.try_get("name")?,
+                    devices: devices.remove(&uid).unwrap_or_default(),
+                    preferences: match preferences {
+                        Some(raw) => serde_json::from_str(&raw)?,
+                        None => NotificationPreferences::default(),
+                    },
+                    deactivated_at: optional_time_column(row, "deactivated_at")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn query_devices(
+        &self,
+        user_ids: &[String],
+    ) -> StoreResult<HashMap<String, Vec<String>>> {
+        let mut devices: HashMap<String, Vec<String>> = HashMap::new();
+        if user_ids.is_empty() {
+            return Ok(devices);
+        }
+
+        let sql = format!(
+            "SELECT user_id, token FROM user_devices WHERE user_id IN ({}) ORDER BY token",
+            placeholders(1, user_ids.len())
+        );
+        let mut query = sqlx::query(&sql);
+        for id in user_ids {
+            query = query.bind(id.as_str());
+        }
+
+        for row in query.fetch_all(&self.pool).await? {
+            devices
+                .entry(row.try_get("user_id")?)
+                .or_default()
+                .push(row.try_get("token")?);
+        }
+
+        Ok(devices)
+    }
+
+    async fn query_courses(&self, filter: &str, args: Vec<String>) -> StoreResult<Vec<Course>> {
+        let sql = format!(
+            "SELECT id, title, content, teacher_id, archived_at, capacity, teacher_approval, \
+             parent_consent FROM courses WHERE {} ORDER BY title",
+            filter
+        );
+        let mut query = sqlx::query(&sql);
+        for arg in args {
+            query = query.bind(arg);
+        }
+
+        query
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(course_from_row)
+            .collect()
+    }
+
+    async fn query_enrollments(
+        &self,
+        filter: &str,
+        args: Vec<String>,
+    ) -> StoreResult<Vec<Enrollment>> {
+        let sql = format!(
+            "SELECT id, course_id, student_id, status, enrolled_at, ended_at, approved_by, \
+             consented_by FROM enrollments WHERE {}",
+            filter
+        );
+        let mut query = sqlx::query(&sql);
+        for arg in args {
+            query = query.bind(arg);
+        }
+
+        query
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(enrollment_from_row)
+            .collect()
+    }
+
+    async fn query_students_parents(
+        &self,
+        column: &str,
+        id: &Uuid,
+    ) -> StoreResult<Vec<StudentsParents>> {
+        let sql = format!(
+            "SELECT student_id, parent_id FROM students_parents WHERE {} = $1",
+            column
+        );
+
+        sqlx::query(&sql)
+            .bind(id.to_string())
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|row| {
+                Ok(StudentsParents {
+                    student_id: uuid_column(row, "student_id")?,
+                    parent_id: uuid_column(row, "parent_id")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn query_messages(
+        &self,
+        filter: &str,
+        order_by: &str,
+        args: Vec<String>,
+    ) -> StoreResult<Vec<Message>> {
+        let sql = format!(
+            "SELECT id, sender_id, subject, content, state, created_at, thread_id, reply_to, \
+             category, attachments FROM messages WHERE {} ORDER BY {}",
+            filter, order_by
+        );
+        let mut query = sqlx::query(&sql);
+        for arg in args {
+            query = query.bind(arg);
+        }
+        let rows = query.fetch_all(&self.pool).await?;
+
+        let ids = rows
+            .iter()
+            .map(|r| r.try_get::<String, _>("id"))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut receipts = self.query_receipts(&ids).await?;
+
+        rows.iter()
+            .map(|row| {
+                let id: String = row.try_get("id")?;
+                let receipts = receipts.remove(&id).unwrap_or_default();
+                Ok(Message {
+                    id: Uuid::parse_str(&id)?,
+                    sender_id: uuid_column(row, "sender_id")?,
+                    receiver_ids: receipts.iter().map(|r| r.receiver.clone()).collect(),
+                    subject: row.try_get("subject")?,
+                    content: row.try_get("content")?,
+                    state: from_sql_enum(&row.try_get::<String, _>("state")?)?,
+                    created_at: time_column(row, "created_at")?,
+                    thread_id: optional_uuid_column(row, "thread_id")?,
+                    reply_to: optional_uuid_column(row, "reply_to")?,
+                    category: from_sql_enum(&row.try_get::<String, _>("category")?)?,
+                    receipts,
+                    attachments: serde_json::from_str(&row.try_get::<String, _>("attachments")?)?,
+                })
+            })
+            .collect()
+    }
+
+    async fn query_receipts(
+        &self,
+        message_ids: &[String],
+    ) -> StoreResult<HashMap<String, Vec<MessageReceipt>>> {
+        let mut receipts: HashMap<String, Vec<MessageReceipt>> = HashMap::new();
+        if message_ids.is_empty() {
+            return Ok(receipts);
+        }
+
+        let sql = format!(
+            "SELECT message_id, receiver, state, sent_at, received_at, read_at \
+             FROM message_receivers WHERE message_id IN ({}) ORDER BY position",
+            placeholders(1, message_ids.len())
+        );
+        let mut query = sqlx::query(&sql);
+        for id in message_ids {
+            query = query.bind(id.as_str());
+        }
+
+        for row in query.fetch_all(&self.pool).await? {
+            receipts
+                .entry(row.try_get("message_id")?)
+                .or_default()
+                .push(MessageReceipt {
+                    receiver: row.try_get("receiver")?,
+                    state: from_sql_enum(&row.try_get::<String, _>("state")?)?,
+                    sent_at: optional_time_column(&row, "sent_at")?,
+                    received_at: optional_time_column(&row, "received_at")?,
+                    read_at: optional_time_column(&row, "read_at")?,
+                });
+        }
+
+        Ok(receipts)
+    }
+}
+
+/// `$from, $from+1, ...` for `count` bind parameters.
+fn placeholders(from: usize, count: usize) -> String {
+    (from..from + count)
+        .map(|i| format!("${}", i))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Matches `text` anywhere, case-insensitively, against a `LOWER(...)`
+/// column with `ESCAPE '\'`.
+fn like_pattern(text: &str) -> String {
+    let escaped = text
+        .to_lowercase()
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+fn to_sql_enum<T: Serialize>(value: &T) -> StoreResult<String> {
+    match serde_json::to_value(value)? {
+        serde_json::Value::String(s) => Ok(s),
+        other => Err(format!("not a unit enum: {}", other).into()),
+    }
+}
+
+fn from_sql_enum<T: DeserializeOwned>(value: &str) -> StoreResult<T> {
+    Ok(serde_json::from_value(serde_json::Value::String(
+        value.to_string(),
+    ))?)
+}
+
+fn to_sql_time(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+fn time_column(row: &AnyRow, column: &str) -> StoreResult<DateTime<Utc>> {
+    let raw: String = row.try_get(column)?;
+    Ok(DateTime::parse_from_rfc3339(&raw)?.with_timezone(&Utc))
+}
+
+fn optional_time_column(row: &AnyRow, column: &str) -> StoreResult<Option<DateTime<Utc>>> {
+    let raw: Option<String> = row.try_get(column)?;
+    match raw {
+        Some(raw) => Ok(Some(
+            DateTime::parse_from_rfc3339(&raw)?.with_timezone(&Utc),
+        )),
+        None => Ok(None),
+    }
+}
+
+fn uuid_column(row: &AnyRow, column: &str) -> StoreResult<Uuid> {
+    let raw: String = row.try_get(column)?;
+    Ok(Uuid::parse_str(&raw)?)
+}
+
+fn optional_uuid_column(row: &AnyRow, column: &str) -> StoreResult<Option<Uuid>> {
+    let raw: Option<String> = row.try_get(column)?;
+    match raw {
+        Some(raw) => Ok(Some(Uuid::parse_str(&raw)?)),
+        None => Ok(None),
+    }
+}
+
+fn course_from_row(row: &AnyRow) -> StoreResult<Course> {
+    Ok(Course {
+        id: uuid_column(row, "id")?,
+        title: row.try_get("title")?,
+        content: row.try_get("content")?,
+        teacher_id: uuid_column(row, "teacher_id")?,
+        archived_at: optional_time_column(row, "archived_at")?,
+        capacity: row
+            .try_get::<Option<i64>, _>("capacity")?
+            .map(|c| c as usize),
+        teacher_approval: row.try_get::<i64, _>("teacher_approval")? != 0,
+        parent_consent: row.try_get::<i64, _>("parent_consent")? != 0,
+    })
+}
+
+fn module_from_row(row: &AnyRow) -> StoreResult<CourseModule> {
+    Ok(CourseModule {
+        id: uuid_column(row, "id")?,
+        course_id: uuid_column(row, "course_id")?,
+        title: row.try_get("title")?,
+        position: row.try_get::<i64, _>("position")? as u32,
+    })
+}
+
+fn lesson_from_row(row: &AnyRow) -> StoreResult<Lesson> {
+    Ok(Lesson {
+        id: uuid_column(row, "id")?,
+        course_id: uuid_column(row, "course_id")?,
+        module_id: uuid_column(row, "module_id")?,
+        title: row.try_get("title")?,
+        body: row.try_get("body")?,
+        links: serde_json::from_str(&row.try_get::<String, _>("links")?)?,
+        materials: serde_json::from_str(&row.try_get::<String, _>("materials")?)?,
+        position: row.try_get::<i64, _>("position")? as u32,
+        published: row.try_get::<i64, _>("published")? != 0,
+        updated_at: time_column(row, "updated_at")?,
+    })
+}
+
+fn attachment_from_row(row: &AnyRow) -> StoreResult<Attachment> {
+    Ok(Attachment {
+        id: uuid_column(row, "id")?,
+        owner_id: uuid_column(row, "owner_id")?,
+        file_name: row.try_get("file_name")?,
+        content_type: row.try_get("content_type")?,
+        size: row.try_get::<i64, _>("size")? as u64,
+        created_at: time_column(row, "created_at")?,
+        refs: serde_json::from_str(&row.try_get::<String, _>("refs")?)?,
+    })
+}
+
+fn enrollment_from_row(row: &AnyRow) -> StoreResult<Enrollment> {
+    Ok(Enrollment {
+        id: uuid_column(row, "id")?,
+        course_id: uuid_column(row, "course_id")?,
+        student_id: uuid_column(row, "student_id")?,
+        status: from_sql_enum(&row.try_get::<String, _>("status")?)?,
+        enrolled_at: time_column(row, "enrolled_at")?,
+        ended_at: optional_time_column(row, "ended_at")?,
+        approved_by: optional_uuid_column(row, "approved_by")?,
+        consented_by: optional_uuid_column(row, "consented_by")?,
+    })
+}
+
+/// Writes `message` and its receivers on `conn`, for use inside a transaction.
+async fn insert_message_rows(conn: &mut AnyConnection, message: &Message) -> StoreResult<()> {
+    sqlx::query(
+        "INSERT INTO messages (id, sender_id, subject, content, state, created_at, thread_id, \
+         reply_to, category, attachments) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+    )
+    .bind(message.id.to_string())
+    .bind(message.sender_id.to_string())
+    .bind(message.subject.clone())
+    .bind(message.content.as_str())
+    .bind(to_sql_enum(&message.state)?)
+    .bind(to_sql_time(&message.created_at))
+    .bind(message.thread_id.map(|id| id.to_string()))
+    .bind(message.reply_to.map(|id| id.to_string()))
+    .bind(to_sql_enum(&message.category)?)
+    .bind(serde_json::to_string(&message.attachments)?)
+    .execute(&mut *conn)
+    .await?;
+
+    for (position, receiver) in message.receiver_ids.iter().enumerate() {
+        let receipt = message
+            .receipt(receiver)
+            .unwrap_or_else(|| MessageReceipt::pending(receiver));
+        sqlx::query(
+            "INSERT INTO message_receivers \
+             (message_id, receiver, position, state, sent_at, received_at, read_at) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7) ON CONFLICT DO NOTHING",
+        )
+        .bind(message.id.to_string())
+        .bind(receiver.as_str())
+        .bind(position as i64)
+        .bind(to_sql_enum(&receipt.state)?)
+        .bind(receipt.sent_at.as_ref().map(to_sql_time))
+        .bind(receipt.received_at.as_ref().map(to_sql_time))
+        .bind(receipt.read_at.as_ref().map(to_sql_time))
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+fn outbox_entry_from_row(row: &AnyRow) -> StoreResult<OutboxEntry> {
+    let retry_tokens: Option<String> = row.try_get("retry_tokens")?;
+    Ok(OutboxEntry {
+        message_id: uuid_column(row, "message_id")?,
+        state: from_sql_enum(&row.try_get::<String, _>("state")?)?,
+        attempts: row.try_get::<i64, _>("attempts")? as u32,
+        next_attempt_at: time_column(row, "next_attempt_at")?,
+        last_error: row.try_get("last_error")?,
+        retry_tokens: match retry_tokens {
+            Some(raw) => Some(serde_json::from_str(&raw)?),
+            None => None,
+        },
+        created_at: time_column(row, "created_at")?,
+    })
+}
+
+fn digest_schedule_from_row(row: &AnyRow) -> StoreResult<DigestSchedule> {
+    Ok(DigestSchedule {
+        user_id: uuid_column(row, "user_id")?,
+        frequency: from_sql_enum(&row.try_get::<String, _>("frequency")?)?,
+        next_digest_at: time_column(row, "next_digest_at")?,
+        last_digest_at: time_column(row, "last_digest_at")?,
+        enrollments: serde_json::from_str(&row.try_get::<String, _>("enrollments")?)?,
+    })
+}
+
+#[async_trait]
+impl UserStore for SqlStore {
+    async fn get_user(&self, id: &Uuid) -> StoreResult<Option<User>> {
+        let users = self.query_users("uid = $1", vec![id.to_string()]).await?;
+        Ok(users.into_iter().next().map(User::from))
+    }
+
+    async fn find_user(&self, email_or_id: &str) -> StoreResult<Option<UserWithPassword>> {
+        let users = self
+            .query_users("email = $1 OR uid = $1", vec![email_or_id.to_string()])
+            .await?;
+        Ok(users.into_iter().next())
+    }
+
+    async fn insert_user(&self, user: &UserWithPassword) -> StoreResult<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO users (uid, email, password, role, name, preferences, deactivated_at) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        )
+        .bind(user.uid.to_string())
+        .bind(user.email.as_str())
+        .bind(user.password.as_str())
+        .bind(to_sql_enum(&user.role)?)
+        .bind(user.name.as_str())
+        .bind(serde_json::to_string(&user.preferences)?)
+        .bind(user.deactivated_at.as_ref().map(to_sql_time))
+        .execute(&mut *tx)
+        .await?;
+
+        for token in &user.devices {
+            sqlx::query(
+                "INSERT INTO user_devices (user_id, token) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            )
+            .bind(user.uid.to_string())
+            .bind(token.as_str())
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn set_devices(&self, id: &Uuid, devices: &[String]) -> StoreResult<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM user_devices WHERE user_id = $1")
+            .bind(id.to_string())
+            .execute(&mut *tx)
+            .await?;
+
+        for token in devices {
+            sqlx::query(
+                "INSERT INTO user_devices (user_id, token) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            )
+            .bind(id.to_string())
+            .bind(token.as_str())
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn set_preferences(
+        &self,
+        id: &Uuid,
+        preferences: &NotificationPreferences,
+    ) -> StoreResult<()> {
+        let result = sqlx::query("UPDATE users SET preferences = $1 WHERE uid = $2")
+            .bind(serde_json::to_string(preferences)?)
+            .bind(id.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "user not found",
+            )));
+        }
+
+        Ok(())
+    }
+
+    async fn get_users(&self, ids: &[Uuid]) -> StoreResult<Vec<User>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let filter = format!("uid IN ({})", placeholders(1, ids.len()));
+        let args = ids.iter().map(|id| id.to_string()).collect();
+        let users = self.query_users(&filter, args).await?;
+        Ok(users.into_iter().map(User::from).collect())
+    }
+
+    async fn get_users_by_emails(&self, emails: &[String]) -> StoreResult<Vec<User>> {
+        if emails.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let filter = format!("email IN ({})", placeholders(1, emails.len()));
+        let users = self.query_users(&filter, emails.to_vec()).await?;
+        Ok(users.into_iter().map(User::from).collect())
+    }
+
+    async fn get_system_user(&self) -> StoreResult<Option<User>> {
+        let users = self
+            .query_users("role = $1", vec![to_sql_enum(&UserRole::System)?])
+            .await?;
+        Ok(users.into_iter().next().map(User::from))
+    }
+
+    async fn list_users(&self, query: &UserQuery) -> StoreResult<UserPage> {
+        let mut args: Vec<String> = Vec::new();
+        let mut arg = |value: String| {
+            args.push(value);
+            format!("${}", args.len())
+        };
+
+        let mut conditions = vec!["1 = 1".to_string()];
+        if let Some(role) = &query.role {
+            conditions.push(format!("role = {}", arg(to_sql_enum(role)?)));
+        }
+        match query.active {
+            Some(true) => conditions.push("deactivated_at IS NULL".to_string()),
+            Some(false) => conditions.push("deactivated_at IS NOT NULL".to_string()),
+            None => {}
+        }
+        if let Some(text) = &query.search {
+            let pattern = arg(like_pattern(text));
+            conditions.push(format!(
+                "(LOWER(name) LIKE {0} ESCAPE '\\' OR LOWER(email) LIKE {0} ESCAPE '\\')",
+                pattern
+            ));
+        }
+        if let Some(after) = &query.after {
+            conditions.push(format!("email > {}", arg(after.clone())));
+        }
+
+        let filter = format!(
+            "{} ORDER BY email LIMIT {}",
+            conditions.join(" AND "),
+            query.limit + 1
+        );
+        let users = self.query_users(&filter, args).await?;
+        Ok(UserPage::from_overfetch(
+            users.into_iter().map(User::from).collect(),
+            query.limit,
+        ))
+    }
+
+    async fn update_account(&self, user: &User) -> StoreResult<()> {
+        let result = sqlx::query(
+            "UPDATE users SET name = $1, role = $2, deactivated_at = $3 WHERE uid = $4",
+        )
+        .bind(user.name.as_str())
+        .bind(to_sql_enum(&user.role)?)
+        .bind(user.deactivated_at.as_ref().map(to_sql_time))
+        .bind(user.uid.to_string())
+        .execute(&self.pool)
+        .await?;
+
+        match result.rows_affected() {
+            0 => Err(not_found("user")),
+            _ => Ok(()),
+        }
+    }
+
+    async fn set_password(&self, id: &Uuid, password: &str) -> StoreResult<()> {
+        let result = sqlx::query("UPDATE users SET password = $1 WHERE uid = $2")
+            .bind(password)
+            .bind(id.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        match result.rows_affected() {
+            0 => Err(not_found("user")),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl StudentParentStore for SqlStore {
+    async fn link_students(&self, parent_id: &Uuid, student_ids: &[Uuid]) -> StoreResult<()> {
+        let mut tx = self.pool.begin().await?;
+        for id in student_ids {
+            sqlx::query(
+                "INSERT INTO students_parents (student_id, parent_id) VALUES ($1, $2) \
+                 ON CONFLICT DO NOTHING",
+            )
+            .bind(id.to_string())
+            .bind(parent_id.to_string())
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn list_parents_of(&self, student_id: &Uuid) -> StoreResult<Vec<StudentsParents>> {
+        self.query_students_parents("student_id", student_id).await
+    }
+
+    async fn list_students_of(&self, parent_id: &Uuid) -> StoreResult<Vec<StudentsParents>> {
+        self.query_students_parents("parent_id", parent_id).await
+    }
+
+    async fn unlink_student(&self, parent_id: &Uuid, student_id: &Uuid) -> StoreResult<bool> {
+        let result =
+            sqlx::query("DELETE FROM students_parents WHERE student_id = $1 AND parent_id = $2")
+                .bind(student_id.to_string())
+                .bind(parent_id.to_string())
+                .execute(&self.pool)
+                .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+#[async_trait]
+impl CourseStore for SqlStore {
+    async fn list_courses(&self) -> StoreResult<Vec<Course>> {
+        self.query_courses("1 = 1", Vec::new()).await
+    }
+
+    async fn get_course(&self, id: &Uuid) -> StoreResult<Option<Course>> {
+        let courses = self.query_courses("id = $1", vec![id.to_string()]).await?;
+        Ok(courses.into_iter().next())
+    }
+
+    async fn get_courses(&self, ids: &[Uuid]) -> StoreResult<Vec<Course>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let filter = format!("id IN ({})", placeholders(1, ids.len()));
+        let args = ids.iter().map(|id| id.to_string()).collect();
+        self.query_courses(&filter, args).await
+    }
+
+    async fn list_teacher_courses(&self, teacher_id: &Uuid) -> StoreResult<Vec<Course>> {
+        self.query_courses("teacher_id = $1", vec![teacher_id.to_string()])
+            .await
+    }
+
+    async fn insert_course(&self, course: &Course) -> StoreResult<()> {
+        sqlx::query(
+            "INSERT INTO courses (id, title, content, teacher_id, archived_at, capacity, \
+             teacher_approval, parent_consent) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        )
+        .bind(course.id.to_string())
+        .bind(course.title.as_str())
+        .bind(course.content.as_str())
+        .bind(course.teacher_id.to_string())
+        .bind(course.archived_at.as_ref().map(to_sql_time))
+        .bind(course.capacity.map(|c| c as i64))
+        .bind(course.teacher_approval as i64)
+        .bind(course.parent_consent as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn update_course(&self, course: &Course) -> StoreResult<()> {
+        let result = sqlx::query(
+            "UPDATE courses SET title = $1, content = $2, teacher_id = $3, archived_at = $4, \
+             capacity = $5, teacher_approval = $6, parent_consent = $7 WHERE id = $8",
+        )
+        .bind(course.title.as_str())
+        .bind(course.content.as_str())
+        .bind(course.teacher_id.to_string())
+        .bind(course.archived_at.as_ref().map(to_sql_time))
+        .bind(course.capacity.map(|c| c as i64))
+        .bind(course.teacher_approval as i64)
+        .bind(course.parent_consent as i64)
+        .bind(course.id.to_string())
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "course not found",
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+const MODULE_COLUMNS: &str = "id, course_id, title, position";
+const LESSON_COLUMNS: &str =
+    "id, course_id, module_id, title, body, links, materials, position, published, updated_at";
+
+fn not_found(what: &str) -> Box<dyn std::error::Error + Send + Sync> {
+    Box::new(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("{} not found", what),
+    ))
+}
+
+#[async_trait]
+impl LessonStore for SqlStore {
+    async fn list_course_modules(&self, course_id: &Uuid) -> StoreResult<Vec<CourseModule>> {
+        sqlx::query(&format!(
+            "SELECT {} FROM course_modules WHERE course_id = $1 ORDER BY position",
+            MODULE_COLUMNS
+        ))
+        .bind(course_id.to_string())
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(module_from_row)
+        .collect()
+    }
+
+    async fn insert_module(&self, module: &CourseModule) -> StoreResult<()> {
+        sqlx::query(&format!(
+            "INSERT INTO course_modules ({}) VALUES ($1, $2, $3, $4)",
+            MODULE_COLUMNS
+        ))
+        .bind(module.id.to_string())
+        .bind(module.course_id.to_string())
+        .bind(module.title.as_str())
+        .bind(module.position as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn update_module(&self, module: &CourseModule) -> StoreResult<()> {
+        let result =
+            sqlx::query("UPDATE course_modules SET title = $1, position = $2 WHERE id = $3")
+                .bind(module.title.as_str())
+                .bind(module.position as i64)
+                .bind(module.id.to_string())
+                .execute(&self.pool)
+                .await?;
+
+        match result.rows_affected() {
+            0 => Err(not_found("module")),
+            _ => Ok(()),
+        }
+    }
+
+    async fn delete_module(&self, id: &Uuid) -> StoreResult<()> {
+        sqlx::query("DELETE FROM course_modules WHERE id = $1")
+            .bind(id.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn reorder_modules(&self, course_id: &Uuid, ids: &[Uuid]) -> StoreResult<()> {
+        let mut tx = self.pool.begin().await?;
+        for (position, id) in ids.iter().enumerate() {
+            sqlx::query("UPDATE course_modules SET position = $1 WHERE id = $2 AND course_id = $3")
+                .bind(position as i64)
+                .bind(id.to_string())
+                .bind(course_id.to_string())
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn list_course_lessons(&self, course_id: &Uuid) -> StoreResult<Vec<Lesson>> {
+        sqlx::query(&format!(
+            "SELECT {} FROM lessons WHERE course_id = $1 ORDER BY position",
+            LESSON_COLUMNS
+        ))
+        .bind(course_id.to_string())
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(lesson_from_row)
+        .collect()
+    }
+
+    async fn get_lesson(&self, id: &Uuid) -> StoreResult<Option<Lesson>> {
+        let row = sqlx::query(&format!(
+            "SELECT {} FROM lessons WHERE id = $1",
+            LESSON_COLUMNS
+        ))
+        .bind(id.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.as_ref().map(lesson_from_row).transpose()
+    }
+
+    async fn insert_lesson(&self, lesson: &Lesson) -> StoreResult<()> {
+        sqlx::query(&format!(
+            "INSERT INTO lessons ({}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+            LESSON_COLUMNS
+        ))
+        .bind(lesson.id.to_string())
+        .bind(lesson.course_id.to_string())
+        .bind(lesson.module_id.to_string())
+        .bind(lesson.title.as_str())
+        .bind(lesson.body.as_str())
+        .bind(serde_json::to_string(&lesson.links)?)
+        .bind(serde_json::to_string(&lesson.materials)?)
+        .bind(lesson.position as i64)
+        .bind(lesson.published as i64)
+        .bind(to_sql_time(&lesson.updated_at))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn update_lesson(&self, lesson: &Lesson) -> StoreResult<()> {
+        let result = sqlx::query(
+            "UPDATE lessons SET module_id = $1, title = $2, body = $3, links = $4, \
+             materials = $5, position = $6, published = $7, updated_at = $8 WHERE id = $9",
+        )
+        .bind(lesson.module_id.to_string())
+        .bind(lesson.title.as_str())
+        .bind(lesson.body.as_str())
+        .bind(serde_json::to_string(&lesson.links)?)
+        .bind(serde_json::to_string(&lesson.materials)?)
+        .bind(lesson.position as i64)
+        .bind(lesson.published as i64)
+        .bind(to_sql_time(&lesson.updated_at))
+        .bind(lesson.id.to_string())
+        .execute(&self.pool)
+        .await?;
+
+        match result.rows_affected() {
+            0 => Err(not_found("lesson")),
+            _ => Ok(()),
+        }
+    }
+
+    async fn delete_lesson(&self, id: &Uuid) -> StoreResult<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM lesson_completions WHERE lesson_id = $1")
+            .bind(id.to_string())
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM lessons WHERE id = $1")
+            .bind(id.to_string())
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn reorder_lessons(
+        &self,
+        course_id: &Uuid,
+        module_id: &Uuid,
+        ids: &[Uuid],
+    ) -> StoreResult<()> {
+        let mut tx = self.pool.begin().await?;
+        for (position, id) in ids.iter().enumerate() {
+            sqlx::query(
+                "UPDATE lessons SET module_id = $1, position = $2 \
+                 WHERE id = $3 AND course_id = $4",
+            )
+            .bind(module_id.to_string())
+            .bind(position as i64)
+            .bind(id.to_string())
+            .bind(course_id.to_string())
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn insert_lesson_completion(&self, completion: &LessonCompletion) -> StoreResult<bool> {
+        let result = sqlx::query(
+            "INSERT INTO lesson_completions (lesson_id, student_id, course_id, completed_at) \
+             VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING",
+        )
+        .bind(completion.lesson_id.to_string())
+        .bind(completion.student_id.to_string())
+        .bind(completion.course_id.to_string())
+        .bind(to_sql_time(&completion.completed_at))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn list_course_completions(
+        &self,
+        course_id: &Uuid,
+    ) -> StoreResult<Vec<LessonCompletion>> {
+        let rows = sqlx::query(
+            "SELECT lesson_id, student_id, course_id, completed_at FROM lesson_completions \
+             WHERE course_id = $1",
+        )
+        .bind(course_id.to_string())
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(LessonCompletion {
+                    lesson_id: uuid_column(row, "lesson_id")?,
+                    student_id: uuid_column(row, "student_id")?,
+                    course_id: uuid_column(row, "course_id")?,
+                    completed_at: time_column(row, "completed_at")?,
+                })
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl EnrollmentStore for SqlStore {
+    async fn find_enrollment(
+        &self,
+        student_id: &Uuid,
+        course_id: &Uuid,
+    ) -> StoreResult<Option<Enrollment>> {
+        let enrollments = self
+            .query_enrollments(
+                "student_id = $1 AND course_id = $2",
+                vec![student_id.to_string(), course_id.to_string()],
+            )
+            .await?;
+        Ok(enrollments.into_iter().next())
+    }
+
+    async fn insert_enrollment(&self, enrollment: &Enrollment) -> StoreResult<bool> {
+        // the (student_id, course_id) unique constraint makes this race free
+        let result = sqlx::query(
+            "INSERT INTO enrollments (id, course_id, student_id, status, enrolled_at, ended_at, \
+             approved_by, consented_by) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) \
+             ON CONFLICT (student_id, course_id) DO NOTHING",
+        )
+        .bind(enrollment.id.to_string())
+        .bind(enrollment.course_id.to_string())
+        .bind(enrollment.student_id.to_string())
+        .bind(to_sql_enum(&enrollment.status)?)
+        .bind(to_sql_time(&enrollment.enrolled_at))
+        .bind(enrollment.ended_at.as_ref().map(to_sql_time))
+        .bind(enrollment.approved_by.map(|id| id.to_string()))
+        .bind(enrollment.consented_by.map(|id| id.to_string()))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn insert_enrollments(&self, enrollments: &[Enrollment]) -> StoreResult<Vec<Enrollment>> {
+        let mut tx = self.pool.begin().await?;
+        let mut written = Vec::new();
+        for enrollment in enrollments {
+            let result = sqlx::query(
+                "INSERT INTO enrollments (id, course_id, student_id, status, enrolled_at, \
+                 ended_at, approved_by, consented_by) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) \
+                 ON CONFLICT (student_id, course_id) DO NOTHING",
+            )
+            .bind(enrollment.id.to_string())
+            .bind(enrollment.course_id.to_string())
+            .bind(enrollment.student_id.to_string())
+            .bind(to_sql_enum(&enrollment.status)?)
+            .bind(to_sql_time(&enrollment.enrolled_at))
+            .bind(enrollment.ended_at.as_ref().map(to_sql_time))
+            .bind(enrollment.approved_by.map(|id| id.to_string()))
+            .bind(enrollment.consented_by.map(|id| id.to_string()))
+            .execute(&mut *tx)
+            .await?;
+            if result.rows_affected() > 0 {
+                written.push(enrollment.clone());
+            }
+        }
+        tx.commit().await?;
+
+        Ok(written)
+    }
+
+    async fn update_enrollment(
+        &self,
+        enrollment: &Enrollment,
+        from: EnrollmentStatus,
+    ) -> StoreResult<bool> {
+        let result = sqlx::query(
+            "UPDATE enrollments SET status = $1, enrolled_at = $2, ended_at = $3, \
+             approved_by = $4, consented_by = $5 WHERE id = $6 AND status = $7",
+        )
+        .bind(to_sql_enum(&enrollment.status)?)
+        .bind(to_sql_time(&enrollment.enrolled_at))
+        .bind(enrollment.ended_at.as_ref().map(to_sql_time))
+        .bind(enrollment.approved_by.map(|id| id.to_string()))
+        .bind(enrollment.consented_by.map(|id| id.to_string()))
+        .bind(enrollment.id.to_string())
+        .bind(to_sql_enum(&from)?)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn approve_enrollment(
+        &self,
+        enrollment: &Enrollment,
+        approver: Approver,
+        by: &Uuid,
+    ) -> StoreResult<bool> {
+        // only the one column, so the teacher and a parent approving at the
+        // same time don't undo each other
+        let sql = match approver {
+            Approver::Teacher => {
+                "UPDATE enrollments SET approved_by = $1 WHERE id = $2 AND status = $3"
+            }
+            Approver::Parent => {
+                "UPDATE enrollments SET consented_by = $1 WHERE id = $2 AND status = $3"
+            }
+        };
+        let result = sqlx::query(sql)
+            .bind(by.to_string())
+            .bind(enrollment.id.to_string())
+            .bind(to_sql_enum(&EnrollmentStatus::Pending)?)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn list_course_enrollments(&self, course_id: &Uuid) -> StoreResult<Vec<Enrollment>> {
+        self.query_enrollments("course_id = $1", vec![course_id.to_string()])
+            .await
+    }
+
+    async fn list_student_enrollments(&self, student_id: &Uuid) -> StoreResult<Vec<Enrollment>> {
+        self.query_enrollments("student_id = $1", vec![student_id.to_string()])
+            .await
+    }
+
+    async fn count_course_enrollments(&self, course_id: &Uuid) -> StoreResult<usize> {
+        let count: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM enrollments WHERE course_id = $1 AND status = $2",
+        )
+        .bind(course_id.to_string())
+        .bind(to_sql_enum(&EnrollmentStatus::Active)?)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(count as usize)
+    }
+
+    async fn take_seat(&self, course_id: &Uuid, capacity: Option<usize>) -> StoreResult<bool> {
+        sqlx::query(
+            "INSERT INTO course_seats (course_id, taken) VALUES ($1, 0) \
+             ON CONFLICT (course_id) DO NOTHING",
+        )
+        .bind(course_id.to_string())
+        .execute(&self.pool)
+        .await?;
+
+        // a single statement, so concurrent enrollments can't both take the
+        // last seat
+        let result = match capacity {
+            Some(capacity) => {
+                sqlx::query(
+                    "UPDATE course_seats SET taken = taken + 1 \
+                     WHERE course_id = $1 AND taken < $2",
+                )
+                .bind(course_id.to_string())
+                .bind(capacity as i64)
+                .execute(&self.pool)
+                .await?
+            }
+            None => {
+                sqlx::query("UPDATE course_seats SET taken = taken + 1 WHERE course_id = $1")
+                    .bind(course_id.to_string())
+                    .execute(&self.pool)
+                    .await?
+            }
+        };
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn release_seat(&self, course_id: &Uuid) -> StoreResult<()> {
+        sqlx::query("UPDATE course_seats SET taken = taken - 1 WHERE course_id = $1 AND taken > 0")
+            .bind(course_id.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageStore for SqlStore {
+    async fn list_messages(
+        &self,
+        user: &User,
+        message_type: MessageType,
+        query: &MessageQuery,
+    ) -> StoreResult<MessagePage> {
+        let filter = &query.filter;
+        let mut args: Vec<String> = Vec::new();
+        let mut arg = |value: String| {
+            args.push(value);
+            format!("${}", args.len())
+        };
+
+        // the state filter applies to the receiver's receipt or, for the
+        // sender, to the message itself
+        let state = match &filter.state {
+            Some(s) => Some(arg(to_sql_enum(s)?)),
+            None => None,
+        };
+        let state_cond = |column: &str| match &state {
+            Some(p) => format!(" AND {} = {}", column, p),
+            None => String::new(),
+        };
+        let received = format!(
+            "id IN (SELECT message_id FROM message_receivers WHERE receiver = {}{})",
+            arg(user.email.clone()),
+            state_cond("state")
+        );
+        let sent = format!(
+            "(sender_id = {}{})",
+            arg(user.uid.to_string()),
+            state_cond("state")
+        );
+        let mut conditions = vec![match message_type {
+            MessageType::Received => received,
+            MessageType::Sent => sent,
+            MessageType::All => format!("({} OR {})", received, sent),
+        }];
+
+        if let Some(sender_id) = filter.sender_id {
+            conditions.push(format!("sender_id = {}", arg(sender_id.to_string())));
+        }
+        if let Some(from) = &filter.from {
+            conditions.push(format!("created_at >= {}", arg(to_sql_time(from))));
+        }
+        if let Some(to) = &filter.to {
+            conditions.push(format!("created_at < {}", arg(to_sql_time(to))));
+        }
+        if let Some(text) = &filter.subject {
+            conditions.push(format!(
+                "LOWER(subject) LIKE {} ESCAPE '\\'",
+                arg(like_pattern(text))
+            ));
+        }
+        if let Some(after) = &query.after {
+            let created_at = arg(to_sql_time(&after.created_at));
+            let id = arg(after.id.to_string());
+            conditions.push(format!(
+                "(created_at < {0} OR (created_at = {0} AND id < {1}))",
+                created_at, id
+            ));
+        }
+
+        let messages = self
+            .query_messages(
+                &conditions.join(" AND "),
+                &format!("created_at DESC, id DESC LIMIT {}", query.limit + 1),
+                args,
+            )
+            .await?;
+        Ok(MessagePage::from_overfetch(messages, query.limit))
+    }
+
+    async fn get_message(&self, id: &Uuid) -> StoreResult<Option<Message>> {
+        let messages = self
+            .query_messages("id = $1", "created_at", vec![id.to_string()])
+            .await?;
+        Ok(messages.into_iter().next())
+    }
+
+    async fn insert_message(&self, message: &Message) -> StoreResult<()> {
+        let mut tx = self.pool.begin().await?;
+        insert_message_rows(&mut tx, message).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn set_message_state(&self, id: &Uuid, state: MessageState) -> StoreResult<()> {
+        let result = sqlx::query("UPDATE messages SET state = $1 WHERE id = $2")
+            .bind(to_sql_enum(&state)?)
+            .bind(id.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "message not found",
+            )));
+        }
+
+        Ok(())
+    }
+
+    async fn list_thread(&self, thread_id: &Uuid) -> StoreResult<Vec<Message>> {
+        self.query_messages(
+            "id = $1 OR thread_id = $1",
+            "created_at ASC, id ASC",
+            vec![thread_id.to_string()],
+        )
+        .await
+    }
+
+    async fn set_message_receipt(&self, id: &Uuid, receipt: &MessageReceipt) -> StoreResult<()> {
+        let result = sqlx::query(
+            "UPDATE message_receivers SET state = $1, sent_at = $2, received_at = $3, \
+             read_at = $4 WHERE message_id = $5 AND receiver = $6",
+        )
+        .bind(to_sql_enum(&receipt.state)?)
+        .bind(receipt.sent_at.as_ref().map(to_sql_time))
+        .bind(receipt.received_at.as_ref().map(to_sql_time))
+        .bind(receipt.read_at.as_ref().map(to_sql_time))
+        .bind(id.to_string())
+        .bind(receipt.receiver.as_str())
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "message receiver not found",
+            )));
+        }
+
+        Ok(())
+    }
+
+    async fn count_unread_messages(&self, user: &User) -> StoreResult<usize> {
+        let row = sqlx::query(
+            "SELECT COUNT(*) AS unread FROM message_receivers WHERE receiver = $1 AND state <> $2",
+        )
+        .bind(user.email.as_str())
+        .bind(to_sql_enum(&MessageState::Read)?)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.try_get::<i64, _>("unread")? as usize)
+    }
+}
+
+#[async_trait]
+impl TokenStore for SqlStore {
+    async fn insert_refresh_token(&self, token: &RefreshToken) -> StoreResult<()> {
+        sqlx::query(
+            "INSERT INTO refresh_tokens (id, user_id, created_at, expires_at, revoked) \
+             VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(token.id.as_str())
+        .bind(token.user_id.to_string())
+        .bind(to_sql_time(&token.created_at))
+        .bind(to_sql_time(&token.expires_at))
+        .bind(token.revoked as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_refresh_token(&self, id: &str) -> StoreResult<Option<RefreshToken>> {
+        let row = sqlx::query(
+            "SELECT id, user_id, created_at, expires_at, revoked FROM refresh_tokens \
+             WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match row {
+            Some(row) => Ok(Some(RefreshToken {
+                id: row.try_get("id")?,
+                user_id: uuid_column(&row, "user_id")?,
+                created_at: time_column(&row, "created_at")?,
+                expires_at: time_column(&row, "expires_at")?,
+                revoked: row.try_get::<i64, _>("revoked")? != 0,
+            })),
+            _ => Ok(None),
+        }
+    }
+
+    async fn revoke_refresh_token(&self, id: &str) -> StoreResult<bool> {
+        let result =
+            sqlx::query("UPDATE refresh_tokens SET revoked = 1 WHERE id = $1 AND revoked = 0")
+                .bind(id)
+                .execute(&self.pool)
+                .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn revoke_access_token(&self, token: &RevokedToken) -> StoreResult<()> {
+        sqlx::query(
+            "INSERT INTO revoked_tokens (jti, expires_at) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        )
+        .bind(token.jti.to_string())
+        .bind(to_sql_time(&token.expires_at))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn is_access_token_revoked(&self, jti: &Uuid) -> StoreResult<bool> {
+        let row = sqlx::query("SELECT jti FROM revoked_tokens WHERE jti = $1")
+            .bind(jti.to_string())
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.is_some())
+    }
+
+    async fn revoke_user_refresh_tokens(&self, user_id: &Uuid) -> StoreResult<()> {
+        sqlx::query("UPDATE refresh_tokens SET revoked = 1 WHERE user_id = $1 AND revoked = 0")
+            .bind(user_id.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+const OUTBOX_COLUMNS: &str =
+    "message_id, state, attempts, next_attempt_at, last_error, retry_tokens, created_at";
+
+#[async_trait]
+impl OutboxStore for SqlStore {
+    async fn insert_message_with_outbox(
+        &self,
+        message: &Message,
+        entry: &OutboxEntry,
+    ) -> StoreResult<()> {
+        let mut tx = self.pool.begin().await?;
+        insert_message_rows(&mut tx, message).await?;
+        sqlx::query(&format!(
+            "INSERT INTO outbox ({}) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            OUTBOX_COLUMNS
+        ))
+        .bind(entry.message_id.to_string())
+        .bind(to_sql_enum(&entry.state)?)
+        .bind(entry.attempts as i64)
+        .bind(to_sql_time(&entry.next_attempt_at))
+        .bind(entry.last_error.clone())
+        .bind(match &entry.retry_tokens {
+            Some(tokens) => Some(serde_json::to_string(tokens)?),
+            None => None,
+        })
+        .bind(to_sql_time(&entry.created_at))
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn claim_outbox_entries(
+        &self,
+        now: &DateTime<Utc>,
+        lease_until: &DateTime<Utc>,
+        limit: usize,
+    ) -> StoreResult<Vec<OutboxEntry>> {
+        // the outer conditions are checked again after waiting on a row lock,
+        // so two workers never claim the same entry
+        let sql = format!(
+            "UPDATE outbox SET next_attempt_at = $1 \
+             WHERE state = $2 AND next_attempt_at <= $3 AND message_id IN \
+             (SELECT message_id FROM outbox WHERE state = $2 AND next_attempt_at <= $3 \
+             ORDER BY next_attempt_at LIMIT $4) \
+             RETURNING {}",
+            OUTBOX_COLUMNS
+        );
+        let rows = sqlx::query(&sql)
+            .bind(to_sql_time(lease_until))
+            .bind(to_sql_enum(&OutboxState::Pending)?)
+            .bind(to_sql_time(now))
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut entries = rows
+            .iter()
+            .map(outbox_entry_from_row)
+            .collect::<StoreResult<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.created_at);
+        Ok(entries)
+    }
+
+    async fn update_outbox_entry(&self, entry: &OutboxEntry) -> StoreResult<()> {
+        let result = sqlx::query(
+            "UPDATE outbox SET state = $1, attempts = $2, next_attempt_at = $3, \
+             last_error = $4, retry_tokens = $5 WHERE message_id = $6",
+        )
+        .bind(to_sql_enum(&entry.state)?)
+        .bind(entry.attempts as i64)
+        .bind(to_sql_time(&entry.next_attempt_at))
+        .bind(entry.last_error.clone())
+        .bind(match &entry.retry_tokens {
+            Some(tokens) => Some(serde_json::to_string(tokens)?),
+            None => None,
+        })
+        .bind(entry.message_id.to_string())
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "outbox entry not found",
+            )));
+        }
+
+        Ok(())
+    }
+
+    async fn get_outbox_entry(&self, message_id: &Uuid) -> StoreResult<Option<OutboxEntry>> {
+        let row = sqlx::query(&format!(
+            "SELECT {} FROM outbox WHERE message_id = $1",
+            OUTBOX_COLUMNS
+        ))
+        .bind(message_id.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.as_ref().map(outbox_entry_from_row).transpose()
+    }
+}
+
+const DIGEST_COLUMNS: &str = "user_id, frequency, next_digest_at, last_digest_at, enrollments";
+
+#[async_trait]
+impl DigestStore for SqlStore {
+    async fn get_digest_schedule(&self, user_id: &Uuid) -> StoreResult<Option<DigestSchedule>> {
+        let row = sqlx::query(&format!(
+            "SELECT {} FROM digest_schedules WHERE user_id = $1",
+            DIGEST_COLUMNS
+        ))
+        .bind(user_id.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.as_ref().map(digest_schedule_from_row).transpose()
+    }
+
+    async fn set_digest_schedule(&self, schedule: &DigestSchedule) -> StoreResult<()> {
+        sqlx::query(&format!(
+            "INSERT INTO digest_schedules ({}) VALUES ($1, $2, $3, $4, $5) \
+             ON CONFLICT (user_id) DO UPDATE SET frequency = $2, next_digest_at = $3, \
+             last_digest_at = $4, enrollments = $5",
+            DIGEST_COLUMNS
+        ))
+        .bind(schedule.user_id.to_string())
+        .bind(to_sql_enum(&schedule.frequency)?)
+        .bind(to_sql_time(&schedule.next_digest_at))
+        .bind(to_sql_time(&schedule.last_digest_at))
+        .bind(serde_json::to_string(&schedule.enrollments)?)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn delete_digest_schedule(&self, user_id: &Uuid) -> StoreResult<()> {
+        sqlx::query("DELETE FROM digest_schedules WHERE user_id = $1")
+            .bind(user_id.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn claim_digest_schedules(
+        &self,
+        now: &DateTime<Utc>,
+        lease_until: &DateTime<Utc>,
+        limit: usize,
+    ) -> StoreResult<Vec<DigestSchedule>> {
+        // same double check as `claim_outbox_entries`
+        let sql = format!(
+            "UPDATE digest_schedules SET next_digest_at = $1 \
+             WHERE next_digest_at <= $2 AND user_id IN \
+             (SELECT user_id FROM digest_schedules WHERE next_digest_at <= $2 \
+             ORDER BY next_digest_at LIMIT $3) \
+             RETURNING {}",
+            DIGEST_COLUMNS
+        );
+        let rows = sqlx::query(&sql)
+            .bind(to_sql_time(lease_until))
+            .bind(to_sql_time(now))
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.iter().map(digest_schedule_from_row).collect()
+    }
+}
+
+#[async_trait]
+impl AttachmentStore for SqlStore {
+    async fn insert_attachment(&self, attachment: &Attachment) -> StoreResult<()> {
+        sqlx::query(
+            "INSERT INTO attachments \
+             (id, owner_id, file_name, content_type, size, created_at, refs) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        )
+        .bind(attachment.id.to_string())
+        .bind(attachment.owner_id.to_string())
+        .bind(attachment.file_name.as_str())
+        .bind(attachment.content_type.as_str())
+        .bind(attachment.size as i64)
+        .bind(to_sql_time(&attachment.created_at))
+        .bind(serde_json::to_string(&attachment.refs)?)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_attachment(&self, id: &Uuid) -> StoreResult<Option<Attachment>> {
+        let row = sqlx::query(
+            "SELECT id, owner_id, file_name, content_type, size, created_at, refs \
+             FROM attachments WHERE id = $1",
+        )
+        .bind(id.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.as_ref().map(attachment_from_row).transpose()
+    }
+
+    async fn update_attachment(&self, attachment: &Attachment) -> StoreResult<()> {
+        let result = sqlx::query(
+            "UPDATE attachments SET file_name = $1, content_type = $2, size = $3, refs = $4 \
+             WHERE id = $5",
+        )
+        .bind(attachment.file_name.as_str())
+        .bind(attachment.content_type.as_str())
+        .bind(attachment.size as i64)
+        .bind(serde_json::to_string(&attachment.refs)?)
+        .bind(attachment.id.to_string())
+        .execute(&self.pool)
+        .await?;
+
+        match result.rows_affected() {
+            0 => Err(not_found("attachment")),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl AuditStore for SqlStore {
+    async fn insert_audit_entry(&self, entry: &AuditEntry) -> StoreResult<()> {
+        sqlx::query(
+            "INSERT INTO audit_log (id, actor_id, action, target_id, details, created_at) \
+             VALUES ($1, $2, $3, $4, $5, $6)",
+        )
+        .bind(entry.id.to_string())
+        .bind(entry.actor_id.to_string())
+        .bind(to_sql_enum(&entry.action)?)
+        .bind(entry.target_id.to_string())
+        .bind(entry.details.as_str())
+        .bind(to_sql_time(&entry.created_at))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn list_audit_entries(
+        &self,
+        target_id: Option<&Uuid>,
+        limit: usize,
+    ) -> StoreResult<Vec<AuditEntry>> {
+        let (filter, args) = match target_id {
+            Some(id) => ("WHERE target_id = $1", vec![id.to_string()]),
+            None => ("", vec![]),
+        };
+        let sql = format!(
+            "SELECT id, actor_id, action, target_id, details, created_at FROM audit_log {} \
+             ORDER BY created_at DESC, id DESC LIMIT {}",
+            filter, limit
+        );
+        let mut query = sqlx::query(&sql);
+        for arg in args {
+            query = query.bind(arg);
+        }
+        let rows = query.fetch_all(&self.pool).await?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(AuditEntry {
+                    id: uuid_column(row, "id")?,
+                    actor_id: uuid_column(row, "actor_id")?,
+                    action: from_sql_enum(&row.try_get::<String, _>("action")?)?,
+                    target_id: uuid_column(row, "target_id")?,
+                    details: row.try_get("details")?,
+                    created_at: time_column(row, "created_at")?,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::message::{MessageCursor, MessageFilter, MessageQuery, MessageType};
+    use crate::common::outbox::new_outbox_entry;
+    use crate::common::store::{
+        AttachmentStore, AuditStore, CourseStore, DigestStore, EnrollmentStore, LessonStore,
+        MessageStore, OutboxStore, SqlStore, StudentParentStore, TokenStore, UserStore,
+    };
+    use crate::common::user::UserQuery;
+    use crate::common::{
+        Approver, Attachment, AttachmentRef, AuditAction, AuditEntry, Course, CourseModule,
+        DigestFrequency, DigestSchedule, Enrollment, EnrollmentStatus, Lesson, LessonCompletion,
+        LessonLink, Message, MessageReceipt, MessageState, NotificationCategory, OutboxState,
+        RefreshToken, User, UserRole, UserWithPassword,
+    };
+    use chrono::{Duration, Utc};
+    use uuid::Uuid;
+
+    fn user(email: &str, role: UserRole) -> UserWithPassword {
+        UserWithPassword {
+            uid: Uuid::new_v4(),
+            email: email.to_string(),
+            password: "!".to_string(),
+            role,
+            name: email.to_string(),
+            devices: vec!["device-1".to_string()],
+            preferences: Default::default(),
+            deactivated_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_users_and_enrollment_async() {
+        let db = SqlStore::connect("sqlite::memory:").await.unwrap();
+        let teacher = user("t1@t1.com", UserRole::Teacher);
+        let student = user("hl@hl.com", UserRole::Student);
+        let parent = user("mom@mom.com", UserRole::Parent);
+        for u in [&teacher, &student, &parent] {
+            db.insert_user(u).await.unwrap();
+        }
+        db.link_students(&parent.uid, &[student.uid]).await.unwrap();
+
+        let found = db.find_user("hl@hl.com").await.unwrap().unwrap();
+        assert_eq!(found.uid, student.uid);
+        assert_eq!(found.devices, vec!["device-1".to_string()]);
+        assert_eq!(db.list_parents_of(&student.uid).await.unwrap().len(), 1);
+
+        let course = Course {
+            id: Uuid::new_v4(),
+            title: "Mathematics".to_string(),
+            content: "Algebra".to_string(),
+            teacher_id: teacher.uid,
+            archived_at: None,
+            capacity: None,
+            teacher_approval: false,
+            parent_consent: false,
+        };
+        db.insert_course(&course).await.unwrap();
+        assert_eq!(
+            db.list_teacher_courses(&teacher.uid).await.unwrap(),
+            vec![course.clone()]
+        );
+
+        let archived = Course {
+            title: "Algebra I".to_string(),
+            archived_at: Some(Utc::now()),
+            ..course.clone()
+        };
+        db.update_course(&archived).await.unwrap();
+        let stored = db.get_course(&course.id).await.unwrap().unwrap();
+        assert_eq!(stored.title, "Algebra I");
+        assert!(stored.archived_at.is_some());
+        db.update_course(&course).await.unwrap();
+
+        let enrollment = Enrollment::new(student.uid, course.id);
+        assert!(db.insert_enrollment(&enrollment).await.unwrap());
+        let again = Enrollment {
+            id: Uuid::new_v4(),
+            ..enrollment.clone()
+        };
+        assert!(!db.insert_enrollment(&again).await.unwrap());
+        assert_eq!(db.count_course_enrollments(&course.id).await.unwrap(), 1);
+
+        // a batch skips students already enrolled
+        let science = Course {
+            id: Uuid::new_v4(),
+            title: "Science".to_string(),
+            ..course.clone()
+        };
+        db.insert_course(&science).await.unwrap();
+        let classmate = user("ann@ann.com", UserRole::Student);
+        db.insert_user(&classmate).await.unwrap();
+        let batch = [
+            Enrollment::new(student.uid, science.id),
+            Enrollment::new(classmate.uid, science.id),
+        ];
+        assert!(db.insert_enrollment(&batch[0]).await.unwrap());
+        let written = db.insert_enrollments(&batch).await.unwrap();
+        assert_eq!(written.len(), 1);
+        assert_eq!(written[0].student_id, classmate.uid);
+        assert_eq!(db.count_course_enrollments(&science.id).await.unwrap(), 2);
+
+        let dropped = Enrollment {
+            status: EnrollmentStatus::Dropped,
+            ended_at: Some(Utc::now()),
+            ..enrollment
+        };
+        assert!(db
+            .update_enrollment(&dropped, EnrollmentStatus::Active)
+            .await
+            .unwrap());
+        // already dropped
+        assert!(!db
+            .update_enrollment(&dropped, EnrollmentStatus::Active)
+            .await
+            .unwrap());
+        let stored = db
+            .find_enrollment(&student.uid, &course.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.status, EnrollmentStatus::Dropped);
+        assert!(stored.ended_at.is_some());
+        assert_eq!(db.count_course_enrollments(&course.id).await.unwrap(), 0);
+        assert_eq!(
+            db.list_course_enrollments(&course.id).await.unwrap().len(),
+            1
+        );
+
+        let pending = Enrollment {
+            status: EnrollmentStatus::Pending,
+            ended_at: None,
+            ..dropped
+        };
+        assert!(db
+            .update_enrollment(&pending, EnrollmentStatus::Dropped)
+            .await
+            .unwrap());
+        assert!(db
+            .approve_enrollment(&pending, Approver::Parent, &parent.uid)
+            .await
+            .unwrap());
+        let stored = db
+            .find_enrollment(&student.uid, &course.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.consented_by, Some(parent.uid));
+        assert_eq!(stored.approved_by, None);
+
+        let limited = Course {
+            capacity: Some(1),
+            ..course.clone()
+        };
+        db.update_course(&limited).await.unwrap();
+        let stored = db.get_course(&course.id).await.unwrap().unwrap();
+        assert_eq!(stored.capacity, Some(1));
+        assert!(db.take_seat(&course.id, stored.capacity).await.unwrap());
+        assert!(!db.take_seat(&course.id, stored.capacity).await.unwrap());
+        db.release_seat(&course.id).await.unwrap();
+        assert!(db.take_seat(&course.id, stored.capacity).await.unwrap());
+        assert!(db.take_seat(&course.id, None).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_messages_async() {
+        let db = SqlStore::connect("sqlite::memory:").await.unwrap();
+        let teacher = user("t1@t1.com", UserRole::Teacher);
+        let parent = user("mom@mom.com", UserRole::Parent);
+        for u in [&teacher, &parent] {
+            db.insert_user(u).await.unwrap();
+        }
+
+        let message = Message {
+            id: Uuid::new_v4(),
+            sender_id: teacher.uid,
+            receiver_ids: vec![parent.email.clone()],
+            subject: None,
+            content: "hello".to_string(),
+            state: MessageState::Pending,
+            created_at: Utc::now(),
+            thread_id: None,
+            reply_to: None,
+            category: NotificationCategory::Messages,
+            receipts: vec![],
+            attachments: vec![],
+        };
+        db.insert_message(&message).await.unwrap();
+        db.set_message_state(&message.id, MessageState::Read)
+            .await
+            .unwrap();
+        let parent_user = User::from(parent.clone());
+        assert_eq!(db.count_unread_messages(&parent_user).await.unwrap(), 1);
+        db.set_message_receipt(
+            &message.id,
+            &MessageReceipt {
+                state: MessageState::Read,
+                read_at: Some(Utc::now()),
+                ..MessageReceipt::pending(&parent.email)
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(db.count_unread_messages(&parent_user).await.unwrap(), 0);
+
+        let inbox = db
+            .list_messages(
+                &User::from(parent.clone()),
+                MessageType::Received,
+                &MessageQuery::default(),
+            )
+            .await
+            .unwrap()
+            .messages;
+        assert_eq!(inbox.len(), 1);
+        assert!(matches!(inbox[0].state, MessageState::Read));
+        assert_eq!(inbox[0].receiver_ids, vec![parent.email.clone()]);
+        assert!(inbox[0].receipts[0].read_at.is_some());
+
+        let sent = db
+            .list_messages(
+                &User::from(teacher.clone()),
+                MessageType::Sent,
+                &MessageQuery::default(),
+            )
+            .await
+            .unwrap()
+            .messages;
+        assert_eq!(sent.len(), 1);
+        let teacher_inbox = db
+            .list_messages(
+                &User::from(teacher),
+                MessageType::Received,
+                &MessageQuery::default(),
+            )
+            .await
+            .unwrap()
+            .messages;
+        assert!(teacher_inbox.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_message_pages_async() {
+        let db = SqlStore::connect("sqlite::memory:").await.unwrap();
+        let teacher = user("t1@t1.com", UserRole::Teacher);
+        let parent = user("mom@mom.com", UserRole::Parent);
+        for u in [&teacher, &parent] {
+            db.insert_user(u).await.unwrap();
+        }
+
+        let start = Utc::now();
+        for i in 0..5 {
+            let message = Message {
+                id: Uuid::new_v4(),
+                sender_id: teacher.uid,
+                receiver_ids: vec![parent.email.clone()],
+                subject: Some(format!("Homework {}", i)),
+                content: "hello".to_string(),
+                state: MessageState::Sent,
+                created_at: start + chrono::Duration::seconds(i),
+                thread_id: None,
+                reply_to: None,
+                category: NotificationCategory::Messages,
+                receipts: vec![],
+                attachments: vec![],
+            };
+            db.insert_message(&message).await.unwrap();
+        }
+
+        let parent_user = User::from(parent.clone());
+        let mut query = MessageQuery {
+            limit: 2,
+            ..MessageQuery::default()
+        };
+        let mut subjects = Vec::new();
+        loop {
+            let page = db
+                .list_messages(&parent_user, MessageType::Received, &query)
+                .await
+                .unwrap();
+            assert!(page.messages.len() <= 2);
+            subjects.extend(page.messages.into_iter().filter_map(|m| m.subject));
+            match page.next_cursor {
+                Some(cursor) => query.after = MessageCursor::decode(&cursor),
+                None => break,
+            }
+        }
+        assert_eq!(
+            subjects,
+            (0..5)
+                .rev()
+                .map(|i| format!("Homework {}", i))
+                .collect::<Vec<_>>()
+        );
+
+        let filtered = db
+            .list_messages(
+                &parent_user,
+                MessageType::Received,
+                &MessageQuery {
+                    filter: MessageFilter {
+                        subject: Some("WORK 3".to_string()),
+                        from: Some(start),
+                        ..MessageFilter::default()
+                    },
+                    ..MessageQuery::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(filtered.messages.len(), 1);
+
+        let read = db
+            .list_messages(
+                &parent_user,
+                MessageType::Received,
+                &MessageQuery {
+                    filter: MessageFilter {
+                        state: Some(MessageState::Read),
+                        ..MessageFilter::default()
+                    },
+                    ..MessageQuery::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert!(read.messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_outbox_async() {
+        let db = SqlStore::connect("sqlite::memory:").await.unwrap();
+        let teacher = user("t1@t1.com", UserRole::Teacher);
+        db.insert_user(&teacher).await.unwrap();
+
+        let now = Utc::now();
+        let message = Message {
+            id: Uuid::new_v4(),
+            sender_id: teacher.uid,
+            receiver_ids: vec!["mom@mom.com".to_string()],
+            subject: None,
+            content: "hello".to_string(),
+            state: MessageState::Pending,
+            created_at: now,
+            thread_id: None,
+            reply_to: None,
+            category: NotificationCategory::Messages,
+            receipts: vec![MessageReceipt::pending("mom@mom.com")],
+            attachments: vec![],
+        };
+        let mut entry = new_outbox_entry(&message, now);
+        db.insert_message_with_outbox(&message, &entry)
+            .await
+            .unwrap();
+        assert!(db.get_message(&message.id).await.unwrap().is_some());
+
+        // leased to the sender until OUTBOX_LEASE_SECS
+        let lease = now + Duration::minutes(10);
+        assert!(db
+            .claim_outbox_entries(&now, &lease, 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        entry.attempts = 1;
+        entry.next_attempt_at = now;
+        entry.retry_tokens = Some(vec!["device-1".to_string()]);
+        db.update_outbox_entry(&entry).await.unwrap();
+
+        let claimed = db.claim_outbox_entries(&now, &lease, 10).await.unwrap();
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(claimed[0].attempts, 1);
+        assert_eq!(claimed[0].retry_tokens, entry.retry_tokens);
+        assert!(claimed[0].next_attempt_at > now);
+        // claimed entries aren't handed out twice
+        assert!(db
+            .claim_outbox_entries(&now, &lease, 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        entry.state = OutboxState::Delivered;
+        db.update_outbox_entry(&entry).await.unwrap();
+        let stored = db.get_outbox_entry(&message.id).await.unwrap().unwrap();
+        assert_eq!(stored.state, OutboxState::Delivered);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_digests_async() {
+        let db = SqlStore::connect("sqlite::memory:").await.unwrap();
+        let parent = user("mom@mom.com", UserRole::Parent);
+        db.insert_user(&parent).await.unwrap();
+
+        let now = Utc::now();
+        let mut schedule = DigestSchedule {
+            user_id: parent.uid,
+            frequency: DigestFrequency::Daily,
+            next_digest_at: now + Duration::days(1),
+            last_digest_at: now,
+            enrollments: vec![format!("{}:{}", Uuid::new_v4(), Uuid::new_v4())],
+        };
+        db.set_digest_schedule(&schedule).await.unwrap();
+        let lease = now + Duration::minutes(10);
+        assert!(db
+            .claim_digest_schedules(&now, &lease, 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // replaced in place
+        schedule.frequency = DigestFrequency::Weekly;
+        schedule.next_digest_at = now - Duration::minutes(1);
+        db.set_digest_schedule(&schedule).await.unwrap();
+        let claimed = db.claim_digest_schedules(&now, &lease, 10).await.unwrap();
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(claimed[0].frequency, DigestFrequency::Weekly);
+        assert_eq!(claimed[0].enrollments, schedule.enrollments);
+        assert!(claimed[0].next_digest_at > now);
+        assert!(db
+            .claim_digest_schedules(&now, &lease, 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        db.delete_digest_schedule(&parent.uid).await.unwrap();
+        assert!(db.get_digest_schedule(&parent.uid).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_lessons_async() {
+        let db = SqlStore::connect("sqlite::memory:").await.unwrap();
+        let teacher = user("t1@t1.com", UserRole::Teacher);
+        let student = user("hl@hl.com", UserRole::Student);
+        for u in [&teacher, &student] {
+            db.insert_user(u).await.unwrap();
+        }
+        let course = Course {
+            id: Uuid::new_v4(),
+            title: "Mathematics".to_string(),
+            content: "Algebra".to_string(),
+            teacher_id: teacher.uid,
+            archived_at: None,
+            capacity: None,
+            teacher_approval: false,
+            parent_consent: false,
+        };
+        db.insert_course(&course).await.unwrap();
+
+        let modules: Vec<CourseModule> = (0..2)
+            .map(|position| CourseModule {
+                id: Uuid::new_v4(),
+                course_id: course.id,
+                title: format!("Module {}", position),
+                position,
+            })
+            .collect();
+        for m in &modules {
+            db.insert_module(m).await.unwrap();
+        }
+        db.reorder_modules(&course.id, &[modules[1].id, modules[0].id])
+            .await
+            .unwrap();
+        let listed = db.list_course_modules(&course.id).await.unwrap();
+        assert_eq!(listed[0].id, modules[1].id);
+
+        let lesson = Lesson {
+            id: Uuid::new_v4(),
+            course_id: course.id,
+            module_id: modules[0].id,
+            title: "Fractions".to_string(),
+            body: "Halves".to_string(),
+            links: vec![LessonLink {
+                title: "Video".to_string(),
+                url: "https://example.com".to_string(),
+            }],
+            materials: vec![],
+            position: 0,
+            published: true,
+            updated_at: Utc::now(),
+        };
+        db.insert_lesson(&lesson).await.unwrap();
+        db.reorder_lessons(&course.id, &modules[1].id, &[lesson.id])
+            .await
+            .unwrap();
+        let found = db.get_lesson(&lesson.id).await.unwrap().unwrap();
+        assert_eq!(found.module_id, modules[1].id);
+        assert_eq!(found.links, lesson.links);
+        assert!(found.published);
+
+        let completion = LessonCompletion {
+            lesson_id: lesson.id,
+            student_id: student.uid,
+            course_id: course.id,
+            completed_at: Utc::now(),
+        };
+        assert!(db.insert_lesson_completion(&completion).await.unwrap());
+        assert!(!db.insert_lesson_completion(&completion).await.unwrap());
+        assert_eq!(
+            db.list_course_completions(&course.id).await.unwrap().len(),
+            1
+        );
+
+        db.delete_lesson(&lesson.id).await.unwrap();
+        assert!(db.get_lesson(&lesson.id).await.unwrap().is_none());
+        assert!(db
+            .list_course_completions(&course.id)
+            .await
+            .unwrap()
+            .is_empty());
+        db.delete_module(&modules[0].id).await.unwrap();
+        assert_eq!(db.list_course_modules(&course.id).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_attachments_async() {
+        let db = SqlStore::connect("sqlite::memory:").await.unwrap();
+        let teacher = user("t1@t1.com", UserRole::Teacher);
+        db.insert_user(&teacher).await.unwrap();
+
+        let mut attachment = Attachment {
+            id: Uuid::new_v4(),
+            owner_id: teacher.uid,
+            file_name: "sheet.pdf".to_string(),
+            content_type: "application/pdf".to_string(),
+            size: 42,
+            created_at: Utc::now(),
+            refs: vec![],
+        };
+        db.insert_attachment(&attachment).await.unwrap();
+        let message = Message {
+            id: Uuid::new_v4(),
+            sender_id: teacher.uid,
+            receiver_ids: vec!["mom@mom.com".to_string()],
+            subject: None,
+            content: "homework".to_string(),
+            state: MessageState::Sent,
+            created_at: Utc::now(),
+            thread_id: None,
+            reply_to: None,
+            category: NotificationCategory::Messages,
+            receipts: vec![],
+            attachments: vec![attachment.id],
+        };
+        db.insert_message(&message).await.unwrap();
+        attachment.refs.push(AttachmentRef::Message {
+            message_id: message.id,
+        });
+        db.update_attachment(&attachment).await.unwrap();
+
+        let found = db.get_attachment(&attachment.id).await.unwrap().unwrap();
+        assert_eq!(found.refs, attachment.refs);
+        assert_eq!(found.size, 42);
+        let found = db.get_message(&message.id).await.unwrap().unwrap();
+        assert_eq!(found.attachments, vec![attachment.id]);
+        assert!(db.get_attachment(&Uuid::new_v4()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_admin_async() {
+        let db = SqlStore::connect("sqlite::memory:").await.unwrap();
+        let admin = user("admin@admin.com", UserRole::Admin);
+        let student = user("hl@hl.com", UserRole::Student);
+        let parent = user("mom@mom.com", UserRole::Parent);
+        for u in [&admin, &student, &parent] {
+            db.insert_user(u).await.unwrap();
+        }
+
+        let query = UserQuery {
+            search: Some("MOM".to_string()),
+            ..UserQuery::default()
+        };
+        let page = db.list_users(&query).await.unwrap();
+        assert_eq!(page.users.len(), 1);
+        assert_eq!(page.users[0].uid, parent.uid);
+        let query = UserQuery {
+            limit: 2,
+            ..UserQuery::default()
+        };
+        let first = db.list_users(&query).await.unwrap();
+        assert_eq!(first.users.len(), 2);
+        assert_eq!(first.next_cursor.as_deref(), Some("hl@hl.com"));
+        let query = UserQuery {
+            after: first.next_cursor,
+            ..query
+        };
+        let second = db.list_users(&query).await.unwrap();
+        assert_eq!(second.users.len(), 1);
+        assert!(second.next_cursor.is_none());
+
+        let deactivated = User {
+            name: "Hannah".to_string(),
+            deactivated_at: Some(Utc::now()),
+            ..User::from(student.clone())
+        };
+        db.update_account(&deactivated).await.unwrap();
+        db.set_password(&student.uid, "hash").await.unwrap();
+        let found = db.find_user("hl@hl.com").await.unwrap().unwrap();
+        assert_eq!(found.name, "Hannah");
+        assert_eq!(found.password, "hash");
+        assert!(found.deactivated_at.is_some());
+        let query = UserQuery {
+            active: Some(false),
+            ..UserQuery::default()
+        };
+        assert_eq!(db.list_users(&query).await.unwrap().users.len(), 1);
+
+        let token = RefreshToken {
+            id: "refresh".to_string(),
+            user_id: student.uid,
+            created_at: Utc::now(),
+            expires_at: Utc::now() + Duration::days(1),
+            revoked: false,
+        };
+        db.insert_refresh_token(&token).await.unwrap();
+        db.revoke_user_refresh_tokens(&student.uid).await.unwrap();
+        assert!(
+            db.get_refresh_token("refresh")
+                .await
+                .unwrap()
+                .unwrap()
+                .revoked
+        );
+
+        db.link_students(&parent.uid, &[student.uid]).await.unwrap();
+        assert!(db.unlink_student(&parent.uid, &student.uid).await.unwrap());
+        assert!(!db.unlink_student(&parent.uid, &student.uid).await.unwrap());
+
+        for (action, target_id) in [
+            (AuditAction::Deactivate, student.uid),
+            (AuditAction::UnlinkStudent, parent.uid),
+        ] {
+            let entry = AuditEntry {
+                id: Uuid::new_v4(),
+                actor_id: admin.uid,
+                action,
+                target_id,
+                details: String::new(),
+                created_at: Utc::now(),
+            };
+            db.insert_audit_entry(&entry).await.unwrap();
+        }
+        let entries = db.list_audit_entries(None, 10).await.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].action, AuditAction::UnlinkStudent);
+        let entries = db.list_audit_entries(Some(&student.uid), 10).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].action, AuditAction::Deactivate);
+    }
+}
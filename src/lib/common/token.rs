@@ -0,0 +1,180 @@
+use crate::api::auth::TokenClaims;
+use crate::common::store::{Store, StoreResult};
+use crate::common::{RefreshToken, RevokedToken, ACCESS_TOKEN_TTL_SECS, REFRESH_TOKEN_TTL_SECS};
+use chrono::{Duration, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use jwt::{SignWithKey, VerifyWithKey};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenPair {
+    pub token: String,
+    pub refresh_token: String,
+    /// Lifetime of `token` in seconds.
+    pub expires_in: i64,
+}
+
+pub fn jwt_key() -> Hmac<Sha256> {
+    Hmac::new_from_slice(
+        std::env::var("JWT_SECRET")
+            .expect("JWT_SECRET must be set!")
+            .as_bytes(),
+    )
+    .unwrap()
+}
+
+/// Refresh tokens are stored by their SHA-256 so a leaked collection can't be
+/// replayed.
+pub fn hash_refresh_token(refresh_token: &str) -> String {
+    Sha256::digest(refresh_token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Signs a short-lived access token and opens a new refresh token session.
+pub async fn issue_tokens(db: &dyn Store, user_id: &Uuid) -> StoreResult<TokenPair> {
+    let now = Utc::now();
+    let claims = TokenClaims {
+        id: *user_id,
+        jti: Uuid::new_v4(),
+        iat: now.timestamp(),
+        exp: (now + Duration::seconds(ACCESS_TOKEN_TTL_SECS)).timestamp(),
+    };
+    let token = claims.sign_with_key(&jwt_key())?;
+
+    let refresh_token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    db.insert_refresh_token(&RefreshToken {
+        id: hash_refresh_token(&refresh_token),
+        user_id: *user_id,
+        created_at: now,
+        expires_at: now + Duration::seconds(REFRESH_TOKEN_TTL_SECS),
+        revoked: false,
+    })
+    .await?;
+
+    Ok(TokenPair {
+        token,
+        refresh_token,
+        expires_in: ACCESS_TOKEN_TTL_SECS,
+    })
+}
+
+/// Spends `refresh_token` and issues a fresh pair. Returns `None` when the
+/// token is unknown, expired or was already used, or its user deactivated.
+pub async fn refresh_tokens(db: &dyn Store, refresh_token: &str) -> StoreResult<Option<TokenPair>> {
+    let id = hash_refresh_token(refresh_token);
+    let session = match db.get_refresh_token(&id).await? {
+        Some(s) if !s.revoked && s.expires_at > Utc::now() => s,
+        _ => return Ok(None),
+    };
+    if !db
+        .get_user(&session.user_id)
+        .await?
+        .is_some_and(|u| u.is_active())
+    {
+        return Ok(None);
+    }
+
+    // revoking first makes concurrent refreshes with the same token lose
+    if !db.revoke_refresh_token(&id).await? {
+        return Ok(None);
+    }
+
+    Ok(Some(issue_tokens(db, &session.user_id).await?))
+}
+
+/// Checks signature, expiry and revocation of an access token.
+pub async fn verify_access_token(db: &dyn Store, token: &str) -> StoreResult<Option<TokenClaims>> {
+    let claims: TokenClaims = match token.verify_with_key(&jwt_key()) {
+        Ok(c) => c,
+        _ => return Ok(None),
+    };
+
+    if claims.exp <= Utc::now().timestamp() {
+        return Ok(None);
+    }
+
+    if db.is_access_token_revoked(&claims.jti).await? {
+        return Ok(None);
+    }
+
+    Ok(Some(claims))
+}
+
+/// Revokes the access token behind `claims` and, if given, the caller's
+/// refresh token.
+pub async fn revoke_tokens(
+    db: &dyn Store,
+    claims: &TokenClaims,
+    refresh_token: Option<&str>,
+) -> StoreResult<()> {
+    db.revoke_access_token(&RevokedToken {
+        jti: claims.jti,
+        expires_at: Utc
+            .timestamp_opt(claims.exp, 0)
+            .single()
+            .unwrap_or_else(Utc::now),
+    })
+    .await?;
+
+    if let Some(refresh_token) = refresh_token {
+        let id = hash_refresh_token(refresh_token);
+        if let Some(session) = db.get_refresh_token(&id).await? {
+            if session.user_id == claims.id {
+                db.revoke_refresh_token(&id).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::store::{Fixtures, MemoryStore};
+    use crate::common::token::{issue_tokens, refresh_tokens, revoke_tokens, verify_access_token};
+
+    #[tokio::test]
+    async fn test_refresh_and_logout_async() {
+        std::env::set_var("JWT_SECRET", "test-secret");
+        let db = MemoryStore::from_fixtures(Fixtures::test());
+        // refreshing needs an active user
+        let user_id = Fixtures::id("student");
+
+        let pair = issue_tokens(&db, &user_id).await.unwrap();
+        let claims = verify_access_token(&db, &pair.token)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(claims.id, user_id);
+
+        let next = refresh_tokens(&db, &pair.refresh_token)
+            .await
+            .unwrap()
+            .unwrap();
+        // a refresh token can only be used once
+        assert!(refresh_tokens(&db, &pair.refresh_token)
+            .await
+            .unwrap()
+            .is_none());
+
+        let next_claims = verify_access_token(&db, &next.token)
+            .await
+            .unwrap()
+            .unwrap();
+        revoke_tokens(&db, &next_claims, Some(&next.refresh_token))
+            .await
+            .unwrap();
+        assert!(verify_access_token(&db, &next.token)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(refresh_tokens(&db, &next.refresh_token)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
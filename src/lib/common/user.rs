@@ -1,156 +1,171 @@
-use crate::api::auth::TokenClaims;
+use crate::common::digest::sync_digest_schedule;
+use crate::common::store::{Store, StoreResult};
 use crate::common::{
-    Course, Enrollment, Kid, StudentsParents, User, UserRole, UserWithPassword,
-    UserWithPasswordStudents, COURSES_COLLECTION, ENROLLMENTS_COLLECTION,
-    STUDENTS_PARENTS_COLLECTION, USERS_COLLECTION,
+    Kid, NotificationPreferences, PastCourse, User, UserRole, UserWithPassword,
+    UserWithPasswordStudents, DEFAULT_USERS_PAGE_SIZE,
 };
-use actix_web::web::ReqData;
+use actix_web::web;
 use argonautica::Hasher;
-use firestore::{path, paths, FirestoreDb, FirestoreResult};
-use futures::stream::BoxStream;
-use futures::{StreamExt, TryStreamExt};
 use serde::Serialize;
 use uuid::Uuid;
 
-pub async fn get_user_by_id(
-    db: &FirestoreDb,
-    id: &Uuid,
-) -> Result<Option<User>, Box<dyn std::error::Error + Send + Sync>> {
-    let user: Option<User> = db
-        .fluent()
-        .select()
-        //.fields(paths!(User::{uid, email, name, role, devices}))
-        .by_id_in(USERS_COLLECTION)
-        .obj()
-        .one(&id.to_string())
-        .await?;
-
-    Ok(user)
+/// Optional restrictions on a user listing and where its page starts.
+/// Listings are ordered by email, the next page starts right after the
+/// email in `after`.
+#[derive(Debug, Clone)]
+pub struct UserQuery {
+    // case-insensitive substring of the name or email, on Firestore its
+    // first word has to start the email or a word of the name
+    pub search: Option<String>,
+    pub role: Option<UserRole>,
+    pub active: Option<bool>,
+    pub after: Option<String>,
+    pub limit: usize,
+}
+
+impl Default for UserQuery {
+    fn default() -> Self {
+        UserQuery {
+            search: None,
+            role: None,
+            active: None,
+            after: None,
+            limit: DEFAULT_USERS_PAGE_SIZE,
+        }
+    }
+}
+
+impl UserQuery {
+    /// Checks the filters, not the position.
+    pub fn matches(&self, user: &User) -> bool {
+        self.role.is_none_or(|r| user.role == r)
+            && self.active.is_none_or(|a| user.is_active() == a)
+            && self.search.as_ref().is_none_or(|text| {
+                let text = text.to_lowercase();
+                user.name.to_lowercase().contains(&text)
+                    || user.email.to_lowercase().contains(&text)
+            })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UserPage {
+    pub users: Vec<User>,
+    // pass back as `cursor` for the next page, `None` on the last one
+    pub next_cursor: Option<String>,
+}
+
+impl UserPage {
+    /// Cuts `users`, fetched with one extra row, down to `limit` like
+    /// `MessagePage::from_overfetch`.
+    pub fn from_overfetch(mut users: Vec<User>, limit: usize) -> Self {
+        let next_cursor = match users.len() > limit {
+            true => {
+                users.truncate(limit);
+                users.last().map(|u| u.email.clone())
+            }
+            false => None,
+        };
+
+        UserPage { users, next_cursor }
+    }
+}
+
+pub async fn get_user_by_id(db: &dyn Store, id: &Uuid) -> StoreResult<Option<User>> {
+    db.get_user(id).await
+}
+
+/// Emails are stored and compared trimmed and in lower case.
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// The forms to look emails up by: normalized, and as given for accounts
+/// stored before emails were normalized.
+pub fn email_lookup<T: AsRef<str>>(emails: &[T]) -> Vec<String> {
+    let mut lookup = emails
+        .iter()
+        .flat_map(|e| [e.as_ref().trim().to_string(), normalize_email(e.as_ref())])
+        .collect::<Vec<_>>();
+    lookup.sort();
+    lookup.dedup();
+    lookup
 }
 
 pub async fn try_find_user(
-    db: &FirestoreDb,
+    db: &dyn Store,
     email_or_id: &str,
-) -> Result<Option<UserWithPassword>, Box<dyn std::error::Error + Send + Sync>> {
-    let users: Vec<UserWithPassword> = db
-        .fluent()
-        .select()
-        .from(USERS_COLLECTION)
-        .filter(|q| {
-            q.for_any([
-                q.field(path!(UserWithPassword::email)).eq(email_or_id),
-                q.field(path!(UserWithPassword::uid)).eq(email_or_id),
-            ])
-        })
-        .limit(1)
-        .obj()
-        .query()
-        .await?;
-
-    Ok(users.into_iter().next())
+) -> StoreResult<Option<UserWithPassword>> {
+    let email_or_id = email_or_id.trim();
+    match db.find_user(&normalize_email(email_or_id)).await? {
+        // accounts stored before emails were normalized are found as typed
+        None if normalize_email(email_or_id) != email_or_id => db.find_user(email_or_id).await,
+        user => Ok(user),
+    }
 }
 
 pub async fn save_user_to_db(
-    db: &FirestoreDb,
+    db: &dyn Store,
     user: &UserWithPassword,
     students: Option<Vec<Uuid>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    db.fluent()
-        .insert()
-        .into(USERS_COLLECTION)
-        .document_id(&user.uid.to_string())
-        .object(user)
-        .execute()
-        .await?;
-
-    match students {
-        Some(ids) => {
-            let batch_writer = db.create_simple_batch_writer().await?;
-            let mut current_batch = batch_writer.new_batch();
-            for id in ids {
-                let s_p = StudentsParents {
-                    student_id: id,
-                    parent_id: user.uid,
-                };
-
-                db.fluent()
-                    .update()
-                    .in_col(STUDENTS_PARENTS_COLLECTION)
-                    .document_id(&format!("{}_{}", &s_p.student_id, &s_p.parent_id))
-                    .object(&s_p)
-                    .add_to_batch(&mut current_batch)?;
-            }
+) -> StoreResult<()> {
+    db.insert_user(user).await?;
 
-            let response = current_batch.write().await?;
-            println!("{:?}", response);
-        }
-        _ => {
-            //
-        }
+    if let Some(ids) = students {
+        db.link_students(&user.uid, &ids).await?;
     }
 
     Ok(())
 }
 
-pub async fn try_add_device(
-    db: &FirestoreDb,
-    req: ReqData<TokenClaims>,
-    device_id: String,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    match get_user_by_id(db, &req.id).await? {
-        Some(user) => {
-            let mut devices = user.devices.clone();
-            if !devices.contains(&device_id) {
-                devices.push(device_id);
-            }
+pub async fn try_add_device(db: &dyn Store, user: &User, device_id: String) -> StoreResult<()> {
+    let mut devices = user.devices.clone();
+    if !devices.contains(&device_id) {
+        devices.push(device_id);
+    }
 
-            db.fluent()
-                .update()
-                .fields(paths!(User::{devices}))
-                .in_col(USERS_COLLECTION)
-                .document_id(&user.uid.to_string())
-                .object(&User {
-                    devices,
-                    ..user.clone()
-                })
-                .execute()
-                .await?;
+    db.set_devices(&user.uid, &devices).await
+}
 
-            Ok(())
-        }
-        _ => Err(Box::from(std::io::Error::new(
-            std::io::ErrorKind::NotFound,
-            "data not found".to_string(),
-        ))),
-    }
+pub async fn try_set_preferences(
+    db: &dyn Store,
+    user: &User,
+    preferences: &NotificationPreferences,
+) -> StoreResult<()> {
+    db.set_preferences(&user.uid, preferences).await?;
+    sync_digest_schedule(db, user, preferences.digest).await
+}
+
+/// Hashes on the blocking thread pool, a hash takes long enough to stall
+/// the worker running the request, and imports hash thousands.
+pub async fn hash_password(password: &str) -> StoreResult<String> {
+    let hash_secret = std::env::var("HASH_SECRET").expect("HASH_SECRET must be set!");
+    let password = password.to_string();
+    let hash = web::block(move || {
+        let mut hasher = Hasher::default();
+        hasher
+            .with_password(password)
+            .with_secret_key(hash_secret)
+            .hash()
+            .unwrap()
+    })
+    .await?;
+
+    Ok(hash)
 }
 
 pub async fn make_user(
-    db: &FirestoreDb,
+    db: &dyn Store,
     user: &UserWithPasswordStudents,
-) -> Result<(UserWithPassword, Option<Vec<Uuid>>), Box<dyn std::error::Error>> {
-    let hash_secret = std::env::var("HASH_SECRET").expect("HASH_SECRET must be set!");
-    let mut hasher = Hasher::default();
-    let hash = hasher
-        .with_password(&user.password)
-        .with_secret_key(hash_secret)
-        .hash()
-        .unwrap();
+) -> StoreResult<(UserWithPassword, Option<Vec<Uuid>>)> {
+    let hash = hash_password(&user.password).await?;
 
     let (role, kids) = match &user.role {
         UserRole::Parent => {
             let student_emails = user.students.as_ref().cloned();
             let k = match student_emails {
                 Some(mails) => {
-                    let students: Vec<User> = db
-                        .fluent()
-                        .select()
-                        .from(USERS_COLLECTION)
-                        .filter(|q| q.for_all([q.field(path!(User::email)).is_in(&mails)]))
-                        .obj()
-                        .query()
-                        .await?;
-
+                    let students = db.get_users_by_emails(&email_lookup(&mails)).await?;
                     Some(students.into_iter().map(|s| s.uid).collect::<Vec<_>>())
                 }
                 _ => Some(Vec::new()),
@@ -166,96 +181,41 @@ pub async fn make_user(
     Ok((
         UserWithPassword {
             uid: Uuid::new_v4(),
-            email: user.email.clone(),
+            email: normalize_email(&user.email),
             role,
             name: user.name.clone(),
             password: hash,
             devices: Vec::new(),
+            preferences: NotificationPreferences::default(),
+            deactivated_at: None,
         },
         kids,
     ))
 }
 
-pub async fn try_get_users_from_emails<T: AsRef<str> + Serialize>(
-    db: &FirestoreDb,
+pub async fn try_get_users_from_emails<T: AsRef<str>>(
+    db: &dyn Store,
     emails: &[T],
-) -> Result<Vec<User>, Box<dyn std::error::Error>> {
-    let box_receivers = db
-        .fluent()
-        .select()
-        .from(USERS_COLLECTION)
-        .filter(|q| q.for_any([q.field(path!(User::email)).is_in(emails)]))
-        .obj()
-        .stream_query_with_errors()
-        .await?;
-
-    let receivers: Vec<User> = box_receivers.try_collect().await?;
-    Ok(receivers)
+) -> StoreResult<Vec<User>> {
+    let emails = emails
+        .iter()
+        .map(|e| e.as_ref().to_string())
+        .collect::<Vec<_>>();
+    db.get_users_by_emails(&emails).await
 }
 
-pub async fn try_get_student_parents<T: ToString>(
-    db: &FirestoreDb,
-    user_id: T,
-) -> Result<Vec<User>, Box<dyn std::error::Error>> {
-    let box_students_parents = db
-        .fluent()
-        .select()
-        .from(STUDENTS_PARENTS_COLLECTION)
-        .filter(|q| {
-            q.for_all([
-                //
-                q.field(
-                    //
-                    path!(StudentsParents::student_id),
-                )
-                .eq(&user_id.to_string()),
-            ])
-        })
-        .obj()
-        .stream_query_with_errors()
-        .await?;
-
-    let students_parents: Vec<StudentsParents> = box_students_parents.try_collect().await?;
+pub async fn try_get_student_parents(db: &dyn Store, user_id: &Uuid) -> StoreResult<Vec<User>> {
+    let students_parents = db.list_parents_of(user_id).await?;
     let parents_ids = students_parents
-        .as_slice()
         .iter()
-        .map(|sp| sp.parent_id.to_string())
+        .map(|sp| sp.parent_id)
         .collect::<Vec<_>>();
 
-    let box_users = db
-        .fluent()
-        .select()
-        .from(USERS_COLLECTION)
-        .filter(|q| {
-            q.for_all([
-                //
-                q.field(
-                    //
-                    path!(User::uid),
-                )
-                .is_in(parents_ids.as_slice()),
-            ])
-        })
-        .obj()
-        .stream_query_with_errors()
-        .await?;
-    let parents: Vec<User> = box_users.try_collect().await?;
-    Ok(parents)
+    db.get_users(&parents_ids).await
 }
 
-pub async fn get_system_user(db: &FirestoreDb) -> Result<User, Box<dyn std::error::Error>> {
-    let obj_stream = db
-        .fluent()
-        .select()
-        .from(USERS_COLLECTION)
-        .limit(1)
-        .filter(|q| q.for_all(q.field(path!(User::role)).eq(&UserRole::System)))
-        .obj()
-        .stream_query_with_errors()
-        .await?;
-
-    let to_vec: Vec<User> = obj_stream.try_collect().await?;
-    match to_vec.into_iter().next() {
+pub async fn get_system_user(db: &dyn Store) -> StoreResult<User> {
+    match db.get_system_user().await? {
         Some(u) => Ok(u),
         _ => Err(Box::new(std::io::Error::new(
             std::io::ErrorKind::NotFound,
@@ -264,61 +224,57 @@ pub async fn get_system_user(db: &FirestoreDb) -> Result<User, Box<dyn std::erro
     }
 }
 
-pub async fn get_kids(
-    db: &FirestoreDb,
-    user: &User,
-) -> Result<Vec<Kid>, Box<dyn std::error::Error>> {
-    let mut sp: BoxStream<FirestoreResult<StudentsParents>> = db
-        .fluent()
-        .select()
-        .from(STUDENTS_PARENTS_COLLECTION)
-        .filter(|q| q.for_all([q.field(path!(StudentsParents::parent_id)).eq(&user.uid)]))
-        .obj()
-        .stream_query_with_errors()
-        .await?;
+pub async fn get_kids(db: &dyn Store, user: &User) -> StoreResult<Vec<Kid>> {
+    let students_parents = db.list_students_of(&user.uid).await?;
 
     let mut kids = Vec::new();
-    while let Some(Ok(s)) = sp.next().await {
-        if let Ok(Some(student)) = get_user_by_id(&db, &s.student_id).await {
-            let mut enrollments: BoxStream<FirestoreResult<Enrollment>> = db
-                .fluent()
-                .select()
-                .from(ENROLLMENTS_COLLECTION)
-                .filter(|q| {
-                    q.for_all([
-                        //
-                        q.field(path!(Enrollment::student_id)).eq(&student.uid),
-                    ])
-                })
-                .obj()
-                .stream_query_with_errors()
-                .await?;
+    for s in students_parents {
+        if let Ok(Some(student)) = get_user_by_id(db, &s.student_id).await {
+            let (current, ended): (Vec<_>, Vec<_>) = db
+                .list_student_enrollments(&student.uid)
+                .await?
+                .into_iter()
+                .partition(|e| e.is_active() || e.is_waitlisted() || e.is_pending());
+            let (active, waiting): (Vec<_>, Vec<_>) =
+                current.into_iter().partition(|e| e.is_active());
+            let (waitlisted, pending): (Vec<_>, Vec<_>) =
+                waiting.into_iter().partition(|e| e.is_waitlisted());
 
-            let mut enrollment_ids = Vec::new();
-            while let Some(Ok(enrollment)) = enrollments.next().await {
-                enrollment_ids.push(enrollment.course_id);
-            }
+            let course_ids = active.iter().map(|e| e.course_id).collect::<Vec<_>>();
+            let courses = db.get_courses(&course_ids).await?;
 
-            let courses = db
-                .fluent()
-                .select()
-                .from(COURSES_COLLECTION)
-                .filter(|q| {
-                    q.for_any([
-                        // enrollment course
-                        q.field(path!(Course::id)).is_in(&enrollment_ids),
-                    ])
+            let course_ids = waitlisted.iter().map(|e| e.course_id).collect::<Vec<_>>();
+            let waitlisted_courses = db.get_courses(&course_ids).await?;
+
+            let course_ids = pending.iter().map(|e| e.course_id).collect::<Vec<_>>();
+            let pending_courses = db.get_courses(&course_ids).await?;
+
+            let course_ids = ended.iter().map(|e| e.course_id).collect::<Vec<_>>();
+            let ended_courses = db.get_courses(&course_ids).await?;
+            let mut past_courses = ended
+                .into_iter()
+                .filter_map(|e| {
+                    let course = ended_courses.iter().find(|c| c.id == e.course_id)?;
+                    Some(PastCourse {
+                        course: course.clone(),
+                        status: e.status,
+                        enrolled_at: e.enrolled_at,
+                        ended_at: e.ended_at,
+                    })
                 })
-                .obj()
-                .query()
-                .await?;
+                .collect::<Vec<_>>();
+            // most recently ended first
+            past_courses.sort_by_key(|p| std::cmp::Reverse(p.ended_at));
 
             kids.push(Kid {
                 user: student,
                 courses,
+                waitlisted_courses,
+                pending_courses,
+                past_courses,
             });
         }
     }
-    println!("============== kids {:#?}", kids);
+
     Ok(kids)
 }